@@ -0,0 +1,164 @@
+//throughput.rs
+// Baselines for the hot BalanceManager paths on synthetic state: `cargo bench`.
+// Nothing touches Redis; snapshots go to a temp file.
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use rust_decimal::Decimal;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+use tokio::sync::RwLock;
+
+use engine::balance_manager::{AssetPrice, BalanceManager, Order};
+use engine::config::EngineConfig;
+use engine::processor::Processor;
+use engine::stream_backend::{MemoryBackend, StreamBackend};
+
+// Orders each synthetic user holds, so per-user indexes look like a busy account
+const ORDERS_PER_USER: usize = 10;
+// Open order counts the scan and snapshot benchmarks run at
+const BOOK_SIZES: [usize; 3] = [100, 1000, 10000];
+
+fn create_and_close(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+
+    // Each iteration needs an order that doesn't exist yet (or, for closes, one that does), so
+    // these time batches of `iters` against fresh state
+    c.bench_function("create_order", |b| {
+        b.to_async(&runtime).iter_custom(|iters| async move {
+            let balance_manager = seeded_manager(EngineConfig::default()).await;
+            let started = Instant::now();
+            for i in 0..iters as usize {
+                balance_manager.create_order(bench_order(i)).await.unwrap();
+            }
+            started.elapsed()
+        })
+    });
+
+    c.bench_function("close_order", |b| {
+        b.to_async(&runtime).iter_custom(|iters| async move {
+            let balance_manager = seeded_manager(EngineConfig::default()).await;
+            for i in 0..iters as usize {
+                balance_manager.create_order(bench_order(i)).await.unwrap();
+            }
+            let started = Instant::now();
+            for i in 0..iters as usize {
+                balance_manager
+                    .close_order(&format!("bench-{}", i), None, None, None)
+                    .await
+                    .unwrap();
+            }
+            started.elapsed()
+        })
+    });
+}
+
+fn liquidation_scan(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("check_liquidations");
+    for orders in BOOK_SIZES {
+        let balance_manager = runtime.block_on(filled_manager(EngineConfig::default(), orders));
+        group.bench_with_input(BenchmarkId::from_parameter(orders), &orders, |b, _| {
+            b.to_async(&runtime)
+                .iter(|| balance_manager.check_liquidations(true))
+        });
+    }
+    group.finish();
+}
+
+// Snapshot cost grows with state, so it is timed at each book size
+fn snapshot(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("snapshot");
+    group
+        .sample_size(10)
+        .measurement_time(Duration::from_secs(10));
+    for orders in BOOK_SIZES {
+        let path = std::env::temp_dir().join(format!(
+            "engine-bench-{}-{}.json",
+            orders,
+            std::process::id()
+        ));
+        let config = EngineConfig {
+            snapshot_enabled: true,
+            snapshot_path: path.to_string_lossy().to_string(),
+            snapshot_retention: 1,
+            ..EngineConfig::default()
+        };
+        let balance_manager = Arc::new(runtime.block_on(filled_manager(config.clone(), orders)));
+        let backend: Arc<RwLock<dyn StreamBackend>> = Arc::new(RwLock::new(MemoryBackend::new(0)));
+        let processor = Processor::new(backend.clone(), balance_manager, config.clone());
+
+        group.bench_with_input(BenchmarkId::new("save", orders), &orders, |b, _| {
+            b.to_async(&runtime)
+                .iter(|| async { processor.save_snapshot().await.unwrap() })
+        });
+        group.bench_with_input(BenchmarkId::new("load", orders), &orders, |b, _| {
+            b.to_async(&runtime).iter(|| async {
+                let restored = Processor::new(
+                    backend.clone(),
+                    Arc::new(BalanceManager::new(config.clone())),
+                    config.clone(),
+                );
+                restored.load_snapshot().await.unwrap()
+            })
+        });
+
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(format!("{}.gz", path.to_string_lossy()));
+    }
+    group.finish();
+}
+
+async fn seeded_manager(config: EngineConfig) -> BalanceManager {
+    let balance_manager = BalanceManager::new(config);
+    balance_manager
+        .update_price(AssetPrice {
+            symbol: "BTC".to_string(),
+            buy_price: Decimal::from(1000),
+            sell_price: Decimal::from(999),
+            decimals: 4,
+            mark_price: None,
+            ema_price: None,
+            tick_seq: 0,
+            updated_at: 0,
+            bid_size: None,
+            ask_size: None,
+        })
+        .await
+        .unwrap();
+    balance_manager
+}
+
+async fn filled_manager(config: EngineConfig, orders: usize) -> BalanceManager {
+    let balance_manager = seeded_manager(config).await;
+    for i in 0..orders {
+        balance_manager.create_order(bench_order(i)).await.unwrap();
+    }
+    balance_manager
+}
+
+fn bench_order(i: usize) -> Order {
+    Order {
+        order_id: format!("bench-{}", i),
+        user_id: format!("bench-user-{}", i / ORDERS_PER_USER),
+        asset: "BTC".to_string(),
+        order_type: if i.is_multiple_of(2) { "long" } else { "short" }.to_string(),
+        margin: Decimal::from(10),
+        leverage: 10,
+        open_price: Decimal::ZERO,
+        quantity: Decimal::ZERO,
+        timestamp: chrono::Utc::now().timestamp(),
+        breach_since: None,
+        breach_ticks: 0,
+        breach_tick_seq: 0,
+        client_ref: None,
+        extra_margin: Decimal::ZERO,
+        stream_id: None,
+        realized_pnl: Decimal::ZERO,
+        decimals: None,
+        tag: None,
+    }
+}
+
+criterion_group!(benches, create_and_close, liquidation_scan, snapshot);
+criterion_main!(benches);
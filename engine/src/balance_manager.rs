@@ -1,8 +1,15 @@
 //balance_manager.rs
+use crate::config::{BookSizePolicy, EngineConfig, HaltPolicy, StaleFeedPolicy};
+use crate::pnl::{InversePnl, LinearPnl, PnlModel};
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
-use std::collections::{BTreeMap, HashMap};
-use tokio::sync::RwLock;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex as StdMutex};
+use tokio::sync::{Mutex, OwnedMutexGuard, RwLock};
+use tracing::{error, info, warn};
+
+// Returned when an order was closed or liquidated before this attempt to settle it
+pub const ALREADY_SETTLED: &str = "Order already settled";
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Order {
@@ -15,6 +22,83 @@ pub struct Order {
     pub open_price: Decimal,
     pub quantity: Decimal,
     pub timestamp: i64,
+    // When the mark price first crossed the liquidation price (ms), reset once it recovers
+    #[serde(default)]
+    pub breach_since: Option<i64>,
+    // Consecutive price ticks the breach has been seen on, and the last tick counted
+    #[serde(default)]
+    pub breach_ticks: u32,
+    #[serde(default)]
+    pub breach_tick_seq: u64,
+    // Opaque client reference echoed back on every response and record about this order
+    #[serde(default)]
+    pub client_ref: Option<String>,
+    // Collateral added (or removed) after the open via ADJUST_MARGIN; position size is unchanged
+    #[serde(default)]
+    pub extra_margin: Decimal,
+    // Id of the orders stream entry that created this order
+    #[serde(default)]
+    pub stream_id: Option<String>,
+    // Pnl already booked by partial closes of this position
+    #[serde(default)]
+    pub realized_pnl: Decimal,
+    // Price decimals of the feed when the order filled; None for orders from older snapshots
+    #[serde(default)]
+    pub decimals: Option<u32>,
+    // Trader-assigned label GET_ORDERS can filter on, e.g. "swing"
+    #[serde(default)]
+    pub tag: Option<String>,
+}
+
+impl Order {
+    pub fn notional(&self) -> Decimal {
+        self.margin * Decimal::from(self.leverage)
+    }
+
+    // Everything the position stands to lose: the opening margin plus any adjustment
+    pub fn collateral(&self) -> Decimal {
+        self.margin + self.extra_margin
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TriggerType {
+    // Fills once the entry price reaches a level at least as good as the trigger
+    Limit,
+    // Fills once the entry price moves through the trigger against the order (momentum entry)
+    Stop,
+}
+
+// An order waiting for its trigger; margin is only taken when it fills
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingOrder {
+    pub order: Order,
+    pub trigger_price: Decimal,
+    pub trigger_type: TriggerType,
+    // Quantity already opened by earlier partial fills; order.quantity is what is left
+    #[serde(default)]
+    pub filled_quantity: Decimal,
+}
+
+impl PendingOrder {
+    pub fn is_triggered(&self, price_info: &AssetPrice) -> bool {
+        let is_long = self.order.order_type == "long";
+        let entry_price = if is_long {
+            price_info.buy_price
+        } else {
+            price_info.sell_price
+        };
+
+        match (self.trigger_type, is_long) {
+            (TriggerType::Limit, true) | (TriggerType::Stop, false) => {
+                entry_price <= self.trigger_price
+            }
+            (TriggerType::Limit, false) | (TriggerType::Stop, true) => {
+                entry_price >= self.trigger_price
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -23,12 +107,184 @@ pub struct AssetPrice {
     pub buy_price: Decimal,
     pub sell_price: Decimal,
     pub decimals: u32,
+    // Smoothed mid price used for liquidations; fills always use the live buy/sell
+    #[serde(default)]
+    pub mark_price: Option<Decimal>,
+    // Exponential moving average of the mid price, smoothed by price_ema_alpha
+    #[serde(default)]
+    pub ema_price: Option<Decimal>,
+    // Number of updates received for this asset; lets liquidations count distinct ticks
+    #[serde(default)]
+    pub tick_seq: u64,
+    // When (ms) the engine last received an update for this asset
+    #[serde(default)]
+    pub updated_at: i64,
+    // Top-of-book sizes, when the feed reports them
+    #[serde(default)]
+    pub bid_size: Option<Decimal>,
+    #[serde(default)]
+    pub ask_size: Option<Decimal>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PricePoint {
+    pub timestamp: i64,
+    pub buy_price: Decimal,
+    pub sell_price: Decimal,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EquityPoint {
+    pub timestamp: i64,
+    pub equity: Decimal,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UserBalance {
     pub usd_balance: Decimal,
     pub asset_balances: HashMap<String, (Decimal, u32)>, // For actual owned assets (not leveraged positions)
+    // Used when an order doesn't specify leverage
+    #[serde(default)]
+    pub default_leverage: Option<u32>,
+    #[serde(default)]
+    pub risk_profile: Option<RiskProfile>,
+    // Last time (secs) the user opened, closed or queried anything; 0 when restored from an older snapshot
+    #[serde(default)]
+    pub last_active: i64,
+    // When (secs) one of the user's orders was last liquidated
+    #[serde(default)]
+    pub last_liquidation_at: Option<i64>,
+    // Last order submission (ms) per asset, for min_order_interval_ms
+    #[serde(default)]
+    pub last_order_at: HashMap<String, i64>,
+}
+
+// Isolated positions are backed by their own collateral only; cross positions by the account
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MarginMode {
+    Isolated,
+    Cross,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RiskProfile {
+    Conservative,
+    Aggressive,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClosedOrder {
+    pub order_id: String,
+    pub user_id: String,
+    pub asset: String,
+    pub order_type: String,
+    pub margin: Decimal,
+    pub leverage: u32,
+    pub open_price: Decimal,
+    pub close_price: Decimal,
+    pub quantity: Decimal,
+    pub pnl: Decimal,
+    pub open_time: i64,
+    pub close_time: i64,
+    pub liquidated: bool,
+    #[serde(default)]
+    pub fee: Decimal,
+    #[serde(default)]
+    pub fee_rebate: Decimal,
+    #[serde(default)]
+    pub client_ref: Option<String>,
+    #[serde(default)]
+    pub stream_id: Option<String>,
+}
+
+impl ClosedOrder {
+    pub fn from_order(order: &Order, close_price: Decimal, pnl: Decimal, liquidated: bool) -> Self {
+        Self {
+            order_id: order.order_id.clone(),
+            user_id: order.user_id.clone(),
+            asset: order.asset.clone(),
+            order_type: order.order_type.clone(),
+            margin: order.collateral(),
+            leverage: order.leverage,
+            open_price: order.open_price,
+            close_price,
+            quantity: order.quantity,
+            pnl,
+            open_time: order.timestamp,
+            close_time: chrono::Utc::now().timestamp(),
+            liquidated,
+            fee: Decimal::ZERO,
+            fee_rebate: Decimal::ZERO,
+            client_ref: order.client_ref.clone(),
+            stream_id: order.stream_id.clone(),
+        }
+    }
+
+    // What was credited back to the user's balance when the order was settled
+    pub fn settled_amount(&self) -> Decimal {
+        self.margin + self.pnl - self.fee + self.fee_rebate
+    }
+}
+
+// Running platform totals since the engine started (not snapshotted), kept in step with
+// settlements so reading them never scans history
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PlatformStats {
+    pub realized_pnl: Decimal,
+    pub fees_collected: Decimal,
+    pub liquidations: u64,
+}
+
+// A user's open notional against their equity (balance plus collateral and unrealized pnl of
+// open orders), overall and per asset. Leverage is None while equity is not positive.
+#[derive(Debug, Clone)]
+pub struct LeverageUtilization {
+    pub equity: Decimal,
+    pub total_notional: Decimal,
+    pub effective_leverage: Option<Decimal>,
+    pub assets: BTreeMap<String, Decimal>,
+}
+
+// One funding run: the rate charged per asset and each user's net payment (negative when
+// charged), which is what the db_queue record carries
+#[derive(Debug, Clone, Default)]
+pub struct FundingRun {
+    pub rates: Vec<(String, Decimal)>,
+    pub payments: BTreeMap<String, Decimal>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TradeStatistics {
+    pub total_trades: usize,
+    pub win_count: usize,
+    pub loss_count: usize,
+    pub win_rate: Decimal,
+    pub average_win: Decimal,
+    pub average_loss: Decimal,
+    pub largest_drawdown: Decimal,
+}
+
+#[derive(Debug, Clone)]
+pub struct SpotFill {
+    pub asset: String,
+    pub side: String,
+    pub quantity: Decimal,
+    pub price: Decimal,
+    pub fee: Decimal,
+    pub usd_balance: Decimal,
+    pub asset_balance: Decimal,
+    pub decimals: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct AdlEvent {
+    pub order_id: String,
+    pub user_id: String,
+    pub pnl: Decimal,
+    pub haircut: Decimal,
+    pub settled_amount: Decimal,
+    pub client_ref: Option<String>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -38,7 +294,39 @@ pub struct LiquidationEntry {
     pub liquidation_price: Decimal,
 }
 
+type UserLockMap = Arc<StdMutex<HashMap<String, Arc<Mutex<()>>>>>;
+
+// Held across one user-scoped mutation; see BalanceManager::lock_user. The map entry goes away
+// with the last holder or waiter.
+pub struct UserLock {
+    user_id: String,
+    locks: UserLockMap,
+    _guard: OwnedMutexGuard<()>,
+}
+
+impl Drop for UserLock {
+    fn drop(&mut self) {
+        let mut locks = self.locks.lock().unwrap_or_else(|e| e.into_inner());
+        // One reference is the map's and one is our guard's; any more are queued waiters
+        if locks
+            .get(&self.user_id)
+            .is_some_and(|lock| Arc::strong_count(lock) <= 2)
+        {
+            locks.remove(&self.user_id);
+        }
+    }
+}
+
+// Lock order. Any method holding more than one of these locks takes them in this order,
+// skipping the ones it doesn't need; a lock released before the next is taken doesn't count:
+//   users -> orders_by_id -> orders_by_user -> liquidation_map -> asset_prices
+//   -> price_history -> recent_price_updates -> dirty_assets -> closed_orders -> insurance_fund -> disabled_assets
+//   -> pending_orders -> equity_history -> total_open_notional -> platform_stats
+// A per-user lock from lock_user is taken before any of them.
+// tokio's RwLock queues new readers behind a waiting writer, so two readers taking the
+// same pair in opposite orders can deadlock against a writer just as two writers would.
 pub struct BalanceManager {
+    pub config: EngineConfig,
     pub users: RwLock<HashMap<String, UserBalance>>,
     // Fast order lookup by order_id
     pub orders_by_id: RwLock<HashMap<String, Order>>,
@@ -47,354 +335,3936 @@ pub struct BalanceManager {
     // Liquidation tracking: asset -> BTreeMap<liquidation_price, Vec<LiquidationEntry>>
     pub liquidation_map: RwLock<HashMap<String, BTreeMap<String, Vec<LiquidationEntry>>>>, // Using String keys for BTreeMap to handle Decimal sorting
     pub asset_prices: RwLock<HashMap<String, AssetPrice>>,
+    // Bounded ring buffer of recent prices per asset (not snapshotted)
+    pub price_history: RwLock<HashMap<String, VecDeque<PricePoint>>>,
+    // When each asset's last min_recent_price_updates ticks arrived, in ms (not snapshotted)
+    pub recent_price_updates: RwLock<HashMap<String, VecDeque<i64>>>,
+    // Realized history per user: user_id -> closed orders in close order
+    pub closed_orders: RwLock<HashMap<String, Vec<ClosedOrder>>>,
+    // Running total of open notional, kept in step with orders_by_id
+    pub total_open_notional: RwLock<Decimal>,
+    // Absorbs liquidation leftovers and covers bankruptcy shortfalls
+    pub insurance_fund: RwLock<Decimal>,
+    // Assets an operator has halted: no new opens; closes and liquidations follow
+    // config.halt_close_policy and config.halt_suspends_liquidations
+    pub disabled_assets: RwLock<HashSet<String>>,
+    // Limit and stop orders waiting for their trigger: order_id -> pending order
+    pub pending_orders: RwLock<HashMap<String, PendingOrder>>,
+    // Bounded per-user equity series sampled by the equity task (not snapshotted)
+    pub equity_history: RwLock<HashMap<String, VecDeque<EquityPoint>>>,
+    // Assets whose price moved since the last liquidation scan (not snapshotted)
+    pub dirty_assets: RwLock<HashSet<String>>,
+    pub platform_stats: RwLock<PlatformStats>,
+    // Per-asset pnl model overrides; assets not listed use LinearPnl
+    pnl_models: HashMap<String, Box<dyn PnlModel>>,
+    // Spot holdings every new user starts with, parsed from config.initial_asset_balances
+    initial_holdings: HashMap<String, (Decimal, u32)>,
+    // (min_notional, maintenance rate) bands from config.maintenance_tiers, ascending
+    maintenance_bands: Vec<(Decimal, Decimal)>,
+    // Serializes each user's mutations: user_id -> lock, present only while held or awaited
+    user_locks: UserLockMap,
 }
 
 impl BalanceManager {
-    pub fn new() -> Self {
+    pub fn new(config: EngineConfig) -> Self {
+        let mut pnl_models: HashMap<String, Box<dyn PnlModel>> = HashMap::new();
+        for asset in config.inverse_assets.split(',').map(str::trim) {
+            if !asset.is_empty() {
+                pnl_models.insert(asset.to_string(), Box::new(InversePnl));
+            }
+        }
+
+        // validate() reports a malformed list at startup; fall back to no holdings here
+        let initial_holdings = config.initial_holdings().unwrap_or_default();
+        let maintenance_bands = config.maintenance_bands().unwrap_or_default();
+
         Self {
+            config,
             users: RwLock::new(HashMap::new()),
             orders_by_id: RwLock::new(HashMap::new()),
             orders_by_user: RwLock::new(HashMap::new()),
             liquidation_map: RwLock::new(HashMap::new()),
             asset_prices: RwLock::new(HashMap::new()),
+            price_history: RwLock::new(HashMap::new()),
+            recent_price_updates: RwLock::new(HashMap::new()),
+            closed_orders: RwLock::new(HashMap::new()),
+            total_open_notional: RwLock::new(Decimal::ZERO),
+            insurance_fund: RwLock::new(Decimal::ZERO),
+            disabled_assets: RwLock::new(HashSet::new()),
+            pending_orders: RwLock::new(HashMap::new()),
+            equity_history: RwLock::new(HashMap::new()),
+            dirty_assets: RwLock::new(HashSet::new()),
+            platform_stats: RwLock::new(PlatformStats::default()),
+            initial_holdings,
+            maintenance_bands,
+            pnl_models,
+            user_locks: Arc::new(StdMutex::new(HashMap::new())),
         }
     }
 
-    pub async fn get_or_create_user(&self, user_id: &str) -> UserBalance {
-        let mut users = self.users.write().await;
-        users
-            .entry(user_id.to_string())
-            .or_insert_with(|| UserBalance {
-                usd_balance: Decimal::from(5000), // Initialize new user with $5000
-                asset_balances: HashMap::new(),
-            })
-            .clone()
+    // Recomputes the running notional total from open orders, e.g. after a snapshot load
+    pub async fn rebuild_open_notional(&self) {
+        let orders_by_id = self.orders_by_id.read().await;
+        let total: Decimal = orders_by_id.values().map(|order| order.notional()).sum();
+        *self.total_open_notional.write().await = total;
     }
 
-    pub async fn update_price(&self, asset_price: AssetPrice) {
-        let mut prices = self.asset_prices.write().await;
-        prices.insert(asset_price.symbol.clone(), asset_price);
-    }
+    // Safety net for removal paths that miss cleanup: drops empty price levels, empty
+    // per-asset maps and empty per-user order lists. Returns how many entries were pruned.
+    pub async fn compact_maps(&self) -> usize {
+        let mut orders_by_user = self.orders_by_user.write().await;
+        let mut liquidation_map = self.liquidation_map.write().await;
+        let mut pruned = 0;
 
-    pub async fn get_price(&self, symbol: &str) -> Option<AssetPrice> {
-        let prices = self.asset_prices.read().await;
-        prices.get(symbol).cloned()
-    }
+        let users_before = orders_by_user.len();
+        orders_by_user.retain(|_, order_ids| !order_ids.is_empty());
+        pruned += users_before - orders_by_user.len();
 
-    pub async fn create_order(&self, mut order: Order) -> Result<(), String> {
-        let mut users = self.users.write().await;
+        for asset_liquidations in liquidation_map.values_mut() {
+            let levels_before = asset_liquidations.len();
+            asset_liquidations.retain(|_, entries| !entries.is_empty());
+            pruned += levels_before - asset_liquidations.len();
+        }
 
-        // Ensure user exists
-        let user_balance = users
-            .entry(order.user_id.clone())
-            .or_insert_with(|| UserBalance {
-                usd_balance: Decimal::from(5000),
-                asset_balances: HashMap::new(),
-            });
+        let assets_before = liquidation_map.len();
+        liquidation_map.retain(|_, asset_liquidations| !asset_liquidations.is_empty());
+        pruned += assets_before - liquidation_map.len();
 
-        let required_margin = order.margin;
+        // Left behind when a lock_user caller was cancelled while waiting
+        let mut user_locks = self.user_locks.lock().unwrap_or_else(|e| e.into_inner());
+        let locks_before = user_locks.len();
+        user_locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+        pruned += locks_before - user_locks.len();
 
-        if user_balance.usd_balance < required_margin {
-            return Err("Insufficient balance".to_string());
+        pruned
+    }
+
+    // Makes a user's multi-lock operations (open, close, reset, spot, margin changes) one
+    // logical transaction: holders for the same user run one at a time, other users are
+    // unaffected. Take it before any field lock and hold it until the mutation is done.
+    pub async fn lock_user(&self, user_id: &str) -> UserLock {
+        let lock = self
+            .user_locks
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(user_id.to_string())
+            .or_default()
+            .clone();
+        UserLock {
+            user_id: user_id.to_string(),
+            locks: self.user_locks.clone(),
+            _guard: lock.lock_owned().await,
         }
+    }
 
-        // Get current price
-        let current_price = {
-            let prices = self.asset_prices.read().await;
-            prices
-                .get(&order.asset)
-                .map(|p| {
-                    if order.order_type == "long" {
-                        p.buy_price
-                    } else {
-                        p.sell_price
-                    }
-                })
-                .ok_or("Asset price not available")?
-        };
+    pub async fn set_asset_tradeable(&self, symbol: &str, tradeable: bool) {
+        let mut disabled_assets = self.disabled_assets.write().await;
+        if tradeable {
+            disabled_assets.remove(symbol);
+        } else {
+            disabled_assets.insert(symbol.to_string());
+        }
+    }
 
-        order.open_price = current_price;
-        order.quantity = (order.margin * Decimal::from(order.leverage)) / current_price;
+    pub async fn is_asset_tradeable(&self, symbol: &str) -> bool {
+        !self.disabled_assets.read().await.contains(symbol)
+    }
 
-        // Calculate liquidation price
-        let liquidation_price = self.calculate_liquidation_price(&order);
+    // Rejects a submission that follows the user's previous one on the same asset within
+    // min_order_interval_ms, otherwise stamps it. A stamped submission counts even if the open fails.
+    pub async fn throttle_order(&self, user_id: &str, asset: &str) -> Result<(), String> {
+        let interval = self.config.min_order_interval_ms;
+        if interval <= 0 {
+            return Ok(());
+        }
 
-        // Deduct margin from user balance
-        user_balance.usd_balance -= required_margin;
+        let mut users = self.users.write().await;
+        let user_balance = users
+            .entry(user_id.to_string())
+            .or_insert_with(|| self.new_user_balance());
 
-        // Store the order in fast lookup map
+        let now = chrono::Utc::now().timestamp_millis();
+        let previous = user_balance.last_order_at.insert(asset.to_string(), now);
+        if let Some(previous) = previous
+            && now - previous < interval
         {
-            let mut orders_by_id = self.orders_by_id.write().await;
-            orders_by_id.insert(order.order_id.clone(), order.clone());
+            // Keep the window anchored at the last accepted submission
+            user_balance
+                .last_order_at
+                .insert(asset.to_string(), previous);
+            return Err("Too frequent".to_string());
         }
+        Ok(())
+    }
 
-        // Add to user's order list
-        {
-            let mut orders_by_user = self.orders_by_user.write().await;
-            orders_by_user
-                .entry(order.user_id.clone())
-                .or_insert_with(Vec::new)
-                .push(order.order_id.clone());
+    pub async fn place_pending_order(&self, pending: PendingOrder) -> Result<(), String> {
+        if !self.is_asset_tradeable(&pending.order.asset).await {
+            return Err("Trading disabled for asset".to_string());
+        }
+        if pending.trigger_price <= Decimal::ZERO {
+            return Err("Trigger price must be positive".to_string());
         }
 
-        // Add to liquidation map
-        {
-            let mut liquidation_map = self.liquidation_map.write().await;
-            let asset_liquidations = liquidation_map
-                .entry(order.asset.clone())
-                .or_insert_with(BTreeMap::new);
-
-            let liquidation_entry = LiquidationEntry {
-                order_id: order.order_id.clone(),
-                user_id: order.user_id.clone(),
-                liquidation_price,
-            };
-
-            // Use liquidation price as string key for BTreeMap
-            let price_key = liquidation_price.to_string();
-            asset_liquidations
-                .entry(price_key)
-                .or_insert_with(Vec::new)
-                .push(liquidation_entry);
+        let order_id = pending.order.order_id.clone();
+        if self.orders_by_id.read().await.contains_key(&order_id) {
+            return Err("Duplicate order id".to_string());
         }
 
+        let mut pending_orders = self.pending_orders.write().await;
+        if pending_orders.contains_key(&order_id) {
+            return Err("Duplicate order id".to_string());
+        }
+        pending_orders.insert(order_id, pending);
         Ok(())
     }
 
-    pub async fn close_order(&self, order_id: &str) -> Result<(Decimal, String), String> {
-        println!("Attempting to close order: {}", order_id);
+    // Removes and returns the pending orders on an asset whose trigger the current price has hit,
+    // oldest first
+    pub async fn take_triggered_orders(&self, asset: &str) -> Vec<PendingOrder> {
+        let Some(price_info) = self.asset_prices.read().await.get(asset).cloned() else {
+            return Vec::new();
+        };
 
-        let mut users = self.users.write().await;
+        let mut pending_orders = self.pending_orders.write().await;
+        let triggered_ids: Vec<String> = pending_orders
+            .values()
+            .filter(|p| p.order.asset == asset && p.is_triggered(&price_info))
+            .map(|p| p.order.order_id.clone())
+            .collect();
+
+        let mut triggered: Vec<PendingOrder> = triggered_ids
+            .iter()
+            .filter_map(|order_id| pending_orders.remove(order_id))
+            .collect();
+        triggered.sort_by(|a, b| {
+            (a.order.timestamp, &a.order.order_id).cmp(&(b.order.timestamp, &b.order.order_id))
+        });
+        triggered
+    }
+
+    // Puts a partly filled pending order back to wait for its next tick
+    pub async fn requeue_pending_order(&self, pending: PendingOrder) {
+        let mut pending_orders = self.pending_orders.write().await;
+        pending_orders.insert(pending.order.order_id.clone(), pending);
+    }
+
+    // Folds a newly opened fill slice into the open position it belongs to: sizes add up and
+    // the open price becomes the quantity-weighted average of the two
+    pub async fn merge_fill(&self, order_id: &str, slice_id: &str) -> Result<Order, String> {
         let mut orders_by_id = self.orders_by_id.write().await;
         let mut orders_by_user = self.orders_by_user.write().await;
         let mut liquidation_map = self.liquidation_map.write().await;
 
-        // Fast lookup by order_id
-        let order = orders_by_id.remove(order_id).ok_or_else(|| {
-            println!("Order {} not found", order_id);
-            "Order not found".to_string()
-        })?;
-
-        println!("Order found: {:?}", order);
+        if !orders_by_id.contains_key(order_id) {
+            return Err("Order not found".to_string());
+        }
+        let slice = self
+            .detach_order(
+                slice_id,
+                &mut orders_by_id,
+                &mut orders_by_user,
+                &mut liquidation_map,
+            )
+            .await
+            .ok_or("Fill not found")?;
+        let mut order = self
+            .detach_order(
+                order_id,
+                &mut orders_by_id,
+                &mut orders_by_user,
+                &mut liquidation_map,
+            )
+            .await
+            .ok_or("Order not found")?;
 
-        // Remove from user's order list
-        if let Some(user_orders) = orders_by_user.get_mut(&order.user_id) {
-            user_orders.retain(|id| id != order_id);
-            if user_orders.is_empty() {
-                orders_by_user.remove(&order.user_id);
-            }
+        let quantity = order.quantity + slice.quantity;
+        if quantity > Decimal::ZERO {
+            order.open_price =
+                (order.open_price * order.quantity + slice.open_price * slice.quantity) / quantity;
         }
+        order.quantity = quantity;
+        order.margin += slice.margin;
+        order.extra_margin += slice.extra_margin;
+        order.breach_since = None;
+        order.breach_ticks = 0;
 
-        // Remove from liquidation map
-        if let Some(asset_liquidations) = liquidation_map.get_mut(&order.asset) {
-            let liquidation_price = self.calculate_liquidation_price(&order);
-            let price_key = liquidation_price.to_string();
+        self.index_order(
+            &order,
+            &mut orders_by_id,
+            &mut orders_by_user,
+            &mut liquidation_map,
+        );
+        *self.total_open_notional.write().await += order.notional();
+        Ok(order)
+    }
 
-            if let Some(entries) = asset_liquidations.get_mut(&price_key) {
-                entries.retain(|entry| entry.order_id != order_id);
-                if entries.is_empty() {
-                    asset_liquidations.remove(&price_key);
-                }
-            }
+    fn new_user_balance(&self) -> UserBalance {
+        UserBalance {
+            usd_balance: Decimal::from(5000), // Initialize new user with $5000
+            asset_balances: self.initial_holdings.clone(),
+            default_leverage: None,
+            risk_profile: None,
+            last_active: chrono::Utc::now().timestamp(),
+            last_liquidation_at: None,
+            last_order_at: HashMap::new(),
+        }
+    }
 
-            if asset_liquidations.is_empty() {
-                liquidation_map.remove(&order.asset);
-            }
+    pub async fn set_risk_profile(
+        &self,
+        user_id: &str,
+        risk_profile: RiskProfile,
+        default_leverage: Option<u32>,
+    ) -> Result<(), String> {
+        if default_leverage == Some(0) {
+            return Err("Default leverage must be at least 1".to_string());
         }
 
-        let user_balance = users.get_mut(&order.user_id).ok_or_else(|| {
-            println!("User {} not found in users map", order.user_id);
-            "User not found".to_string()
-        })?;
+        let mut users = self.users.write().await;
+        let user_balance = users
+            .entry(user_id.to_string())
+            .or_insert_with(|| self.new_user_balance());
+        user_balance.last_active = chrono::Utc::now().timestamp();
+        user_balance.risk_profile = Some(risk_profile);
+        if default_leverage.is_some() {
+            user_balance.default_leverage = default_leverage;
+        }
+        Ok(())
+    }
 
-        // Get current price
-        let current_price = {
-            let prices = self.asset_prices.read().await;
-            let price_info = prices.get(&order.asset).ok_or_else(|| {
-                println!("Asset price not available for {}", order.asset);
-                "Asset price not available".to_string()
-            })?;
+    // Falls back to the user's default leverage, then clamps to the global and profile caps
+    pub async fn resolve_leverage(
+        &self,
+        user_id: &str,
+        requested: Option<u32>,
+    ) -> Result<u32, String> {
+        let users = self.users.read().await;
+        let user_balance = users.get(user_id);
 
-            let price = if order.order_type == "long" {
-                price_info.sell_price
-            } else {
-                price_info.buy_price
-            };
+        let leverage = requested
+            .or_else(|| user_balance.and_then(|u| u.default_leverage))
+            .ok_or("Leverage not specified and no default leverage set")?;
+        if leverage == 0 {
+            return Err("Leverage must be at least 1".to_string());
+        }
 
-            println!("Current price for {}: {}", order.asset, price);
-            price
+        let cap = match user_balance.and_then(|u| u.risk_profile) {
+            Some(RiskProfile::Conservative) => self
+                .config
+                .max_leverage
+                .min(self.config.conservative_max_leverage),
+            _ => self.config.max_leverage,
         };
 
-        let pnl = self.calculate_pnl(&order, current_price);
-        let close_amount = order.margin + pnl;
+        Ok(leverage.min(cap.max(1)))
+    }
 
-        println!(
-            "PnL: {}, Close amount: {}, User balance before: {}",
-            pnl, close_amount, user_balance.usd_balance
-        );
+    // Drops orders_by_user / liquidation_map references to orders that no longer exist.
+    // These derived indexes can drift when a removal path recomputes a key differently.
+    pub async fn prune_dangling_references(&self) -> usize {
+        let orders_by_id = self.orders_by_id.read().await;
+        let mut orders_by_user = self.orders_by_user.write().await;
+        let mut liquidation_map = self.liquidation_map.write().await;
+        let mut pruned = 0;
 
-        // Return funds to user
-        user_balance.usd_balance += close_amount;
+        for order_ids in orders_by_user.values_mut() {
+            let before = order_ids.len();
+            order_ids.retain(|id| orders_by_id.contains_key(id));
+            pruned += before - order_ids.len();
+        }
+        orders_by_user.retain(|_, order_ids| !order_ids.is_empty());
 
-        println!("User balance after: {}", user_balance.usd_balance);
+        for levels in liquidation_map.values_mut() {
+            for entries in levels.values_mut() {
+                let before = entries.len();
+                entries.retain(|entry| orders_by_id.contains_key(&entry.order_id));
+                pruned += before - entries.len();
+            }
+            levels.retain(|_, entries| !entries.is_empty());
+        }
+        liquidation_map.retain(|_, levels| !levels.is_empty());
 
-        Ok((pnl, format!("Order closed at price {}", current_price)))
+        pruned
     }
 
-    pub async fn check_liquidations(&self) -> Vec<(String, String)> {
+    // Cross-checks the order indexes and balances; returns a description of every violation
+    pub async fn check_invariants(&self) -> Vec<String> {
+        let users = self.users.read().await;
+        let orders_by_id = self.orders_by_id.read().await;
+        let orders_by_user = self.orders_by_user.read().await;
         let liquidation_map = self.liquidation_map.read().await;
-        let prices = self.asset_prices.read().await;
-        let mut liquidated_orders = Vec::new();
+        let mut violations = Vec::new();
 
-        for (asset, asset_liquidations) in liquidation_map.iter() {
-            if let Some(price_info) = prices.get(asset) {
-                let current_price =
-                    (price_info.buy_price + price_info.sell_price) / Decimal::from(2);
-
-                // For each asset, check liquidation prices in order
-                for (price_key, entries) in asset_liquidations.iter() {
-                    if let Ok(liquidation_price) = price_key.parse::<Decimal>() {
-                        // Check if current price has crossed liquidation threshold
-                        let should_liquidate = entries.iter().any(|entry| {
-                            if let Ok(order) = self.orders_by_id.try_read() {
-                                if let Some(order) = order.get(&entry.order_id) {
-                                    if order.order_type == "long" {
-                                        current_price <= liquidation_price
-                                    } else {
-                                        current_price >= liquidation_price
-                                    }
-                                } else {
-                                    false
-                                }
-                            } else {
-                                false
-                            }
-                        });
-
-                        if should_liquidate {
-                            for entry in entries {
-                                liquidated_orders
-                                    .push((entry.order_id.clone(), entry.user_id.clone()));
-                            }
-                        }
-                    }
-                }
+        for (user_id, user_balance) in users.iter() {
+            if user_balance.usd_balance < Decimal::ZERO {
+                violations.push(format!("User {} has a negative balance", user_id));
             }
         }
 
-        liquidated_orders
-    }
+        for (order_id, order) in orders_by_id.iter() {
+            let listed = orders_by_user
+                .get(&order.user_id)
+                .is_some_and(|ids| ids.contains(order_id));
+            if !listed {
+                violations.push(format!("Order {} missing from orders_by_user", order_id));
+            }
 
-    pub async fn liquidate_order(&self, order_id: &str) -> Result<(), String> {
-        let mut orders_by_id = self.orders_by_id.write().await;
-        let mut orders_by_user = self.orders_by_user.write().await;
-        let mut liquidation_map = self.liquidation_map.write().await;
+            let tracked = liquidation_map.get(&order.asset).is_some_and(|levels| {
+                levels
+                    .values()
+                    .any(|entries| entries.iter().any(|e| &e.order_id == order_id))
+            });
+            if !tracked {
+                violations.push(format!("Order {} missing from liquidation_map", order_id));
+            }
+        }
 
-        // Fast removal by order_id
-        let order = orders_by_id.remove(order_id).ok_or("Order not found")?;
+        for (user_id, order_ids) in orders_by_user.iter() {
+            for order_id in order_ids {
+                if !orders_by_id.contains_key(order_id) {
+                    violations.push(format!(
+                        "orders_by_user lists unknown order {} for {}",
+                        order_id, user_id
+                    ));
+                }
+            }
+        }
 
-        // Remove from user's order list
-        if let Some(user_orders) = orders_by_user.get_mut(&order.user_id) {
-            user_orders.retain(|id| id != order_id);
-            if user_orders.is_empty() {
-                orders_by_user.remove(&order.user_id);
+        for levels in liquidation_map.values() {
+            for entry in levels.values().flatten() {
+                if !orders_by_id.contains_key(&entry.order_id) {
+                    violations.push(format!(
+                        "liquidation_map tracks unknown order {}",
+                        entry.order_id
+                    ));
+                }
             }
         }
 
-        // Remove from liquidation map
-        if let Some(asset_liquidations) = liquidation_map.get_mut(&order.asset) {
-            let liquidation_price = self.calculate_liquidation_price(&order);
-            let price_key = liquidation_price.to_string();
+        let total: Decimal = orders_by_id.values().map(|order| order.notional()).sum();
+        if total != *self.total_open_notional.read().await {
+            violations.push("total_open_notional out of sync with open orders".to_string());
+        }
 
-            if let Some(entries) = asset_liquidations.get_mut(&price_key) {
-                entries.retain(|entry| entry.order_id != order_id);
-                if entries.is_empty() {
-                    asset_liquidations.remove(&price_key);
+        violations
+    }
+
+    // Long and short open notional for an asset
+    pub async fn open_interest(&self, asset: &str) -> (Decimal, Decimal) {
+        let orders_by_id = self.orders_by_id.read().await;
+        let positions: Vec<&Order> = orders_by_id.values().filter(|o| o.asset == asset).collect();
+        split_notional(&positions)
+    }
+
+    // Positive when longs dominate (longs pay shorts), negative when shorts dominate
+    pub fn funding_rate(&self, long_notional: Decimal, short_notional: Decimal) -> Decimal {
+        let total = long_notional + short_notional;
+        if total <= Decimal::ZERO {
+            return Decimal::ZERO;
+        }
+
+        let max_rate = self.config.funding_max_rate;
+        let rate = self.config.funding_sensitivity * (long_notional - short_notional) / total;
+        rate.max(-max_rate).min(max_rate)
+    }
+
+    // Charges the dominant side rate * notional and splits what was collected across the
+    // other side pro rata by notional. Returns the rate for every asset charged and what each
+    // user paid or received.
+    pub async fn apply_funding(&self) -> FundingRun {
+        let mut users = self.users.write().await;
+        let orders_by_id = self.orders_by_id.read().await;
+
+        let mut assets: Vec<&String> = orders_by_id.values().map(|o| &o.asset).collect();
+        assets.sort();
+        assets.dedup();
+
+        let mut run = FundingRun::default();
+        for asset in assets {
+            let positions: Vec<&Order> = orders_by_id
+                .values()
+                .filter(|o| &o.asset == asset)
+                .collect();
+            let (long_notional, short_notional) = split_notional(&positions);
+
+            let rate = self.funding_rate(long_notional, short_notional);
+            if rate == Decimal::ZERO {
+                continue;
+            }
+
+            let (payer_side, receiver_notional) = if rate > Decimal::ZERO {
+                ("long", short_notional)
+            } else {
+                ("short", long_notional)
+            };
+            if receiver_notional <= Decimal::ZERO {
+                continue;
+            }
+
+            // Payers are never charged more than their free balance
+            let mut collected = Decimal::ZERO;
+            for order in positions.iter().filter(|o| o.order_type == payer_side) {
+                if let Some(user_balance) = users.get_mut(&order.user_id) {
+                    let payment = self
+                        .round_money(order.notional() * rate.abs())
+                        .min(user_balance.usd_balance.max(Decimal::ZERO));
+                    user_balance.usd_balance -= payment;
+                    collected += payment;
+                    *run.payments.entry(order.user_id.clone()).or_default() -= payment;
                 }
             }
 
-            if asset_liquidations.is_empty() {
-                liquidation_map.remove(&order.asset);
+            for order in positions.iter().filter(|o| o.order_type != payer_side) {
+                if let Some(user_balance) = users.get_mut(&order.user_id) {
+                    // Truncated so the receivers never get more than was collected
+                    let share = (collected * order.notional() / receiver_notional)
+                        .round_dp_with_strategy(
+                            self.config.money_decimals,
+                            rust_decimal::RoundingStrategy::ToZero,
+                        );
+                    user_balance.usd_balance += share;
+                    *run.payments.entry(order.user_id.clone()).or_default() += share;
+                }
             }
+
+            run.rates.push((asset.clone(), rate));
         }
 
-        Ok(())
+        run
     }
 
-    fn calculate_pnl(&self, order: &Order, current_price: Decimal) -> Decimal {
-        if order.order_type == "long" {
-            (current_price - order.open_price) * order.quantity
-        } else {
-            (order.open_price - current_price) * order.quantity
+    // CSV dumps of open orders and user balances, sorted by id.
+    // Orders: order_id,user_id,asset,type,margin,leverage,open_price,quantity,timestamp
+    // Balances: user_id,usd_balance,default_leverage,risk_profile
+    pub async fn export_state_csv(&self) -> (String, String) {
+        let users = self.users.read().await;
+        let orders_by_id = self.orders_by_id.read().await;
+
+        let mut orders: Vec<&Order> = orders_by_id.values().collect();
+        orders.sort_by(|a, b| a.order_id.cmp(&b.order_id));
+        let mut orders_csv =
+            "order_id,user_id,asset,type,margin,leverage,open_price,quantity,timestamp\n"
+                .to_string();
+        for order in orders {
+            orders_csv.push_str(&format!(
+                "{},{},{},{},{},{},{},{},{}\n",
+                csv_field(&order.order_id),
+                csv_field(&order.user_id),
+                csv_field(&order.asset),
+                csv_field(&order.order_type),
+                order.margin,
+                order.leverage,
+                order.open_price,
+                order.quantity,
+                order.timestamp
+            ));
+        }
+
+        let mut user_ids: Vec<&String> = users.keys().collect();
+        user_ids.sort();
+        let mut balances_csv = "user_id,usd_balance,default_leverage,risk_profile\n".to_string();
+        for user_id in user_ids {
+            let balance = &users[user_id];
+            let risk_profile = match balance.risk_profile {
+                Some(RiskProfile::Conservative) => "conservative",
+                Some(RiskProfile::Aggressive) => "aggressive",
+                None => "",
+            };
+            balances_csv.push_str(&format!(
+                "{},{},{},{}\n",
+                csv_field(user_id),
+                balance.usd_balance,
+                balance
+                    .default_leverage
+                    .map(|l| l.to_string())
+                    .unwrap_or_default(),
+                risk_profile
+            ));
         }
+
+        (orders_csv, balances_csv)
     }
 
-    fn calculate_liquidation_price(&self, order: &Order) -> Decimal {
-        let liquidation_threshold = Decimal::from(90) / Decimal::from(order.leverage * 100);
+    pub async fn get_or_create_user(&self, user_id: &str) -> UserBalance {
+        let mut users = self.users.write().await;
+        let user_balance = users
+            .entry(user_id.to_string())
+            .or_insert_with(|| self.new_user_balance());
+        user_balance.last_active = chrono::Utc::now().timestamp();
+        user_balance.clone()
+    }
 
-        if order.order_type == "long" {
-            // For long positions, liquidation happens when price drops
-            order.open_price * (Decimal::from(1) - liquidation_threshold)
+    // Once more than max_tracked_users are held, evicts the least recently active users that
+    // have no open or pending orders, hold no spot assets beyond what a new user starts with,
+    // and have been idle for user_idle_secs. Returns the evicted ids.
+    pub async fn evict_idle_users(&self) -> Vec<String> {
+        let max_users = self.config.max_tracked_users;
+        let mut users = self.users.write().await;
+        if max_users == 0 || users.len() <= max_users {
+            return Vec::new();
+        }
+
+        let orders_by_user = self.orders_by_user.read().await;
+        // A pending order that triggers later would otherwise recreate its owner from scratch
+        let pending_owners: HashSet<String> = self
+            .pending_orders
+            .read()
+            .await
+            .values()
+            .map(|pending| pending.order.user_id.clone())
+            .collect();
+        let now = chrono::Utc::now().timestamp();
+        let mut candidates: Vec<(i64, String)> = Vec::new();
+        for (user_id, user_balance) in users.iter_mut() {
+            // Users restored without activity info start their idle clock now
+            if user_balance.last_active == 0 {
+                user_balance.last_active = now;
+            }
+            let has_orders = orders_by_user
+                .get(user_id)
+                .is_some_and(|order_ids| !order_ids.is_empty())
+                || pending_owners.contains(user_id);
+            let has_holdings = user_balance
+                .asset_balances
+                .iter()
+                .any(|(asset, (amount, _))| {
+                    !amount.is_zero()
+                        && self.initial_holdings.get(asset).map(|(initial, _)| initial)
+                            != Some(amount)
+                });
+            if !has_orders
+                && !has_holdings
+                && now - user_balance.last_active >= self.config.user_idle_secs
+            {
+                candidates.push((user_balance.last_active, user_id.clone()));
+            }
+        }
+        candidates.sort();
+
+        let excess = users.len() - max_users;
+        let evicted: Vec<String> = candidates
+            .into_iter()
+            .take(excess)
+            .map(|(_, user_id)| user_id)
+            .collect();
+        for user_id in &evicted {
+            users.remove(user_id);
+        }
+
+        let mut closed_orders = self.closed_orders.write().await;
+        let mut equity_history = self.equity_history.write().await;
+        for user_id in &evicted {
+            closed_orders.remove(user_id);
+            equity_history.remove(user_id);
+        }
+
+        evicted
+    }
+
+    // Puts one user back to a fresh account: open and pending orders are cancelled without
+    // settlement (the balance is reset anyway), closed order and equity history are dropped, and
+    // the balance and holdings return to what a new user starts with. Returns the cancelled ids.
+    pub async fn reset_account(&self, user_id: &str) -> Vec<String> {
+        let mut users = self.users.write().await;
+        let mut orders_by_id = self.orders_by_id.write().await;
+        let mut orders_by_user = self.orders_by_user.write().await;
+        let mut liquidation_map = self.liquidation_map.write().await;
+
+        let mut cancelled = orders_by_user.get(user_id).cloned().unwrap_or_default();
+        for order_id in &cancelled {
+            self.detach_order(
+                order_id,
+                &mut orders_by_id,
+                &mut orders_by_user,
+                &mut liquidation_map,
+            )
+            .await;
+        }
+
+        self.closed_orders.write().await.remove(user_id);
+        {
+            let mut pending_orders = self.pending_orders.write().await;
+            pending_orders.retain(|order_id, pending| {
+                let owned = pending.order.user_id == user_id;
+                if owned {
+                    cancelled.push(order_id.clone());
+                }
+                !owned
+            });
+        }
+        self.equity_history.write().await.remove(user_id);
+
+        // Risk settings and the liquidation cooldown and order throttle survive the reset, so
+        // a reset can't be used to skip them
+        let mut fresh = self.new_user_balance();
+        if let Some(previous) = users.get(user_id) {
+            fresh.default_leverage = previous.default_leverage;
+            fresh.risk_profile = previous.risk_profile;
+            fresh.last_liquidation_at = previous.last_liquidation_at;
+            fresh.last_order_at = previous.last_order_at.clone();
+        }
+        users.insert(user_id.to_string(), fresh);
+        cancelled
+    }
+
+    pub async fn update_price(&self, mut asset_price: AssetPrice) -> Result<(), String> {
+        let mut prices = self.asset_prices.write().await;
+
+        // A decimals change mid-flight would silently rescale stored balances
+        if let Some(existing) = prices.get(&asset_price.symbol)
+            && existing.decimals != asset_price.decimals
+        {
+            if !self.config.allow_decimals_change {
+                return Err(format!(
+                    "Decimals mismatch for {}: expected {}, got {}",
+                    asset_price.symbol, existing.decimals, asset_price.decimals
+                ));
+            }
+            warn!(
+                "Decimals for {} changed from {} to {}",
+                asset_price.symbol, existing.decimals, asset_price.decimals
+            );
+        }
+
+        let mid = (asset_price.buy_price + asset_price.sell_price) / Decimal::from(2);
+        let previous = prices.get(&asset_price.symbol);
+        asset_price.tick_seq = previous.map_or(1, |p| p.tick_seq + 1);
+        asset_price.updated_at = chrono::Utc::now().timestamp_millis();
+
+        asset_price.ema_price = Some(ema(
+            previous.and_then(|p| p.ema_price),
+            mid,
+            self.config.price_ema_alpha,
+        ));
+
+        let period = self.config.mark_price_ema_period;
+        asset_price.mark_price = if period > 1 {
+            let alpha = Decimal::from(2) / Decimal::from(period + 1);
+            Some(ema(previous.and_then(|p| p.mark_price), mid, alpha))
         } else {
-            // For short positions, liquidation happens when price rises
-            order.open_price * (Decimal::from(1) + liquidation_threshold)
+            Some(mid)
+        };
+
+        let history_len = self.config.price_history_len;
+        if history_len > 0 {
+            let mut price_history = self.price_history.write().await;
+            let history = price_history
+                .entry(asset_price.symbol.clone())
+                .or_insert_with(VecDeque::new);
+            history.push_back(PricePoint {
+                timestamp: chrono::Utc::now().timestamp_millis(),
+                buy_price: asset_price.buy_price,
+                sell_price: asset_price.sell_price,
+            });
+            while history.len() > history_len {
+                history.pop_front();
+            }
+        }
+
+        // Only the latest K arrival times matter: the gate passes when the oldest is recent
+        let min_updates = self.config.min_recent_price_updates;
+        if min_updates > 0 {
+            let mut recent_price_updates = self.recent_price_updates.write().await;
+            let updates = recent_price_updates
+                .entry(asset_price.symbol.clone())
+                .or_insert_with(VecDeque::new);
+            updates.push_back(asset_price.updated_at);
+            while updates.len() > min_updates {
+                updates.pop_front();
+            }
         }
+
+        self.dirty_assets
+            .write()
+            .await
+            .insert(asset_price.symbol.clone());
+        prices.insert(asset_price.symbol.clone(), asset_price);
+        Ok(())
     }
 
-    pub async fn get_user_balance_usd(&self, user_id: &str) -> Result<Decimal, String> {
-        let users = self.users.read().await;
-        let balance = users.get(user_id).ok_or("User not found")?;
-        Ok(balance.usd_balance)
+    // Liquidity freshness gate: false until the asset has had min_recent_price_updates ticks
+    // within the last recent_price_window_secs
+    async fn has_recent_price_updates(&self, symbol: &str) -> bool {
+        let min_updates = self.config.min_recent_price_updates;
+        if min_updates == 0 {
+            return true;
+        }
+        let window_start =
+            chrono::Utc::now().timestamp_millis() - self.config.recent_price_window_secs * 1000;
+        self.recent_price_updates
+            .read()
+            .await
+            .get(symbol)
+            .is_some_and(|updates| {
+                updates.len() >= min_updates
+                    && updates
+                        .front()
+                        .is_some_and(|oldest| *oldest >= window_start)
+            })
     }
 
-    pub async fn get_user_positions(&self, user_id: &str) -> Result<Vec<(Order, Decimal)>, String> {
-        let orders_by_user = self.orders_by_user.read().await;
-        let orders_by_id = self.orders_by_id.read().await;
+    pub async fn get_price_history(&self, symbol: &str) -> Vec<PricePoint> {
+        let price_history = self.price_history.read().await;
+        price_history
+            .get(symbol)
+            .map(|history| history.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub async fn get_price(&self, symbol: &str) -> Option<AssetPrice> {
         let prices = self.asset_prices.read().await;
-        let mut positions = Vec::new();
+        prices.get(symbol).cloned()
+    }
 
-        if let Some(user_order_ids) = orders_by_user.get(user_id) {
-            for order_id in user_order_ids {
-                if let Some(order) = orders_by_id.get(order_id) {
-                    if let Some(price_info) = prices.get(&order.asset) {
-                        let current_price =
-                            (price_info.buy_price + price_info.sell_price) / Decimal::from(2);
-                        let pnl = self.calculate_pnl(order, current_price);
-                        positions.push((order.clone(), pnl));
-                    }
-                }
-            }
+    // Whether the asset's feed has been silent past feed_timeout_secs
+    fn is_feed_stale(&self, price_info: &AssetPrice) -> bool {
+        let timeout = self.config.feed_timeout_secs;
+        timeout > 0
+            && chrono::Utc::now().timestamp_millis() - price_info.updated_at > timeout * 1000
+    }
+
+    // Open orders to force-close under StaleFeedPolicy::Close: those on assets whose feed has
+    // been silent past feed_timeout_secs
+    pub async fn stale_feed_orders(&self) -> Vec<String> {
+        if self.config.stale_feed_policy != StaleFeedPolicy::Close {
+            return Vec::new();
         }
 
-        Ok(positions)
+        let orders_by_id = self.orders_by_id.read().await;
+        let prices = self.asset_prices.read().await;
+        let mut order_ids: Vec<String> = orders_by_id
+            .values()
+            .filter(|order| {
+                prices
+                    .get(&order.asset)
+                    .is_some_and(|p| self.is_feed_stale(p))
+            })
+            .map(|order| order.order_id.clone())
+            .collect();
+        order_ids.sort();
+        order_ids
     }
 
-    pub async fn get_user_balance(
+    // Non-leveraged exchange between usd_balance and asset_balances at the current price.
+    // Buys pay the buy price plus the spot fee; sells receive the sell price less the fee.
+    pub async fn spot_trade(
         &self,
         user_id: &str,
-    ) -> Result<HashMap<String, (Decimal, u32)>, String> {
-        let users = self.users.read().await;
+        asset: &str,
+        side: &str,
+        quantity: Decimal,
+    ) -> Result<SpotFill, String> {
+        if quantity <= Decimal::ZERO {
+            return Err("Quantity must be positive".to_string());
+        }
+        if !self.is_asset_tradeable(asset).await {
+            return Err("Trading disabled for asset".to_string());
+        }
 
-        if let Some(user_balance) = users.get(user_id) {
-            Ok(user_balance.asset_balances.clone())
-        } else {
-            Ok(HashMap::new())
+        let mut users = self.users.write().await;
+        let user_balance = users
+            .entry(user_id.to_string())
+            .or_insert_with(|| self.new_user_balance());
+        user_balance.last_active = chrono::Utc::now().timestamp();
+
+        let price_info = {
+            let prices = self.asset_prices.read().await;
+            prices
+                .get(asset)
+                .cloned()
+                .ok_or("Asset price not available")?
+        };
+
+        if self.is_feed_stale(&price_info) {
+            return Err("Price feed down for asset".to_string());
+        }
+
+        let price = match side {
+            "buy" => price_info.buy_price,
+            "sell" => price_info.sell_price,
+            _ => return Err("Side must be buy or sell".to_string()),
+        };
+        if price <= Decimal::ZERO || price < self.config.min_fill_price {
+            return Err("Price below minimum fill price".to_string());
         }
+
+        let value = self.round_money(quantity * price);
+        let fee = self.round_money(value * self.config.spot_fee_bps / Decimal::from(10000));
+        let held = user_balance
+            .asset_balances
+            .get(asset)
+            .map(|(balance, _)| *balance)
+            .unwrap_or(Decimal::ZERO);
+
+        // Both sides are checked before either is touched
+        let asset_balance = if side == "buy" {
+            if user_balance.usd_balance < value + fee {
+                return Err("Insufficient balance".to_string());
+            }
+            user_balance.usd_balance -= value + fee;
+            held + quantity
+        } else {
+            if held < quantity {
+                return Err("Insufficient holdings".to_string());
+            }
+            user_balance.usd_balance += value - fee;
+            held - quantity
+        };
+
+        let decimals = user_balance
+            .asset_balances
+            .get(asset)
+            .map(|(_, decimals)| *decimals)
+            .unwrap_or(price_info.decimals);
+        user_balance
+            .asset_balances
+            .insert(asset.to_string(), (asset_balance, decimals));
+        self.platform_stats.write().await.fees_collected += fee;
+
+        Ok(SpotFill {
+            asset: asset.to_string(),
+            side: side.to_string(),
+            quantity,
+            price,
+            fee,
+            usd_balance: user_balance.usd_balance,
+            asset_balance,
+            decimals,
+        })
     }
 
-    pub async fn get_user_orders(&self, user_id: &str) -> Vec<Order> {
-        let orders_by_user = self.orders_by_user.read().await;
-        let orders_by_id = self.orders_by_id.read().await;
-        let mut user_orders = Vec::new();
+    pub async fn create_order(&self, mut order: Order) -> Result<Order, String> {
+        if !self.is_asset_tradeable(&order.asset).await {
+            return Err("Trading disabled for asset".to_string());
+        }
 
-        if let Some(user_order_ids) = orders_by_user.get(user_id) {
-            for order_id in user_order_ids {
-                if let Some(order) = orders_by_id.get(order_id) {
-                    user_orders.push(order.clone());
+        // Held until the margin is deducted: the balance check and the deduction must stay one
+        // critical section per user, or concurrent opens could overdraw together
+        let mut users = self.users.write().await;
+
+        // Ensure user exists
+        let user_balance = users
+            .entry(order.user_id.clone())
+            .or_insert_with(|| self.new_user_balance());
+
+        let now = chrono::Utc::now().timestamp();
+        user_balance.last_active = now;
+
+        let cooldown = self.config.liquidation_cooldown_secs;
+        if cooldown > 0
+            && let Some(liquidated_at) = user_balance.last_liquidation_at
+            && now - liquidated_at < cooldown
+        {
+            return Err(format!(
+                "Cooldown active: {}s remaining",
+                cooldown - (now - liquidated_at)
+            ));
+        }
+
+        // Orders carry either a margin or, when margin is zero, a target quantity
+        let by_quantity = order.margin.is_zero() && order.quantity > Decimal::ZERO;
+
+        if user_balance.usd_balance < order.margin {
+            return Err("Insufficient balance".to_string());
+        }
+
+        // Get current price
+        let price_info = {
+            let prices = self.asset_prices.read().await;
+            prices
+                .get(&order.asset)
+                .cloned()
+                .ok_or("Asset price not available")?
+        };
+
+        let max_spread = self.config.max_relative_spread;
+        if max_spread > Decimal::ZERO {
+            let mid = (price_info.buy_price + price_info.sell_price) / Decimal::from(2);
+            if mid <= Decimal::ZERO
+                || (price_info.buy_price - price_info.sell_price) / mid > max_spread
+            {
+                return Err("Spread too wide".to_string());
+            }
+        }
+
+        if self.is_feed_stale(&price_info) {
+            return Err("Price feed down for asset".to_string());
+        }
+
+        if !self.has_recent_price_updates(&order.asset).await {
+            return Err("Not enough recent price updates for asset".to_string());
+        }
+
+        let current_price = if order.order_type == "long" {
+            price_info.buy_price
+        } else {
+            price_info.sell_price
+        };
+
+        // Near-zero prices blow quantity (and every pnl derived from it) up
+        if current_price <= Decimal::ZERO || current_price < self.config.min_fill_price {
+            return Err("Price below minimum fill price".to_string());
+        }
+
+        order.open_price = current_price;
+        order.decimals = Some(price_info.decimals);
+        if by_quantity {
+            order.margin = order.quantity * current_price / Decimal::from(order.leverage);
+            if user_balance.usd_balance < order.margin {
+                return Err("Insufficient balance".to_string());
+            }
+        } else {
+            order.quantity = (order.margin * Decimal::from(order.leverage)) / current_price;
+        }
+
+        // Longs take the ask side of the book, shorts the bid side
+        let available = if order.order_type == "long" {
+            price_info.ask_size
+        } else {
+            price_info.bid_size
+        };
+        if let Some(available) = available
+            && order.quantity > available
+        {
+            match self.config.book_size_policy {
+                BookSizePolicy::Ignore => {}
+                BookSizePolicy::Reject => {
+                    return Err("Order exceeds available size at top of book".to_string());
+                }
+                BookSizePolicy::Cap => {
+                    order.quantity = available;
+                    order.margin = available * current_price / Decimal::from(order.leverage);
                 }
             }
         }
 
-        user_orders
+        // Keep sub-precision dust out of the ledger: reject in strict mode, round otherwise
+        let rounded_margin = self.round_money(order.margin);
+        if self.config.strict_ledger {
+            let notional = order.notional();
+            if rounded_margin != order.margin || self.round_money(notional) != notional {
+                return Err("Margin or notional not representable at quote precision".to_string());
+            }
+        } else if rounded_margin != order.margin {
+            order.margin = rounded_margin;
+            if user_balance.usd_balance < order.margin {
+                return Err("Insufficient balance".to_string());
+            }
+        }
+        if order.margin <= Decimal::ZERO {
+            return Err("Margin too small".to_string());
+        }
+
+        // A margin too small for the price buys a position worth nothing that still locks margin
+        if order.quantity <= Decimal::ZERO || order.quantity < self.config.min_order_quantity {
+            return Err("Order too small for asset price".to_string());
+        }
+
+        let max_quantity = self.config.max_order_quantity;
+        if max_quantity > Decimal::ZERO && order.quantity > max_quantity {
+            return Err("Quantity exceeds maximum".to_string());
+        }
+
+        // Calculate liquidation price
+        let liquidation_price = self.calculate_liquidation_price(&order);
+
+        // Reject positions that would already be past liquidation at the current close price
+        let exit_price = close_side_price(&order, &price_info);
+        let already_crossed = if order.order_type == "long" {
+            exit_price <= liquidation_price
+        } else {
+            exit_price >= liquidation_price
+        };
+        if already_crossed {
+            return Err("Order would immediately liquidate".to_string());
+        }
+
+        // Reserve platform capacity
+        {
+            let mut total_open_notional = self.total_open_notional.write().await;
+            let cap = self.config.max_platform_notional;
+            if cap > Decimal::ZERO && *total_open_notional + order.notional() > cap {
+                return Err("Platform capacity reached".to_string());
+            }
+            *total_open_notional += order.notional();
+        }
+
+        // Deduct margin from user balance
+        user_balance.usd_balance -= order.margin;
+
+        let mut orders_by_id = self.orders_by_id.write().await;
+        let mut orders_by_user = self.orders_by_user.write().await;
+        let mut liquidation_map = self.liquidation_map.write().await;
+        self.index_order(
+            &order,
+            &mut orders_by_id,
+            &mut orders_by_user,
+            &mut liquidation_map,
+        );
+
+        Ok(order)
+    }
+
+    // Moves free balance into (positive amount) or out of (negative amount) an open order's
+    // collateral and re-indexes it at the resulting liquidation price
+    pub async fn adjust_margin(&self, order_id: &str, amount: Decimal) -> Result<Order, String> {
+        if amount.is_zero() {
+            return Err("Amount must not be zero".to_string());
+        }
+
+        let mut users = self.users.write().await;
+        let mut orders_by_id = self.orders_by_id.write().await;
+        let mut orders_by_user = self.orders_by_user.write().await;
+        let mut liquidation_map = self.liquidation_map.write().await;
+
+        let mut order = orders_by_id
+            .get(order_id)
+            .cloned()
+            .ok_or("Order not found")?;
+        let user_balance = users.get_mut(&order.user_id).ok_or("User not found")?;
+
+        if amount > Decimal::ZERO && user_balance.usd_balance < amount {
+            return Err("Insufficient balance".to_string());
+        }
+
+        order.extra_margin += amount;
+        if order.collateral() <= Decimal::ZERO {
+            return Err("Cannot remove more margin than the position holds".to_string());
+        }
+
+        if amount < Decimal::ZERO {
+            let prices = self.asset_prices.read().await;
+            let price_info = prices
+                .get(&order.asset)
+                .ok_or("Asset price not available")?;
+            let exit_price = close_side_price(&order, price_info);
+            let liquidation_price = self.calculate_liquidation_price(&order);
+            let buffer = exit_price * self.config.margin_removal_buffer_pct / Decimal::from(100);
+            let too_close = if order.order_type == "long" {
+                exit_price - buffer <= liquidation_price
+            } else {
+                exit_price + buffer >= liquidation_price
+            };
+            if too_close {
+                return Err(
+                    "Margin removal would put the position too close to liquidation".to_string(),
+                );
+            }
+        }
+
+        // Re-index under the new liquidation price; the position size (notional) is unchanged
+        self.detach_order(
+            order_id,
+            &mut orders_by_id,
+            &mut orders_by_user,
+            &mut liquidation_map,
+        )
+        .await;
+        *self.total_open_notional.write().await += order.notional();
+        order.breach_since = None;
+        order.breach_ticks = 0;
+        self.index_order(
+            &order,
+            &mut orders_by_id,
+            &mut orders_by_user,
+            &mut liquidation_map,
+        );
+
+        user_balance.usd_balance -= amount;
+        Ok(order)
+    }
+
+    // Inserts an already-filled order (e.g. from a replayed event log) into every index
+    pub async fn restore_order(&self, order: Order) {
+        *self.total_open_notional.write().await += order.notional();
+
+        let mut orders_by_id = self.orders_by_id.write().await;
+        let mut orders_by_user = self.orders_by_user.write().await;
+        let mut liquidation_map = self.liquidation_map.write().await;
+        self.index_order(
+            &order,
+            &mut orders_by_id,
+            &mut orders_by_user,
+            &mut liquidation_map,
+        );
+    }
+
+    // Derives orders_by_user and liquidation_map from orders_by_id, replacing whatever they held.
+    // Orders are indexed oldest first (ties by id), so the result doesn't depend on map order.
+    pub async fn rebuild_order_indexes(&self) {
+        let mut orders_by_id = self.orders_by_id.write().await;
+        let mut orders_by_user = self.orders_by_user.write().await;
+        let mut liquidation_map = self.liquidation_map.write().await;
+
+        let mut orders: Vec<Order> = orders_by_id.values().cloned().collect();
+        orders.sort_by(|a, b| (a.timestamp, &a.order_id).cmp(&(b.timestamp, &b.order_id)));
+
+        orders_by_user.clear();
+        liquidation_map.clear();
+        for order in &orders {
+            self.index_order(
+                order,
+                &mut orders_by_id,
+                &mut orders_by_user,
+                &mut liquidation_map,
+            );
+        }
+    }
+
+    // Removes an order from every index without settling it
+    pub async fn remove_order(&self, order_id: &str) -> Option<Order> {
+        let mut orders_by_id = self.orders_by_id.write().await;
+        let mut orders_by_user = self.orders_by_user.write().await;
+        let mut liquidation_map = self.liquidation_map.write().await;
+        self.detach_order(
+            order_id,
+            &mut orders_by_id,
+            &mut orders_by_user,
+            &mut liquidation_map,
+        )
+        .await
+    }
+
+    // Adds an order to every index. The caller must hold the write locks passed in.
+    fn index_order(
+        &self,
+        order: &Order,
+        orders_by_id: &mut HashMap<String, Order>,
+        orders_by_user: &mut HashMap<String, Vec<String>>,
+        liquidation_map: &mut HashMap<String, BTreeMap<String, Vec<LiquidationEntry>>>,
+    ) {
+        // Store the order in fast lookup map
+        orders_by_id.insert(order.order_id.clone(), order.clone());
+
+        // Add to user's order list
+        orders_by_user
+            .entry(order.user_id.clone())
+            .or_default()
+            .push(order.order_id.clone());
+
+        // Add to liquidation map
+        let liquidation_price = self.calculate_liquidation_price(order);
+        let liquidation_entry = LiquidationEntry {
+            order_id: order.order_id.clone(),
+            user_id: order.user_id.clone(),
+            liquidation_price,
+        };
+
+        // Use liquidation price as string key for BTreeMap
+        let price_key = liquidation_price.to_string();
+        liquidation_map
+            .entry(order.asset.clone())
+            .or_default()
+            .entry(price_key)
+            .or_default()
+            .push(liquidation_entry);
+    }
+
+    // Closes `fraction` of the position (all of it when None) at the current close-side price.
+    // A partial close settles the closed slice and returns the remainder, which keeps its order
+    // id and liquidation price. min_price and max_price reject the close outright, leaving the
+    // position untouched, when the close price falls outside them.
+    pub async fn close_order(
+        &self,
+        order_id: &str,
+        fraction: Option<Decimal>,
+        min_price: Option<Decimal>,
+        max_price: Option<Decimal>,
+    ) -> Result<(ClosedOrder, Option<Order>), String> {
+        self.close_order_with(order_id, fraction, min_price, max_price, None)
+            .await
+    }
+
+    // Operator override that settles the whole position at an agreed price instead of the feed,
+    // e.g. to resolve a dispute over a bad tick. Accounting is the same as a normal close.
+    pub async fn close_order_at_price(
+        &self,
+        order_id: &str,
+        price: Decimal,
+    ) -> Result<(ClosedOrder, Option<Order>), String> {
+        if price <= Decimal::ZERO {
+            return Err("Settlement price must be positive".to_string());
+        }
+        self.close_order_with(order_id, None, None, None, Some(price))
+            .await
+    }
+
+    // Shared by closes at the feed price and override settlements (settle_price)
+    async fn close_order_with(
+        &self,
+        order_id: &str,
+        fraction: Option<Decimal>,
+        min_price: Option<Decimal>,
+        max_price: Option<Decimal>,
+        settle_price: Option<Decimal>,
+    ) -> Result<(ClosedOrder, Option<Order>), String> {
+        println!("Attempting to close order: {}", order_id);
+
+        let fraction = fraction.unwrap_or(Decimal::ONE);
+        if fraction <= Decimal::ZERO || fraction > Decimal::ONE {
+            return Err("Close fraction must be in (0, 1]".to_string());
+        }
+
+        let mut users = self.users.write().await;
+        let mut orders_by_id = self.orders_by_id.write().await;
+        let mut orders_by_user = self.orders_by_user.write().await;
+        let mut liquidation_map = self.liquidation_map.write().await;
+
+        // Everything that can reject the close is checked before the order is detached
+        let Some(order) = orders_by_id.get(order_id).cloned() else {
+            println!("Order {} not found", order_id);
+            return Err(self.missing_order_error(order_id).await);
+        };
+
+        println!("Order found: {:?}", order);
+
+        // An operator settlement is how positions get resolved while closes are blocked
+        if settle_price.is_none()
+            && self.config.halt_close_policy == HaltPolicy::Block
+            && !self.is_asset_tradeable(&order.asset).await
+        {
+            return Err("Trading halted for asset".to_string());
+        }
+
+        let user_balance = users.get_mut(&order.user_id).ok_or_else(|| {
+            println!("User {} not found in users map", order.user_id);
+            "User not found".to_string()
+        })?;
+
+        // Get current price
+        let current_price = if let Some(price) = settle_price {
+            info!("Settling {} at override price {}", order_id, price);
+            price
+        } else {
+            let prices = self.asset_prices.read().await;
+            let price_info = prices.get(&order.asset).ok_or_else(|| {
+                println!("Asset price not available for {}", order.asset);
+                "Asset price not available".to_string()
+            })?;
+
+            if self.config.stale_feed_policy == StaleFeedPolicy::Freeze
+                && self.is_feed_stale(price_info)
+            {
+                return Err("Price feed down for asset, positions are frozen".to_string());
+            }
+
+            let price = self.stressed_close_price(&order, close_side_price(&order, price_info));
+
+            println!("Current price for {}: {}", order.asset, price);
+            price
+        };
+
+        if min_price.is_some_and(|min| current_price < min)
+            || max_price.is_some_and(|max| current_price > max)
+        {
+            return Err(format!(
+                "Close price {} outside requested limits",
+                current_price
+            ));
+        }
+
+        let (order, mut remaining) = if fraction < Decimal::ONE {
+            let mut closing = order.clone();
+            closing.margin = self.round_money(order.margin * fraction);
+            closing.extra_margin = self.round_money(order.extra_margin * fraction);
+            closing.quantity = order.quantity * fraction;
+
+            let mut remaining = order.clone();
+            remaining.margin -= closing.margin;
+            remaining.extra_margin -= closing.extra_margin;
+            remaining.quantity -= closing.quantity;
+            if closing.margin <= Decimal::ZERO || remaining.margin <= Decimal::ZERO {
+                return Err("Close fraction leaves an empty slice".to_string());
+            }
+            (closing, Some(remaining))
+        } else {
+            (order, None)
+        };
+
+        self.detach_order(
+            order_id,
+            &mut orders_by_id,
+            &mut orders_by_user,
+            &mut liquidation_map,
+        )
+        .await;
+
+        user_balance.last_active = chrono::Utc::now().timestamp();
+
+        let (fee, fee_rebate) = self.calculate_close_fee(&order, current_price);
+        let (close_amount, pnl) = self.settle(
+            order.collateral(),
+            self.unrounded_pnl(&order, current_price),
+            fee,
+            fee_rebate,
+        );
+
+        println!(
+            "PnL: {}, Close amount: {}, User balance before: {}",
+            pnl, close_amount, user_balance.usd_balance
+        );
+
+        // Return funds to user
+        user_balance.usd_balance += close_amount;
+
+        println!("User balance after: {}", user_balance.usd_balance);
+
+        let mut closed_order = ClosedOrder::from_order(&order, current_price, pnl, false);
+        closed_order.fee = fee;
+        closed_order.fee_rebate = fee_rebate;
+        self.record_closed_order(closed_order.clone()).await;
+
+        // The remainder goes back under the same id, re-indexed at its reduced size
+        if let Some(remaining) = remaining.as_mut() {
+            remaining.realized_pnl += pnl;
+            *self.total_open_notional.write().await += remaining.notional();
+            self.index_order(
+                remaining,
+                &mut orders_by_id,
+                &mut orders_by_user,
+                &mut liquidation_map,
+            );
+        }
+
+        Ok((closed_order, remaining))
+    }
+
+    // Scans only assets whose price moved since the last call unless full_scan is set.
+    // Assets with an order still inside its grace period stay dirty so it gets re-checked.
+    pub async fn check_liquidations(&self, full_scan: bool) -> Vec<(String, String)> {
+        let dirty: HashSet<String> = std::mem::take(&mut *self.dirty_assets.write().await);
+        if !full_scan && dirty.is_empty() {
+            return Vec::new();
+        }
+
+        // orders_by_id is taken before liquidation_map, matching close_order's lock order
+        let mut orders_by_id = self.orders_by_id.write().await;
+        let liquidation_map = self.liquidation_map.read().await;
+        let prices = self.asset_prices.read().await;
+        let halted = if self.config.halt_suspends_liquidations {
+            self.disabled_assets.read().await.clone()
+        } else {
+            HashSet::new()
+        };
+        let mut liquidated_orders = Vec::new();
+
+        let now = chrono::Utc::now().timestamp_millis();
+        let grace_ms = self.config.liquidation_grace_ms;
+        let confirm_ticks = self.config.liquidation_confirm_ticks.max(1);
+
+        let mut still_breached = HashSet::new();
+
+        for (asset, asset_liquidations) in liquidation_map.iter() {
+            if !full_scan && !dirty.contains(asset) {
+                continue;
+            }
+            // Kept dirty so the asset is checked again as soon as the halt lifts
+            if halted.contains(asset) {
+                still_breached.insert(asset.clone());
+                continue;
+            }
+            // A silent feed's last price is not trusted for liquidations
+            if let Some(price_info) = prices.get(asset).filter(|p| !self.is_feed_stale(p)) {
+                let current_price = price_info
+                    .mark_price
+                    .unwrap_or((price_info.buy_price + price_info.sell_price) / Decimal::from(2));
+
+                // For each asset, check liquidation prices in order
+                for (price_key, entries) in asset_liquidations.iter() {
+                    let Ok(liquidation_price) = price_key.parse::<Decimal>() else {
+                        continue;
+                    };
+
+                    for entry in entries {
+                        let Some(order) = orders_by_id.get_mut(&entry.order_id) else {
+                            continue;
+                        };
+
+                        // Check if current price has crossed liquidation threshold
+                        let crossed = if order.order_type == "long" {
+                            current_price <= liquidation_price
+                        } else {
+                            current_price >= liquidation_price
+                        };
+
+                        if !crossed {
+                            order.breach_since = None;
+                            order.breach_ticks = 0;
+                            continue;
+                        }
+
+                        // Each price tick counts once, however many checks run in between
+                        if order.breach_tick_seq != price_info.tick_seq {
+                            order.breach_tick_seq = price_info.tick_seq;
+                            order.breach_ticks += 1;
+                        }
+
+                        let breach_since = *order.breach_since.get_or_insert(now);
+                        if now - breach_since >= grace_ms && order.breach_ticks >= confirm_ticks {
+                            liquidated_orders.push((entry.order_id.clone(), entry.user_id.clone()));
+                        } else {
+                            still_breached.insert(asset.clone());
+                        }
+                    }
+                }
+            }
+        }
+
+        // prices is still held here, the same order update_price takes these in
+        if !still_breached.is_empty() {
+            self.dirty_assets.write().await.extend(still_breached);
+        }
+
+        liquidated_orders
+    }
+
+    pub async fn liquidate_order(
+        &self,
+        order_id: &str,
+    ) -> Result<(ClosedOrder, Vec<AdlEvent>), String> {
+        let mut users = self.users.write().await;
+        let mut orders_by_id = self.orders_by_id.write().await;
+        let mut orders_by_user = self.orders_by_user.write().await;
+        let mut liquidation_map = self.liquidation_map.write().await;
+
+        // Fast removal by order_id
+        let Some(order) = self
+            .detach_order(
+                order_id,
+                &mut orders_by_id,
+                &mut orders_by_user,
+                &mut liquidation_map,
+            )
+            .await
+        else {
+            return Err(self.missing_order_error(order_id).await);
+        };
+
+        if let Some(user_balance) = users.get_mut(&order.user_id) {
+            user_balance.last_liquidation_at = Some(chrono::Utc::now().timestamp());
+        }
+
+        let prices = self.asset_prices.read().await;
+        let close_price = prices
+            .get(&order.asset)
+            .map(|price_info| close_side_price(&order, price_info))
+            .unwrap_or_else(|| self.calculate_liquidation_price(&order));
+        // From whatever collateral is left after the loss, the liquidation fee goes to the
+        // insurance fund and the rest back to the user. A loss beyond the collateral is a
+        // shortfall the fund has to cover.
+        let (remaining, pnl) = self.settle(
+            order.collateral(),
+            self.unrounded_pnl(&order, close_price),
+            Decimal::ZERO,
+            Decimal::ZERO,
+        );
+        let mut fee = Decimal::ZERO;
+        let uncovered = {
+            let mut insurance_fund = self.insurance_fund.write().await;
+            if remaining >= Decimal::ZERO {
+                fee = self
+                    .round_money(
+                        order.notional() * self.config.liquidation_fee_bps / Decimal::from(10000),
+                    )
+                    .min(remaining);
+                *insurance_fund += fee;
+                Decimal::ZERO
+            } else {
+                let shortfall = -remaining;
+                let covered = shortfall.min(*insurance_fund);
+                *insurance_fund -= covered;
+                shortfall - covered
+            }
+        };
+
+        let mut adl_events = Vec::new();
+        if uncovered > Decimal::ZERO {
+            if self.config.adl_enabled {
+                adl_events = self
+                    .auto_deleverage(
+                        &order,
+                        uncovered,
+                        &prices,
+                        &mut users,
+                        &mut orders_by_id,
+                        &mut orders_by_user,
+                        &mut liquidation_map,
+                    )
+                    .await;
+            } else {
+                error!(
+                    "Uncovered bankruptcy shortfall of {} on order {}",
+                    uncovered, order.order_id
+                );
+            }
+        }
+
+        let closed_order = if remaining >= Decimal::ZERO {
+            let mut closed_order = ClosedOrder::from_order(&order, close_price, pnl, true);
+            closed_order.fee = fee;
+            closed_order
+        } else {
+            ClosedOrder::from_order(&order, close_price, -order.collateral(), true)
+        };
+        let refund = closed_order.settled_amount();
+        if refund > Decimal::ZERO
+            && let Some(user_balance) = users.get_mut(&order.user_id)
+        {
+            user_balance.usd_balance += refund;
+        }
+        self.record_closed_order(closed_order.clone()).await;
+
+        Ok((closed_order, adl_events))
+    }
+
+    // Closes the most profitable, most leveraged opposing positions on the same asset,
+    // haircutting their profit until the shortfall is covered.
+    // Callers hold only the liquidated user's lock_user, not the targets'. Each target's close
+    // happens under the field locks taken in liquidate_order, so it is atomic on its own, but it
+    // can land between two steps of a target user's own multi-step request. The targets aren't
+    // known until the field locks are held, so locking them too would invert the lock order.
+    #[allow(clippy::too_many_arguments)]
+    async fn auto_deleverage(
+        &self,
+        bankrupt: &Order,
+        shortfall: Decimal,
+        prices: &HashMap<String, AssetPrice>,
+        users: &mut HashMap<String, UserBalance>,
+        orders_by_id: &mut HashMap<String, Order>,
+        orders_by_user: &mut HashMap<String, Vec<String>>,
+        liquidation_map: &mut HashMap<String, BTreeMap<String, Vec<LiquidationEntry>>>,
+    ) -> Vec<AdlEvent> {
+        let Some(price_info) = prices.get(&bankrupt.asset) else {
+            return Vec::new();
+        };
+
+        let mut candidates: Vec<(String, Decimal, Decimal)> = orders_by_id
+            .values()
+            .filter(|o| o.asset == bankrupt.asset && o.order_type != bankrupt.order_type)
+            .filter_map(|o| {
+                let pnl = self.calculate_pnl(o, close_side_price(o, price_info));
+                if pnl <= Decimal::ZERO || o.margin <= Decimal::ZERO {
+                    return None;
+                }
+                let rank = pnl / o.margin * Decimal::from(o.leverage);
+                Some((o.order_id.clone(), pnl, rank))
+            })
+            .collect();
+        candidates.sort_by_key(|candidate| std::cmp::Reverse(candidate.2));
+
+        let mut remaining = shortfall;
+        let mut events = Vec::new();
+
+        for (order_id, pnl, _) in candidates {
+            if remaining <= Decimal::ZERO {
+                break;
+            }
+
+            let Some(order) = self
+                .detach_order(&order_id, orders_by_id, orders_by_user, liquidation_map)
+                .await
+            else {
+                continue;
+            };
+
+            let haircut = remaining.min(pnl);
+            remaining -= haircut;
+            let (settled_amount, booked_pnl) = self.settle(
+                order.collateral(),
+                pnl - haircut,
+                Decimal::ZERO,
+                Decimal::ZERO,
+            );
+
+            if let Some(user_balance) = users.get_mut(&order.user_id) {
+                user_balance.usd_balance += settled_amount;
+            }
+
+            self.record_closed_order(ClosedOrder::from_order(
+                &order,
+                close_side_price(&order, price_info),
+                booked_pnl,
+                false,
+            ))
+            .await;
+
+            events.push(AdlEvent {
+                order_id: order.order_id.clone(),
+                user_id: order.user_id.clone(),
+                pnl: booked_pnl,
+                haircut,
+                settled_amount,
+                client_ref: order.client_ref.clone(),
+            });
+        }
+
+        if remaining > Decimal::ZERO {
+            error!("ADL left {} of shortfall uncovered", remaining);
+        }
+
+        events
+    }
+
+    // Removes an order from every index. The caller must hold the write locks passed in.
+    async fn detach_order(
+        &self,
+        order_id: &str,
+        orders_by_id: &mut HashMap<String, Order>,
+        orders_by_user: &mut HashMap<String, Vec<String>>,
+        liquidation_map: &mut HashMap<String, BTreeMap<String, Vec<LiquidationEntry>>>,
+    ) -> Option<Order> {
+        let order = orders_by_id.remove(order_id)?;
+
+        *self.total_open_notional.write().await -= order.notional();
+
+        // Remove from user's order list
+        if let Some(user_orders) = orders_by_user.get_mut(&order.user_id) {
+            user_orders.retain(|id| id != order_id);
+            if user_orders.is_empty() {
+                orders_by_user.remove(&order.user_id);
+            }
+        }
+
+        // Remove from liquidation map
+        if let Some(asset_liquidations) = liquidation_map.get_mut(&order.asset) {
+            let liquidation_price = self.calculate_liquidation_price(&order);
+            let price_key = liquidation_price.to_string();
+
+            if let Some(entries) = asset_liquidations.get_mut(&price_key) {
+                entries.retain(|entry| entry.order_id != order_id);
+                if entries.is_empty() {
+                    asset_liquidations.remove(&price_key);
+                }
+            }
+
+            if asset_liquidations.is_empty() {
+                liquidation_map.remove(&order.asset);
+            }
+        }
+
+        Some(order)
+    }
+
+    async fn record_closed_order(&self, closed_order: ClosedOrder) {
+        {
+            let mut stats = self.platform_stats.write().await;
+            stats.realized_pnl += closed_order.pnl;
+            stats.fees_collected += closed_order.fee - closed_order.fee_rebate;
+            if closed_order.liquidated {
+                stats.liquidations += 1;
+            }
+        }
+
+        let mut closed_orders = self.closed_orders.write().await;
+        closed_orders
+            .entry(closed_order.user_id.clone())
+            .or_insert_with(Vec::new)
+            .push(closed_order);
+    }
+
+    // Rounds a money amount to money_decimals using the configured rounding mode
+    pub fn round_money(&self, value: Decimal) -> Decimal {
+        value.round_dp_with_strategy(
+            self.config.money_decimals,
+            self.config.rounding_mode.strategy(),
+        )
+    }
+
+    // Returns (fee, rebate); quick round-trips inside the rebate window get part of the fee back
+    fn calculate_close_fee(&self, order: &Order, close_price: Decimal) -> (Decimal, Decimal) {
+        let fee = self.round_money(
+            order.quantity * close_price * self.config.close_fee_bps / Decimal::from(10000),
+        );
+
+        let window = self.config.fee_rebate_window_secs;
+        let held_for = chrono::Utc::now().timestamp() - order.timestamp;
+        let rebate = if window > 0 && held_for <= window {
+            self.round_money(fee * self.config.fee_rebate_pct / Decimal::from(100))
+        } else {
+            Decimal::ZERO
+        };
+
+        (fee, rebate)
+    }
+
+    pub async fn get_trade_statistics(&self, user_id: &str) -> TradeStatistics {
+        let closed_orders = self.closed_orders.read().await;
+        let history = match closed_orders.get(user_id) {
+            Some(history) if !history.is_empty() => history,
+            _ => return TradeStatistics::default(),
+        };
+
+        let mut stats = TradeStatistics {
+            total_trades: history.len(),
+            ..Default::default()
+        };
+        let mut total_wins = Decimal::ZERO;
+        let mut total_losses = Decimal::ZERO;
+
+        // Drawdown is measured on the cumulative realized PnL curve
+        let mut cumulative = Decimal::ZERO;
+        let mut peak = Decimal::ZERO;
+
+        for closed in history {
+            if closed.pnl > Decimal::ZERO {
+                stats.win_count += 1;
+                total_wins += closed.pnl;
+            } else if closed.pnl < Decimal::ZERO {
+                stats.loss_count += 1;
+                total_losses += closed.pnl;
+            }
+
+            cumulative += closed.pnl;
+            peak = peak.max(cumulative);
+            stats.largest_drawdown = stats.largest_drawdown.max(peak - cumulative);
+        }
+
+        stats.win_rate = Decimal::from(stats.win_count) / Decimal::from(stats.total_trades);
+        if stats.win_count > 0 {
+            stats.average_win = total_wins / Decimal::from(stats.win_count);
+        }
+        if stats.loss_count > 0 {
+            stats.average_loss = total_losses / Decimal::from(stats.loss_count);
+        }
+
+        stats
+    }
+
+    // Worsens a close price the nearer the position is to liquidation: no slippage until it has
+    // moved stress_slippage_start of the way from open to the liquidation price, then a linear
+    // ramp up to stress_slippage_bps at the liquidation price
+    fn stressed_close_price(&self, order: &Order, price: Decimal) -> Decimal {
+        let max_bps = self.config.stress_slippage_bps;
+        if max_bps <= Decimal::ZERO {
+            return price;
+        }
+
+        let distance = order.open_price - self.calculate_liquidation_price(order);
+        if distance.is_zero() {
+            return price;
+        }
+        let progress = ((order.open_price - price) / distance).clamp(Decimal::ZERO, Decimal::ONE);
+        let start = self.config.stress_slippage_start;
+        if progress <= start {
+            return price;
+        }
+
+        let slippage = max_bps * (progress - start) / (Decimal::ONE - start) / Decimal::from(10000);
+        if order.order_type == "long" {
+            price * (Decimal::ONE - slippage)
+        } else {
+            price * (Decimal::ONE + slippage)
+        }
+    }
+
+    // Pnl at quote precision, for displays, equity and ranking. Settlements don't use it; they
+    // go through settle with the unrounded pnl.
+    fn calculate_pnl(&self, order: &Order, current_price: Decimal) -> Decimal {
+        self.round_money(self.unrounded_pnl(order, current_price))
+    }
+
+    // The canonical settlement: every close, liquidation and ADL route funnels through here.
+    // Pnl stays at full precision until the amount credited back is rounded once, at quote
+    // precision; the booked pnl is whatever makes collateral + pnl - fee + rebate equal that
+    // amount exactly, so ClosedOrder::settled_amount always matches what the user received.
+    // Returns (settled amount, booked pnl).
+    fn settle(
+        &self,
+        collateral: Decimal,
+        pnl: Decimal,
+        fee: Decimal,
+        fee_rebate: Decimal,
+    ) -> (Decimal, Decimal) {
+        let settled = self.round_money(collateral + pnl - fee + fee_rebate);
+        (settled, settled - collateral + fee - fee_rebate)
+    }
+
+    fn unrounded_pnl(&self, order: &Order, current_price: Decimal) -> Decimal {
+        let pnl = match self.pnl_models.get(&order.asset) {
+            Some(model) => model.realized_pnl(order, current_price),
+            None => LinearPnl.realized_pnl(order, current_price),
+        };
+        // Every realized and displayed pnl comes through here, so the cap applies to both alike
+        let cap = self.config.pnl_cap_multiple * order.margin;
+        if cap > Decimal::ZERO {
+            pnl.clamp(-cap, cap)
+        } else {
+            pnl
+        }
+    }
+
+    pub fn calculate_liquidation_price(&self, order: &Order) -> Decimal {
+        self.liquidation_price_with(order, MarginMode::Isolated, order.collateral())
+    }
+
+    // Price at which `collateral` is 90% lost. Isolated passes the order's own collateral;
+    // cross passes everything in the account that stands behind the position.
+    pub fn liquidation_price_with(
+        &self,
+        order: &Order,
+        mode: MarginMode,
+        collateral: Decimal,
+    ) -> Decimal {
+        // With tiers, the position liquidates once its equity falls to the band's maintenance
+        // rate of its notional: a price move of collateral / notional - rate
+        let band_rate = self
+            .maintenance_bands
+            .iter()
+            .rev()
+            .find(|(min_notional, _)| order.notional() >= *min_notional)
+            .map(|(_, rate)| *rate);
+
+        let liquidation_threshold = if let Some(rate) = band_rate {
+            if order.notional().is_zero() {
+                return Decimal::ZERO;
+            }
+            (collateral / order.notional() - rate).max(Decimal::ZERO)
+        } else if mode == MarginMode::Isolated && order.extra_margin.is_zero() {
+            // Unadjusted isolated orders keep the original formula so their liquidation_map keys
+            // stay stable across snapshots
+            Decimal::from(90) / Decimal::from(order.leverage * 100)
+        } else if order.notional().is_zero() {
+            return Decimal::ZERO;
+        } else {
+            Decimal::from(90) * collateral / (order.notional() * Decimal::from(100))
+        };
+
+        if order.order_type == "long" {
+            // For long positions, liquidation happens when price drops
+            let price = order.open_price * (Decimal::from(1) - liquidation_threshold);
+            // Enough cross collateral means it never does
+            if mode == MarginMode::Cross {
+                price.max(Decimal::ZERO)
+            } else {
+                price
+            }
+        } else {
+            // For short positions, liquidation happens when price rises
+            order.open_price * (Decimal::from(1) + liquidation_threshold)
+        }
+    }
+
+    pub async fn get_user_balance_usd(&self, user_id: &str) -> Result<Decimal, String> {
+        let users = self.users.read().await;
+        let balance = users.get(user_id).ok_or("User not found")?;
+        Ok(balance.usd_balance)
+    }
+
+    // Returns (order, displayed pnl, gross pnl); the two differ only under net_pnl_display
+    pub async fn get_user_positions(
+        &self,
+        user_id: &str,
+    ) -> Result<Vec<(Order, Decimal, Decimal)>, String> {
+        let orders_by_id = self.orders_by_id.read().await;
+        let orders_by_user = self.orders_by_user.read().await;
+        let prices = self.asset_prices.read().await;
+        let mut positions = Vec::new();
+
+        if let Some(user_order_ids) = orders_by_user.get(user_id) {
+            for order_id in user_order_ids {
+                if let Some(order) = orders_by_id.get(order_id)
+                    && let Some(price_info) = prices.get(&order.asset)
+                {
+                    let current_price =
+                        (price_info.buy_price + price_info.sell_price) / Decimal::from(2);
+                    let gross_pnl = self.calculate_pnl(order, current_price);
+                    let pnl = if self.config.net_pnl_display {
+                        let (fee, rebate) = self.calculate_close_fee(order, current_price);
+                        gross_pnl - (fee - rebate)
+                    } else {
+                        gross_pnl
+                    };
+                    positions.push((order.clone(), pnl, gross_pnl));
+                }
+            }
+        }
+
+        Ok(positions)
+    }
+
+    pub async fn get_user_balance(
+        &self,
+        user_id: &str,
+    ) -> Result<HashMap<String, (Decimal, u32)>, String> {
+        let users = self.users.read().await;
+
+        if let Some(user_balance) = users.get(user_id) {
+            Ok(user_balance.asset_balances.clone())
+        } else if self.config.unknown_user_not_found {
+            Err("User not found".to_string())
+        } else {
+            Ok(HashMap::new())
+        }
+    }
+
+    pub async fn get_user_orders(&self, user_id: &str) -> Vec<Order> {
+        let orders_by_id = self.orders_by_id.read().await;
+        let orders_by_user = self.orders_by_user.read().await;
+        let mut user_orders = Vec::new();
+
+        if let Some(user_order_ids) = orders_by_user.get(user_id) {
+            for order_id in user_order_ids {
+                if let Some(order) = orders_by_id.get(order_id) {
+                    user_orders.push(order.clone());
+                }
+            }
+        }
+
+        user_orders
+    }
+
+    // Equity per user: free balance plus every open order's collateral and unrealized pnl
+    // at its close-side price
+    pub async fn user_equities(&self) -> HashMap<String, Decimal> {
+        let users = self.users.read().await;
+        let orders_by_id = self.orders_by_id.read().await;
+        let prices = self.asset_prices.read().await;
+
+        let mut equities: HashMap<String, Decimal> = users
+            .iter()
+            .map(|(user_id, balance)| (user_id.clone(), balance.usd_balance))
+            .collect();
+
+        for order in orders_by_id.values() {
+            let mut value = order.collateral();
+            if let Some(price_info) = prices.get(&order.asset) {
+                value += self.calculate_pnl(order, close_side_price(order, price_info));
+            }
+            *equities.entry(order.user_id.clone()).or_default() += value;
+        }
+
+        equities
+    }
+
+    pub async fn leverage_utilization(&self, user_id: &str) -> Result<LeverageUtilization, String> {
+        let users = self.users.read().await;
+        let orders_by_id = self.orders_by_id.read().await;
+        let orders_by_user = self.orders_by_user.read().await;
+        let prices = self.asset_prices.read().await;
+
+        let mut equity = users.get(user_id).ok_or("User not found")?.usd_balance;
+        let mut assets: BTreeMap<String, Decimal> = BTreeMap::new();
+        let orders = orders_by_user
+            .get(user_id)
+            .into_iter()
+            .flatten()
+            .filter_map(|order_id| orders_by_id.get(order_id));
+        for order in orders {
+            equity += order.collateral();
+            if let Some(price_info) = prices.get(&order.asset) {
+                equity += self.calculate_pnl(order, close_side_price(order, price_info));
+            }
+            *assets.entry(order.asset.clone()).or_default() += order.notional();
+        }
+
+        let total_notional = assets.values().copied().sum::<Decimal>();
+        let effective_leverage = (equity > Decimal::ZERO).then(|| total_notional / equity);
+        Ok(LeverageUtilization {
+            equity,
+            total_notional,
+            effective_leverage,
+            assets,
+        })
+    }
+
+    // Appends the current equity of every user to their bounded series
+    pub async fn record_equity(&self) {
+        let equities = self.user_equities().await;
+        let timestamp = chrono::Utc::now().timestamp_millis();
+        let max_len = self.config.equity_history_len;
+
+        let mut equity_history = self.equity_history.write().await;
+        for (user_id, equity) in equities {
+            let series = equity_history.entry(user_id).or_default();
+            series.push_back(EquityPoint { timestamp, equity });
+            while series.len() > max_len {
+                series.pop_front();
+            }
+        }
+    }
+
+    pub async fn get_equity_curve(&self, user_id: &str) -> Vec<EquityPoint> {
+        self.equity_history
+            .read()
+            .await
+            .get(user_id)
+            .map(|series| series.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    // Platform totals with the insurance fund and open notional as of now
+    pub async fn platform_stats(&self) -> (PlatformStats, Decimal, Decimal) {
+        let insurance_fund = *self.insurance_fund.read().await;
+        let total_open_notional = *self.total_open_notional.read().await;
+        let stats = self.platform_stats.read().await.clone();
+        (stats, insurance_fund, total_open_notional)
+    }
+
+    pub async fn get_order(&self, order_id: &str) -> Option<Order> {
+        self.orders_by_id.read().await.get(order_id).cloned()
+    }
+
+    // Removal from orders_by_id, under its write lock, is what arbitrates between a user close
+    // and a liquidation racing for the same order: the first to remove it owns the settlement.
+    // The other finds it gone and gets ALREADY_SETTLED rather than a plain not found.
+    async fn missing_order_error(&self, order_id: &str) -> String {
+        if self.get_closed_order(order_id).await.is_some() {
+            ALREADY_SETTLED.to_string()
+        } else {
+            "Order not found".to_string()
+        }
+    }
+
+    pub async fn get_closed_order(&self, order_id: &str) -> Option<ClosedOrder> {
+        let closed_orders = self.closed_orders.read().await;
+        closed_orders
+            .values()
+            .flatten()
+            .find(|closed_order| closed_order.order_id == order_id)
+            .cloned()
+    }
+}
+
+// One EMA step, seeded from the first observed value
+fn ema(previous: Option<Decimal>, value: Decimal, alpha: Decimal) -> Decimal {
+    match previous {
+        Some(previous) => previous + alpha * (value - previous),
+        None => value,
+    }
+}
+
+// Quotes a CSV field when it contains a delimiter, quote or newline (RFC 4180)
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+// (long notional, short notional) of the given positions
+fn split_notional(positions: &[&Order]) -> (Decimal, Decimal) {
+    positions
+        .iter()
+        .fold((Decimal::ZERO, Decimal::ZERO), |(long, short), order| {
+            if order.order_type == "long" {
+                (long + order.notional(), short)
+            } else {
+                (long, short + order.notional())
+            }
+        })
+}
+
+// Price a position closes at: longs sell, shorts buy back
+fn close_side_price(order: &Order, price_info: &AssetPrice) -> Decimal {
+    if order.order_type == "long" {
+        price_info.sell_price
+    } else {
+        price_info.buy_price
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RoundingMode;
+    use crate::test_support::*;
+
+    #[tokio::test]
+    async fn reset_keeps_the_liquidation_cooldown_and_risk_settings() {
+        let config = EngineConfig {
+            liquidation_cooldown_secs: 60,
+            ..test_config()
+        };
+        let balance_manager = manager_with_price(config, "1000", "999").await;
+        balance_manager
+            .set_risk_profile("u1", RiskProfile::Conservative, Some(3))
+            .await
+            .unwrap();
+        balance_manager
+            .users
+            .write()
+            .await
+            .get_mut("u1")
+            .unwrap()
+            .last_liquidation_at = Some(chrono::Utc::now().timestamp());
+
+        balance_manager.reset_account("u1").await;
+
+        let user = balance_manager.users.read().await["u1"].clone();
+        assert_eq!(user.usd_balance, Decimal::from(5000));
+        assert_eq!(user.risk_profile, Some(RiskProfile::Conservative));
+        assert_eq!(user.default_leverage, Some(3));
+        let err = balance_manager
+            .create_order(order("o1", "u1", "long", "100", 3))
+            .await
+            .unwrap_err();
+        assert!(err.starts_with("Cooldown active"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn eviction_takes_idle_users_and_keeps_ones_with_positions_orders_or_holdings() {
+        let config = EngineConfig {
+            max_tracked_users: 1,
+            user_idle_secs: 0,
+            ..test_config()
+        };
+        let balance_manager = manager_with_price(config, "1000", "999").await;
+        for user_id in ["idle", "pending", "holder"] {
+            balance_manager.get_or_create_user(user_id).await;
+        }
+        balance_manager
+            .create_order(order("o1", "trader", "long", "100", 10))
+            .await
+            .unwrap();
+        balance_manager
+            .place_pending_order(PendingOrder {
+                order: order("p1", "pending", "long", "100", 10),
+                trigger_price: dec("500"),
+                trigger_type: TriggerType::Limit,
+                filled_quantity: Decimal::ZERO,
+            })
+            .await
+            .unwrap();
+        balance_manager
+            .users
+            .write()
+            .await
+            .get_mut("holder")
+            .unwrap()
+            .asset_balances
+            .insert("BTC".to_string(), (dec("0.5"), 4));
+
+        let evicted = balance_manager.evict_idle_users().await;
+
+        assert_eq!(evicted, vec!["idle".to_string()]);
+        let users = balance_manager.users.read().await;
+        assert!(
+            ["trader", "pending", "holder"]
+                .iter()
+                .all(|user_id| users.contains_key(*user_id))
+        );
+    }
+
+    #[tokio::test]
+    async fn trade_statistics_follow_a_known_run_of_wins_and_losses() {
+        let balance_manager = manager_with_price(test_config(), "1000", "999").await;
+        for order_id in ["o1", "o2", "o3"] {
+            balance_manager
+                .create_order(order(order_id, "u1", "long", "100", 10))
+                .await
+                .unwrap();
+        }
+
+        // Each long is 1 BTC opened at 1000, closed at the sell price
+        for (order_id, sell) in [("o1", "1100"), ("o2", "900"), ("o3", "1050")] {
+            balance_manager
+                .update_price(price("BTC", sell, sell))
+                .await
+                .unwrap();
+            balance_manager
+                .close_order(order_id, None, None, None)
+                .await
+                .unwrap();
+        }
+
+        let stats = balance_manager.get_trade_statistics("u1").await;
+        assert_eq!(stats.total_trades, 3);
+        assert_eq!((stats.win_count, stats.loss_count), (2, 1));
+        assert_eq!(stats.win_rate, Decimal::from(2) / Decimal::from(3));
+        assert_eq!(stats.average_win, dec("75"));
+        assert_eq!(stats.average_loss, dec("-100"));
+        assert_eq!(stats.largest_drawdown, dec("100"));
+    }
+
+    // Liquidates a 10x long opened at 1000 (liquidation price 910) if a lone tick to 800 does
+    async fn spike_liquidates(mark_price_ema_period: u32) -> bool {
+        let config = EngineConfig {
+            mark_price_ema_period,
+            ..test_config()
+        };
+        let balance_manager = manager_with_price(config, "1000", "1000").await;
+        balance_manager
+            .create_order(order("o1", "u1", "long", "100", 10))
+            .await
+            .unwrap();
+        for _ in 0..5 {
+            balance_manager
+                .update_price(price("BTC", "1000", "1000"))
+                .await
+                .unwrap();
+        }
+        balance_manager
+            .update_price(price("BTC", "800", "800"))
+            .await
+            .unwrap();
+        !balance_manager.check_liquidations(true).await.is_empty()
+    }
+
+    #[tokio::test]
+    async fn a_single_spike_liquidates_on_live_price_but_not_on_the_smoothed_mark() {
+        assert!(spike_liquidates(0).await);
+        assert!(!spike_liquidates(10).await);
+    }
+
+    #[tokio::test]
+    async fn ema_tracks_a_known_price_sequence() {
+        let balance_manager = BalanceManager::new(EngineConfig {
+            price_ema_alpha: dec("0.5"),
+            ..test_config()
+        });
+
+        let mut averages = Vec::new();
+        for mid in ["100", "110", "120", "100"] {
+            balance_manager
+                .update_price(price("BTC", mid, mid))
+                .await
+                .unwrap();
+            averages.push(balance_manager.get_price("BTC").await.unwrap().ema_price);
+        }
+
+        let expected: Vec<_> = ["100", "105", "112.5", "106.25"]
+            .into_iter()
+            .map(|ema| Some(dec(ema)))
+            .collect();
+        assert_eq!(averages, expected);
+    }
+
+    #[tokio::test]
+    async fn closing_inside_the_rebate_window_refunds_part_of_the_fee() {
+        let config = EngineConfig {
+            close_fee_bps: dec("10"),
+            fee_rebate_window_secs: 60,
+            fee_rebate_pct: dec("50"),
+            ..test_config()
+        };
+        let balance_manager = manager_with_price(config, "1000", "1000").await;
+        let mut stale = order("outside", "u1", "long", "100", 10);
+        stale.timestamp -= 120;
+        for order in [order("inside", "u1", "long", "100", 10), stale] {
+            balance_manager.create_order(order).await.unwrap();
+        }
+
+        // 1 BTC closed at 1000 pays a 1.00 fee
+        let (inside, _) = balance_manager
+            .close_order("inside", None, None, None)
+            .await
+            .unwrap();
+        assert_eq!((inside.fee, inside.fee_rebate), (dec("1"), dec("0.5")));
+
+        let (outside, _) = balance_manager
+            .close_order("outside", None, None, None)
+            .await
+            .unwrap();
+        assert_eq!((outside.fee, outside.fee_rebate), (dec("1"), Decimal::ZERO));
+    }
+
+    #[tokio::test]
+    async fn platform_notional_cap_rejects_the_overflowing_order_until_a_close() {
+        let config = EngineConfig {
+            max_platform_notional: dec("2000"),
+            ..test_config()
+        };
+        let balance_manager = manager_with_price(config, "1000", "1000").await;
+        // Each order is 1000 of notional, so two fill the cap exactly
+        for order_id in ["o1", "o2"] {
+            balance_manager
+                .create_order(order(order_id, "u1", "long", "100", 10))
+                .await
+                .unwrap();
+        }
+
+        let err = balance_manager
+            .create_order(order("o3", "u2", "long", "100", 10))
+            .await
+            .unwrap_err();
+        assert_eq!(err, "Platform capacity reached");
+        assert_eq!(
+            *balance_manager.total_open_notional.read().await,
+            dec("2000")
+        );
+
+        balance_manager
+            .close_order("o1", None, None, None)
+            .await
+            .unwrap();
+        balance_manager
+            .create_order(order("o3", "u2", "long", "100", 10))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn orders_are_rejected_only_when_the_spread_is_wider_than_allowed() {
+        let config = EngineConfig {
+            max_relative_spread: dec("0.01"),
+            ..test_config()
+        };
+        let balance_manager = BalanceManager::new(config);
+        // Tight, exactly 1% of the 1000 mid, and just over it
+        let quotes = [
+            ("1001", "999", true),
+            ("1005", "995", true),
+            ("1005.01", "995", false),
+        ];
+        for (i, (buy, sell, accepted)) in quotes.into_iter().enumerate() {
+            balance_manager
+                .update_price(price("BTC", buy, sell))
+                .await
+                .unwrap();
+            let result = balance_manager
+                .create_order(order(&format!("o{}", i), "u1", "long", "100", 10))
+                .await;
+            if accepted {
+                assert!(result.is_ok(), "{:?}", result);
+            } else {
+                assert_eq!(result.unwrap_err(), "Spread too wide");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn compaction_leaves_the_maps_empty_after_every_order_is_removed() {
+        let balance_manager = manager_with_price(test_config(), "1000", "1000").await;
+        for i in 0..50 {
+            let order_type = if i % 2 == 0 { "long" } else { "short" };
+            balance_manager
+                .create_order(order(
+                    &format!("o{}", i),
+                    &format!("u{}", i % 5),
+                    order_type,
+                    "10",
+                    1 + i % 20,
+                ))
+                .await
+                .unwrap();
+        }
+        for i in 0..50 {
+            balance_manager
+                .close_order(&format!("o{}", i), None, None, None)
+                .await
+                .unwrap();
+        }
+
+        // Leftovers a removal path could miss: an empty user list, price level and asset map
+        balance_manager
+            .orders_by_user
+            .write()
+            .await
+            .insert("u0".to_string(), Vec::new());
+        {
+            let mut liquidation_map = balance_manager.liquidation_map.write().await;
+            liquidation_map
+                .entry("BTC".to_string())
+                .or_default()
+                .insert("900".to_string(), Vec::new());
+            liquidation_map.insert("ETH".to_string(), BTreeMap::new());
+        }
+
+        assert_eq!(balance_manager.compact_maps().await, 4);
+        assert!(balance_manager.orders_by_user.read().await.is_empty());
+        assert!(balance_manager.liquidation_map.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn price_history_keeps_the_last_n_updates_in_order() {
+        let balance_manager = BalanceManager::new(EngineConfig {
+            price_history_len: 5,
+            ..test_config()
+        });
+        for i in 1..=12 {
+            let quote = (1000 + i).to_string();
+            balance_manager
+                .update_price(price("BTC", &quote, &quote))
+                .await
+                .unwrap();
+        }
+
+        let history = balance_manager.get_price_history("BTC").await;
+        let prices: Vec<_> = history.iter().map(|point| point.buy_price).collect();
+        let expected: Vec<_> = (1008..=1012).map(Decimal::from).collect();
+        assert_eq!(prices, expected);
+        assert!(
+            history
+                .windows(2)
+                .all(|pair| pair[0].timestamp <= pair[1].timestamp)
+        );
+    }
+
+    #[tokio::test]
+    async fn bankruptcy_with_an_empty_fund_deleverages_the_top_ranked_opposing_position() {
+        let config = EngineConfig {
+            adl_enabled: true,
+            ..test_config()
+        };
+        let balance_manager = manager_with_price(config, "1000", "1000").await;
+        for order in [
+            order("bankrupt", "u1", "long", "100", 10),
+            order("cautious", "u2", "short", "100", 2),
+            order("leveraged", "u3", "short", "100", 10),
+        ] {
+            balance_manager.create_order(order).await.unwrap();
+        }
+        assert!(balance_manager.insurance_fund.read().await.is_zero());
+
+        // The 1 BTC long loses 150 on 100 of margin, leaving 50 for ADL to recover
+        balance_manager
+            .update_price(price("BTC", "850", "850"))
+            .await
+            .unwrap();
+        let (_, events) = balance_manager.liquidate_order("bankrupt").await.unwrap();
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].order_id, "leveraged");
+        assert_eq!(events[0].haircut, dec("50"));
+        assert_eq!(events[0].pnl, dec("100"));
+        assert!(balance_manager.get_order("leveraged").await.is_none());
+        assert!(balance_manager.get_order("cautious").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn a_decimals_change_is_rejected_unless_allowed() {
+        let mut six_decimals = price("BTC", "1000", "999");
+        six_decimals.decimals = 6;
+
+        let balance_manager = manager_with_price(test_config(), "1000", "999").await;
+        let err = balance_manager
+            .update_price(six_decimals.clone())
+            .await
+            .unwrap_err();
+        assert_eq!(err, "Decimals mismatch for BTC: expected 4, got 6");
+        assert_eq!(balance_manager.get_price("BTC").await.unwrap().decimals, 4);
+
+        let permissive = EngineConfig {
+            allow_decimals_change: true,
+            ..test_config()
+        };
+        let balance_manager = manager_with_price(permissive, "1000", "999").await;
+        balance_manager.update_price(six_decimals).await.unwrap();
+        assert_eq!(balance_manager.get_price("BTC").await.unwrap().decimals, 6);
+    }
+
+    #[tokio::test]
+    async fn orders_already_past_liquidation_at_the_exit_price_are_rejected() {
+        // A 1% spread: 100x liquidates at 991, below the 1000 ask but above the 990 bid
+        let balance_manager = manager_with_price(test_config(), "1000", "990").await;
+
+        balance_manager
+            .create_order(order("safe", "u1", "long", "100", 10))
+            .await
+            .unwrap();
+        let err = balance_manager
+            .create_order(order("doomed", "u1", "long", "100", 100))
+            .await
+            .unwrap_err();
+        assert_eq!(err, "Order would immediately liquidate");
+        assert!(balance_manager.get_order("doomed").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn a_breach_liquidates_only_once_it_outlasts_the_grace_period() {
+        let config = EngineConfig {
+            liquidation_grace_ms: 100,
+            ..test_config()
+        };
+        let balance_manager = manager_with_price(config, "1000", "1000").await;
+        balance_manager
+            .create_order(order("o1", "u1", "long", "100", 10))
+            .await
+            .unwrap();
+
+        // Below the 910 liquidation price for one tick, then back above it
+        for quote in ["900", "1000"] {
+            balance_manager
+                .update_price(price("BTC", quote, quote))
+                .await
+                .unwrap();
+            assert!(balance_manager.check_liquidations(true).await.is_empty());
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(150)).await;
+        assert!(balance_manager.check_liquidations(true).await.is_empty());
+
+        balance_manager
+            .update_price(price("BTC", "900", "900"))
+            .await
+            .unwrap();
+        assert!(balance_manager.check_liquidations(true).await.is_empty());
+        tokio::time::sleep(std::time::Duration::from_millis(150)).await;
+        assert_eq!(
+            balance_manager.check_liquidations(true).await,
+            vec![("o1".to_string(), "u1".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn a_disabled_asset_rejects_opens_but_allows_closes() {
+        let balance_manager = manager_with_price(test_config(), "1000", "999").await;
+        balance_manager
+            .create_order(order("open", "u1", "long", "100", 10))
+            .await
+            .unwrap();
+        balance_manager.set_asset_tradeable("BTC", false).await;
+
+        let err = balance_manager
+            .create_order(order("new", "u1", "long", "100", 10))
+            .await
+            .unwrap_err();
+        assert_eq!(err, "Trading disabled for asset");
+        balance_manager
+            .close_order("open", None, None, None)
+            .await
+            .unwrap();
+
+        balance_manager.set_asset_tradeable("BTC", true).await;
+        balance_manager
+            .create_order(order("new", "u1", "long", "100", 10))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn leverage_falls_back_to_the_default_and_clamps_to_the_profile_cap() {
+        let balance_manager = BalanceManager::new(EngineConfig {
+            max_leverage: 50,
+            conservative_max_leverage: 5,
+            ..test_config()
+        });
+        balance_manager.get_or_create_user("plain").await;
+        assert!(
+            balance_manager
+                .resolve_leverage("plain", None)
+                .await
+                .is_err()
+        );
+        assert_eq!(
+            balance_manager.resolve_leverage("plain", Some(80)).await,
+            Ok(50)
+        );
+
+        balance_manager
+            .set_risk_profile("cautious", RiskProfile::Conservative, Some(3))
+            .await
+            .unwrap();
+        assert_eq!(
+            balance_manager.resolve_leverage("cautious", None).await,
+            Ok(3)
+        );
+        assert_eq!(
+            balance_manager.resolve_leverage("cautious", Some(20)).await,
+            Ok(5)
+        );
+
+        // A default above the profile cap is clamped just like a requested one
+        balance_manager
+            .set_risk_profile("cautious", RiskProfile::Conservative, Some(25))
+            .await
+            .unwrap();
+        assert_eq!(
+            balance_manager.resolve_leverage("cautious", None).await,
+            Ok(5)
+        );
+    }
+
+    #[tokio::test]
+    async fn a_long_skewed_book_charges_longs_the_funding_shorts_receive() {
+        let balance_manager = manager_with_price(
+            EngineConfig {
+                funding_sensitivity: dec("0.01"),
+                funding_max_rate: dec("0.001"),
+                ..test_config()
+            },
+            "1000",
+            "1000",
+        )
+        .await;
+        // 5000 of longs against 1000 of shorts
+        for order in [
+            order("l1", "u1", "long", "400", 10),
+            order("l2", "u2", "long", "100", 10),
+            order("s1", "u3", "short", "100", 10),
+        ] {
+            balance_manager.create_order(order).await.unwrap();
+        }
+
+        let run = balance_manager.apply_funding().await;
+
+        // The skew asks for a 0.00667 rate, capped at 0.001
+        assert_eq!(run.rates, vec![("BTC".to_string(), dec("0.001"))]);
+        let users = balance_manager.users.read().await;
+        assert_eq!(users["u1"].usd_balance, dec("4596"));
+        assert_eq!(users["u2"].usd_balance, dec("4899"));
+        assert_eq!(users["u3"].usd_balance, dec("4905"));
+        let payments: Vec<(&str, Decimal)> = run
+            .payments
+            .iter()
+            .map(|(user_id, amount)| (user_id.as_str(), *amount))
+            .collect();
+        assert_eq!(
+            payments,
+            [("u1", dec("-4")), ("u2", dec("-1")), ("u3", dec("5"))]
+        );
+    }
+
+    #[tokio::test]
+    async fn state_export_csv_has_the_expected_headers_and_rows() {
+        let balance_manager = manager_with_price(test_config(), "1000", "999").await;
+        let mut long = order("o1", "desk,7", "long", "100", 10);
+        long.timestamp = 1_700_000_000;
+        balance_manager.create_order(long).await.unwrap();
+        balance_manager
+            .set_risk_profile("u2", RiskProfile::Conservative, Some(3))
+            .await
+            .unwrap();
+
+        let (orders_csv, balances_csv) = balance_manager.export_state_csv().await;
+
+        assert_eq!(
+            orders_csv,
+            "order_id,user_id,asset,type,margin,leverage,open_price,quantity,timestamp\n\
+             o1,\"desk,7\",BTC,long,100,10,1000,1,1700000000\n"
+        );
+        assert_eq!(
+            balances_csv,
+            "user_id,usd_balance,default_leverage,risk_profile\n\
+             \"desk,7\",4900,,\n\
+             u2,5000,3,conservative\n"
+        );
+    }
+
+    // Rounds 2.5 under `mode` directly and through a close that makes exactly 2.5 of pnl,
+    // returning (round_money, booked pnl, balance after the close)
+    async fn rounded_to_whole_dollars(mode: RoundingMode) -> (Decimal, Decimal, Decimal) {
+        let config = EngineConfig {
+            rounding_mode: mode,
+            money_decimals: 0,
+            ..test_config()
+        };
+        let balance_manager = manager_with_price(config, "1000", "1000").await;
+        balance_manager
+            .create_order(order("o1", "u1", "long", "100", 10))
+            .await
+            .unwrap();
+        balance_manager
+            .update_price(price("BTC", "1002.5", "1002.5"))
+            .await
+            .unwrap();
+        let (closed, _) = balance_manager
+            .close_order("o1", None, None, None)
+            .await
+            .unwrap();
+        let balance = balance_manager.users.read().await["u1"].usd_balance;
+        (balance_manager.round_money(dec("2.5")), closed.pnl, balance)
+    }
+
+    #[tokio::test]
+    async fn the_configured_rounding_mode_applies_to_settlements() {
+        assert_eq!(
+            rounded_to_whole_dollars(RoundingMode::HalfUp).await,
+            (dec("3"), dec("3"), dec("5003"))
+        );
+        assert_eq!(
+            rounded_to_whole_dollars(RoundingMode::HalfEven).await,
+            (dec("2"), dec("2"), dec("5002"))
+        );
+    }
+
+    #[tokio::test]
+    async fn near_zero_prices_cannot_blow_up_the_quantity() {
+        let balance_manager = BalanceManager::new(EngineConfig {
+            min_fill_price: dec("0.01"),
+            max_order_quantity: dec("1000"),
+            ..test_config()
+        });
+        // Each quote as (price, result); 1000 of notional at each price
+        let cases = [
+            ("0.0001", Err("Price below minimum fill price")),
+            ("0.5", Err("Quantity exceeds maximum")),
+            ("1000", Ok(())),
+        ];
+        for (i, (quote, expected)) in cases.into_iter().enumerate() {
+            balance_manager
+                .update_price(price("BTC", quote, quote))
+                .await
+                .unwrap();
+            let result = balance_manager
+                .create_order(order(&format!("o{}", i), "u1", "long", "100", 10))
+                .await
+                .map(|_| ());
+            assert_eq!(result, expected.map_err(str::to_string), "at {}", quote);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_single_bad_tick_does_not_liquidate_but_a_confirmed_breach_does() {
+        let config = EngineConfig {
+            liquidation_confirm_ticks: 2,
+            ..test_config()
+        };
+        let balance_manager = manager_with_price(config, "1000", "1000").await;
+        balance_manager
+            .create_order(order("o1", "u1", "long", "100", 10))
+            .await
+            .unwrap();
+
+        // One print below the 910 liquidation price, then the feed recovers
+        for quote in ["900", "1000"] {
+            balance_manager
+                .update_price(price("BTC", quote, quote))
+                .await
+                .unwrap();
+            assert!(balance_manager.check_liquidations(true).await.is_empty());
+        }
+
+        // Re-checking the same tick does not count as a confirmation
+        balance_manager
+            .update_price(price("BTC", "900", "900"))
+            .await
+            .unwrap();
+        assert!(balance_manager.check_liquidations(true).await.is_empty());
+        assert!(balance_manager.check_liquidations(true).await.is_empty());
+
+        balance_manager
+            .update_price(price("BTC", "905", "905"))
+            .await
+            .unwrap();
+        assert_eq!(
+            balance_manager.check_liquidations(true).await,
+            vec![("o1".to_string(), "u1".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn adding_margin_lowers_the_liquidation_price_and_unsafe_removal_is_rejected() {
+        let balance_manager = manager_with_price(test_config(), "1000", "1000").await;
+        let opened = balance_manager
+            .create_order(order("o1", "u1", "long", "100", 10))
+            .await
+            .unwrap();
+        let before = balance_manager.calculate_liquidation_price(&opened);
+
+        let adjusted = balance_manager
+            .adjust_margin("o1", dec("50"))
+            .await
+            .unwrap();
+        assert!(balance_manager.calculate_liquidation_price(&adjusted) < before);
+        assert_eq!(
+            balance_manager.users.read().await["u1"].usd_balance,
+            dec("4850")
+        );
+
+        // 900 is past the original 910 liquidation price but safe with the added margin
+        balance_manager
+            .update_price(price("BTC", "900", "900"))
+            .await
+            .unwrap();
+        assert!(balance_manager.check_liquidations(true).await.is_empty());
+
+        let err = balance_manager
+            .adjust_margin("o1", dec("-100"))
+            .await
+            .unwrap_err();
+        assert!(err.contains("too close to liquidation"), "{}", err);
+        assert_eq!(
+            balance_manager.orders_by_id.read().await["o1"].extra_margin,
+            dec("50")
+        );
+        assert_eq!(
+            balance_manager.users.read().await["u1"].usd_balance,
+            dec("4850")
+        );
+    }
+
+    #[tokio::test]
+    async fn a_close_beyond_the_client_limit_is_rejected_and_leaves_the_position_intact() {
+        let balance_manager = manager_with_price(test_config(), "1000", "999").await;
+        for id in ["within", "beyond"] {
+            balance_manager
+                .create_order(order(id, "u1", "long", "100", 10))
+                .await
+                .unwrap();
+        }
+        let balance_before = balance_manager.users.read().await["u1"].usd_balance;
+
+        // Longs close at the 999 sell price
+        let err = balance_manager
+            .close_order("beyond", None, Some(dec("1005")), None)
+            .await
+            .unwrap_err();
+        assert!(err.contains("outside requested limits"), "{}", err);
+        assert!(balance_manager.get_order("beyond").await.is_some());
+        assert_eq!(
+            balance_manager.users.read().await["u1"].usd_balance,
+            balance_before
+        );
+
+        let (closed, remaining) = balance_manager
+            .close_order("within", None, Some(dec("990")), None)
+            .await
+            .unwrap();
+        assert_eq!(closed.close_price, dec("999"));
+        assert!(remaining.is_none());
+        assert!(balance_manager.get_order("within").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn an_order_inside_the_liquidation_cooldown_is_rejected_and_one_after_it_succeeds() {
+        let config = EngineConfig {
+            liquidation_cooldown_secs: 60,
+            ..test_config()
+        };
+        let balance_manager = manager_with_price(config, "1000", "1000").await;
+        balance_manager
+            .create_order(order("o1", "u1", "long", "100", 10))
+            .await
+            .unwrap();
+        balance_manager
+            .update_price(price("BTC", "900", "900"))
+            .await
+            .unwrap();
+        for (order_id, _) in balance_manager.check_liquidations(true).await {
+            balance_manager.liquidate_order(&order_id).await.unwrap();
+        }
+
+        let err = balance_manager
+            .create_order(order("o2", "u1", "long", "100", 10))
+            .await
+            .unwrap_err();
+        assert!(err.starts_with("Cooldown active"), "{}", err);
+        assert!(err.contains("s remaining"), "{}", err);
+
+        // The liquidation now lies a full cooldown in the past
+        balance_manager
+            .users
+            .write()
+            .await
+            .get_mut("u1")
+            .unwrap()
+            .last_liquidation_at = Some(chrono::Utc::now().timestamp() - 60);
+        balance_manager
+            .create_order(order("o3", "u1", "long", "100", 10))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn sub_cent_margin_is_rejected_in_strict_mode_and_rounded_otherwise() {
+        let cents = EngineConfig {
+            money_decimals: 2,
+            ..test_config()
+        };
+        let strict = manager_with_price(
+            EngineConfig {
+                strict_ledger: true,
+                ..cents.clone()
+            },
+            "1000",
+            "999",
+        )
+        .await;
+        let err = strict
+            .create_order(order("o1", "u1", "long", "100.005", 10))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err,
+            "Margin or notional not representable at quote precision"
+        );
+        assert_eq!(strict.users.read().await["u1"].usd_balance, dec("5000"));
+
+        let normal = manager_with_price(cents, "1000", "999").await;
+        let opened = normal
+            .create_order(order("o1", "u1", "long", "100.005", 10))
+            .await
+            .unwrap();
+        assert_eq!(opened.margin, dec("100.00"));
+        assert_eq!(normal.users.read().await["u1"].usd_balance, dec("4900"));
+    }
+
+    #[tokio::test]
+    async fn the_equity_curve_keeps_the_latest_points_in_chronological_order() {
+        let config = EngineConfig {
+            equity_history_len: 3,
+            ..test_config()
+        };
+        let balance_manager = manager_with_price(config, "1000", "1000").await;
+        balance_manager
+            .create_order(order("o1", "u1", "long", "100", 10))
+            .await
+            .unwrap();
+
+        // One interval per price; the long holds quantity 1, so equity is 5000 + (price - 1000)
+        for quote in ["1000", "1010", "1020", "1030", "1040"] {
+            balance_manager
+                .update_price(price("BTC", quote, quote))
+                .await
+                .unwrap();
+            balance_manager.record_equity().await;
+            tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        }
+
+        let curve = balance_manager.get_equity_curve("u1").await;
+        let equities: Vec<Decimal> = curve.iter().map(|point| point.equity).collect();
+        assert_eq!(equities, vec![dec("5020"), dec("5030"), dec("5040")]);
+        assert!(
+            curve
+                .windows(2)
+                .all(|pair| pair[0].timestamp < pair[1].timestamp)
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_reads_and_writes_share_one_arc_without_an_outer_lock() {
+        let balance_manager = Arc::new(manager_with_price(test_config(), "1000", "1000").await);
+
+        let mut tasks = Vec::new();
+        for i in 0..20 {
+            let writer = balance_manager.clone();
+            tasks.push(tokio::spawn(async move {
+                let (order_id, user_id) = (format!("o{}", i), format!("u{}", i));
+                for _ in 0..10 {
+                    writer
+                        .create_order(order(&order_id, &user_id, "long", "100", 10))
+                        .await
+                        .unwrap();
+                    writer
+                        .close_order(&order_id, None, None, None)
+                        .await
+                        .unwrap();
+                }
+            }));
+            let reader = balance_manager.clone();
+            tasks.push(tokio::spawn(async move {
+                for _ in 0..10 {
+                    let _ = reader.get_user_positions(&format!("u{}", i)).await;
+                    reader.check_liquidations(true).await;
+                }
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        // Opening and closing at an unchanged price with no fees is balance-neutral
+        let users = balance_manager.users.read().await;
+        assert_eq!(users.len(), 20);
+        assert!(users.values().all(|user| user.usd_balance == dec("5000")));
+        assert!(balance_manager.orders_by_id.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn only_assets_whose_price_changed_are_scanned() {
+        let balance_manager = manager_with_price(test_config(), "1000", "1000").await;
+        balance_manager
+            .update_price(price("ETH", "100", "100"))
+            .await
+            .unwrap();
+        balance_manager
+            .create_order(order("btc", "u1", "long", "100", 10))
+            .await
+            .unwrap();
+        balance_manager
+            .create_order(Order {
+                asset: "ETH".to_string(),
+                ..order("eth", "u2", "long", "100", 10)
+            })
+            .await
+            .unwrap();
+        balance_manager.check_liquidations(false).await;
+
+        // BTC breaches without going through update_price, so nothing marks it dirty
+        balance_manager
+            .asset_prices
+            .write()
+            .await
+            .insert("BTC".to_string(), price("BTC", "900", "900"));
+        balance_manager
+            .update_price(price("ETH", "101", "101"))
+            .await
+            .unwrap();
+        assert!(balance_manager.check_liquidations(false).await.is_empty());
+
+        balance_manager
+            .update_price(price("BTC", "900", "900"))
+            .await
+            .unwrap();
+        assert_eq!(
+            balance_manager.check_liquidations(false).await,
+            vec![("btc".to_string(), "u1".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn spot_trades_move_usd_and_holdings_and_overselling_is_rejected() {
+        let balance_manager = manager_with_price(test_config(), "1000", "999").await;
+        let holdings = |users: &HashMap<String, UserBalance>| {
+            let user = &users["u1"];
+            let held = user.asset_balances.get("BTC").map(|(balance, _)| *balance);
+            (user.usd_balance, held)
+        };
+
+        // 2 at the 1000 ask plus the 10bps fee
+        balance_manager
+            .spot_trade("u1", "BTC", "buy", dec("2"))
+            .await
+            .unwrap();
+        assert_eq!(
+            holdings(&*balance_manager.users.read().await),
+            (dec("2998"), Some(dec("2")))
+        );
+
+        // 1 at the 999 bid less the fee
+        balance_manager
+            .spot_trade("u1", "BTC", "sell", dec("1"))
+            .await
+            .unwrap();
+        assert_eq!(
+            holdings(&*balance_manager.users.read().await),
+            (dec("3996.001"), Some(dec("1")))
+        );
+
+        let err = balance_manager
+            .spot_trade("u1", "BTC", "sell", dec("5"))
+            .await
+            .unwrap_err();
+        assert_eq!(err, "Insufficient holdings");
+        assert_eq!(
+            holdings(&*balance_manager.users.read().await),
+            (dec("3996.001"), Some(dec("1")))
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_create_close_and_liquidate_do_not_deadlock() {
+        let balance_manager = Arc::new(manager_with_price(test_config(), "1000", "1000").await);
+
+        let mut tasks = Vec::new();
+        for i in 0..8 {
+            let traders = balance_manager.clone();
+            tasks.push(tokio::spawn(async move {
+                for round in 0..25 {
+                    let order_id = format!("o{}-{}", i, round);
+                    let order_type = if round % 2 == 0 { "long" } else { "short" };
+                    let opened = traders
+                        .create_order(order(&order_id, &format!("u{}", i), order_type, "100", 50))
+                        .await;
+                    if opened.is_ok() && round % 3 == 0 {
+                        let _ = traders.close_order(&order_id, None, None, None).await;
+                    }
+                }
+            }));
+        }
+        let feed = balance_manager.clone();
+        tasks.push(tokio::spawn(async move {
+            for tick in 0..100 {
+                let quote = if tick % 2 == 0 { "1030" } else { "970" };
+                feed.update_price(price("BTC", quote, quote)).await.unwrap();
+                for (order_id, _) in feed.check_liquidations(true).await {
+                    let _ = feed.liquidate_order(&order_id).await;
+                }
+            }
+        }));
+
+        let all_done = async {
+            for task in tasks {
+                task.await.unwrap();
+            }
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(10), all_done)
+            .await
+            .expect("create, close and liquidate deadlocked");
+
+        // Every indexed liquidation still points at a live order
+        let orders_by_id = balance_manager.orders_by_id.read().await;
+        let liquidation_map = balance_manager.liquidation_map.read().await;
+        assert!(
+            liquidation_map
+                .values()
+                .flat_map(|levels| levels.values().flatten())
+                .all(|entry| orders_by_id.contains_key(&entry.order_id))
+        );
+    }
+
+    #[tokio::test]
+    async fn cross_margin_liquidates_further_away_than_isolated_with_free_balance() {
+        let balance_manager = manager_with_price(test_config(), "1000", "1000").await;
+        let long = balance_manager
+            .create_order(order("long", "u1", "long", "100", 10))
+            .await
+            .unwrap();
+        let short = balance_manager
+            .create_order(order("short", "u1", "short", "100", 10))
+            .await
+            .unwrap();
+
+        // Isolated loses 90% of the 100 margin; cross also has 400 of free balance behind it
+        let cross_collateral = long.collateral() + dec("400");
+        for (position, isolated, cross) in [(&long, "910", "550"), (&short, "1090", "1450")] {
+            assert_eq!(
+                balance_manager.liquidation_price_with(
+                    position,
+                    MarginMode::Isolated,
+                    position.collateral()
+                ),
+                dec(isolated)
+            );
+            assert_eq!(
+                balance_manager.liquidation_price_with(
+                    position,
+                    MarginMode::Cross,
+                    cross_collateral
+                ),
+                dec(cross)
+            );
+        }
+
+        // A long backed by more than its notional can't be liquidated under cross
+        assert_eq!(
+            balance_manager.liquidation_price_with(&long, MarginMode::Cross, dec("5000")),
+            Decimal::ZERO
+        );
+    }
+
+    #[tokio::test]
+    async fn pnl_beyond_the_cap_is_clamped_and_pnl_within_it_is_not() {
+        let config = EngineConfig {
+            pnl_cap_multiple: dec("2"),
+            ..test_config()
+        };
+        let balance_manager = manager_with_price(config, "1000", "1000").await;
+        for order_id in ["within", "beyond"] {
+            balance_manager
+                .create_order(order(order_id, "u1", "long", "100", 10))
+                .await
+                .unwrap();
+        }
+
+        // 150 up on 100 of margin is inside the 2x cap
+        balance_manager
+            .update_price(price("BTC", "1150", "1150"))
+            .await
+            .unwrap();
+        let (closed, _) = balance_manager
+            .close_order("within", None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(closed.pnl, dec("150"));
+
+        // 500 up is clamped to 200, the same in the display as at settlement
+        balance_manager
+            .update_price(price("BTC", "1500", "1500"))
+            .await
+            .unwrap();
+        let positions = balance_manager.get_user_positions("u1").await.unwrap();
+        assert_eq!(positions[0].1, dec("200"));
+        let (closed, _) = balance_manager
+            .close_order("beyond", None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(closed.pnl, dec("200"));
+        assert_eq!(
+            balance_manager.users.read().await["u1"].usd_balance,
+            dec("5350")
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn a_racing_close_and_liquidation_settle_the_order_exactly_once() {
+        let balance_manager = Arc::new(manager_with_price(test_config(), "1000", "1000").await);
+        for round in 0..50 {
+            balance_manager
+                .update_price(price("BTC", "1000", "1000"))
+                .await
+                .unwrap();
+            let (order_id, user_id) = (format!("o{}", round), format!("u{}", round));
+            balance_manager
+                .create_order(order(&order_id, &user_id, "long", "100", 10))
+                .await
+                .unwrap();
+            // Past the 910 liquidation price, so both the user and the liquidator want it
+            balance_manager
+                .update_price(price("BTC", "905", "905"))
+                .await
+                .unwrap();
+
+            let barrier = Arc::new(tokio::sync::Barrier::new(2));
+            let close = {
+                let (balance_manager, barrier, order_id) =
+                    (balance_manager.clone(), barrier.clone(), order_id.clone());
+                tokio::spawn(async move {
+                    barrier.wait().await;
+                    balance_manager
+                        .close_order(&order_id, None, None, None)
+                        .await
+                        .map(|(closed, _)| closed)
+                })
+            };
+            let liquidate = {
+                let (balance_manager, barrier, order_id) =
+                    (balance_manager.clone(), barrier.clone(), order_id.clone());
+                tokio::spawn(async move {
+                    barrier.wait().await;
+                    balance_manager
+                        .liquidate_order(&order_id)
+                        .await
+                        .map(|(closed, _)| closed)
+                })
+            };
+            let outcomes = [close.await.unwrap(), liquidate.await.unwrap()];
+
+            let settled: Vec<&ClosedOrder> = outcomes.iter().flatten().collect();
+            assert_eq!(settled.len(), 1, "round {}: {:?}", round, outcomes);
+            assert!(
+                outcomes
+                    .iter()
+                    .filter_map(|outcome| outcome.as_ref().err())
+                    .all(|e| e == ALREADY_SETTLED)
+            );
+            assert_eq!(
+                balance_manager.closed_orders.read().await[&user_id].len(),
+                1
+            );
+            assert_eq!(
+                balance_manager.users.read().await[&user_id].usd_balance,
+                dec("4900") + settled[0].settled_amount()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn the_liquidation_fee_comes_out_of_the_refund_and_goes_to_the_fund() {
+        let config = EngineConfig {
+            liquidation_fee_bps: dec("50"),
+            ..test_config()
+        };
+        let balance_manager = manager_with_price(config, "1000", "1000").await;
+        balance_manager
+            .create_order(order("o1", "u1", "long", "100", 10))
+            .await
+            .unwrap();
+        balance_manager
+            .update_price(price("BTC", "920", "920"))
+            .await
+            .unwrap();
+
+        // 20 of collateral left after the 80 loss; 50bps of the 1000 notional is 5
+        let (closed, _) = balance_manager.liquidate_order("o1").await.unwrap();
+        assert_eq!(closed.fee, dec("5"));
+        assert_eq!(closed.settled_amount(), dec("15"));
+        assert_eq!(
+            balance_manager.users.read().await["u1"].usd_balance,
+            dec("4915")
+        );
+        assert_eq!(*balance_manager.insurance_fund.read().await, dec("5"));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn two_concurrent_orders_exceeding_the_balance_fill_exactly_one() {
+        let balance_manager = Arc::new(manager_with_price(test_config(), "1000", "1000").await);
+        for round in 0..50 {
+            let user_id = format!("u{}", round);
+            let barrier = Arc::new(tokio::sync::Barrier::new(2));
+            // 3000 each against the 5000 starting balance, taken under the user lock as
+            // process_message does
+            let attempts: Vec<_> = ["a", "b"]
+                .into_iter()
+                .map(|suffix| {
+                    let (balance_manager, barrier) = (balance_manager.clone(), barrier.clone());
+                    let order = order(
+                        &format!("{}{}", user_id, suffix),
+                        &user_id,
+                        "long",
+                        "3000",
+                        2,
+                    );
+                    tokio::spawn(async move {
+                        barrier.wait().await;
+                        let _user_lock = balance_manager.lock_user(&order.user_id).await;
+                        balance_manager.create_order(order).await
+                    })
+                })
+                .collect();
+
+            let mut outcomes = Vec::new();
+            for attempt in attempts {
+                outcomes.push(attempt.await.unwrap());
+            }
+            assert_eq!(
+                outcomes.iter().filter(|outcome| outcome.is_ok()).count(),
+                1,
+                "round {}: {:?}",
+                round,
+                outcomes
+            );
+            assert!(
+                outcomes
+                    .iter()
+                    .any(|outcome| outcome.as_ref().err().map(String::as_str)
+                        == Some("Insufficient balance"))
+            );
+            assert_eq!(
+                balance_manager.users.read().await[&user_id].usd_balance,
+                dec("2000")
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn a_near_liquidation_close_gets_a_worse_price_than_a_healthy_one() {
+        let config = EngineConfig {
+            stress_slippage_bps: dec("100"),
+            stress_slippage_start: dec("0.5"),
+            ..test_config()
+        };
+        let balance_manager = manager_with_price(config, "1000", "1000").await;
+        // The 10x long liquidates at 910, the 2x long at 550
+        balance_manager
+            .create_order(order("stressed", "u1", "long", "100", 10))
+            .await
+            .unwrap();
+        balance_manager
+            .create_order(order("healthy", "u2", "long", "500", 2))
+            .await
+            .unwrap();
+        balance_manager
+            .update_price(price("BTC", "920", "920"))
+            .await
+            .unwrap();
+
+        let (healthy, _) = balance_manager
+            .close_order("healthy", None, None, None)
+            .await
+            .unwrap();
+        let (stressed, _) = balance_manager
+            .close_order("stressed", None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(healthy.close_price, dec("920"));
+        assert!(stressed.close_price < healthy.close_price);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn a_user_lock_holder_never_sees_a_half_applied_open_or_close() {
+        let balance_manager = Arc::new(manager_with_price(test_config(), "1000", "1000").await);
+        // At an unchanged price with no fees, free balance plus open margin stays 5000
+        let writer = balance_manager.clone();
+        let trades = tokio::spawn(async move {
+            for round in 0..200 {
+                let order_id = format!("o{}", round);
+                let _user_lock = writer.lock_user("u1").await;
+                writer
+                    .create_order(order(&order_id, "u1", "long", "10", 10))
+                    .await
+                    .unwrap();
+                if round % 2 == 0 {
+                    writer
+                        .close_order(&order_id, None, None, None)
+                        .await
+                        .unwrap();
+                }
+            }
+        });
+
+        let mut observed = 0;
+        while !trades.is_finished() {
+            let _user_lock = balance_manager.lock_user("u1").await;
+            let free = balance_manager
+                .users
+                .read()
+                .await
+                .get("u1")
+                .map_or(dec("5000"), |user| user.usd_balance);
+            let open_margin: Decimal = balance_manager
+                .orders_by_id
+                .read()
+                .await
+                .values()
+                .filter(|order| order.user_id == "u1")
+                .map(|order| order.margin)
+                .sum();
+            assert_eq!(free + open_margin, dec("5000"));
+            observed += 1;
+            tokio::task::yield_now().await;
+        }
+        trades.await.unwrap();
+        assert!(observed > 0);
+
+        // A second holder for the same user waits; another user's does not
+        let held = balance_manager.lock_user("u1").await;
+        let same_user = tokio::time::timeout(
+            std::time::Duration::from_millis(50),
+            balance_manager.lock_user("u1"),
+        );
+        assert!(same_user.await.is_err());
+        let _other_user = balance_manager.lock_user("u2").await;
+        drop(held);
+    }
+
+    #[tokio::test]
+    async fn an_order_too_small_for_the_price_is_rejected_before_reserving_margin() {
+        // 0.01 of margin at 1e27 divides below the smallest representable quantity
+        let balance_manager = manager_with_price(
+            test_config(),
+            "1000000000000000000000000000",
+            "1000000000000000000000000000",
+        )
+        .await;
+        assert_eq!(
+            balance_manager
+                .create_order(order("dust", "u1", "long", "0.01", 1))
+                .await
+                .unwrap_err(),
+            "Order too small for asset price"
+        );
+        assert!(balance_manager.orders_by_id.read().await.is_empty());
+
+        let balance_manager = manager_with_price(
+            EngineConfig {
+                min_order_quantity: dec("0.001"),
+                ..test_config()
+            },
+            "1000",
+            "1000",
+        )
+        .await;
+        assert_eq!(
+            balance_manager
+                .create_order(order("small", "u1", "long", "0.5", 1))
+                .await
+                .unwrap_err(),
+            "Order too small for asset price"
+        );
+        assert_eq!(
+            balance_manager.users.read().await["u1"].usd_balance,
+            dec("5000")
+        );
+        let opened = balance_manager
+            .create_order(order("enough", "u1", "long", "1", 1))
+            .await
+            .unwrap();
+        assert_eq!(opened.quantity, dec("0.001"));
+    }
+
+    #[tokio::test]
+    async fn platform_totals_equal_the_sum_over_users_after_closes() {
+        let config = EngineConfig {
+            close_fee_bps: dec("10"),
+            ..test_config()
+        };
+        let balance_manager = manager_with_price(config, "1000", "1000").await;
+        for user in 0..3 {
+            for order_type in ["long", "short"] {
+                let order_id = format!("u{}-{}", user, order_type);
+                balance_manager
+                    .create_order(order(
+                        &order_id,
+                        &format!("u{}", user),
+                        order_type,
+                        "100",
+                        10,
+                    ))
+                    .await
+                    .unwrap();
+            }
+        }
+        balance_manager
+            .create_order(order("doomed", "u3", "long", "100", 10))
+            .await
+            .unwrap();
+
+        balance_manager
+            .update_price(price("BTC", "1020", "1020"))
+            .await
+            .unwrap();
+        for user in 0..3 {
+            for order_type in ["long", "short"] {
+                balance_manager
+                    .close_order(&format!("u{}-{}", user, order_type), None, None, None)
+                    .await
+                    .unwrap();
+            }
+        }
+        balance_manager
+            .update_price(price("BTC", "900", "900"))
+            .await
+            .unwrap();
+        balance_manager.liquidate_order("doomed").await.unwrap();
+
+        let (stats, _, total_open_notional) = balance_manager.platform_stats().await;
+        let closed_orders = balance_manager.closed_orders.read().await;
+        let per_user = |field: fn(&ClosedOrder) -> Decimal| -> Decimal {
+            closed_orders
+                .values()
+                .map(|orders| orders.iter().map(field).sum::<Decimal>())
+                .sum()
+        };
+        assert_eq!(stats.realized_pnl, per_user(|closed| closed.pnl));
+        assert_eq!(
+            stats.fees_collected,
+            per_user(|closed| closed.fee - closed.fee_rebate)
+        );
+        assert!(stats.fees_collected > Decimal::ZERO);
+        assert_eq!(stats.liquidations, 1);
+        assert_eq!(total_open_notional, Decimal::ZERO);
+    }
+
+    #[tokio::test]
+    async fn many_rounded_settlements_add_up_to_the_balance_within_a_cent() {
+        let balance_manager = manager_with_price(
+            EngineConfig {
+                money_decimals: 2,
+                ..test_config()
+            },
+            "1234.5678",
+            "1234.5678",
+        )
+        .await;
+        for round in 0..200 {
+            let order_id = format!("o{}", round);
+            let order_type = if round % 2 == 0 { "long" } else { "short" };
+            balance_manager
+                .create_order(order(&order_id, "u1", order_type, "33.33", 7))
+                .await
+                .unwrap();
+            // A coarser close quote than the open one
+            let close = (1230 + round % 9).to_string();
+            balance_manager
+                .update_price(price("BTC", &close, &close))
+                .await
+                .unwrap();
+            balance_manager
+                .close_order(&order_id, None, None, None)
+                .await
+                .unwrap();
+            balance_manager
+                .update_price(price("BTC", "1234.5678", "1234.5678"))
+                .await
+                .unwrap();
+        }
+
+        let closed_orders = balance_manager.closed_orders.read().await;
+        let closed = &closed_orders["u1"];
+        assert_eq!(closed.len(), 200);
+        let mut settled_total = Decimal::ZERO;
+        for closed_order in closed {
+            let direction = if closed_order.order_type == "long" {
+                Decimal::ONE
+            } else {
+                -Decimal::ONE
+            };
+            let exact = direction
+                * (closed_order.close_price - closed_order.open_price)
+                * closed_order.quantity;
+            assert_eq!(closed_order.pnl, closed_order.pnl.round_dp(2));
+            assert!((closed_order.pnl - exact).abs() <= dec("0.005"));
+            settled_total += closed_order.pnl;
+        }
+        let balance = balance_manager.users.read().await["u1"].usd_balance;
+        assert!((balance - dec("5000") - settled_total).abs() <= dec("0.01"));
+    }
+
+    #[tokio::test]
+    async fn an_order_beyond_the_ask_size_is_capped_or_rejected_per_policy() {
+        let mut quote = price("BTC", "1000", "1000");
+        quote.ask_size = Some(dec("0.5"));
+        let mut outcomes = Vec::new();
+        for policy in [
+            BookSizePolicy::Ignore,
+            BookSizePolicy::Cap,
+            BookSizePolicy::Reject,
+        ] {
+            let balance_manager = BalanceManager::new(EngineConfig {
+                book_size_policy: policy,
+                ..test_config()
+            });
+            balance_manager.update_price(quote.clone()).await.unwrap();
+            // 1 BTC wanted against 0.5 on the ask
+            outcomes.push(
+                balance_manager
+                    .create_order(order("o1", "u1", "long", "100", 10))
+                    .await
+                    .map(|opened| (opened.quantity, opened.margin)),
+            );
+        }
+
+        assert_eq!(outcomes[0], Ok((dec("1"), dec("100"))));
+        assert_eq!(outcomes[1], Ok((dec("0.5"), dec("50"))));
+        assert_eq!(
+            outcomes[2],
+            Err("Order exceeds available size at top of book".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn effective_leverage_is_open_notional_over_equity() {
+        let balance_manager = manager_with_price(test_config(), "1000", "1000").await;
+        balance_manager
+            .update_price(price("ETH", "100", "100"))
+            .await
+            .unwrap();
+        balance_manager
+            .create_order(order("btc", "u1", "long", "100", 10))
+            .await
+            .unwrap();
+        balance_manager
+            .create_order(Order {
+                asset: "ETH".to_string(),
+                ..order("eth", "u1", "short", "200", 5)
+            })
+            .await
+            .unwrap();
+        balance_manager
+            .update_price(price("BTC", "1050", "1050"))
+            .await
+            .unwrap();
+
+        // 4700 free + 300 collateral + 50 unrealized on the BTC long
+        let utilization = balance_manager.leverage_utilization("u1").await.unwrap();
+        assert_eq!(utilization.equity, dec("5050"));
+        assert_eq!(utilization.total_notional, dec("2000"));
+        assert_eq!(
+            utilization.effective_leverage,
+            Some(dec("2000") / dec("5050"))
+        );
+        assert_eq!(utilization.assets["BTC"], dec("1000"));
+        assert_eq!(utilization.assets["ETH"], dec("1000"));
+        assert_eq!(
+            balance_manager
+                .leverage_utilization("nobody")
+                .await
+                .unwrap_err(),
+            "User not found"
+        );
+    }
+
+    // Two BTC longs opened at 1000 (liquidating at 910), then BTC halted
+    async fn halted_manager(config: EngineConfig) -> BalanceManager {
+        let balance_manager = manager_with_price(config, "1000", "1000").await;
+        for order_id in ["o1", "o2"] {
+            balance_manager
+                .create_order(order(order_id, "u1", "long", "100", 10))
+                .await
+                .unwrap();
+        }
+        balance_manager.set_asset_tradeable("BTC", false).await;
+        balance_manager
+    }
+
+    #[tokio::test]
+    async fn a_reduce_only_halt_allows_closes_but_not_opens() {
+        let balance_manager = halted_manager(EngineConfig {
+            halt_close_policy: HaltPolicy::ReduceOnly,
+            halt_suspends_liquidations: false,
+            ..test_config()
+        })
+        .await;
+        assert_eq!(
+            balance_manager
+                .create_order(order("o3", "u1", "long", "100", 10))
+                .await
+                .unwrap_err(),
+            "Trading disabled for asset"
+        );
+        balance_manager
+            .close_order("o1", None, None, None)
+            .await
+            .unwrap();
+
+        // Liquidations carry on through the halt
+        balance_manager
+            .update_price(price("BTC", "900", "900"))
+            .await
+            .unwrap();
+        let due: Vec<String> = balance_manager
+            .check_liquidations(true)
+            .await
+            .into_iter()
+            .map(|(order_id, _)| order_id)
+            .collect();
+        assert_eq!(due, ["o2"]);
+    }
+
+    #[tokio::test]
+    async fn a_blocking_halt_rejects_closes_and_can_suspend_liquidations() {
+        let balance_manager = halted_manager(EngineConfig {
+            halt_close_policy: HaltPolicy::Block,
+            halt_suspends_liquidations: true,
+            ..test_config()
+        })
+        .await;
+        assert_eq!(
+            balance_manager
+                .create_order(order("o3", "u1", "long", "100", 10))
+                .await
+                .unwrap_err(),
+            "Trading disabled for asset"
+        );
+        assert_eq!(
+            balance_manager
+                .close_order("o1", None, None, None)
+                .await
+                .unwrap_err(),
+            "Trading halted for asset"
+        );
+        assert!(balance_manager.orders_by_id.read().await.contains_key("o1"));
+
+        balance_manager
+            .update_price(price("BTC", "900", "900"))
+            .await
+            .unwrap();
+        assert!(balance_manager.check_liquidations(true).await.is_empty());
+
+        // Lifting the halt resumes liquidations
+        balance_manager.set_asset_tradeable("BTC", true).await;
+        assert_eq!(balance_manager.check_liquidations(true).await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn net_pnl_is_gross_minus_the_close_fee_in_net_mode() {
+        let mut displayed = Vec::new();
+        for net_pnl_display in [false, true] {
+            let config = EngineConfig {
+                close_fee_bps: dec("10"),
+                net_pnl_display,
+                ..test_config()
+            };
+            let balance_manager = manager_with_price(config, "1000", "1000").await;
+            balance_manager
+                .create_order(order("o1", "u1", "long", "100", 10))
+                .await
+                .unwrap();
+            balance_manager
+                .update_price(price("BTC", "1050", "1050"))
+                .await
+                .unwrap();
+            let positions = balance_manager.get_user_positions("u1").await.unwrap();
+            let (_, pnl, gross_pnl) = &positions[0];
+            displayed.push((*pnl, *gross_pnl));
+        }
+
+        // 1 BTC up 50; closing 1050 of notional at 10 bps costs 1.05
+        assert_eq!(displayed[0], (dec("50"), dec("50")));
+        assert_eq!(displayed[1], (dec("48.95"), dec("50")));
+    }
+
+    #[tokio::test]
+    async fn an_asset_needs_k_recent_updates_before_orders_are_accepted() {
+        let config = EngineConfig {
+            min_recent_price_updates: 3,
+            recent_price_window_secs: 60,
+            ..test_config()
+        };
+        let balance_manager = BalanceManager::new(config);
+        for quote in ["1000", "1001"] {
+            balance_manager
+                .update_price(price("BTC", quote, quote))
+                .await
+                .unwrap();
+            assert_eq!(
+                balance_manager
+                    .create_order(order("o1", "u1", "long", "100", 10))
+                    .await
+                    .unwrap_err(),
+                "Not enough recent price updates for asset"
+            );
+        }
+
+        balance_manager
+            .update_price(price("BTC", "1000", "1000"))
+            .await
+            .unwrap();
+        balance_manager
+            .create_order(order("o1", "u1", "long", "100", 10))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_larger_tier_liquidates_sooner_at_the_same_leverage() {
+        let liquidation_price = |balance_manager: &BalanceManager, order_id: &str| {
+            let order = balance_manager.orders_by_id.try_read().unwrap()[order_id].clone();
+            balance_manager.calculate_liquidation_price(&order)
+        };
+        let config = EngineConfig {
+            maintenance_tiers: "0:0.01, 5000:0.05".to_string(),
+            ..test_config()
+        };
+        let tiered = manager_with_price(config, "1000", "1000").await;
+        let untiered = manager_with_price(test_config(), "1000", "1000").await;
+        for balance_manager in [&tiered, &untiered] {
+            for (order_id, order_type, margin) in [
+                ("small-long", "long", "100"),
+                ("large-long", "long", "1000"),
+                ("small-short", "short", "100"),
+                ("large-short", "short", "1000"),
+            ] {
+                balance_manager
+                    .create_order(order(order_id, order_id, order_type, margin, 10))
+                    .await
+                    .unwrap();
+            }
+        }
+
+        // 1000 of notional keeps 1% maintenance, 10000 falls in the 5% band
+        assert_eq!(liquidation_price(&tiered, "small-long"), dec("910"));
+        assert_eq!(liquidation_price(&tiered, "large-long"), dec("950"));
+        assert_eq!(liquidation_price(&tiered, "small-short"), dec("1090"));
+        assert_eq!(liquidation_price(&tiered, "large-short"), dec("1050"));
+        // Without tiers size makes no difference
+        for order_id in ["small-long", "large-long"] {
+            assert_eq!(liquidation_price(&untiered, order_id), dec("910"));
+        }
+    }
+
+    #[tokio::test]
+    async fn a_reset_restores_one_user_and_leaves_another_untouched() {
+        let balance_manager = manager_with_price(test_config(), "1000", "1000").await;
+        for (order_id, user_id) in [("o1", "u1"), ("o2", "u1"), ("o3", "u2")] {
+            balance_manager
+                .create_order(order(order_id, user_id, "long", "100", 10))
+                .await
+                .unwrap();
+        }
+        balance_manager
+            .update_price(price("BTC", "1020", "1020"))
+            .await
+            .unwrap();
+        balance_manager
+            .close_order("o2", None, None, None)
+            .await
+            .unwrap();
+        let other_before = balance_manager.users.read().await["u2"].usd_balance;
+
+        let cancelled = balance_manager.reset_account("u1").await;
+
+        assert_eq!(cancelled, ["o1"]);
+        assert_eq!(
+            balance_manager.users.read().await["u1"].usd_balance,
+            dec("5000")
+        );
+        assert!(balance_manager.get_user_orders("u1").await.is_empty());
+        assert!(
+            !balance_manager
+                .closed_orders
+                .read()
+                .await
+                .contains_key("u1")
+        );
+        let indexed: Vec<String> = balance_manager
+            .liquidation_map
+            .read()
+            .await
+            .values()
+            .flat_map(|levels| levels.values().flatten())
+            .map(|entry| entry.order_id.clone())
+            .collect();
+        assert_eq!(indexed, ["o3"]);
+
+        assert_eq!(
+            balance_manager.users.read().await["u2"].usd_balance,
+            other_before
+        );
+        let other_orders = balance_manager.get_user_orders("u2").await;
+        assert_eq!(other_orders.len(), 1);
+        assert_eq!(other_orders[0].order_id, "o3");
     }
 }
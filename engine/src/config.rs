@@ -0,0 +1,731 @@
+//config.rs
+use rust_decimal::{Decimal, RoundingStrategy};
+use std::collections::HashMap;
+use std::env;
+use std::str::FromStr;
+
+#[derive(Debug, Clone)]
+pub struct EngineConfig {
+    // Address the /healthz endpoint listens on
+    pub health_addr: String,
+    // Hold back non-price messages until at least one price has been received
+    pub warmup_require_price: bool,
+    // EMA period for the mark price used by liquidations (0 or 1 = live mid)
+    pub mark_price_ema_period: u32,
+    // Smoothing factor for the per-asset price EMA reported alongside raw prices
+    pub price_ema_alpha: Decimal,
+    // Fee charged on close, in basis points of the closing notional
+    pub close_fee_bps: Decimal,
+    // Closes within this many seconds of opening get part of the close fee rebated (0 = off)
+    pub fee_rebate_window_secs: i64,
+    // Percentage of the close fee rebated inside the rebate window
+    pub fee_rebate_pct: Decimal,
+    // Cap on aggregate open notional across all users and assets (0 = unlimited)
+    pub max_platform_notional: Decimal,
+    // Reject opens when (buy - sell) / mid exceeds this (0 = disabled)
+    pub max_relative_spread: Decimal,
+    // Interval of the task pruning empty entries from liquidation_map and orders_by_user
+    pub compaction_interval_secs: u64,
+    // Number of recent price points kept per asset for GET_PRICE_HISTORY (0 = off)
+    pub price_history_len: usize,
+    // Auto-deleverage profitable opposing positions when a bankruptcy exceeds the insurance fund
+    pub adl_enabled: bool,
+    // Accept price updates whose decimals differ from the ones already recorded for the asset
+    pub allow_decimals_change: bool,
+    // Response envelope version used when a message carries no apiVersion
+    pub default_api_version: u32,
+    // Consume the orders stream through a consumer group (XREADGROUP + XACK) instead of plain XREAD
+    pub use_consumer_group: bool,
+    // Consumer group reading the orders stream
+    pub consumer_group: String,
+    // This engine's consumer name within the group
+    pub consumer_name: String,
+    // How long a position must stay past its liquidation price before it is liquidated (0 = immediately)
+    pub liquidation_grace_ms: i64,
+    // Load and periodically save the snapshot; false runs purely in memory
+    pub snapshot_enabled: bool,
+    // Add RFC 3339 siblings (<field>Iso) next to numeric time fields in responses and db_queue records
+    pub iso_timestamps: bool,
+    // Global leverage ceiling applied to every order
+    pub max_leverage: u32,
+    // Leverage ceiling for users on the conservative risk profile
+    pub conservative_max_leverage: u32,
+    // Interval of the funding task (0 = no funding)
+    pub funding_interval_secs: u64,
+    // Funding rate per interval when the book is entirely one-sided; scales with the long/short imbalance
+    pub funding_sensitivity: Decimal,
+    // Clamp on the absolute funding rate per interval
+    pub funding_max_rate: Decimal,
+    // First retry delay after a failed stream read; doubles on each consecutive failure
+    pub retry_base_ms: u64,
+    // Cap on the retry delay between failed stream reads
+    pub retry_max_ms: u64,
+    // Rounding applied to pnl, fees and funding (half_up, half_even or truncate)
+    pub rounding_mode: RoundingMode,
+    // Decimal places money amounts are rounded to
+    pub money_decimals: u32,
+    // Address of the embedded WebSocket gateway (empty = disabled)
+    pub ws_addr: String,
+    // How many recent CLOSE_ORDER requestIds are remembered for duplicate detection (0 = off)
+    pub request_dedup_capacity: usize,
+    // How long a remembered requestId stays eligible for replay
+    pub request_dedup_ttl_secs: i64,
+    // Evict idle users once more than this many are tracked (0 = unbounded)
+    pub max_tracked_users: usize,
+    // How long a user without open orders must be inactive before it can be evicted
+    pub user_idle_secs: i64,
+    // Max db_queue records buffered in memory while the stream is unavailable; oldest are dropped beyond this
+    pub db_retry_capacity: usize,
+    // Reject opens when the fill price is below this floor (0 = only non-positive prices are rejected)
+    pub min_fill_price: Decimal,
+    // Reject opens whose quantity exceeds this (0 = unlimited)
+    pub max_order_quantity: Decimal,
+    // Where the snapshot is saved and loaded from
+    pub snapshot_path: String,
+    // Gzip the snapshot (written to <snapshot_path>.gz); loading detects gzip either way
+    pub snapshot_compress: bool,
+    // Price ticks in a row a position must be past its liquidation price before it is liquidated (1 = first breach)
+    pub liquidation_confirm_ticks: u32,
+    // Margin removals must leave the liquidation price at least this percent away from the current exit price
+    pub margin_removal_buffer_pct: Decimal,
+    // Comma-separated assets settled as inverse contracts; every other asset is linear
+    pub inverse_assets: String,
+    // How long a user is blocked from opening after one of their orders is liquidated (0 = off)
+    pub liquidation_cooldown_secs: i64,
+    // Reject opens whose margin or notional can't be represented at money_decimals instead of rounding them
+    pub strict_ledger: bool,
+    // Interval of the task recording each user's equity for GET_EQUITY_CURVE (0 = off)
+    pub equity_interval_secs: u64,
+    // Number of equity points kept per user
+    pub equity_history_len: usize,
+    // Seconds between liquidation scans of every asset, regardless of price moves
+    pub liquidation_full_scan_secs: u64,
+    // Spot holdings seeded into new users, as SYMBOL:balance:decimals pairs separated by commas
+    pub initial_asset_balances: String,
+    // Fee on spot buys and sells, in basis points of the traded value
+    pub spot_fee_bps: Decimal,
+    // When set, GET_BALANCE answers USER_NOT_FOUND for users the engine has never seen instead of creating them
+    pub unknown_user_not_found: bool,
+    // Minimum time between a user's order submissions on the same asset; 0 disables
+    pub min_order_interval_ms: i64,
+    // Where orders are read from and responses published: redis, memory to run without Redis, or
+    // kafka to read orders from kafka_topic while responses still go through Redis
+    pub stream_backend: String,
+    // Snapshot saves slower than this are logged as a warning
+    pub snapshot_warn_ms: u64,
+    // An asset whose price hasn't updated for this long is treated as having no feed (0 = never)
+    pub feed_timeout_secs: i64,
+    // What happens to positions on an asset past feed_timeout_secs (freeze or close)
+    pub stale_feed_policy: StaleFeedPolicy,
+    // Secret that privileged actions must carry as adminToken (empty = privileged actions are unrestricted)
+    pub admin_token: String,
+    // Processing pauses while this file exists (empty = not watched); SIGUSR1 also toggles a pause
+    pub pause_file: String,
+    // Approximate number of entries kept in the orders stream when appending (0 keeps everything); db_queue is never trimmed
+    pub stream_max_len: usize,
+    // Stream a separate task reads read-only queries (GET_*) from; empty keeps queries on the orders stream
+    pub query_stream: String,
+    // Stream ids of applied mutations remembered (and snapshotted) to skip redeliveries
+    pub processed_id_capacity: usize,
+    // Caps a position's pnl to +/- this multiple of its margin, at close and in displays (0 is unbounded)
+    pub pnl_cap_multiple: Decimal,
+    // Largest quantity a triggered limit/stop order fills per price tick; the rest waits for later ticks (0 fills in full)
+    pub max_fill_quantity: Decimal,
+    // Applies at most one price per asset per this many ms, keeping only the latest tick in between (0 applies every tick)
+    pub price_coalesce_ms: u64,
+    // Writes liquidation_map and orders_by_user into snapshots; when off they are rebuilt from orders_by_id on load
+    pub snapshot_derived_maps: bool,
+    // Liquidation fee in bps of notional, taken from the collateral left after the loss into the insurance fund; the rest is credited back to the user (0 refunds all of it)
+    pub liquidation_fee_bps: Decimal,
+    // Responses kept per channel in the Redis list responses:{orderId} for clients to replay (0 keeps none)
+    pub response_log_cap: usize,
+    // How long a channel's response list lives after its latest response
+    pub response_log_ttl_secs: u64,
+    // Maintenance margin tiers as min_notional:rate,... (rate on notional, bigger positions in higher bands); empty keeps the flat 10%-of-margin rule. Changing it invalidates liquidation_map keys in existing snapshots
+    pub maintenance_tiers: String,
+    // Slippage in bps applied to a close price at the liquidation price, ramping up from stress_slippage_start (0 = none)
+    pub stress_slippage_bps: Decimal,
+    // How far (0..1) from open toward the liquidation price a position has moved before close slippage starts
+    pub stress_slippage_start: Decimal,
+    // How far a snapshot's timestamp may be ahead of the clock before it is treated as future-dated (clock skew, copied from another host)
+    pub snapshot_future_tolerance_secs: i64,
+    // Refuses to start from a future-dated snapshot instead of warning and loading it
+    pub snapshot_future_abort: bool,
+    // File the oldest db_queue records move to once db_retry_capacity is exceeded, drained ahead of the in-memory ones (empty drops them)
+    pub db_spill_path: String,
+    // Smallest position quantity an open may produce; smaller ones are rejected before margin is reserved (0 only rejects a zero quantity)
+    pub min_order_quantity: Decimal,
+    // Comma-separated assets that must each get a price after startup before the engine goes ready (empty = no per-asset requirement)
+    pub warmup_assets: String,
+    // Goes ready anyway, with a warning, when warmup_assets are still missing prices this long after startup (0 waits indefinitely)
+    pub warmup_timeout_secs: u64,
+    // Snapshots kept on disk: the latest plus snapshot.1.json, snapshot.2.json, ... which load falls back to when a newer one is corrupt
+    pub snapshot_retention: usize,
+    // What an open larger than the feed's top-of-book size on its side (askSize for longs, bidSize for shorts) gets: ignore, cap (shrunk to the size) or reject. Ticks without sizes never limit
+    pub book_size_policy: BookSizePolicy,
+    // While an asset is halted with DISABLE_ASSET: reduce_only still lets positions close, block stops closes too (admin CLOSE_AT_PRICE still settles)
+    pub halt_close_policy: HaltPolicy,
+    // Pauses liquidations on halted assets until they are re-enabled
+    pub halt_suspends_liquidations: bool,
+    // How far the local clock may drift from the stream backend's (Redis TIME) before a warning, in ms; 0 disables the check
+    pub clock_drift_tolerance_ms: i64,
+    // Seconds between clock drift checks after the startup one
+    pub clock_drift_check_secs: u64,
+    // Displayed unrealized pnl has the estimated close fee (less any rebate) taken off; gross stays under grossUnrealizedPnl
+    pub net_pnl_display: bool,
+    // One SAVE_LIQUIDATIONS db_queue record per liquidation scan ({"records": [...]} holding the usual per-order records) instead of a record each. Off by default: db_queue consumers must unpack the envelope first, as replay does
+    pub batch_liquidation_records: bool,
+    // Price updates an asset needs within recent_price_window_secs before opens are accepted; 0 disables the gate
+    pub min_recent_price_updates: usize,
+    // Window min_recent_price_updates counts over, in seconds
+    pub recent_price_window_secs: i64,
+    // Orders held back during warmup before further ones are rejected as not ready, bounding memory while a feed is down
+    pub warmup_max_deferred: usize,
+    // Kafka bootstrap servers when stream_backend is kafka
+    pub kafka_brokers: String,
+    // Topic orders are read from when stream_backend is kafka; only partition 0 is read, so ingestion stays totally ordered
+    pub kafka_topic: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RoundingMode {
+    HalfUp,
+    HalfEven,
+    Truncate,
+}
+
+impl RoundingMode {
+    pub fn strategy(self) -> RoundingStrategy {
+        match self {
+            RoundingMode::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+            RoundingMode::HalfEven => RoundingStrategy::MidpointNearestEven,
+            RoundingMode::Truncate => RoundingStrategy::ToZero,
+        }
+    }
+}
+
+impl FromStr for RoundingMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "half_up" => Ok(RoundingMode::HalfUp),
+            "half_even" => Ok(RoundingMode::HalfEven),
+            "truncate" => Ok(RoundingMode::Truncate),
+            _ => Err(format!("Unknown rounding mode: {}", s)),
+        }
+    }
+}
+
+// Freeze rejects closes until the feed returns; Close force-closes at the last known price
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StaleFeedPolicy {
+    Freeze,
+    Close,
+}
+
+impl FromStr for StaleFeedPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "freeze" => Ok(StaleFeedPolicy::Freeze),
+            "close" => Ok(StaleFeedPolicy::Close),
+            _ => Err(format!("Unknown stale feed policy: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HaltPolicy {
+    ReduceOnly,
+    Block,
+}
+
+impl FromStr for HaltPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "reduce_only" => Ok(HaltPolicy::ReduceOnly),
+            "block" => Ok(HaltPolicy::Block),
+            _ => Err(format!("Unknown halt policy: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BookSizePolicy {
+    Ignore,
+    Cap,
+    Reject,
+}
+
+impl FromStr for BookSizePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "ignore" => Ok(BookSizePolicy::Ignore),
+            "cap" => Ok(BookSizePolicy::Cap),
+            "reject" => Ok(BookSizePolicy::Reject),
+            _ => Err(format!("Unknown book size policy: {}", s)),
+        }
+    }
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        Self {
+            health_addr: "0.0.0.0:8081".to_string(),
+            warmup_require_price: true,
+            mark_price_ema_period: 0,
+            price_ema_alpha: Decimal::new(1, 1),
+            close_fee_bps: Decimal::ZERO,
+            fee_rebate_window_secs: 0,
+            fee_rebate_pct: Decimal::from(50),
+            max_platform_notional: Decimal::ZERO,
+            max_relative_spread: Decimal::ZERO,
+            compaction_interval_secs: 60,
+            price_history_len: 100,
+            adl_enabled: false,
+            allow_decimals_change: false,
+            default_api_version: 1,
+            use_consumer_group: true,
+            consumer_group: "engine".to_string(),
+            consumer_name: "engine-1".to_string(),
+            liquidation_grace_ms: 0,
+            snapshot_enabled: true,
+            iso_timestamps: false,
+            max_leverage: 100,
+            conservative_max_leverage: 10,
+            funding_interval_secs: 0,
+            funding_sensitivity: Decimal::new(1, 4),
+            funding_max_rate: Decimal::new(1, 3),
+            retry_base_ms: 1000,
+            retry_max_ms: 30000,
+            rounding_mode: RoundingMode::HalfEven,
+            money_decimals: 8,
+            ws_addr: "".to_string(),
+            request_dedup_capacity: 1000,
+            request_dedup_ttl_secs: 300,
+            max_tracked_users: 0,
+            user_idle_secs: 86400,
+            db_retry_capacity: 10000,
+            min_fill_price: Decimal::ZERO,
+            max_order_quantity: Decimal::ZERO,
+            snapshot_path: "snapshot.json".to_string(),
+            snapshot_compress: false,
+            liquidation_confirm_ticks: 1,
+            margin_removal_buffer_pct: Decimal::from(5),
+            inverse_assets: "".to_string(),
+            liquidation_cooldown_secs: 0,
+            strict_ledger: false,
+            equity_interval_secs: 0,
+            equity_history_len: 288,
+            liquidation_full_scan_secs: 30,
+            initial_asset_balances: "".to_string(),
+            spot_fee_bps: Decimal::from(10),
+            unknown_user_not_found: false,
+            min_order_interval_ms: 0,
+            stream_backend: "redis".to_string(),
+            snapshot_warn_ms: 1000,
+            feed_timeout_secs: 0,
+            stale_feed_policy: StaleFeedPolicy::Freeze,
+            admin_token: "".to_string(),
+            pause_file: "".to_string(),
+            stream_max_len: 100000,
+            query_stream: "".to_string(),
+            processed_id_capacity: 100000,
+            pnl_cap_multiple: Decimal::ZERO,
+            max_fill_quantity: Decimal::ZERO,
+            price_coalesce_ms: 0,
+            snapshot_derived_maps: true,
+            liquidation_fee_bps: Decimal::ZERO,
+            response_log_cap: 0,
+            response_log_ttl_secs: 300,
+            maintenance_tiers: "".to_string(),
+            stress_slippage_bps: Decimal::ZERO,
+            stress_slippage_start: Decimal::new(5, 1),
+            snapshot_future_tolerance_secs: 60,
+            snapshot_future_abort: false,
+            db_spill_path: "".to_string(),
+            min_order_quantity: Decimal::ZERO,
+            warmup_assets: "".to_string(),
+            warmup_timeout_secs: 0,
+            snapshot_retention: 1,
+            book_size_policy: BookSizePolicy::Ignore,
+            halt_close_policy: HaltPolicy::ReduceOnly,
+            halt_suspends_liquidations: false,
+            clock_drift_tolerance_ms: 1000,
+            clock_drift_check_secs: 60,
+            net_pnl_display: false,
+            batch_liquidation_records: false,
+            min_recent_price_updates: 0,
+            recent_price_window_secs: 60,
+            warmup_max_deferred: 10000,
+            kafka_brokers: "localhost:9092".to_string(),
+            kafka_topic: "orders".to_string(),
+        }
+    }
+}
+
+impl EngineConfig {
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        Self {
+            health_addr: env_or("ENGINE_HEALTH_ADDR", defaults.health_addr),
+            warmup_require_price: env_or(
+                "ENGINE_WARMUP_REQUIRE_PRICE",
+                defaults.warmup_require_price,
+            ),
+            mark_price_ema_period: env_or(
+                "ENGINE_MARK_PRICE_EMA_PERIOD",
+                defaults.mark_price_ema_period,
+            ),
+            price_ema_alpha: env_or("ENGINE_PRICE_EMA_ALPHA", defaults.price_ema_alpha),
+            close_fee_bps: env_or("ENGINE_CLOSE_FEE_BPS", defaults.close_fee_bps),
+            fee_rebate_window_secs: env_or(
+                "ENGINE_FEE_REBATE_WINDOW_SECS",
+                defaults.fee_rebate_window_secs,
+            ),
+            fee_rebate_pct: env_or("ENGINE_FEE_REBATE_PCT", defaults.fee_rebate_pct),
+            max_platform_notional: env_or(
+                "ENGINE_MAX_PLATFORM_NOTIONAL",
+                defaults.max_platform_notional,
+            ),
+            max_relative_spread: env_or("ENGINE_MAX_RELATIVE_SPREAD", defaults.max_relative_spread),
+            compaction_interval_secs: env_or(
+                "ENGINE_COMPACTION_INTERVAL_SECS",
+                defaults.compaction_interval_secs,
+            ),
+            price_history_len: env_or("ENGINE_PRICE_HISTORY_LEN", defaults.price_history_len),
+            adl_enabled: env_or("ENGINE_ADL_ENABLED", defaults.adl_enabled),
+            allow_decimals_change: env_or(
+                "ENGINE_ALLOW_DECIMALS_CHANGE",
+                defaults.allow_decimals_change,
+            ),
+            default_api_version: env_or("ENGINE_DEFAULT_API_VERSION", defaults.default_api_version),
+            use_consumer_group: env_or("ENGINE_USE_CONSUMER_GROUP", defaults.use_consumer_group),
+            consumer_group: env_or("ENGINE_CONSUMER_GROUP", defaults.consumer_group),
+            consumer_name: env_or("ENGINE_CONSUMER_NAME", defaults.consumer_name),
+            liquidation_grace_ms: env_or(
+                "ENGINE_LIQUIDATION_GRACE_MS",
+                defaults.liquidation_grace_ms,
+            ),
+            snapshot_enabled: env_or("ENGINE_SNAPSHOT_ENABLED", defaults.snapshot_enabled),
+            iso_timestamps: env_or("ENGINE_ISO_TIMESTAMPS", defaults.iso_timestamps),
+            max_leverage: env_or("ENGINE_MAX_LEVERAGE", defaults.max_leverage),
+            conservative_max_leverage: env_or(
+                "ENGINE_CONSERVATIVE_MAX_LEVERAGE",
+                defaults.conservative_max_leverage,
+            ),
+            funding_interval_secs: env_or(
+                "ENGINE_FUNDING_INTERVAL_SECS",
+                defaults.funding_interval_secs,
+            ),
+            funding_sensitivity: env_or("ENGINE_FUNDING_SENSITIVITY", defaults.funding_sensitivity),
+            funding_max_rate: env_or("ENGINE_FUNDING_MAX_RATE", defaults.funding_max_rate),
+            retry_base_ms: env_or("ENGINE_RETRY_BASE_MS", defaults.retry_base_ms),
+            retry_max_ms: env_or("ENGINE_RETRY_MAX_MS", defaults.retry_max_ms),
+            rounding_mode: env_or("ENGINE_ROUNDING_MODE", defaults.rounding_mode),
+            money_decimals: env_or("ENGINE_MONEY_DECIMALS", defaults.money_decimals),
+            ws_addr: env_or("ENGINE_WS_ADDR", defaults.ws_addr),
+            request_dedup_capacity: env_or(
+                "ENGINE_REQUEST_DEDUP_CAPACITY",
+                defaults.request_dedup_capacity,
+            ),
+            request_dedup_ttl_secs: env_or(
+                "ENGINE_REQUEST_DEDUP_TTL_SECS",
+                defaults.request_dedup_ttl_secs,
+            ),
+            max_tracked_users: env_or("ENGINE_MAX_TRACKED_USERS", defaults.max_tracked_users),
+            user_idle_secs: env_or("ENGINE_USER_IDLE_SECS", defaults.user_idle_secs),
+            db_retry_capacity: env_or("ENGINE_DB_RETRY_CAPACITY", defaults.db_retry_capacity),
+            min_fill_price: env_or("ENGINE_MIN_FILL_PRICE", defaults.min_fill_price),
+            max_order_quantity: env_or("ENGINE_MAX_ORDER_QUANTITY", defaults.max_order_quantity),
+            snapshot_path: env_or("ENGINE_SNAPSHOT_PATH", defaults.snapshot_path),
+            snapshot_compress: env_or("ENGINE_SNAPSHOT_COMPRESS", defaults.snapshot_compress),
+            liquidation_confirm_ticks: env_or(
+                "ENGINE_LIQUIDATION_CONFIRM_TICKS",
+                defaults.liquidation_confirm_ticks,
+            ),
+            margin_removal_buffer_pct: env_or(
+                "ENGINE_MARGIN_REMOVAL_BUFFER_PCT",
+                defaults.margin_removal_buffer_pct,
+            ),
+            inverse_assets: env_or("ENGINE_INVERSE_ASSETS", defaults.inverse_assets),
+            liquidation_cooldown_secs: env_or(
+                "ENGINE_LIQUIDATION_COOLDOWN_SECS",
+                defaults.liquidation_cooldown_secs,
+            ),
+            strict_ledger: env_or("ENGINE_STRICT_LEDGER", defaults.strict_ledger),
+            equity_interval_secs: env_or(
+                "ENGINE_EQUITY_INTERVAL_SECS",
+                defaults.equity_interval_secs,
+            ),
+            equity_history_len: env_or("ENGINE_EQUITY_HISTORY_LEN", defaults.equity_history_len),
+            liquidation_full_scan_secs: env_or(
+                "ENGINE_LIQUIDATION_FULL_SCAN_SECS",
+                defaults.liquidation_full_scan_secs,
+            ),
+            initial_asset_balances: env_or(
+                "ENGINE_INITIAL_ASSET_BALANCES",
+                defaults.initial_asset_balances,
+            ),
+            spot_fee_bps: env_or("ENGINE_SPOT_FEE_BPS", defaults.spot_fee_bps),
+            unknown_user_not_found: env_or(
+                "ENGINE_UNKNOWN_USER_NOT_FOUND",
+                defaults.unknown_user_not_found,
+            ),
+            min_order_interval_ms: env_or(
+                "ENGINE_MIN_ORDER_INTERVAL_MS",
+                defaults.min_order_interval_ms,
+            ),
+            stream_backend: env_or("ENGINE_STREAM_BACKEND", defaults.stream_backend),
+            snapshot_warn_ms: env_or("ENGINE_SNAPSHOT_WARN_MS", defaults.snapshot_warn_ms),
+            feed_timeout_secs: env_or("ENGINE_FEED_TIMEOUT_SECS", defaults.feed_timeout_secs),
+            stale_feed_policy: env_or("ENGINE_STALE_FEED_POLICY", defaults.stale_feed_policy),
+            admin_token: env_or("ENGINE_ADMIN_TOKEN", defaults.admin_token),
+            pause_file: env_or("ENGINE_PAUSE_FILE", defaults.pause_file),
+            stream_max_len: env_or("ENGINE_STREAM_MAX_LEN", defaults.stream_max_len),
+            query_stream: env_or("ENGINE_QUERY_STREAM", defaults.query_stream),
+            processed_id_capacity: env_or(
+                "ENGINE_PROCESSED_ID_CAPACITY",
+                defaults.processed_id_capacity,
+            ),
+            pnl_cap_multiple: env_or("ENGINE_PNL_CAP_MULTIPLE", defaults.pnl_cap_multiple),
+            max_fill_quantity: env_or("ENGINE_MAX_FILL_QUANTITY", defaults.max_fill_quantity),
+            price_coalesce_ms: env_or("ENGINE_PRICE_COALESCE_MS", defaults.price_coalesce_ms),
+            snapshot_derived_maps: env_or(
+                "ENGINE_SNAPSHOT_DERIVED_MAPS",
+                defaults.snapshot_derived_maps,
+            ),
+            liquidation_fee_bps: env_or("ENGINE_LIQUIDATION_FEE_BPS", defaults.liquidation_fee_bps),
+            response_log_cap: env_or("ENGINE_RESPONSE_LOG_CAP", defaults.response_log_cap),
+            response_log_ttl_secs: env_or(
+                "ENGINE_RESPONSE_LOG_TTL_SECS",
+                defaults.response_log_ttl_secs,
+            ),
+            maintenance_tiers: env_or("ENGINE_MAINTENANCE_TIERS", defaults.maintenance_tiers),
+            stress_slippage_bps: env_or("ENGINE_STRESS_SLIPPAGE_BPS", defaults.stress_slippage_bps),
+            stress_slippage_start: env_or(
+                "ENGINE_STRESS_SLIPPAGE_START",
+                defaults.stress_slippage_start,
+            ),
+            snapshot_future_tolerance_secs: env_or(
+                "ENGINE_SNAPSHOT_FUTURE_TOLERANCE_SECS",
+                defaults.snapshot_future_tolerance_secs,
+            ),
+            snapshot_future_abort: env_or(
+                "ENGINE_SNAPSHOT_FUTURE_ABORT",
+                defaults.snapshot_future_abort,
+            ),
+            db_spill_path: env_or("ENGINE_DB_SPILL_PATH", defaults.db_spill_path),
+            min_order_quantity: env_or("ENGINE_MIN_ORDER_QUANTITY", defaults.min_order_quantity),
+            warmup_assets: env_or("ENGINE_WARMUP_ASSETS", defaults.warmup_assets),
+            warmup_timeout_secs: env_or("ENGINE_WARMUP_TIMEOUT_SECS", defaults.warmup_timeout_secs),
+            snapshot_retention: env_or("ENGINE_SNAPSHOT_RETENTION", defaults.snapshot_retention),
+            book_size_policy: env_or("ENGINE_BOOK_SIZE_POLICY", defaults.book_size_policy),
+            halt_close_policy: env_or("ENGINE_HALT_CLOSE_POLICY", defaults.halt_close_policy),
+            halt_suspends_liquidations: env_or(
+                "ENGINE_HALT_SUSPENDS_LIQUIDATIONS",
+                defaults.halt_suspends_liquidations,
+            ),
+            clock_drift_tolerance_ms: env_or(
+                "ENGINE_CLOCK_DRIFT_TOLERANCE_MS",
+                defaults.clock_drift_tolerance_ms,
+            ),
+            clock_drift_check_secs: env_or(
+                "ENGINE_CLOCK_DRIFT_CHECK_SECS",
+                defaults.clock_drift_check_secs,
+            ),
+            net_pnl_display: env_or("ENGINE_NET_PNL_DISPLAY", defaults.net_pnl_display),
+            batch_liquidation_records: env_or(
+                "ENGINE_BATCH_LIQUIDATION_RECORDS",
+                defaults.batch_liquidation_records,
+            ),
+            min_recent_price_updates: env_or(
+                "ENGINE_MIN_RECENT_PRICE_UPDATES",
+                defaults.min_recent_price_updates,
+            ),
+            recent_price_window_secs: env_or(
+                "ENGINE_RECENT_PRICE_WINDOW_SECS",
+                defaults.recent_price_window_secs,
+            ),
+            warmup_max_deferred: env_or("ENGINE_WARMUP_MAX_DEFERRED", defaults.warmup_max_deferred),
+            kafka_brokers: env_or("ENGINE_KAFKA_BROKERS", defaults.kafka_brokers),
+            kafka_topic: env_or("ENGINE_KAFKA_TOPIC", defaults.kafka_topic),
+        }
+    }
+}
+
+impl EngineConfig {
+    // Returns a description of every setting that is out of range
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if self.max_leverage == 0 {
+            problems.push("max_leverage must be at least 1".to_string());
+        }
+        if self.conservative_max_leverage == 0 {
+            problems.push("conservative_max_leverage must be at least 1".to_string());
+        }
+        if self.price_ema_alpha <= Decimal::ZERO || self.price_ema_alpha > Decimal::ONE {
+            problems.push("price_ema_alpha must be in (0, 1]".to_string());
+        }
+        if self.close_fee_bps < Decimal::ZERO {
+            problems.push("close_fee_bps must not be negative".to_string());
+        }
+        if self.spot_fee_bps < Decimal::ZERO {
+            problems.push("spot_fee_bps must not be negative".to_string());
+        }
+        if self.pnl_cap_multiple < Decimal::ZERO {
+            problems.push("pnl_cap_multiple must not be negative".to_string());
+        }
+        if self.max_fill_quantity < Decimal::ZERO {
+            problems.push("max_fill_quantity must not be negative".to_string());
+        }
+        if self.liquidation_fee_bps < Decimal::ZERO {
+            problems.push("liquidation_fee_bps must not be negative".to_string());
+        }
+        if self.stress_slippage_bps < Decimal::ZERO
+            || self.stress_slippage_bps >= Decimal::from(10000)
+        {
+            problems.push("stress_slippage_bps must be in [0, 10000)".to_string());
+        }
+        if self.stress_slippage_start < Decimal::ZERO || self.stress_slippage_start >= Decimal::ONE
+        {
+            problems.push("stress_slippage_start must be in [0, 1)".to_string());
+        }
+        if self.snapshot_future_tolerance_secs < 0 {
+            problems.push("snapshot_future_tolerance_secs must not be negative".to_string());
+        }
+        if self.snapshot_retention == 0 {
+            problems.push("snapshot_retention must be at least 1".to_string());
+        }
+        if self.clock_drift_tolerance_ms < 0 {
+            problems.push("clock_drift_tolerance_ms must not be negative".to_string());
+        }
+        if self.min_recent_price_updates > 0 && self.recent_price_window_secs <= 0 {
+            problems.push(
+                "recent_price_window_secs must be positive when min_recent_price_updates is set"
+                    .to_string(),
+            );
+        }
+        if self.fee_rebate_pct < Decimal::ZERO || self.fee_rebate_pct > Decimal::from(100) {
+            problems.push("fee_rebate_pct must be in [0, 100]".to_string());
+        }
+        if self.max_relative_spread < Decimal::ZERO {
+            problems.push("max_relative_spread must not be negative".to_string());
+        }
+        if self.max_platform_notional < Decimal::ZERO {
+            problems.push("max_platform_notional must not be negative".to_string());
+        }
+        if self.liquidation_grace_ms < 0 {
+            problems.push("liquidation_grace_ms must not be negative".to_string());
+        }
+        if self.feed_timeout_secs < 0 {
+            problems.push("feed_timeout_secs must not be negative".to_string());
+        }
+        if self.min_order_interval_ms < 0 {
+            problems.push("min_order_interval_ms must not be negative".to_string());
+        }
+        if self.min_fill_price < Decimal::ZERO || self.max_order_quantity < Decimal::ZERO {
+            problems.push("min_fill_price and max_order_quantity must not be negative".to_string());
+        }
+        if self.min_order_quantity < Decimal::ZERO {
+            problems.push("min_order_quantity must not be negative".to_string());
+        }
+        if self.money_decimals > 28 {
+            problems.push("money_decimals must be at most 28".to_string());
+        }
+        if self.retry_base_ms == 0 || self.retry_max_ms < self.retry_base_ms {
+            problems
+                .push("retry_base_ms must be at least 1 and not above retry_max_ms".to_string());
+        }
+        if self.use_consumer_group
+            && (self.consumer_group.is_empty() || self.consumer_name.is_empty())
+        {
+            problems.push("consumer_group and consumer_name must be set".to_string());
+        }
+        match self.stream_backend.as_str() {
+            "redis" | "memory" => {}
+            "kafka" if !cfg!(feature = "kafka") => problems.push(
+                "stream_backend kafka needs the engine built with --features kafka".to_string(),
+            ),
+            "kafka" => {
+                if self.kafka_brokers.is_empty() || self.kafka_topic.is_empty() {
+                    problems.push("kafka_brokers and kafka_topic must be set".to_string());
+                }
+            }
+            _ => problems.push("stream_backend must be redis, memory or kafka".to_string()),
+        }
+        if let Err(e) = self.initial_holdings() {
+            problems.push(format!("initial_asset_balances: {}", e));
+        }
+        if let Err(e) = self.maintenance_bands() {
+            problems.push(format!("maintenance_tiers: {}", e));
+        }
+
+        problems
+    }
+
+    // Parses initial_asset_balances ("BTC:0.5:8,ETH:2:18") into symbol -> (balance, decimals)
+    pub fn initial_holdings(&self) -> Result<HashMap<String, (Decimal, u32)>, String> {
+        let mut holdings = HashMap::new();
+        for entry in self.initial_asset_balances.split(',').map(str::trim) {
+            if entry.is_empty() {
+                continue;
+            }
+
+            let parts: Vec<&str> = entry.split(':').map(str::trim).collect();
+            let [symbol, balance, decimals] = parts[..] else {
+                return Err(format!("{} is not SYMBOL:balance:decimals", entry));
+            };
+            let balance =
+                Decimal::from_str(balance).map_err(|_| format!("invalid balance in {}", entry))?;
+            if symbol.is_empty() || balance < Decimal::ZERO {
+                return Err(format!("invalid holding {}", entry));
+            }
+            let decimals = decimals
+                .parse::<u32>()
+                .map_err(|_| format!("invalid decimals in {}", entry))?;
+
+            holdings.insert(symbol.to_string(), (balance, decimals));
+        }
+        Ok(holdings)
+    }
+
+    // Parses maintenance_tiers into (min_notional, rate) bands sorted by min_notional
+    pub fn maintenance_bands(&self) -> Result<Vec<(Decimal, Decimal)>, String> {
+        let mut tiers = Vec::new();
+        for entry in self.maintenance_tiers.split(',').map(str::trim) {
+            if entry.is_empty() {
+                continue;
+            }
+
+            let Some((min_notional, rate)) = entry.split_once(':') else {
+                return Err(format!("{} is not min_notional:rate", entry));
+            };
+            let min_notional = Decimal::from_str(min_notional.trim())
+                .map_err(|_| format!("invalid min_notional in {}", entry))?;
+            let rate =
+                Decimal::from_str(rate.trim()).map_err(|_| format!("invalid rate in {}", entry))?;
+            if min_notional < Decimal::ZERO || rate < Decimal::ZERO || rate >= Decimal::ONE {
+                return Err(format!("invalid tier {}", entry));
+            }
+
+            tiers.push((min_notional, rate));
+        }
+        tiers.sort_by_key(|(min_notional, _)| *min_notional);
+        Ok(tiers)
+    }
+}
+
+fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    env::var(key)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
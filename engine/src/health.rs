@@ -0,0 +1,62 @@
+//health.rs
+use anyhow::Result;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tracing::{error, info};
+
+use crate::processor::Processor;
+
+// Minimal HTTP responder for GET /healthz: 200 when the engine is ready and not paused, 503 otherwise
+pub async fn serve_health(processor: Arc<Processor>, addr: String) -> Result<()> {
+    let listener = TcpListener::bind(&addr).await?;
+    info!("Health endpoint listening on {}", addr);
+
+    loop {
+        let (mut socket, _) = listener.accept().await?;
+        let processor = processor.clone();
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            let n = match socket.read(&mut buf).await {
+                Ok(n) => n,
+                Err(e) => {
+                    error!("Failed to read health request: {}", e);
+                    return;
+                }
+            };
+
+            let request = String::from_utf8_lossy(&buf[..n]);
+            let (status_line, body) = if request.starts_with("GET /healthz") {
+                let health = processor.health().await;
+                let ready = health
+                    .get("ready")
+                    .and_then(|v| v.as_bool())
+                    .unwrap_or(false);
+                let paused = health
+                    .get("paused")
+                    .and_then(|v| v.as_bool())
+                    .unwrap_or(false);
+                let status_line = if ready && !paused {
+                    "HTTP/1.1 200 OK"
+                } else {
+                    "HTTP/1.1 503 Service Unavailable"
+                };
+                (status_line, health.to_string())
+            } else {
+                ("HTTP/1.1 404 Not Found", "{}".to_string())
+            };
+
+            let response = format!(
+                "{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status_line,
+                body.len(),
+                body
+            );
+
+            if let Err(e) = socket.write_all(response.as_bytes()).await {
+                error!("Failed to write health response: {}", e);
+            }
+        });
+    }
+}
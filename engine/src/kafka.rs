@@ -0,0 +1,487 @@
+//kafka.rs
+use anyhow::Result;
+use redis::Value as RedisValue;
+use redis::streams::{StreamId, StreamKey, StreamReadReply};
+use std::collections::HashMap;
+
+use crate::processor::parse_stream_id;
+use crate::stream_backend::{BackendFuture, StreamBackend};
+
+// Records fetched per read, the same COUNT the Redis backend reads with
+const KAFKA_BATCH: usize = 10;
+
+pub struct KafkaRecord {
+    pub offset: i64,
+    // The record's timestamp in ms since the epoch, when it carries one
+    pub timestamp_ms: Option<i64>,
+    pub payload: Vec<u8>,
+}
+
+// The consumer calls KafkaBackend makes, so it runs against a mock without a broker
+pub trait KafkaConsumer: Send + Sync {
+    // Up to `max` records from the current position, waiting briefly when there are none so an
+    // idle processing loop doesn't spin
+    fn poll(&mut self, max: usize) -> BackendFuture<'_, Vec<KafkaRecord>>;
+
+    // The next poll starts at `offset`
+    fn seek(&mut self, offset: i64) -> BackendFuture<'_, ()>;
+
+    // Records `offset` as the next one the consumer group reads
+    fn commit(&mut self, offset: i64) -> BackendFuture<'_, ()>;
+}
+
+// Feeds one stream (the processor's "orders") from a single Kafka partition and passes every
+// other call to `inner`, which still carries responses and db_queue. Entries are given ids
+// "<record ms>-<offset>": the ms keeps the freshness check on arrival time, and the offset is
+// what last_processed_id restores the read position from. The ms never goes backwards, so ids
+// keep increasing even when producer clocks disagree.
+pub struct KafkaBackend {
+    consumer: Box<dyn KafkaConsumer>,
+    inner: Box<dyn StreamBackend>,
+    stream: String,
+    // Offset the next poll reads from, once known; until then the group's committed offset
+    position: Option<i64>,
+    last_ms: u64,
+}
+
+impl KafkaBackend {
+    pub fn new(
+        consumer: Box<dyn KafkaConsumer>,
+        inner: Box<dyn StreamBackend>,
+        stream: &str,
+    ) -> Self {
+        Self {
+            consumer,
+            inner,
+            stream: stream.to_string(),
+            position: None,
+            last_ms: 0,
+        }
+    }
+
+    // Seeks to the record after `last_id` unless already there. "$" and "0" carry no offset,
+    // so the consumer stays at the group's committed offset.
+    async fn resume_after(&mut self, last_id: &str) -> Result<()> {
+        let Some((ms, offset)) = parse_stream_id(last_id).filter(|id| *id != (0, 0)) else {
+            return Ok(());
+        };
+        self.last_ms = self.last_ms.max(ms);
+
+        let next = offset as i64 + 1;
+        if self.position != Some(next) {
+            self.consumer.seek(next).await?;
+            self.position = Some(next);
+        }
+        Ok(())
+    }
+
+    async fn read(&mut self) -> Result<StreamReadReply> {
+        let records = self.consumer.poll(KAFKA_BATCH).await?;
+        if records.is_empty() {
+            return Ok(StreamReadReply { keys: Vec::new() });
+        }
+
+        let mut ids = Vec::with_capacity(records.len());
+        for record in records {
+            let ms = record
+                .timestamp_ms
+                .unwrap_or_else(|| chrono::Utc::now().timestamp_millis())
+                .max(0) as u64;
+            self.last_ms = self.last_ms.max(ms);
+            self.position = Some(record.offset + 1);
+            ids.push(StreamId {
+                id: format!("{}-{}", self.last_ms, record.offset),
+                map: HashMap::from([("data".to_string(), RedisValue::Data(record.payload))]),
+            });
+        }
+
+        Ok(StreamReadReply {
+            keys: vec![StreamKey {
+                key: self.stream.clone(),
+                ids,
+            }],
+        })
+    }
+}
+
+impl StreamBackend for KafkaBackend {
+    fn ping(&mut self) -> BackendFuture<'_, ()> {
+        self.inner.ping()
+    }
+
+    fn server_time_ms(&mut self) -> BackendFuture<'_, i64> {
+        self.inner.server_time_ms()
+    }
+
+    fn read_stream<'a>(
+        &'a mut self,
+        stream: &'a str,
+        last_id: &'a str,
+    ) -> BackendFuture<'a, StreamReadReply> {
+        if stream != self.stream {
+            return self.inner.read_stream(stream, last_id);
+        }
+        Box::pin(async move {
+            self.resume_after(last_id).await?;
+            self.read().await
+        })
+    }
+
+    // The Kafka group is the consumer's own (group.id); this only restores the read position
+    fn ensure_group<'a>(
+        &'a mut self,
+        stream: &'a str,
+        group: &'a str,
+        start_id: &'a str,
+    ) -> BackendFuture<'a, ()> {
+        if stream != self.stream {
+            return self.inner.ensure_group(stream, group, start_id);
+        }
+        Box::pin(async move { self.resume_after(start_id).await })
+    }
+
+    // Kafka keeps no per-consumer pending list: unacked records are read again from the position
+    // ensure_group restored, so "0" has nothing to return
+    fn read_group<'a>(
+        &'a mut self,
+        stream: &'a str,
+        group: &'a str,
+        consumer: &'a str,
+        id: &'a str,
+    ) -> BackendFuture<'a, StreamReadReply> {
+        if stream != self.stream {
+            return self.inner.read_group(stream, group, consumer, id);
+        }
+        Box::pin(async move {
+            if id == ">" {
+                self.read().await
+            } else {
+                Ok(StreamReadReply { keys: Vec::new() })
+            }
+        })
+    }
+
+    fn ack<'a>(
+        &'a mut self,
+        stream: &'a str,
+        group: &'a str,
+        id: &'a str,
+    ) -> BackendFuture<'a, ()> {
+        if stream != self.stream {
+            return self.inner.ack(stream, group, id);
+        }
+        Box::pin(async move {
+            match parse_stream_id(id) {
+                Some((_, offset)) => self.consumer.commit(offset as i64 + 1).await,
+                None => Err(anyhow::anyhow!("Invalid Kafka entry id {}", id)),
+            }
+        })
+    }
+
+    // The topic is created and retained on the Kafka side
+    fn ensure_stream<'a>(&'a mut self, stream: &'a str) -> BackendFuture<'a, ()> {
+        if stream != self.stream {
+            return self.inner.ensure_stream(stream);
+        }
+        Box::pin(async { Ok(()) })
+    }
+
+    fn add_to_stream<'a>(&'a mut self, stream: &'a str, data: &'a str) -> BackendFuture<'a, ()> {
+        if stream != self.stream {
+            return self.inner.add_to_stream(stream, data);
+        }
+        Box::pin(async move {
+            Err(anyhow::anyhow!(
+                "{} is read from Kafka; produce to the topic instead",
+                stream
+            ))
+        })
+    }
+
+    fn publisher<'a>(&'a mut self, channel: &'a str, message: &'a str) -> BackendFuture<'a, ()> {
+        self.inner.publisher(channel, message)
+    }
+
+    fn retain_response<'a>(
+        &'a mut self,
+        key: &'a str,
+        message: &'a str,
+        cap: usize,
+        ttl_secs: u64,
+    ) -> BackendFuture<'a, ()> {
+        self.inner.retain_response(key, message, cap, ttl_secs)
+    }
+}
+
+#[cfg(feature = "kafka")]
+pub use rd::RdKafkaConsumer;
+
+#[cfg(feature = "kafka")]
+mod rd {
+    use anyhow::Result;
+    use rdkafka::consumer::{CommitMode, Consumer, StreamConsumer};
+    use rdkafka::{ClientConfig, Message, Offset, TopicPartitionList};
+    use std::time::Duration;
+
+    use super::{KafkaConsumer, KafkaRecord};
+    use crate::stream_backend::BackendFuture;
+
+    // How long an empty poll waits for the first record
+    const KAFKA_EMPTY_READ_MS: u64 = 1000;
+
+    // Reads partition 0 of the topic, with offsets committed only through KafkaBackend's acks
+    pub struct RdKafkaConsumer {
+        consumer: StreamConsumer,
+        topic: String,
+    }
+
+    impl RdKafkaConsumer {
+        pub fn new(brokers: &str, topic: &str, group: &str) -> Result<Self> {
+            let consumer: StreamConsumer = ClientConfig::new()
+                .set("bootstrap.servers", brokers)
+                .set("group.id", group)
+                .set("enable.auto.commit", "false")
+                .set("auto.offset.reset", "earliest")
+                .create()?;
+
+            // Assigned rather than subscribed, so the partition never moves to another consumer
+            let mut partitions = TopicPartitionList::new();
+            partitions.add_partition(topic, 0);
+            consumer.assign(&partitions)?;
+
+            Ok(Self {
+                consumer,
+                topic: topic.to_string(),
+            })
+        }
+
+        fn at_offset(&self, offset: i64) -> Result<TopicPartitionList> {
+            let mut partitions = TopicPartitionList::new();
+            partitions.add_partition_offset(&self.topic, 0, Offset::Offset(offset))?;
+            Ok(partitions)
+        }
+    }
+
+    impl KafkaConsumer for RdKafkaConsumer {
+        fn poll(&mut self, max: usize) -> BackendFuture<'_, Vec<KafkaRecord>> {
+            Box::pin(async move {
+                let mut records = Vec::new();
+                // Only the first record is waited for; the rest are whatever is already fetched
+                let mut wait = Duration::from_millis(KAFKA_EMPTY_READ_MS);
+                while records.len() < max {
+                    let Ok(message) = tokio::time::timeout(wait, self.consumer.recv()).await else {
+                        break;
+                    };
+                    let message = message?;
+                    records.push(KafkaRecord {
+                        offset: message.offset(),
+                        timestamp_ms: message.timestamp().to_millis(),
+                        payload: message.payload().unwrap_or_default().to_vec(),
+                    });
+                    wait = Duration::ZERO;
+                }
+                Ok(records)
+            })
+        }
+
+        // Reassigning at the offset works before the first fetch, unlike Consumer::seek
+        fn seek(&mut self, offset: i64) -> BackendFuture<'_, ()> {
+            Box::pin(async move {
+                self.consumer.assign(&self.at_offset(offset)?)?;
+                Ok(())
+            })
+        }
+
+        fn commit(&mut self, offset: i64) -> BackendFuture<'_, ()> {
+            Box::pin(async move {
+                self.consumer
+                    .commit(&self.at_offset(offset)?, CommitMode::Async)?;
+                Ok(())
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EngineConfig;
+    use crate::stream_backend::MemoryBackend;
+    use crate::test_support::*;
+    use serde_json::{Value, json};
+    use std::sync::{Arc, Mutex};
+    use tokio::sync::RwLock;
+
+    // A partition's records (timestamp, payload) with the reader's position and last commit
+    #[derive(Default)]
+    struct Topic {
+        records: Vec<(i64, String)>,
+        position: i64,
+        committed: Option<i64>,
+    }
+
+    struct MockConsumer(Arc<Mutex<Topic>>);
+
+    impl KafkaConsumer for MockConsumer {
+        fn poll(&mut self, max: usize) -> BackendFuture<'_, Vec<KafkaRecord>> {
+            Box::pin(async move {
+                let records: Vec<KafkaRecord> = {
+                    let mut topic = self.0.lock().unwrap();
+                    let start = topic.position as usize;
+                    let records: Vec<_> = topic
+                        .records
+                        .iter()
+                        .enumerate()
+                        .skip(start)
+                        .take(max)
+                        .map(|(offset, (ms, payload))| KafkaRecord {
+                            offset: offset as i64,
+                            timestamp_ms: Some(*ms),
+                            payload: payload.as_bytes().to_vec(),
+                        })
+                        .collect();
+                    topic.position += records.len() as i64;
+                    records
+                };
+                if records.is_empty() {
+                    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                }
+                Ok(records)
+            })
+        }
+
+        fn seek(&mut self, offset: i64) -> BackendFuture<'_, ()> {
+            Box::pin(async move {
+                self.0.lock().unwrap().position = offset;
+                Ok(())
+            })
+        }
+
+        fn commit(&mut self, offset: i64) -> BackendFuture<'_, ()> {
+            Box::pin(async move {
+                self.0.lock().unwrap().committed = Some(offset);
+                Ok(())
+            })
+        }
+    }
+
+    fn kafka_backend(topic: &Arc<Mutex<Topic>>) -> KafkaBackend {
+        KafkaBackend::new(
+            Box::new(MockConsumer(topic.clone())),
+            Box::new(MemoryBackend::new(0)),
+            "orders",
+        )
+    }
+
+    fn session() -> Vec<Value> {
+        vec![
+            json!({
+                "action": "LATEST_PRICE",
+                "symbol": "BTC",
+                "buyPrice": "1000",
+                "sellPrice": "999",
+                "decimals": 4
+            }),
+            create_order_message("o1", "u1", "long", "100"),
+            create_order_message("o2", "u1", "short", "50"),
+            json!({ "action": "CLOSE_ORDER", "orderId": "o1" }),
+        ]
+    }
+
+    // The responses to o1's create and close and o2's create, then u1's balances
+    async fn outcome(harness: &mut Harness) -> (Vec<Value>, Value) {
+        let responses = vec![
+            harness.response("o1").await,
+            harness.response("o2").await,
+            harness.response("o1").await,
+        ];
+        let balances = harness
+            .balance_manager
+            .get_user_balance("u1")
+            .await
+            .unwrap();
+        (responses, json!(format!("{:?}", balances)))
+    }
+
+    #[tokio::test]
+    async fn kafka_orders_are_processed_like_stream_orders() {
+        let config = test_config();
+
+        let mut redis_path = Harness::new(config.clone());
+        for message in session() {
+            redis_path.push(message).await;
+        }
+        redis_path.start().await;
+
+        let topic = Arc::new(Mutex::new(Topic::default()));
+        let now = chrono::Utc::now().timestamp_millis();
+        topic.lock().unwrap().records = session()
+            .into_iter()
+            .map(|message| (now, message.to_string()))
+            .collect();
+        let backend: Arc<RwLock<dyn StreamBackend>> = Arc::new(RwLock::new(kafka_backend(&topic)));
+        let mut kafka_path = Harness::with_backend(config, backend);
+        kafka_path.start().await;
+
+        let expected = outcome(&mut redis_path).await;
+        assert_eq!(expected.0[2]["action"], "ORDER_SUCCESS");
+        assert_eq!(outcome(&mut kafka_path).await, expected);
+        assert!(kafka_path.balance_manager.get_order("o2").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn offsets_follow_last_processed_id() {
+        let topic = Arc::new(Mutex::new(Topic {
+            records: (0..4)
+                .map(|i| (1_000 - i, format!("record {}", i)))
+                .collect(),
+            ..Topic::default()
+        }));
+
+        let mut backend = kafka_backend(&topic);
+        let reply = backend.read_stream("orders", "$").await.unwrap();
+        let ids: Vec<_> = reply.keys[0].ids.iter().map(|id| id.id.clone()).collect();
+        // Earlier timestamps on later records don't move ids backwards
+        assert_eq!(ids, ["1000-0", "1000-1", "1000-2", "1000-3"]);
+
+        backend.ack("orders", "engine", "1000-1").await.unwrap();
+        assert_eq!(topic.lock().unwrap().committed, Some(2));
+
+        // A restart from last_processed_id picks up at the record after it
+        let mut restarted = kafka_backend(&topic);
+        restarted
+            .ensure_group("orders", "engine", "1000-1")
+            .await
+            .unwrap();
+        let reply = restarted
+            .read_group("orders", "engine", "engine-1", ">")
+            .await;
+        let ids: Vec<_> = reply.unwrap().keys[0]
+            .ids
+            .iter()
+            .map(|id| id.id.clone())
+            .collect();
+        assert_eq!(ids, ["1000-2", "1000-3"]);
+
+        assert!(backend.add_to_stream("orders", "{}").await.is_err());
+        backend.add_to_stream("db_queue", "{}").await.unwrap();
+        assert_eq!(
+            backend
+                .read_stream("db_queue", "0")
+                .await
+                .unwrap()
+                .keys
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn kafka_backend_needs_the_feature_to_validate() {
+        let config = EngineConfig {
+            stream_backend: "kafka".to_string(),
+            ..test_config()
+        };
+        assert_eq!(config.validate().is_empty(), cfg!(feature = "kafka"));
+    }
+}
@@ -0,0 +1,15 @@
+//lib.rs
+// The engine's modules, shared by the binary in main.rs and the benchmarks in benches/
+pub mod balance_manager;
+pub mod config;
+pub mod health;
+pub mod kafka;
+pub mod pnl;
+pub mod processor;
+pub mod redis_manager;
+pub mod replay;
+pub mod stream_backend;
+#[cfg(test)]
+mod test_support;
+pub mod types;
+pub mod ws_gateway;
@@ -1,67 +1,220 @@
-use crate::balance_manager::BalanceManager;
-use crate::processor::Processor;
-use crate::redis_manager::RedisManager;
 use anyhow::Result;
+use engine::balance_manager::BalanceManager;
+use engine::config::EngineConfig;
+use engine::health::serve_health;
+use engine::processor::{Processor, run_spaced};
+use engine::redis_manager::RedisManager;
+use engine::replay;
+use engine::stream_backend::{MemoryBackend, StreamBackend};
+use engine::ws_gateway::serve_ws;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use tokio::time::{Duration, interval};
-use tracing::{error, info};
-
-mod balance_manager;
-mod processor;
-mod redis_manager;
+use tracing::{error, info, warn};
 
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
     info!("Starting Trading Engine");
 
-    let redis_manager = Arc::new(RwLock::new(RedisManager::new().await?));
-    let balance_manager = Arc::new(RwLock::new(BalanceManager::new()));
-    let processor = Arc::new(Processor::new(
-        redis_manager.clone(),
-        balance_manager.clone(),
-    ));
+    let config = EngineConfig::from_env();
 
-    // Load snapshot if exists
-    processor.load_snapshot().await?;
+    // Offline reconstruction: `engine replay <events.jsonl> [output.json]`
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("replay") {
+        let events_path = args
+            .get(2)
+            .ok_or_else(|| anyhow::anyhow!("Usage: engine replay <events.jsonl> [output.json]"))?;
+        let output_path = args.get(3).map(String::as_str).unwrap_or("snapshot.json");
+        return replay::run(events_path, output_path, config).await;
+    }
 
-    // Start snapshot saving task
-    let processor_snapshot = processor.clone();
+    let backend: Arc<RwLock<dyn StreamBackend>> = match config.stream_backend.as_str() {
+        "memory" => {
+            info!("Using the in-memory stream backend; nothing is read from or written to Redis");
+            Arc::new(RwLock::new(MemoryBackend::new(config.stream_max_len)))
+        }
+        #[cfg(feature = "kafka")]
+        "kafka" => {
+            info!(
+                "Reading orders from Kafka topic {}; responses still go through Redis",
+                config.kafka_topic
+            );
+            let consumer = engine::kafka::RdKafkaConsumer::new(
+                &config.kafka_brokers,
+                &config.kafka_topic,
+                &config.consumer_group,
+            )?;
+            Arc::new(RwLock::new(engine::kafka::KafkaBackend::new(
+                Box::new(consumer),
+                Box::new(RedisManager::new(config.stream_max_len).await?),
+                "orders",
+            )))
+        }
+        _ => Arc::new(RwLock::new(RedisManager::new(config.stream_max_len).await?)),
+    };
+    let balance_manager = Arc::new(BalanceManager::new(config.clone()));
+    let mut processor = Processor::new(backend.clone(), balance_manager.clone(), config.clone());
+    if !config.query_stream.is_empty() {
+        // The memory backend only exists in this process, so queries share it
+        let query_backend: Arc<RwLock<dyn StreamBackend>> = match config.stream_backend.as_str() {
+            "memory" => backend.clone(),
+            _ => Arc::new(RwLock::new(RedisManager::new(config.stream_max_len).await?)),
+        };
+        processor = processor.with_query_backend(query_backend);
+    }
+    let processor = Arc::new(processor);
+
+    // Start health endpoint
+    let processor_health = processor.clone();
+    let health_addr = config.health_addr.clone();
     tokio::spawn(async move {
-        let mut interval = interval(Duration::from_secs(5));
-        loop {
-            interval.tick().await;
-            if let Err(e) = processor_snapshot.save_snapshot().await {
-                error!("Failed to save snapshot: {}", e);
-            }
+        if let Err(e) = serve_health(processor_health, health_addr).await {
+            error!("Health endpoint stopped: {}", e);
         }
     });
 
+    // Start WebSocket gateway
+    if !config.ws_addr.is_empty() {
+        let processor_ws = processor.clone();
+        let ws_addr = config.ws_addr.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_ws(processor_ws, ws_addr).await {
+                error!("WebSocket gateway stopped: {}", e);
+            }
+        });
+    }
+
+    if config.admin_token.is_empty() {
+        warn!("No admin token configured, privileged actions are open to any stream writer");
+    }
+
+    // Load snapshot if exists and validate Redis, config and restored state
+    processor.startup_self_test().await?;
+
+    // SIGUSR1 toggles the processing kill-switch
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        let processor_signal = processor.clone();
+        let mut pause_signal = signal(SignalKind::user_defined1())?;
+        tokio::spawn(async move {
+            while pause_signal.recv().await.is_some() {
+                processor_signal.toggle_pause().await;
+            }
+        });
+    }
+
+    // Start the read-only query loop
+    if !config.query_stream.is_empty() {
+        let processor_query = processor.clone();
+        tokio::spawn(async move {
+            if let Err(e) = processor_query.start_query_processing().await {
+                error!("Query processing stopped: {}", e);
+            }
+        });
+    }
+
+    // Start snapshot saving task (not spawned in memory-only mode)
+    if config.snapshot_enabled {
+        let processor_snapshot = processor.clone();
+        tokio::spawn(async move {
+            run_spaced(Duration::from_secs(5), || async {
+                if let Err(e) = processor_snapshot.save_snapshot().await {
+                    error!("Failed to save snapshot: {}", e);
+                }
+            })
+            .await;
+        });
+    }
+
     // Start liquidation checker
-    let balance_manager_liquidation = balance_manager.clone();
+    let processor_liquidation = processor.clone();
+    let full_scan_every = config.liquidation_full_scan_secs.max(1);
     tokio::spawn(async move {
         let mut interval = interval(Duration::from_secs(1));
+        let mut ticks: u64 = 0;
         loop {
             interval.tick().await;
-            let liquidated_orders = {
-                let balance_manager = balance_manager_liquidation.read().await;
-                balance_manager.check_liquidations().await
-            };
-
-            for (order_id, user_id) in liquidated_orders {
-                info!("Liquidating order: {} for user: {}", order_id, user_id);
-                let result = {
-                    let balance_manager = balance_manager_liquidation.read().await;
-                    balance_manager.liquidate_order(&order_id).await
-                };
-                if let Err(e) = result {
-                    error!("Failed to liquidate order {}: {}", order_id, e);
-                }
+            // The first tick and every full_scan_every-th one re-check all assets
+            let full_scan = ticks.is_multiple_of(full_scan_every);
+            ticks += 1;
+            processor_liquidation.liquidate_breached(full_scan).await;
+            processor_liquidation.close_stale_positions().await;
+        }
+    });
+
+    // Start map compaction task
+    let balance_manager_compaction = balance_manager.clone();
+    let compaction_interval = config.compaction_interval_secs.max(1);
+    tokio::spawn(async move {
+        let mut interval = interval(Duration::from_secs(compaction_interval));
+        loop {
+            interval.tick().await;
+            let pruned = balance_manager_compaction.compact_maps().await;
+            if pruned > 0 {
+                info!("Compaction pruned {} empty map entries", pruned);
+            }
+
+            let evicted = balance_manager_compaction.evict_idle_users().await;
+            for user_id in evicted {
+                info!("Evicted idle user {}", user_id);
             }
         }
     });
 
+    // Start funding task
+    if config.funding_interval_secs > 0 {
+        let processor_funding = processor.clone();
+        let funding_interval = config.funding_interval_secs;
+        tokio::spawn(async move {
+            let mut interval = interval(Duration::from_secs(funding_interval));
+            // The first tick fires immediately; funding is charged at the end of each period
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                processor_funding.apply_funding().await;
+            }
+        });
+    }
+
+    // Start equity sampling task
+    if config.equity_interval_secs > 0 {
+        let balance_manager_equity = balance_manager.clone();
+        let equity_interval = config.equity_interval_secs;
+        tokio::spawn(async move {
+            let mut interval = interval(Duration::from_secs(equity_interval));
+            loop {
+                interval.tick().await;
+                balance_manager_equity.record_equity().await;
+            }
+        });
+    }
+
+    // Start held price flusher
+    if config.price_coalesce_ms > 0 {
+        let processor_prices = processor.clone();
+        tokio::spawn(async move {
+            processor_prices.run_held_price_flusher().await;
+        });
+    }
+
+    // Start clock drift checker; the startup check ran in the self-test
+    if config.clock_drift_tolerance_ms > 0 {
+        let processor_clock = processor.clone();
+        let clock_interval = config.clock_drift_check_secs.max(1);
+        tokio::spawn(async move {
+            let mut interval = interval(Duration::from_secs(clock_interval));
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if let Err(e) = processor_clock.check_clock_drift().await {
+                    warn!("Failed to check clock drift: {}", e);
+                }
+            }
+        });
+    }
+
     // Start processing orders
     processor.start_processing().await?;
     Ok(())
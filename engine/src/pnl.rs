@@ -0,0 +1,73 @@
+//pnl.rs
+use rust_decimal::Decimal;
+
+use crate::balance_manager::Order;
+
+// How an instrument turns a price move into profit or loss for an open order
+pub trait PnlModel: Send + Sync {
+    fn realized_pnl(&self, order: &Order, current_price: Decimal) -> Decimal;
+}
+
+// Linear contracts: pnl in the quote currency, quantity * price move
+pub struct LinearPnl;
+
+impl PnlModel for LinearPnl {
+    fn realized_pnl(&self, order: &Order, current_price: Decimal) -> Decimal {
+        if order.order_type == "long" {
+            (current_price - order.open_price) * order.quantity
+        } else {
+            (order.open_price - current_price) * order.quantity
+        }
+    }
+}
+
+// Inverse contracts: the position is sized in the quote currency and pnl is paid in the base
+// asset, notional * (1 / open - 1 / current) for longs
+pub struct InversePnl;
+
+impl PnlModel for InversePnl {
+    fn realized_pnl(&self, order: &Order, current_price: Decimal) -> Decimal {
+        if order.open_price <= Decimal::ZERO || current_price <= Decimal::ZERO {
+            return Decimal::ZERO;
+        }
+
+        let move_per_unit = Decimal::ONE / order.open_price - Decimal::ONE / current_price;
+        if order.order_type == "long" {
+            order.notional() * move_per_unit
+        } else {
+            -order.notional() * move_per_unit
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+
+    fn filled(order_type: &str) -> Order {
+        Order {
+            open_price: dec("1000"),
+            quantity: dec("1"),
+            ..order("o1", "u1", order_type, "100", 10)
+        }
+    }
+
+    #[test]
+    fn linear_and_inverse_models_price_the_same_move_differently() {
+        let (long, short) = (filled("long"), filled("short"));
+
+        // Linear pays the quote move; inverse pays 1000 * (1/1000 - 1/1250) in the base asset
+        assert_eq!(LinearPnl.realized_pnl(&long, dec("1250")), dec("250"));
+        assert_eq!(LinearPnl.realized_pnl(&short, dec("1250")), dec("-250"));
+        assert_eq!(InversePnl.realized_pnl(&long, dec("1250")), dec("0.2"));
+        assert_eq!(InversePnl.realized_pnl(&short, dec("1250")), dec("-0.2"));
+
+        // A move of the same size the other way is symmetric for linear but not for inverse
+        assert_eq!(LinearPnl.realized_pnl(&long, dec("750")), dec("-250"));
+        assert_eq!(
+            InversePnl.realized_pnl(&long, dec("750")).round_dp(8),
+            dec("-0.33333333")
+        );
+    }
+}
@@ -1,238 +1,767 @@
 //processor.rs
 use anyhow::Result;
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
 use redis::Value as RedisValue;
 use rust_decimal::Decimal;
 use serde_json::{Value, json};
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io::{Read, Write};
 use std::str::FromStr;
 use std::sync::Arc;
 use tokio::fs;
-use tokio::sync::RwLock;
+use tokio::sync::{RwLock, broadcast};
 use tracing::{error, info, warn};
 
-use crate::balance_manager::{AssetPrice, BalanceManager, LiquidationEntry, Order};
-use crate::redis_manager::RedisManager;
+use crate::balance_manager::{
+    ALREADY_SETTLED, AssetPrice, BalanceManager, ClosedOrder, LiquidationEntry, MarginMode, Order,
+    PendingOrder, RiskProfile, TriggerType,
+};
+use crate::config::EngineConfig;
+use crate::stream_backend::StreamBackend;
+use crate::types::Action;
+
+// Bounded memory of recently handled request ids and the response each one produced
+struct RecentRequests {
+    responses: HashMap<String, (i64, Value)>,
+    order: VecDeque<String>,
+}
+
+impl RecentRequests {
+    fn new() -> Self {
+        Self {
+            responses: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn get(&self, request_id: &str) -> Option<&Value> {
+        self.responses.get(request_id).map(|(_, response)| response)
+    }
+
+    // Records a response, dropping entries past the ttl and the oldest ones over capacity
+    fn insert(
+        &mut self,
+        request_id: String,
+        response: Value,
+        now: i64,
+        ttl_secs: i64,
+        capacity: usize,
+    ) {
+        while let Some(oldest) = self.order.front() {
+            let expired = self
+                .responses
+                .get(oldest)
+                .is_none_or(|(seen_at, _)| now - seen_at > ttl_secs);
+            if !expired && self.order.len() < capacity {
+                break;
+            }
+            if let Some(oldest) = self.order.pop_front() {
+                self.responses.remove(&oldest);
+            }
+        }
+
+        if self
+            .responses
+            .insert(request_id.clone(), (now, response))
+            .is_none()
+        {
+            self.order.push_back(request_id);
+        }
+    }
+}
+
+fn message_action(message: &Value) -> Option<Action> {
+    message
+        .get("action")
+        .and_then(|v| v.as_str())
+        .and_then(|action| action.parse().ok())
+}
+
+async fn append_line(path: &str, line: &str) -> std::io::Result<()> {
+    use tokio::io::AsyncWriteExt;
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    file.write_all(format!("{}\n", line).as_bytes()).await
+}
+
+// Rolling set of the stream ids of applied mutations, oldest evicted first
+struct ProcessedIds {
+    ids: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+impl ProcessedIds {
+    fn new() -> Self {
+        Self {
+            ids: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn contains(&self, id: &str) -> bool {
+        self.ids.contains(id)
+    }
+
+    fn insert(&mut self, id: String, capacity: usize) {
+        if !self.ids.insert(id.clone()) {
+            return;
+        }
+        self.order.push_back(id);
+        while self.order.len() > capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+    }
+}
+
+// db_queue records that failed to write, oldest first. `spilled` means older ones still wait in
+// config.db_spill_path and must go out before any of `records`.
+struct DbRetryBuffer {
+    records: VecDeque<String>,
+    spilled: bool,
+}
 
 pub struct Processor {
-    redis_manager: Arc<RwLock<RedisManager>>,
-    balance_manager: Arc<RwLock<BalanceManager>>,
+    backend: Arc<RwLock<dyn StreamBackend>>,
+    // Separate connection for the query stream, so its blocking reads never hold up orders
+    query_backend: Option<Arc<RwLock<dyn StreamBackend>>>,
+    balance_manager: Arc<BalanceManager>,
     last_processed_id: Arc<RwLock<String>>,
+    config: EngineConfig,
+    // When this processor was created, in ms; warmup_assets prices must be newer than this
+    started_at: i64,
+    // Warmup gate: orders are deferred until the snapshot is loaded and prices are flowing
+    snapshot_loaded: Arc<RwLock<bool>>,
+    ready: Arc<RwLock<bool>>,
+    // Kill-switch: set by SIGUSR1, combined with config.pause_file into `paused`
+    pause_requested: Arc<RwLock<bool>>,
+    paused: Arc<RwLock<bool>>,
+    // Every published (channel, payload), relayed to WebSocket gateway clients
+    responses: broadcast::Sender<(String, String)>,
+    // CLOSE_ORDER responses by requestId, so a redelivered close replays instead of re-executing
+    recent_closes: Arc<RwLock<RecentRequests>>,
+    // db_queue records that failed to write, retried in order ahead of any newer record
+    pending_db_records: Arc<RwLock<DbRetryBuffer>>,
+    // How long the last snapshot save took, end to end
+    last_snapshot_ms: Arc<RwLock<Option<u64>>>,
+    // Ids of applied mutations, persisted in the snapshot. Held for writing while a message is
+    // applied and for reading while a snapshot is taken, so the two always agree.
+    processed_ids: Arc<RwLock<ProcessedIds>>,
+    // Price coalescing: the latest held-back tick per asset, and when each asset last applied one
+    held_prices: Arc<RwLock<HashMap<String, AssetPrice>>>,
+    price_applied_at: Arc<RwLock<HashMap<String, i64>>>,
+    // Subscriber count per update channel (prices:{symbol}, positions:{user}); updates are only
+    // computed and published for channels someone is subscribed to
+    subscriptions: Arc<RwLock<HashMap<String, usize>>>,
+    // Backend clock minus local clock at the last drift check, in ms
+    clock_drift_ms: Arc<RwLock<Option<i64>>>,
 }
 
 impl Processor {
     pub fn new(
-        redis_manager: Arc<RwLock<RedisManager>>,
-        balance_manager: Arc<RwLock<BalanceManager>>,
+        backend: Arc<RwLock<dyn StreamBackend>>,
+        balance_manager: Arc<BalanceManager>,
+        config: EngineConfig,
     ) -> Self {
+        // A spill file left by a previous run still has to be drained
+        let spilled = !config.db_spill_path.is_empty()
+            && std::path::Path::new(&config.db_spill_path).exists();
         Self {
-            redis_manager,
+            backend,
+            query_backend: None,
+            started_at: chrono::Utc::now().timestamp_millis(),
             balance_manager,
             last_processed_id: Arc::new(RwLock::new("$".to_string())),
+            config,
+            snapshot_loaded: Arc::new(RwLock::new(false)),
+            ready: Arc::new(RwLock::new(false)),
+            pause_requested: Arc::new(RwLock::new(false)),
+            paused: Arc::new(RwLock::new(false)),
+            responses: broadcast::channel(1024).0,
+            recent_closes: Arc::new(RwLock::new(RecentRequests::new())),
+            pending_db_records: Arc::new(RwLock::new(DbRetryBuffer {
+                records: VecDeque::new(),
+                spilled,
+            })),
+            last_snapshot_ms: Arc::new(RwLock::new(None)),
+            processed_ids: Arc::new(RwLock::new(ProcessedIds::new())),
+            held_prices: Arc::new(RwLock::new(HashMap::new())),
+            price_applied_at: Arc::new(RwLock::new(HashMap::new())),
+            subscriptions: Arc::new(RwLock::new(HashMap::new())),
+            clock_drift_ms: Arc::new(RwLock::new(None)),
+        }
+    }
+
+    pub fn with_query_backend(mut self, query_backend: Arc<RwLock<dyn StreamBackend>>) -> Self {
+        self.query_backend = Some(query_backend);
+        self
+    }
+
+    pub fn subscribe_responses(&self) -> broadcast::Receiver<(String, String)> {
+        self.responses.subscribe()
+    }
+
+    pub async fn health(&self) -> Value {
+        let clock_drift_ms = *self.clock_drift_ms.read().await;
+        json!({
+            "ready": *self.ready.read().await,
+            "paused": *self.paused.read().await,
+            "snapshotLoaded": *self.snapshot_loaded.read().await,
+            "lastProcessedId": *self.last_processed_id.read().await,
+            "pendingDbRecords": self.pending_db_records.read().await.records.len(),
+            "dbRecordsSpilled": self.pending_db_records.read().await.spilled,
+            "lastSnapshotMs": *self.last_snapshot_ms.read().await,
+            "clockDriftMs": clock_drift_ms,
+            "clockDriftExceeded": self.clock_drift_exceeded(clock_drift_ms),
+        })
+    }
+
+    fn clock_drift_exceeded(&self, drift_ms: Option<i64>) -> bool {
+        let tolerance = self.config.clock_drift_tolerance_ms;
+        tolerance > 0 && drift_ms.is_some_and(|drift| drift.abs() > tolerance)
+    }
+
+    // Compares the local clock to the backend's. Stream ids carry the backend's clock while
+    // staleness checks use ours, so drift past the tolerance is worth a warning.
+    pub async fn check_clock_drift(&self) -> Result<i64> {
+        let before = chrono::Utc::now().timestamp_millis();
+        let server_ms = self.backend.write().await.server_time_ms().await?;
+        let after = chrono::Utc::now().timestamp_millis();
+        // Half the round trip is the best guess for when the backend read its clock
+        let drift = server_ms - (before + after) / 2;
+        *self.clock_drift_ms.write().await = Some(drift);
+        if self.clock_drift_exceeded(Some(drift)) {
+            warn!(
+                "Clock drift of {}ms against the stream backend exceeds the {}ms tolerance",
+                drift, self.config.clock_drift_tolerance_ms
+            );
+        }
+        Ok(drift)
+    }
+
+    pub async fn toggle_pause(&self) {
+        let mut pause_requested = self.pause_requested.write().await;
+        *pause_requested = !*pause_requested;
+        info!(
+            "Pause {} by signal",
+            if *pause_requested {
+                "requested"
+            } else {
+                "lifted"
+            }
+        );
+    }
+
+    // Re-evaluates the kill-switch and logs transitions; returns whether processing is paused
+    async fn refresh_paused(&self) -> bool {
+        let pause_file = &self.config.pause_file;
+        let file_present =
+            !pause_file.is_empty() && fs::try_exists(pause_file).await.unwrap_or(false);
+        let now_paused = file_present || *self.pause_requested.read().await;
+
+        let mut paused = self.paused.write().await;
+        if *paused != now_paused {
+            if now_paused {
+                warn!("Processing paused");
+            } else {
+                info!("Processing resumed");
+            }
+            *paused = now_paused;
+        }
+        now_paused
+    }
+
+    // Flips the engine to ready once the snapshot is in place and (optionally) a price has arrived
+    async fn refresh_readiness(&self) -> bool {
+        if *self.ready.read().await {
+            return true;
+        }
+
+        if !*self.snapshot_loaded.read().await {
+            return false;
+        }
+
+        if self.config.warmup_require_price
+            && self.balance_manager.asset_prices.read().await.is_empty()
+        {
+            return false;
+        }
+
+        // Prices restored from the snapshot don't count; each listed asset needs a live one
+        let missing: Vec<String> = {
+            let prices = self.balance_manager.asset_prices.read().await;
+            self.config
+                .warmup_assets
+                .split(',')
+                .map(str::trim)
+                .filter(|asset| !asset.is_empty())
+                .filter(|asset| {
+                    prices
+                        .get(*asset)
+                        .is_none_or(|price| price.updated_at < self.started_at)
+                })
+                .map(str::to_string)
+                .collect()
+        };
+        if !missing.is_empty() {
+            let timeout_secs = self.config.warmup_timeout_secs;
+            let waited_ms = chrono::Utc::now().timestamp_millis() - self.started_at;
+            if timeout_secs == 0 || waited_ms < timeout_secs as i64 * 1000 {
+                return false;
+            }
+            warn!(
+                "Warmup timed out after {}s still missing prices for {}; going ready anyway",
+                timeout_secs,
+                missing.join(", ")
+            );
         }
+
+        *self.ready.write().await = true;
+        info!("Engine warmed up, processing orders");
+        true
     }
 
     pub async fn load_snapshot(&self) -> Result<()> {
-        match fs::read_to_string("snapshot.json").await {
-            Ok(content) => {
-                let snapshot: Value = serde_json::from_str(&content)?;
-                info!("Loading snapshot from file");
-
-                // Restore users
-                if let Some(users_data) = snapshot.get("users") {
-                    if let Ok(users_map) = serde_json::from_value::<
-                        HashMap<String, crate::balance_manager::UserBalance>,
-                    >(users_data.clone())
-                    {
-                        let balance_manager = self.balance_manager.write().await;
-                        let mut users = balance_manager.users.write().await;
-                        *users = users_map;
-                        info!("Restored {} users from snapshot", users.len());
-                    }
-                }
+        if !self.config.snapshot_enabled {
+            info!("Snapshots disabled, running in memory-only mode");
+            *self.snapshot_loaded.write().await = true;
+            return Ok(());
+        }
 
-                // Restore orders in new optimized format
-                if let Some(orders_data) = snapshot.get("orders_by_id") {
-                    if let Ok(orders_map) =
-                        serde_json::from_value::<HashMap<String, Order>>(orders_data.clone())
-                    {
-                        let balance_manager = self.balance_manager.write().await;
-                        let mut orders_by_id = balance_manager.orders_by_id.write().await;
-                        *orders_by_id = orders_map;
-                        info!("Restored {} orders by ID from snapshot", orders_by_id.len());
-                    }
-                }
+        let Some((path, snapshot)) = self.read_latest_snapshot().await? else {
+            info!("No snapshot found, starting fresh");
+            *self.snapshot_loaded.write().await = true;
+            return Ok(());
+        };
+        info!("Loading snapshot from {}", path);
 
-                if let Some(user_orders_data) = snapshot.get("orders_by_user") {
-                    if let Ok(user_orders_map) = serde_json::from_value::<
-                        HashMap<String, Vec<String>>,
-                    >(user_orders_data.clone())
-                    {
-                        let balance_manager = self.balance_manager.write().await;
-                        let mut orders_by_user = balance_manager.orders_by_user.write().await;
-                        *orders_by_user = user_orders_map;
-                        info!("Restored user order mappings from snapshot");
-                    }
+        // Staleness, expiry and cooldowns compare stored times against the clock, so a
+        // snapshot from ahead of it would make them misbehave
+        if let Some(saved_at) = snapshot.get("timestamp").and_then(|v| v.as_i64()) {
+            let ahead = saved_at - chrono::Utc::now().timestamp();
+            if ahead > self.config.snapshot_future_tolerance_secs {
+                if self.config.snapshot_future_abort {
+                    return Err(anyhow::anyhow!(
+                        "{} is dated {}s in the future; check the clock or remove it",
+                        path,
+                        ahead
+                    ));
                 }
+                warn!(
+                    "{} is dated {}s in the future; time-based checks may misbehave",
+                    path, ahead
+                );
+            }
+        }
 
-                // Restore liquidation map
-                if let Some(liquidation_data) = snapshot.get("liquidation_map") {
-                    if let Ok(liquidation_map) = serde_json::from_value::<
-                        HashMap<String, BTreeMap<String, Vec<LiquidationEntry>>>,
-                    >(liquidation_data.clone())
-                    {
-                        let balance_manager = self.balance_manager.write().await;
-                        let mut liquidation_map_lock =
-                            balance_manager.liquidation_map.write().await;
-                        *liquidation_map_lock = liquidation_map;
-                        info!("Restored liquidation map from snapshot");
-                    }
+        // Restore users
+        if let Some(users_data) = snapshot.get("users")
+            && let Ok(users_map) = serde_json::from_value::<
+                HashMap<String, crate::balance_manager::UserBalance>,
+            >(users_data.clone())
+        {
+            let mut users = self.balance_manager.users.write().await;
+            *users = users_map;
+            info!("Restored {} users from snapshot", users.len());
+        }
+
+        // Restore orders in new optimized format
+        if let Some(orders_data) = snapshot.get("orders_by_id")
+            && let Ok(orders_map) =
+                serde_json::from_value::<HashMap<String, Order>>(orders_data.clone())
+        {
+            let mut orders_by_id = self.balance_manager.orders_by_id.write().await;
+            *orders_by_id = orders_map;
+            info!("Restored {} orders by ID from snapshot", orders_by_id.len());
+        }
+
+        if let Some(user_orders_data) = snapshot.get("orders_by_user")
+            && let Ok(user_orders_map) =
+                serde_json::from_value::<HashMap<String, Vec<String>>>(user_orders_data.clone())
+        {
+            let mut orders_by_user = self.balance_manager.orders_by_user.write().await;
+            *orders_by_user = user_orders_map;
+            info!("Restored user order mappings from snapshot");
+        }
+
+        // Restore liquidation map
+        if let Some(liquidation_data) = snapshot.get("liquidation_map")
+            && let Ok(liquidation_map) = serde_json::from_value::<
+                HashMap<String, BTreeMap<String, Vec<LiquidationEntry>>>,
+            >(liquidation_data.clone())
+        {
+            let mut liquidation_map_lock = self.balance_manager.liquidation_map.write().await;
+            *liquidation_map_lock = liquidation_map;
+            info!("Restored liquidation map from snapshot");
+        }
+
+        // Snapshots written without the derived maps get them rebuilt from the orders
+        if snapshot.get("orders_by_id").is_some()
+            && (snapshot.get("orders_by_user").is_none()
+                || snapshot.get("liquidation_map").is_none())
+        {
+            self.balance_manager.rebuild_order_indexes().await;
+            info!("Rebuilt user order mappings and liquidation map from orders");
+        }
+
+        // Support old format for backward compatibility
+        if let Some(old_orders_data) = snapshot.get("orders")
+            && let Ok(old_orders_map) =
+                serde_json::from_value::<HashMap<String, Vec<Order>>>(old_orders_data.clone())
+        {
+            info!("Found old format orders, converting to new format...");
+
+            let mut orders_by_id = self.balance_manager.orders_by_id.write().await;
+            let mut orders_by_user = self.balance_manager.orders_by_user.write().await;
+            let mut liquidation_map = self.balance_manager.liquidation_map.write().await;
+
+            for (_user_id, user_orders) in old_orders_map {
+                for order in user_orders {
+                    // Add to orders_by_id
+                    orders_by_id.insert(order.order_id.clone(), order.clone());
+
+                    // Add to orders_by_user
+                    orders_by_user
+                        .entry(order.user_id.clone())
+                        .or_insert_with(Vec::new)
+                        .push(order.order_id.clone());
+
+                    // Add to liquidation_map
+                    let liquidation_price =
+                        self.balance_manager.calculate_liquidation_price(&order);
+                    let liquidation_entry = LiquidationEntry {
+                        order_id: order.order_id.clone(),
+                        user_id: order.user_id.clone(),
+                        liquidation_price,
+                    };
+
+                    let price_key = liquidation_price.to_string();
+                    liquidation_map
+                        .entry(order.asset.clone())
+                        .or_insert_with(BTreeMap::new)
+                        .entry(price_key)
+                        .or_insert_with(Vec::new)
+                        .push(liquidation_entry);
                 }
+            }
 
-                // Support old format for backward compatibility
-                if let Some(old_orders_data) = snapshot.get("orders") {
-                    if let Ok(old_orders_map) = serde_json::from_value::<HashMap<String, Vec<Order>>>(
-                        old_orders_data.clone(),
-                    ) {
-                        info!("Found old format orders, converting to new format...");
-
-                        let balance_manager = self.balance_manager.write().await;
-                        let mut orders_by_id = balance_manager.orders_by_id.write().await;
-                        let mut orders_by_user = balance_manager.orders_by_user.write().await;
-                        let mut liquidation_map = balance_manager.liquidation_map.write().await;
-
-                        for (_user_id, user_orders) in old_orders_map {
-                            for order in user_orders {
-                                // Add to orders_by_id
-                                orders_by_id.insert(order.order_id.clone(), order.clone());
-
-                                // Add to orders_by_user
-                                orders_by_user
-                                    .entry(order.user_id.clone())
-                                    .or_insert_with(Vec::new)
-                                    .push(order.order_id.clone());
-
-                                // Add to liquidation_map
-                                let liquidation_price = self.calculate_liquidation_price(&order);
-                                let liquidation_entry = LiquidationEntry {
-                                    order_id: order.order_id.clone(),
-                                    user_id: order.user_id.clone(),
-                                    liquidation_price,
-                                };
-
-                                let price_key = liquidation_price.to_string();
-                                liquidation_map
-                                    .entry(order.asset.clone())
-                                    .or_insert_with(BTreeMap::new)
-                                    .entry(price_key)
-                                    .or_insert_with(Vec::new)
-                                    .push(liquidation_entry);
-                            }
-                        }
+            info!(
+                "Converted {} orders to new optimized format",
+                orders_by_id.len()
+            );
+        }
 
-                        info!(
-                            "Converted {} orders to new optimized format",
-                            orders_by_id.len()
+        // Restore realized history
+        if let Some(closed_data) = snapshot.get("closed_orders")
+            && let Ok(closed_map) =
+                serde_json::from_value::<HashMap<String, Vec<ClosedOrder>>>(closed_data.clone())
+        {
+            let mut closed_orders = self.balance_manager.closed_orders.write().await;
+            *closed_orders = closed_map;
+            info!(
+                "Restored closed order history for {} users",
+                closed_orders.len()
+            );
+        }
+
+        // Restore insurance fund
+        if let Some(fund) = snapshot
+            .get("insurance_fund")
+            .and_then(|v| serde_json::from_value::<Decimal>(v.clone()).ok())
+        {
+            *self.balance_manager.insurance_fund.write().await = fund;
+            info!("Restored insurance fund: {}", fund);
+        }
+
+        // Restore disabled assets
+        if let Some(disabled_data) = snapshot.get("disabled_assets")
+            && let Ok(disabled) = serde_json::from_value::<HashSet<String>>(disabled_data.clone())
+        {
+            *self.balance_manager.disabled_assets.write().await = disabled;
+        }
+
+        // Restore pending limit/stop orders
+        if let Some(pending_data) = snapshot.get("pending_orders")
+            && let Ok(pending) =
+                serde_json::from_value::<HashMap<String, PendingOrder>>(pending_data.clone())
+        {
+            *self.balance_manager.pending_orders.write().await = pending;
+        }
+
+        // Restore prices
+        if let Some(prices_data) = snapshot.get("prices")
+            && let Ok(prices_map) =
+                serde_json::from_value::<HashMap<String, AssetPrice>>(prices_data.clone())
+        {
+            let mut prices = self.balance_manager.asset_prices.write().await;
+            *prices = prices_map;
+            // Restored prices get a fresh feed_timeout_secs window to resume updating
+            let now = chrono::Utc::now().timestamp_millis();
+            for price in prices.values_mut() {
+                price.updated_at = now;
+            }
+            info!("Restored {} asset prices from snapshot", prices.len());
+        }
+
+        // Restore last processed ID
+        if let Some(last_id) = snapshot.get("last_processed_id").and_then(|v| v.as_str()) {
+            let mut last_processed_id = self.last_processed_id.write().await;
+            *last_processed_id = last_id.to_string();
+            info!("Restored last processed ID: {}", last_id);
+        }
+
+        // Restore the mutation ledger
+        if let Some(ids_data) = snapshot.get("processed_ids")
+            && let Ok(ids) = serde_json::from_value::<Vec<String>>(ids_data.clone())
+        {
+            let mut processed_ids = self.processed_ids.write().await;
+            for id in ids {
+                processed_ids.insert(id, self.config.processed_id_capacity);
+            }
+            info!("Restored {} processed ids", processed_ids.order.len());
+        }
+
+        self.balance_manager.rebuild_open_notional().await;
+
+        info!("Snapshot loaded successfully");
+        *self.snapshot_loaded.write().await = true;
+        Ok(())
+    }
+
+    // The newest snapshot that decodes, falling back through the rotated copies when a newer one
+    // is corrupt. None when there is no snapshot at all; an error when every copy is corrupt.
+    async fn read_latest_snapshot(&self) -> Result<Option<(String, Value)>> {
+        let latest = snapshot_file(&self.config);
+        let mut corrupt = Vec::new();
+        for generation in 0..self.config.snapshot_retention.max(1) {
+            let path = rotated_snapshot_file(&latest, generation);
+            let Ok(bytes) = fs::read(&path).await else {
+                continue;
+            };
+            match decode_snapshot(&bytes) {
+                Ok(snapshot) => {
+                    if !corrupt.is_empty() {
+                        warn!(
+                            "Falling back to {} because {} could not be read",
+                            path,
+                            corrupt.join(", ")
                         );
                     }
+                    return Ok(Some((path, snapshot)));
                 }
-
-                // Restore prices
-                if let Some(prices_data) = snapshot.get("prices") {
-                    if let Ok(prices_map) =
-                        serde_json::from_value::<HashMap<String, AssetPrice>>(prices_data.clone())
-                    {
-                        let balance_manager = self.balance_manager.write().await;
-                        let mut prices = balance_manager.asset_prices.write().await;
-                        *prices = prices_map;
-                        info!("Restored {} asset prices from snapshot", prices.len());
-                    }
+                Err(e) => {
+                    error!("{} is corrupt: {}", path, e);
+                    corrupt.push(path);
                 }
+            }
+        }
+
+        if corrupt.is_empty() {
+            Ok(None)
+        } else {
+            Err(anyhow::anyhow!(
+                "{} is corrupt and no older snapshot could be loaded",
+                corrupt.join(", ")
+            ))
+        }
+    }
 
-                // Restore last processed ID
-                if let Some(last_id) = snapshot.get("last_processed_id").and_then(|v| v.as_str()) {
-                    let mut last_processed_id = self.last_processed_id.write().await;
-                    *last_processed_id = last_id.to_string();
-                    info!("Restored last processed ID: {}", last_id);
+    // Checks config, snapshot and stream backend before processing, failing if any check fails
+    pub async fn startup_self_test(&self) -> Result<()> {
+        let mut failures = Vec::new();
+
+        let config_problems = self.config.validate();
+        let config_check = if config_problems.is_empty() {
+            Ok(())
+        } else {
+            Err(config_problems.join("; "))
+        };
+        self.report_check("config", config_check, &mut failures);
+
+        let snapshot_check = match self.load_snapshot().await {
+            Ok(()) => {
+                let repaired = self.balance_manager.prune_dangling_references().await;
+                if repaired > 0 {
+                    warn!("Pruned {} dangling index entries from snapshot", repaired);
+                }
+                let violations = self.balance_manager.check_invariants().await;
+                if violations.is_empty() {
+                    Ok(())
+                } else {
+                    Err(violations.join("; "))
                 }
+            }
+            Err(e) => Err(e.to_string()),
+        };
+        self.report_check("snapshot", snapshot_check, &mut failures);
 
-                info!("Snapshot loaded successfully");
-                Ok(())
+        let backend_check = {
+            let mut backend = self.backend.write().await;
+            let ready = match backend.ping().await {
+                Ok(()) => match backend.ensure_stream("orders").await {
+                    Ok(()) => backend.ensure_stream("db_queue").await,
+                    err => err,
+                },
+                err => err,
+            };
+            match ready {
+                Ok(()) if self.config.use_consumer_group => {
+                    let start_id = self.last_processed_id.read().await.clone();
+                    backend
+                        .ensure_group("orders", &self.config.consumer_group, &start_id)
+                        .await
+                }
+                other => other,
             }
-            Err(_) => {
-                info!("No snapshot found, starting fresh");
-                Ok(())
+        };
+        self.report_check(
+            "backend",
+            backend_check.map_err(|e| e.to_string()),
+            &mut failures,
+        );
+
+        // Drift only warns; a skewed clock is no reason to refuse to start
+        if self.config.clock_drift_tolerance_ms > 0
+            && let Err(e) = self.check_clock_drift().await
+        {
+            warn!("Failed to check clock drift: {}", e);
+        }
+
+        if failures.is_empty() {
+            info!("Self-test PASS");
+            Ok(())
+        } else {
+            error!("Self-test FAIL: {}", failures.join(", "));
+            Err(anyhow::anyhow!(
+                "Startup self-test failed: {}",
+                failures.join(" | ")
+            ))
+        }
+    }
+
+    fn report_check(&self, name: &str, result: Result<(), String>, failures: &mut Vec<String>) {
+        match result {
+            Ok(()) => info!("Self-test {}: PASS", name),
+            Err(e) => {
+                error!("Self-test {}: FAIL ({})", name, e);
+                failures.push(format!("{}: {}", name, e));
             }
         }
     }
 
     pub async fn save_snapshot(&self) -> Result<()> {
-        let balance_manager = self.balance_manager.read().await;
-        let users = balance_manager.users.read().await;
-        let orders_by_id = balance_manager.orders_by_id.read().await;
-        let orders_by_user = balance_manager.orders_by_user.read().await;
-        let liquidation_map = balance_manager.liquidation_map.read().await;
-        let prices = balance_manager.asset_prices.read().await;
-        let last_processed_id = self.last_processed_id.read().await;
-
-        // Log snapshot stats
-        let total_users_with_orders = orders_by_user.len();
-        info!(
-            "Saving snapshot: {} users, {} orders, {} users with orders, {} prices",
-            users.len(),
-            orders_by_id.len(),
-            total_users_with_orders,
-            prices.len()
-        );
-
-        let snapshot = json!({
-            "users": *users,
-            "orders_by_id": *orders_by_id,
-            "orders_by_user": *orders_by_user,
-            "liquidation_map": *liquidation_map,
-            "prices": *prices,
-            "last_processed_id": *last_processed_id,
-            "timestamp": chrono::Utc::now().timestamp()
-        });
+        if !self.config.snapshot_enabled {
+            return Ok(());
+        }
 
-        fs::write("snapshot.json", serde_json::to_string_pretty(&snapshot)?).await?;
-        info!("Snapshot saved successfully");
-        Ok(())
-    }
+        let started = std::time::Instant::now();
+        let snapshot = {
+            let processed_ids = self.processed_ids.read().await;
+            let last_processed_id = self.last_processed_id.read().await.clone();
+            let mut snapshot = snapshot_value(&self.balance_manager, &last_processed_id).await;
+            snapshot["processed_ids"] = json!(processed_ids.order);
+            snapshot
+        };
 
-    fn calculate_liquidation_price(&self, order: &Order) -> Decimal {
-        let liquidation_threshold = Decimal::from(90) / Decimal::from(order.leverage * 100);
+        let path = snapshot_file(&self.config);
+        // Shift the older copies down one generation; the oldest kept one is overwritten
+        for generation in (1..self.config.snapshot_retention).rev() {
+            let from = rotated_snapshot_file(&path, generation - 1);
+            if let Err(e) = fs::rename(&from, rotated_snapshot_file(&path, generation)).await
+                && e.kind() != std::io::ErrorKind::NotFound
+            {
+                warn!("Failed to rotate snapshot {}: {}", from, e);
+            }
+        }
+        fs::write(&path, encode_snapshot(&snapshot, path.ends_with(".gz"))?).await?;
 
-        if order.order_type == "long" {
-            order.open_price * (Decimal::from(1) - liquidation_threshold)
+        let elapsed_ms = started.elapsed().as_millis() as u64;
+        *self.last_snapshot_ms.write().await = Some(elapsed_ms);
+        if elapsed_ms > self.config.snapshot_warn_ms {
+            warn!("Snapshot saved, but took {}ms", elapsed_ms);
         } else {
-            order.open_price * (Decimal::from(1) + liquidation_threshold)
+            info!("Snapshot saved successfully in {}ms", elapsed_ms);
         }
+        Ok(())
     }
 
     pub async fn start_processing(&self) -> Result<()> {
         info!("Starting order processing loop");
 
+        if self.config.use_consumer_group {
+            self.recover_pending().await?;
+        }
+
+        // The read cursor runs ahead of last_processed_id while orders are deferred during
+        // warmup, so a snapshot taken mid-warmup never skips a message that wasn't applied yet
+        let mut read_id = self.last_processed_id.read().await.clone();
+        let mut deferred: VecDeque<(String, HashMap<String, RedisValue>)> = VecDeque::new();
+        let mut consecutive_failures: u32 = 0;
+
         loop {
-            let last_id = self.last_processed_id.read().await.clone();
+            self.flush_db_records().await;
+            self.flush_held_prices().await;
+
+            // Nothing is read (so nothing is acked) while paused; snapshots keep running
+            if self.refresh_paused().await {
+                tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+                continue;
+            }
 
             let result = {
-                let mut redis_manager = self.redis_manager.write().await;
-                redis_manager.read_stream("orders", &last_id).await
+                let mut backend = self.backend.write().await;
+                if self.config.use_consumer_group {
+                    backend
+                        .read_group(
+                            "orders",
+                            &self.config.consumer_group,
+                            &self.config.consumer_name,
+                            ">",
+                        )
+                        .await
+                } else {
+                    backend.read_stream("orders", &read_id).await
+                }
             };
             // println!("results: {:?} ", result);
 
             match result {
                 Ok(reply) => {
+                    consecutive_failures = 0;
                     for stream_key in reply.keys {
                         for stream_id in stream_key.ids {
                             let id = stream_id.id.clone();
+                            read_id = id.clone();
 
-                            if let Err(e) = self.process_message(stream_id.map).await {
-                                error!("Failed to process message {}: {}", id, e);
+                            // Redeliveries at or below the last applied id are acked, not reapplied
+                            if self.is_already_processed(&id).await {
+                                warn!("Skipping already processed message {}", id);
+                                self.ack_entry(&id).await;
+                                continue;
                             }
 
-                            // Update last processed ID
+                            // Deferred messages stay unacked until they are actually applied
+                            if !self.refresh_readiness().await
+                                && !self.is_price_message(&stream_id.map)
                             {
+                                if deferred.len() < self.config.warmup_max_deferred {
+                                    deferred.push_back((id, stream_id.map));
+                                } else {
+                                    self.reject_not_ready(&id, &stream_id.map).await;
+                                }
+                                continue;
+                            }
+
+                            self.process_entry(&id, stream_id.map).await;
+
+                            // Only advance past this message if nothing before it is still deferred
+                            if deferred.is_empty() {
                                 let mut last_processed_id = self.last_processed_id.write().await;
                                 *last_processed_id = id.clone();
                             }
@@ -240,448 +769,4715 @@ impl Processor {
                     }
                 }
                 Err(e) => {
-                    error!("Failed to read from stream: {}", e);
-                    tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+                    consecutive_failures = consecutive_failures.saturating_add(1);
+                    let delay_ms = retry_delay_ms(
+                        consecutive_failures,
+                        self.config.retry_base_ms,
+                        self.config.retry_max_ms,
+                        chrono::Utc::now().timestamp_subsec_nanos() as u64,
+                    );
+                    error!(
+                        "Failed to read from stream (attempt {}), retrying in {}ms: {}",
+                        consecutive_failures, delay_ms, e
+                    );
+                    tokio::time::sleep(tokio::time::Duration::from_millis(delay_ms)).await;
                 }
             }
-        }
-    }
 
-    async fn process_message(&self, data: HashMap<String, RedisValue>) -> Result<()> {
-        // println!("data on process message: {:?}", data);
-        let data_str = data
-            .get("data")
-            .and_then(|v| match v {
-                RedisValue::Data(bytes) => std::str::from_utf8(bytes).ok(),
-                _ => None,
-            })
-            .ok_or_else(|| anyhow::anyhow!("Missing data field"))?;
+            // Checked every pass so a warmup timeout flips readiness even with nothing deferred
+            if self.refresh_readiness().await && !deferred.is_empty() {
+                info!("Replaying {} orders deferred during warmup", deferred.len());
+                while let Some((id, map)) = deferred.pop_front() {
+                    self.process_entry(&id, map).await;
+                }
 
-        let message: Value = serde_json::from_str(data_str)
-            .map_err(|e| anyhow::anyhow!("Failed to parse message: {}", e))?;
+                let mut last_processed_id = self.last_processed_id.write().await;
+                *last_processed_id = read_id.clone();
+            }
+        }
+    }
 
-        let action = message
-            .get("action")
-            .and_then(|v| v.as_str())
-            .ok_or_else(|| anyhow::anyhow!("Missing action"))?;
+    // Serves read-only queries from config.query_stream. Queries are transient, so this reads
+    // new entries only, without a consumer group, acks or last_processed_id bookkeeping.
+    pub async fn start_query_processing(&self) -> Result<()> {
+        let Some(query_backend) = self.query_backend.clone() else {
+            return Ok(());
+        };
+        let stream = &self.config.query_stream;
+        info!("Starting query processing loop on {}", stream);
 
-        match action {
-            "LATEST_PRICE" => {
-                let symbol = self.get_string_field(&message, "symbol")?;
-                let buy_price = self.get_decimal_field(&message, "buyPrice")?;
-                let sell_price = self.get_decimal_field(&message, "sellPrice")?;
-                let decimals = self.get_u32_field(&message, "decimals")?;
+        let mut read_id = "$".to_string();
+        let mut consecutive_failures: u32 = 0;
+        loop {
+            let result = {
+                let mut backend = query_backend.write().await;
+                backend.read_stream(stream, &read_id).await
+            };
 
-                let asset_price = AssetPrice {
-                    symbol: symbol.clone(),
-                    buy_price,
-                    sell_price,
-                    decimals,
-                };
+            match result {
+                Ok(reply) => {
+                    consecutive_failures = 0;
+                    for stream_key in reply.keys {
+                        for stream_id in stream_key.ids {
+                            read_id = stream_id.id.clone();
 
-                let balance_manager = self.balance_manager.read().await;
-                balance_manager.update_price(asset_price).await;
-            }
-            "CREATE_ORDER" => {
-                self.handle_create_order(&message).await?;
-            }
-            "CLOSE_ORDER" => {
-                self.handle_close_order(&message).await?;
-            }
-            "GET_BALANCE_USD" => {
-                self.handle_get_balance_usd(&message).await?;
-            }
-            "GET_BALANCE" => {
-                self.handle_get_balance(&message).await?;
-            }
-            "GET_SUPPORTED_ASSETS" => {
-                self.handle_get_supported_assets(&message).await?;
-            }
-            "GET_ORDERS" => {
-                self.handle_get_orders(&message).await?;
-            }
-            _ => {
-                warn!("Unknown action: {}", action);
+                            match self.entry_action(&stream_id.map) {
+                                Some(action) if action.is_query() => {
+                                    if let Err(e) =
+                                        self.process_message(&stream_id.id, stream_id.map).await
+                                    {
+                                        error!("Failed to process query {}: {}", stream_id.id, e);
+                                    }
+                                }
+                                other => warn!(
+                                    "Ignoring non-query message {} on {}: {:?}",
+                                    stream_id.id, stream, other
+                                ),
+                            }
+                        }
+                    }
+                }
+                Err(e) => {
+                    consecutive_failures = consecutive_failures.saturating_add(1);
+                    let delay_ms = retry_delay_ms(
+                        consecutive_failures,
+                        self.config.retry_base_ms,
+                        self.config.retry_max_ms,
+                        chrono::Utc::now().timestamp_subsec_nanos() as u64,
+                    );
+                    error!(
+                        "Failed to read from query stream (attempt {}), retrying in {}ms: {}",
+                        consecutive_failures, delay_ms, e
+                    );
+                    tokio::time::sleep(tokio::time::Duration::from_millis(delay_ms)).await;
+                }
             }
         }
-
-        Ok(())
     }
 
-    async fn handle_create_order(&self, data: &Value) -> Result<()> {
-        println!("create)order {:?}", data);
-        let order_id = self.get_string_field(data, "orderId")?;
-        let user_id = self.get_string_field(data, "user")?;
-        let asset = self.get_string_field(data, "asset")?;
-        let order_type = self.get_string_field(data, "type")?;
-        let margin = self.get_decimal_field(data, "margin")?;
-        let leverage = self.get_u32_field(data, "leverage")?;
-        let timestamp = self.get_i64_field(data, "timestamp")?;
-
-        // Validate timestamp (within 5 seconds)
-        let current_time = chrono::Utc::now().timestamp();
-        if (current_time - timestamp).abs() > 5 {
+    // Reprocesses entries that were delivered to this consumer before a crash but never acked.
+    // Entries already covered by the restored snapshot are acked without being applied again.
+    // Order freshness is judged by stream arrival, so downtime doesn't reject what's recovered.
+    async fn recover_pending(&self) -> Result<()> {
+        let group = &self.config.consumer_group;
+        let consumer = &self.config.consumer_name;
+
+        {
+            let start_id = self.last_processed_id.read().await.clone();
+            let mut backend = self.backend.write().await;
+            backend.ensure_group("orders", group, &start_id).await?;
+        }
+
+        let mut recovered = 0;
+        loop {
+            let reply = {
+                let mut backend = self.backend.write().await;
+                backend.read_group("orders", group, consumer, "0").await?
+            };
+
+            let entries: Vec<_> = reply.keys.into_iter().flat_map(|key| key.ids).collect();
+            if entries.is_empty() {
+                break;
+            }
+
+            for entry in entries {
+                let id = entry.id.clone();
+                if self.is_already_processed(&id).await {
+                    self.ack_entry(&id).await;
+                    continue;
+                }
+
+                self.process_entry(&id, entry.map).await;
+                recovered += 1;
+
+                let mut last_processed_id = self.last_processed_id.write().await;
+                *last_processed_id = id;
+            }
+        }
+
+        if recovered > 0 {
+            info!("Recovered {} pending messages", recovered);
+        }
+        Ok(())
+    }
+
+    // Turns away a message that arrived during warmup once the deferred queue is full
+    async fn reject_not_ready(&self, id: &str, map: &HashMap<String, RedisValue>) {
+        warn!(
+            "Deferred queue full during warmup, rejecting message {}",
+            id
+        );
+        if let Ok(message) = self.parse_message(map)
+            && let Ok(order_id) = self.get_string_field(&message, "orderId")
+        {
             let response = json!({
-                "action": "ORDER_FAILED",
+                "action": "NOT_READY",
                 "data": {
                     "orderId": order_id,
-                    "message": "Order rejected: timestamp too old"
+                    "message": "Engine is warming up, retry later"
                 }
             });
+            if let Err(e) = self.publish_response(&message, &order_id, response).await {
+                error!("Failed to publish not-ready response for {}: {}", id, e);
+            }
+        }
+        self.ack_entry(id).await;
+    }
 
-            let mut redis_manager = self.redis_manager.write().await;
-            redis_manager
-                .publisher(&order_id, &response.to_string())
-                .await?;
-            return Ok(());
+    async fn process_entry(&self, id: &str, map: HashMap<String, RedisValue>) {
+        let is_mutation = self.is_mutation(&map);
+        {
+            let mut processed_ids = self.processed_ids.write().await;
+            if let Err(e) = self.process_message(id, map).await {
+                error!("Failed to process message {}: {}", id, e);
+            }
+            // Price ticks and queries are left out so they don't push mutations out of the ledger
+            if is_mutation {
+                processed_ids.insert(id.to_string(), self.config.processed_id_capacity);
+            }
+        }
+        self.ack_entry(id).await;
+    }
+
+    // Writes a record to db_queue without ever failing the caller: the engine state is already
+    // authoritative, so a failed write is buffered and retried instead of retrying the message
+    pub async fn enqueue_db_record(&self, record: &Value) {
+        let mut pending = self.pending_db_records.write().await;
+        pending.records.push_back(record.to_string());
+        if pending.records.len() > self.config.db_retry_capacity
+            && let Some(oldest) = pending.records.pop_front()
+        {
+            if self.config.db_spill_path.is_empty() {
+                error!("db_queue retry buffer full, dropped the oldest record");
+            } else if let Err(e) = append_line(&self.config.db_spill_path, &oldest).await {
+                error!(
+                    "db_queue retry buffer full and spilling to {} failed, dropped the oldest record: {}",
+                    self.config.db_spill_path, e
+                );
+            } else {
+                pending.spilled = true;
+            }
         }
+        self.flush_pending_db_records(&mut pending).await;
+    }
 
-        let order = Order {
-            order_id: order_id.clone(),
-            user_id: user_id.clone(),
-            asset,
-            order_type,
-            margin,
-            leverage,
-            open_price: Decimal::from(0),
-            quantity: Decimal::from(0),
-            timestamp,
+    pub async fn flush_db_records(&self) {
+        let mut pending = self.pending_db_records.write().await;
+        if !pending.records.is_empty() || pending.spilled {
+            self.flush_pending_db_records(&mut pending).await;
+        }
+    }
+
+    async fn flush_pending_db_records(&self, pending: &mut DbRetryBuffer) {
+        let mut backend = self.backend.write().await;
+        if pending.spilled {
+            let path = &self.config.db_spill_path;
+            let contents = match fs::read_to_string(path).await {
+                Ok(contents) => contents,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+                Err(e) => {
+                    error!("Failed to read db_queue spill file {}: {}", path, e);
+                    return;
+                }
+            };
+            let lines: Vec<&str> = contents.lines().filter(|line| !line.is_empty()).collect();
+            for (sent, line) in lines.iter().enumerate() {
+                if let Err(e) = backend.add_to_stream("db_queue", line).await {
+                    error!(
+                        "Failed to add to db_queue stream, {} records spilled: {}",
+                        lines.len() - sent,
+                        e
+                    );
+                    // Drop what already went out so it isn't sent twice
+                    if sent > 0
+                        && let Err(e) = fs::write(path, lines[sent..].join("\n") + "\n").await
+                    {
+                        error!("Failed to rewrite db_queue spill file {}: {}", path, e);
+                    }
+                    return;
+                }
+            }
+            if let Err(e) = fs::remove_file(path).await
+                && e.kind() != std::io::ErrorKind::NotFound
+            {
+                error!(
+                    "Failed to remove drained db_queue spill file {}: {}",
+                    path, e
+                );
+                return;
+            }
+            pending.spilled = false;
+            info!("Drained {} spilled db_queue records", lines.len());
+        }
+
+        let pending = &mut pending.records;
+        while let Some(record) = pending.front() {
+            if let Err(e) = backend.add_to_stream("db_queue", record).await {
+                error!(
+                    "Failed to add to db_queue stream, {} records buffered: {}",
+                    pending.len(),
+                    e
+                );
+                return;
+            }
+            pending.pop_front();
+        }
+    }
+
+    async fn ack_entry(&self, id: &str) {
+        if !self.config.use_consumer_group {
+            return;
+        }
+
+        let mut backend = self.backend.write().await;
+        if let Err(e) = backend.ack("orders", &self.config.consumer_group, id).await {
+            error!("Failed to ack message {}: {}", id, e);
+        }
+    }
+
+    // Anything not strictly newer than last_processed_id, or in the mutation ledger, has
+    // already been applied
+    async fn is_already_processed(&self, id: &str) -> bool {
+        if self.processed_ids.read().await.contains(id) {
+            return true;
+        }
+        let last_processed_id = self.last_processed_id.read().await;
+        match (parse_stream_id(id), parse_stream_id(&last_processed_id)) {
+            (Some(id), Some(last)) => id <= last,
+            _ => false,
+        }
+    }
+
+    // Every client response goes through here so the envelope can follow the requested apiVersion
+    async fn publish_response(
+        &self,
+        request: &Value,
+        channel: &str,
+        response: Value,
+    ) -> Result<()> {
+        let api_version = self
+            .get_optional_u32_field(request, "apiVersion")
+            .ok()
+            .flatten()
+            .unwrap_or(self.config.default_api_version);
+
+        let payload = if api_version >= 2 {
+            self.versioned_envelope(response, api_version)
+        } else {
+            response
         };
 
-        let result = {
-            let balance_manager = self.balance_manager.read().await;
-            balance_manager.create_order(order).await
+        let payload = payload.to_string();
+        // No receivers just means no gateway clients are connected
+        let _ = self.responses.send((channel.to_string(), payload.clone()));
+
+        // Query responses go out on the query connection when there is one
+        let is_query = message_action(request).is_some_and(Action::is_query);
+        let backend = match &self.query_backend {
+            Some(query_backend) if is_query => query_backend,
+            _ => &self.backend,
         };
+        let mut backend = backend.write().await;
+        backend.publisher(channel, &payload).await?;
 
-        match result {
-            Ok(()) => {
-                let response = json!({
-                    "action": "ORDER_SUCCESS",
-                    "data": {
-                        "orderId": order_id,
-                        "message": "Order created successfully"
-                    }
-                });
+        // A client that subscribes late can replay what it missed from the list
+        if self.config.response_log_cap > 0
+            && let Err(e) = backend
+                .retain_response(
+                    &format!("responses:{}", channel),
+                    &payload,
+                    self.config.response_log_cap,
+                    self.config.response_log_ttl_secs,
+                )
+                .await
+        {
+            warn!("Failed to retain response for {}: {}", channel, e);
+        }
+        Ok(())
+    }
 
-                let mut redis_manager = self.redis_manager.write().await;
-                redis_manager
-                    .publisher(&order_id, &response.to_string())
-                    .await?;
+    // v2 envelope: { version, action, code, message, data } regardless of the v1 shape
+    fn versioned_envelope(&self, response: Value, api_version: u32) -> Value {
+        let Value::Object(mut fields) = response else {
+            return response;
+        };
+
+        let action = fields.remove("action").unwrap_or(Value::Null);
+        let data = match fields.remove("data") {
+            Some(data) if fields.is_empty() => data,
+            Some(data) => {
+                fields.insert("data".to_string(), data);
+                Value::Object(fields)
+            }
+            None => Value::Object(fields),
+        };
+
+        let failed = action.as_str().is_some_and(is_error_action);
+        let message = data.get("message").cloned().unwrap_or(Value::Null);
+
+        json!({
+            "version": api_version,
+            "action": action,
+            "code": if failed { "ERROR" } else { "OK" },
+            "message": message,
+            "data": data
+        })
+    }
+
+    // The entry's action, if it has a known one
+    fn entry_action(&self, data: &HashMap<String, RedisValue>) -> Option<Action> {
+        self.parse_message(data)
+            .ok()
+            .and_then(|message| message_action(&message))
+    }
+
+    fn is_price_message(&self, data: &HashMap<String, RedisValue>) -> bool {
+        self.entry_action(data) == Some(Action::LatestPrice)
+    }
+
+    fn is_mutation(&self, data: &HashMap<String, RedisValue>) -> bool {
+        self.entry_action(data)
+            .is_some_and(|action| action != Action::LatestPrice && !action.is_query())
+    }
+
+    fn is_admin(&self, message: &Value) -> bool {
+        let token = &self.config.admin_token;
+        token.is_empty() || message.get("adminToken").and_then(|v| v.as_str()) == Some(token)
+    }
+
+    fn parse_message(&self, data: &HashMap<String, RedisValue>) -> Result<Value> {
+        let data_str = match data.get("data") {
+            Some(RedisValue::Data(bytes)) => std::str::from_utf8(bytes)
+                .map_err(|e| anyhow::anyhow!("Data field is not valid UTF-8: {}", e))?,
+            Some(RedisValue::Nil) => return Err(anyhow::anyhow!("Data field is nil")),
+            Some(other) => {
+                return Err(anyhow::anyhow!("Unexpected data field type: {:?}", other));
             }
+            None => return Err(anyhow::anyhow!("Missing data field")),
+        };
+
+        serde_json::from_str(data_str)
+            .map_err(|e| anyhow::anyhow!("Failed to parse message: {}", e))
+    }
+
+    async fn process_message(
+        &self,
+        stream_id: &str,
+        data: HashMap<String, RedisValue>,
+    ) -> Result<()> {
+        // println!("data on process message: {:?}", data);
+        let message = self.parse_message(&data)?;
+
+        let action_name = message
+            .get("action")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| anyhow::anyhow!("Missing action"))?;
+        let action = match action_name.parse::<Action>() {
+            Ok(action) => action,
             Err(e) => {
-                let response = json!({
-                    "action": "ORDER_FAILED",
-                    "data": {
-                        "orderId": order_id,
-                        "message": e
-                    }
-                });
+                warn!("{}", e);
+                return Ok(());
+            }
+        };
 
-                let mut redis_manager = self.redis_manager.write().await;
-                redis_manager
-                    .publisher(&order_id, &response.to_string())
-                    .await?;
+        if action.is_admin() && !self.is_admin(&message) {
+            warn!("Rejected {} without a valid admin token", action_name);
+            let order_id = self.get_string_field(&message, "orderId")?;
+            let response = json!({
+                "action": "UNAUTHORIZED",
+                "data": {
+                    "message": "Unauthorized"
+                }
+            });
+            return self.publish_response(&message, &order_id, response).await;
+        }
+
+        // Closes and margin changes lock the order's owner, never the message's user field, which
+        // nothing checks against it. The owner never changes, so looking it up before locking
+        // is safe.
+        let lock_owner = if action.is_order_addressed() {
+            match message.get("orderId").and_then(|v| v.as_str()) {
+                Some(order_id) => self
+                    .balance_manager
+                    .get_order(order_id)
+                    .await
+                    .map(|order| order.user_id),
+                None => None,
+            }
+        } else if action.is_user_scoped() {
+            message
+                .get("user")
+                .and_then(|v| v.as_str())
+                .map(str::to_string)
+        } else {
+            None
+        };
+        let _user_lock = match &lock_owner {
+            Some(user_id) => Some(self.balance_manager.lock_user(user_id).await),
+            None => None,
+        };
+
+        match action {
+            Action::LatestPrice => {
+                let symbol = self.get_string_field(&message, "symbol")?;
+                let buy_price = self.get_decimal_field(&message, "buyPrice")?;
+                let sell_price = self.get_decimal_field(&message, "sellPrice")?;
+                let decimals = self.get_u32_field(&message, "decimals")?;
+                let bid_size = self.get_optional_decimal_field(&message, "bidSize")?;
+                let ask_size = self.get_optional_decimal_field(&message, "askSize")?;
+
+                let asset_price = AssetPrice {
+                    symbol: symbol.clone(),
+                    buy_price,
+                    sell_price,
+                    decimals,
+                    mark_price: None,
+                    ema_price: None,
+                    tick_seq: 0,
+                    updated_at: 0,
+                    bid_size,
+                    ask_size,
+                };
+
+                let coalesce_ms = self.config.price_coalesce_ms as i64;
+                let now = chrono::Utc::now().timestamp_millis();
+                let last_applied = self.price_applied_at.read().await.get(&symbol).copied();
+                if coalesce_ms > 0 && last_applied.is_some_and(|last| now - last < coalesce_ms) {
+                    // Replaces any tick already held back; flush_held_prices applies it later
+                    self.held_prices.write().await.insert(symbol, asset_price);
+                } else {
+                    self.apply_price(asset_price).await?;
+                }
+            }
+            Action::CreateOrder => {
+                self.handle_create_order(&message, stream_id).await?;
+            }
+            Action::CloseOrder => {
+                self.handle_close_order(&message, false).await?;
+            }
+            Action::CloseAtPrice => {
+                self.handle_close_order(&message, true).await?;
+            }
+            Action::CreateUser => {
+                self.handle_create_user(&message).await?;
+            }
+            Action::ResetAccount => {
+                self.handle_reset_account(&message).await?;
+            }
+            Action::SpotBuy => {
+                self.handle_spot_trade(&message, "buy").await?;
+            }
+            Action::SpotSell => {
+                self.handle_spot_trade(&message, "sell").await?;
+            }
+            Action::GetBalanceUsd => {
+                self.handle_get_balance_usd(&message).await?;
+            }
+            Action::GetBalance => {
+                self.handle_get_balance(&message).await?;
+            }
+            Action::GetSupportedAssets => {
+                self.handle_get_supported_assets(&message).await?;
+            }
+            Action::GetOrders => {
+                self.handle_get_orders(&message).await?;
+            }
+            Action::GetEquityCurve => {
+                self.handle_get_equity_curve(&message).await?;
             }
+            Action::GetPositions => {
+                self.handle_get_positions(&message).await?;
+            }
+            Action::GetOrder => {
+                self.handle_get_order(&message).await?;
+            }
+            Action::GetTradeStatistics => {
+                self.handle_get_trade_statistics(&message).await?;
+            }
+            Action::GetPriceHistory => {
+                self.handle_get_price_history(&message).await?;
+            }
+            Action::GetPrice => {
+                self.handle_get_price(&message).await?;
+            }
+            Action::GetMarginRequirements => {
+                self.handle_get_margin_requirements(&message).await?;
+            }
+            Action::GetLeverageUtilization => {
+                self.handle_get_leverage_utilization(&message).await?;
+            }
+            Action::DisableAsset => {
+                self.handle_set_asset_tradeable(&message, false).await?;
+            }
+            Action::EnableAsset => {
+                self.handle_set_asset_tradeable(&message, true).await?;
+            }
+            Action::SetRiskProfile => {
+                self.handle_set_risk_profile(&message).await?;
+            }
+            Action::AdjustMargin => {
+                self.handle_adjust_margin(&message).await?;
+            }
+            Action::GetStateExport => {
+                self.handle_get_state_export(&message).await?;
+            }
+            Action::GetPlatformStats => {
+                self.handle_get_platform_stats(&message).await?;
+            }
+            Action::Subscribe => {
+                self.handle_subscription(&message, true).await?;
+            }
+            Action::Unsubscribe => {
+                self.handle_subscription(&message, false).await?;
+            }
+        }
+
+        if let Some(user_id) = &lock_owner {
+            self.publish_positions_update(user_id).await;
         }
 
         Ok(())
     }
 
-    async fn handle_close_order(&self, data: &Value) -> Result<()> {
+    async fn handle_create_order(&self, data: &Value, stream_id: &str) -> Result<()> {
+        println!("create)order {:?}", data);
         let order_id = self.get_string_field(data, "orderId")?;
-        println!("Processing close order for: {}", order_id);
+        // Every field is read before rejecting, so the client sees all problems in one response
+        let mut errors = Vec::new();
+        let user_id = collect_field(&mut errors, self.get_string_field(data, "user"));
+        let asset = collect_field(&mut errors, self.get_string_field(data, "asset"));
+        let order_type = collect_field(&mut errors, self.get_string_field(data, "type"));
+        let margin =
+            collect_field(&mut errors, self.get_optional_decimal_field(data, "margin")).flatten();
+        let quantity = collect_field(
+            &mut errors,
+            self.get_optional_decimal_field(data, "quantity"),
+        )
+        .flatten();
+        let requested_leverage =
+            collect_field(&mut errors, self.get_optional_u32_field(data, "leverage")).flatten();
+        let client_ref = collect_field(
+            &mut errors,
+            self.get_optional_string_field(data, "clientRef"),
+        )
+        .flatten();
+        let tag = collect_field(&mut errors, self.get_optional_string_field(data, "tag")).flatten();
+        let trigger_price = collect_field(
+            &mut errors,
+            self.get_optional_decimal_field(data, "triggerPrice"),
+        )
+        .flatten();
+        let trigger_type = collect_field(
+            &mut errors,
+            self.get_optional_string_field(data, "triggerType"),
+        )
+        .flatten();
+        let timestamp = collect_field(&mut errors, self.get_i64_field(data, "timestamp"));
 
-        let result = {
-            let balance_manager = self.balance_manager.read().await;
-            balance_manager.close_order(&order_id).await
+        // Exactly one of margin or quantity; the engine derives the other at the fill price
+        let amounts = match (margin, quantity) {
+            (Some(margin), None) if margin > Decimal::ZERO => Some((margin, Decimal::ZERO)),
+            (None, Some(quantity)) if quantity > Decimal::ZERO => Some((Decimal::ZERO, quantity)),
+            _ => {
+                errors.push("Specify exactly one positive margin or quantity".to_string());
+                None
+            }
         };
 
-        println!("Close order result: {:?}", result);
+        // A field that failed to parse leaves its error behind even when it was optional
+        let (
+            true,
+            Some(user_id),
+            Some(asset),
+            Some(order_type),
+            Some(timestamp),
+            Some((margin, quantity)),
+        ) = (
+            errors.is_empty(),
+            user_id,
+            asset,
+            order_type,
+            timestamp,
+            amounts,
+        )
+        else {
+            let response = json!({
+                "action": "ORDER_FAILED",
+                "data": {
+                    "orderId": order_id,
+                    "message": "Invalid order request",
+                    "errors": errors,
+                    "clientRef": client_ref
+                }
+            });
 
-        match result {
-            Ok((pnl, message)) => {
-                println!("Order closed successfully, preparing response...");
+            self.publish_response(data, &order_id, response).await?;
+            return Ok(());
+        };
 
-                let response = json!({
-                    "action": "ORDER_SUCCESS",
-                    "data": {
-                        "orderId": order_id,
-                        "pnl": pnl.to_string(), // Convert Decimal to String
-                        "message": message
-                    }
-                });
+        // Validate timestamp (within 5 seconds of when the stream received it), so orders
+        // deferred during warmup or recovered after a restart aren't rejected for the wait
+        let received_at = parse_stream_id(stream_id).map_or_else(
+            || chrono::Utc::now().timestamp(),
+            |(ms, _)| ms as i64 / 1000,
+        );
+        if (received_at - timestamp).abs() > 5 {
+            let response = json!({
+                "action": "ORDER_FAILED",
+                "data": {
+                    "orderId": order_id,
+                    "message": "Order rejected: timestamp too old"
+                }
+            });
+
+            self.publish_response(data, &order_id, response).await?;
+            return Ok(());
+        }
+
+        if let Err(e) = self.balance_manager.throttle_order(&user_id, &asset).await {
+            return self
+                .publish_order_failed(data, &order_id, &client_ref, &e)
+                .await;
+        }
+
+        let result = match self
+            .balance_manager
+            .resolve_leverage(&user_id, requested_leverage)
+            .await
+        {
+            Ok(leverage) => {
+                let order = Order {
+                    order_id: order_id.clone(),
+                    user_id: user_id.clone(),
+                    asset,
+                    order_type,
+                    margin,
+                    leverage,
+                    open_price: Decimal::from(0),
+                    quantity,
+                    timestamp,
+                    breach_since: None,
+                    breach_ticks: 0,
+                    breach_tick_seq: 0,
+                    client_ref: client_ref.clone(),
+                    extra_margin: Decimal::ZERO,
+                    stream_id: Some(stream_id.to_string()),
+                    realized_pnl: Decimal::ZERO,
+                    decimals: None,
+                    tag,
+                };
+
+                // Orders with a trigger wait in pending_orders until the price reaches it
+                if let Some(trigger_price) = trigger_price {
+                    let trigger_type = match trigger_type.as_deref() {
+                        None | Some("limit") => TriggerType::Limit,
+                        Some("stop") => TriggerType::Stop,
+                        Some(other) => {
+                            return self
+                                .publish_order_failed(
+                                    data,
+                                    &order_id,
+                                    &client_ref,
+                                    &format!("Unknown trigger type: {}", other),
+                                )
+                                .await;
+                        }
+                    };
+
+                    let pending = PendingOrder {
+                        order,
+                        trigger_price,
+                        trigger_type,
+                        filled_quantity: Decimal::ZERO,
+                    };
+                    return match self.balance_manager.place_pending_order(pending).await {
+                        Ok(()) => {
+                            let response = json!({
+                                "action": "ORDER_PENDING",
+                                "data": {
+                                    "orderId": order_id,
+                                    "triggerPrice": trigger_price.to_string(),
+                                    "clientRef": client_ref
+                                }
+                            });
+                            self.publish_response(data, &order_id, response).await
+                        }
+                        Err(e) => {
+                            self.publish_order_failed(data, &order_id, &client_ref, &e)
+                                .await
+                        }
+                    };
+                }
+
+                self.balance_manager.create_order(order).await
+            }
+            Err(e) => Err(e),
+        };
+
+        self.publish_create_result(data, &order_id, &client_ref, result)
+            .await
+    }
+
+    // Opens pending orders whose trigger the latest price for the asset has reached
+    async fn apply_price(&self, asset_price: AssetPrice) -> Result<()> {
+        let symbol = asset_price.symbol.clone();
+        self.held_prices.write().await.remove(&symbol);
+        self.price_applied_at
+            .write()
+            .await
+            .insert(symbol.clone(), chrono::Utc::now().timestamp_millis());
+
+        let update = self.balance_manager.update_price(asset_price).await;
+        match update {
+            Ok(()) => {
+                self.fill_triggered_orders(&symbol).await?;
+                self.publish_price_update(&symbol).await;
+            }
+            Err(e) => warn!("Rejected price update: {}", e),
+        }
+        Ok(())
+    }
+
+    // Counts a client in or out of an update channel. Each SUBSCRIBE needs its own UNSUBSCRIBE;
+    // publishing stops once the count is back to zero.
+    async fn handle_subscription(&self, data: &Value, subscribe: bool) -> Result<()> {
+        let order_id = self.get_string_field(data, "orderId")?;
+        let channel = self.get_string_field(data, "channel")?;
+
+        let valid = channel
+            .strip_prefix("prices:")
+            .or_else(|| channel.strip_prefix("positions:"))
+            .is_some_and(|key| !key.is_empty());
+        let response = if valid {
+            let mut subscriptions = self.subscriptions.write().await;
+            let count = subscriptions.entry(channel.clone()).or_insert(0);
+            if subscribe {
+                *count += 1;
+            } else {
+                *count = count.saturating_sub(1);
+            }
+            let subscribers = *count;
+            if subscribers == 0 {
+                subscriptions.remove(&channel);
+            }
+            info!("{} now has {} subscribers", channel, subscribers);
+            json!({
+                "action": if subscribe { "SUBSCRIBED" } else { "UNSUBSCRIBED" },
+                "data": {
+                    "channel": channel,
+                    "subscribers": subscribers
+                }
+            })
+        } else {
+            json!({
+                "action": "SUBSCRIPTION_FAILED",
+                "data": {
+                    "channel": channel,
+                    "message": "Channel must be prices:{symbol} or positions:{user}"
+                }
+            })
+        };
+
+        self.publish_response(data, &order_id, response).await
+    }
+
+    async fn is_subscribed(&self, channel: &str) -> bool {
+        self.subscriptions.read().await.contains_key(channel)
+    }
+
+    // Subscription updates aren't replies to a request, so they skip the response envelope
+    // and the response log
+    async fn publish_update(&self, channel: &str, update: Value) {
+        let payload = update.to_string();
+        let _ = self.responses.send((channel.to_string(), payload.clone()));
+        let mut backend = self.backend.write().await;
+        if let Err(e) = backend.publisher(channel, &payload).await {
+            warn!("Failed to publish update to {}: {}", channel, e);
+        }
+    }
+
+    async fn publish_price_update(&self, symbol: &str) {
+        let channel = format!("prices:{}", symbol);
+        if self.is_subscribed(&channel).await
+            && let Some(price) = self.balance_manager.get_price(symbol).await
+        {
+            let mut price_data = json!({
+                "symbol": symbol,
+                "buyPrice": price.buy_price.to_string(),
+                "sellPrice": price.sell_price.to_string(),
+                "decimals": price.decimals,
+                "emaPrice": price.ema_price.map(|ema| ema.to_string()),
+                "lastUpdated": price.updated_at
+            });
+            self.add_iso_time(&mut price_data, "lastUpdated", price.updated_at);
+            self.publish_update(
+                &channel,
+                json!({
+                    "action": "PRICE_UPDATE",
+                    "data": price_data
+                }),
+            )
+            .await;
+        }
+
+        // Subscribed users holding the asset get their positions revalued at the new price
+        let users: Vec<String> = self
+            .subscriptions
+            .read()
+            .await
+            .keys()
+            .filter_map(|channel| channel.strip_prefix("positions:"))
+            .map(str::to_string)
+            .collect();
+        for user_id in users {
+            let holds_asset = self
+                .balance_manager
+                .get_user_positions(&user_id)
+                .await
+                .is_ok_and(|positions| positions.iter().any(|(order, _, _)| order.asset == symbol));
+            if holds_asset {
+                self.publish_positions_update(&user_id).await;
+            }
+        }
+    }
+
+    async fn publish_positions_update(&self, user_id: &str) {
+        let channel = format!("positions:{}", user_id);
+        if !self.is_subscribed(&channel).await {
+            return;
+        }
+        let Ok(positions) = self.position_views(user_id).await else {
+            return;
+        };
+        self.publish_update(
+            &channel,
+            json!({
+                "action": "POSITIONS_UPDATE",
+                "data": {
+                    "user": user_id,
+                    "positions": positions
+                }
+            }),
+        )
+        .await;
+    }
+
+    // Flushes held ticks on a timer, so they don't wait on a blocking stream read that only
+    // returns when some other message arrives
+    pub async fn run_held_price_flusher(&self) {
+        let coalesce_ms = self.config.price_coalesce_ms;
+        if coalesce_ms == 0 {
+            return;
+        }
+        let mut interval = tokio::time::interval(tokio::time::Duration::from_millis(coalesce_ms));
+        loop {
+            interval.tick().await;
+            self.flush_held_prices().await;
+        }
+    }
+
+    // Applies held-back ticks whose coalescing interval has passed, so the last price of a burst
+    // still lands when the feed goes quiet. Runs between stream reads and on a timer.
+    async fn flush_held_prices(&self) {
+        let coalesce_ms = self.config.price_coalesce_ms as i64;
+        if coalesce_ms == 0 {
+            return;
+        }
+        // Held like a message being applied, so a flush never interleaves with one
+        let _processed_ids = self.processed_ids.write().await;
+
+        let now = chrono::Utc::now().timestamp_millis();
+        let due: Vec<AssetPrice> = {
+            let held_prices = self.held_prices.read().await;
+            let price_applied_at = self.price_applied_at.read().await;
+            held_prices
+                .values()
+                .filter(|price| {
+                    price_applied_at
+                        .get(&price.symbol)
+                        .is_none_or(|last| now - last >= coalesce_ms)
+                })
+                .cloned()
+                .collect()
+        };
+
+        for price in due {
+            let symbol = price.symbol.clone();
+            if let Err(e) = self.apply_price(price).await {
+                error!("Failed to apply held price for {}: {}", symbol, e);
+            }
+        }
+    }
+
+    async fn fill_triggered_orders(&self, symbol: &str) -> Result<()> {
+        let triggered = self.balance_manager.take_triggered_orders(symbol).await;
+
+        for pending in triggered {
+            let max_fill = self.config.max_fill_quantity;
+            // Only quantity-sized orders are split; a margin-sized order's size isn't known
+            // until it fills
+            if (max_fill > Decimal::ZERO && pending.order.quantity > max_fill)
+                || pending.filled_quantity > Decimal::ZERO
+            {
+                self.fill_pending_slice(pending, max_fill).await?;
+                continue;
+            }
+
+            let mut order = pending.order;
+            order.timestamp = chrono::Utc::now().timestamp();
+            let order_id = order.order_id.clone();
+            let client_ref = order.client_ref.clone();
+            info!(
+                "Pending order {} triggered at {}",
+                order_id, pending.trigger_price
+            );
+
+            let result = self.balance_manager.create_order(order).await;
+            // The originating request is gone, so fills are answered in the default envelope
+            self.publish_create_result(&Value::Null, &order_id, &client_ref, result)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    // Opens up to max_fill of a triggered order. The first slice opens the position under the
+    // order's id and later ones are merged into it; whatever is left waits for the next tick.
+    async fn fill_pending_slice(&self, mut pending: PendingOrder, max_fill: Decimal) -> Result<()> {
+        let order_id = pending.order.order_id.clone();
+        let client_ref = pending.order.client_ref.clone();
+        let slice_quantity = if max_fill > Decimal::ZERO {
+            pending.order.quantity.min(max_fill)
+        } else {
+            pending.order.quantity
+        };
+
+        // Once the position is open, a slice opens under a temporary id and is merged in. If the
+        // user already closed the earlier fills, the slice simply opens a fresh position.
+        let merge = self.balance_manager.get_order(&order_id).await.is_some();
+        let mut slice = pending.order.clone();
+        slice.quantity = slice_quantity;
+        slice.timestamp = chrono::Utc::now().timestamp();
+        if merge {
+            slice.order_id = format!("{}-fill", order_id);
+        }
+
+        let opened = match self.balance_manager.create_order(slice).await {
+            Ok(opened) => opened,
+            Err(e) => {
+                // The remainder is dropped; the fills so far stay open
+                return self
+                    .publish_order_failed(&Value::Null, &order_id, &client_ref, &e)
+                    .await;
+            }
+        };
+        let margin_charged = opened.margin;
+        let position = if merge {
+            match self
+                .balance_manager
+                .merge_fill(&order_id, &opened.order_id)
+                .await
+            {
+                Ok(position) => position,
+                Err(e) => {
+                    error!("Failed to merge fill into order {}: {}", order_id, e);
+                    opened
+                }
+            }
+        } else {
+            opened
+        };
+
+        pending.filled_quantity += slice_quantity;
+        pending.order.quantity -= slice_quantity;
+        let remaining = pending.order.quantity;
+        info!(
+            "Filled {} of pending order {}, {} remaining",
+            slice_quantity, order_id, remaining
+        );
+
+        let response = json!({
+            "action": if remaining > Decimal::ZERO { "ORDER_PARTIALLY_FILLED" } else { "ORDER_FILLED" },
+            "data": {
+                "orderId": order_id,
+                "filledQuantity": pending.filled_quantity.to_string(),
+                "remainingQuantity": remaining.to_string(),
+                "clientRef": client_ref
+            }
+        });
+        self.publish_response(&Value::Null, &order_id, response)
+            .await?;
+
+        // The record carries the whole position; marginCharged is what this slice cost
+        let db_data = json!({
+            "action": "SAVE_OPENED_ORDER",
+            "order": position,
+            "marginCharged": margin_charged,
+            "timestamp": chrono::Utc::now().timestamp()
+        });
+        self.enqueue_db_record(&db_data).await;
+
+        if remaining > Decimal::ZERO {
+            self.balance_manager.requeue_pending_order(pending).await;
+        }
+        Ok(())
+    }
+
+    // Liquidates every order the scan finds past its liquidation price, publishing ADL notices
+    // as they happen and writing the scan's db_queue records once it is done
+    pub async fn liquidate_breached(&self, full_scan: bool) {
+        let liquidated_orders = self.balance_manager.check_liquidations(full_scan).await;
+
+        // db_queue records from this scan, written together when batching is on
+        let mut db_records = Vec::new();
+        for (order_id, user_id) in liquidated_orders {
+            info!("Liquidating order: {} for user: {}", order_id, user_id);
+            // Keeps the liquidation from interleaving with the user's own opens and closes
+            let user_lock = self.balance_manager.lock_user(&user_id).await;
+            let result = self.balance_manager.liquidate_order(&order_id).await;
+            drop(user_lock);
+            match result {
+                Ok((closed_order, adl_events)) => {
+                    let db_data = json!({
+                        "action": "SAVE_LIQUIDATED_ORDER",
+                        "orderId": order_id,
+                        "user": user_id,
+                        "settledAmount": closed_order.settled_amount(),
+                        "fee": closed_order.fee,
+                        "clientRef": closed_order.client_ref,
+                        "timestamp": chrono::Utc::now().timestamp()
+                    });
+                    db_records.push(db_data);
+
+                    let response = json!({
+                        "action": "ORDER_LIQUIDATED",
+                        "data": {
+                            "orderId": order_id,
+                            "closePrice": closed_order.close_price.to_string(),
+                            "settledAmount": closed_order.settled_amount().to_string(),
+                            "fee": closed_order.fee.to_string(),
+                            "message": "Position liquidated",
+                            "clientRef": closed_order.client_ref
+                        }
+                    });
+                    if let Err(e) = self
+                        .publish_response(&Value::Null, &order_id, response)
+                        .await
+                    {
+                        error!("Failed to publish liquidation notice: {}", e);
+                    }
+
+                    for event in adl_events {
+                        info!(
+                            "Auto-deleveraged order {} for user {} (haircut {})",
+                            event.order_id, event.user_id, event.haircut
+                        );
+                        let message = json!({
+                            "action": "ADL",
+                            "data": {
+                                "orderId": event.order_id,
+                                "user": event.user_id,
+                                "pnl": event.pnl.to_string(),
+                                "haircut": event.haircut.to_string(),
+                                "clientRef": event.client_ref
+                            }
+                        });
+                        let db_data = json!({
+                            "action": "SAVE_CLOSED_ORDER",
+                            "orderId": event.order_id,
+                            "user": event.user_id,
+                            "pnl": event.pnl,
+                            "settledAmount": event.settled_amount,
+                            "clientRef": event.client_ref,
+                            "timestamp": chrono::Utc::now().timestamp()
+                        });
+                        {
+                            let mut backend = self.backend.write().await;
+                            if let Err(e) = backend
+                                .publisher(&event.order_id, &message.to_string())
+                                .await
+                            {
+                                error!("Failed to publish ADL notice: {}", e);
+                            }
+                        }
+                        db_records.push(db_data);
+                    }
+                }
+                // A user close got to it between the scan and now
+                Err(e) if e == ALREADY_SETTLED => {
+                    info!(
+                        "Order {} was settled before it could be liquidated",
+                        order_id
+                    );
+                }
+                Err(e) => {
+                    error!("Failed to liquidate order {}: {}", order_id, e);
+                }
+            }
+        }
+
+        // Only settled liquidations made it into db_records, so the batch matches the
+        // balance changes already applied
+        if self.config.batch_liquidation_records && !db_records.is_empty() {
+            let db_data = json!({
+                "action": "SAVE_LIQUIDATIONS",
+                "records": db_records,
+                "timestamp": chrono::Utc::now().timestamp()
+            });
+            self.enqueue_db_record(&db_data).await;
+        } else {
+            for db_data in &db_records {
+                self.enqueue_db_record(db_data).await;
+            }
+        }
+    }
+
+    // Under StaleFeedPolicy::Close, closes positions on assets whose feed went silent at the
+    // last known price and notifies each order's channel
+    pub async fn close_stale_positions(&self) {
+        for order_id in self.balance_manager.stale_feed_orders().await {
+            // Same as a liquidation: the close mustn't interleave with the owner's own requests
+            let Some(owner) = self.balance_manager.get_order(&order_id).await else {
+                continue;
+            };
+            let user_lock = self.balance_manager.lock_user(&owner.user_id).await;
+            let result = self
+                .balance_manager
+                .close_order(&order_id, None, None, None)
+                .await;
+            drop(user_lock);
+            let (closed_order, _) = match result {
+                Ok(result) => result,
+                Err(e) => {
+                    error!("Failed to force-close order {}: {}", order_id, e);
+                    continue;
+                }
+            };
+            warn!(
+                "Force-closed order {} at last known price {}, feed for {} is down",
+                order_id, closed_order.close_price, closed_order.asset
+            );
+
+            let response = json!({
+                "action": "ORDER_FORCE_CLOSED",
+                "data": {
+                    "orderId": order_id,
+                    "pnl": closed_order.pnl.to_string(),
+                    "closePrice": closed_order.close_price.to_string(),
+                    "message": "Price feed down, closed at the last known price",
+                    "clientRef": closed_order.client_ref
+                }
+            });
+            if let Err(e) = self
+                .publish_response(&Value::Null, &order_id, response)
+                .await
+            {
+                error!("Failed to publish force-close notice: {}", e);
+            }
+
+            let db_data = json!({
+                "action": "SAVE_CLOSED_ORDER",
+                "orderId": order_id,
+                "user": closed_order.user_id,
+                "pnl": closed_order.pnl,
+                "settledAmount": closed_order.settled_amount(),
+                "clientRef": closed_order.client_ref,
+                "timestamp": chrono::Utc::now().timestamp()
+            });
+            self.enqueue_db_record(&db_data).await;
+        }
+    }
+
+    // Runs one funding period and records the payments, so the downstream ledger and replay
+    // see the same balance changes
+    pub async fn apply_funding(&self) {
+        let run = self.balance_manager.apply_funding().await;
+        if run.rates.is_empty() {
+            return;
+        }
+        for (asset, rate) in &run.rates {
+            info!("Applied funding on {} at rate {}", asset, rate);
+        }
+
+        let rates: serde_json::Map<String, Value> = run
+            .rates
+            .iter()
+            .map(|(asset, rate)| (asset.clone(), json!(rate)))
+            .collect();
+        let db_data = json!({
+            "action": "SAVE_FUNDING",
+            "rates": rates,
+            "payments": run.payments,
+            "timestamp": chrono::Utc::now().timestamp()
+        });
+        self.enqueue_db_record(&db_data).await;
+    }
+
+    async fn publish_order_failed(
+        &self,
+        data: &Value,
+        order_id: &str,
+        client_ref: &Option<String>,
+        message: &str,
+    ) -> Result<()> {
+        let response = json!({
+            "action": "ORDER_FAILED",
+            "data": {
+                "orderId": order_id,
+                "message": message,
+                "clientRef": client_ref
+            }
+        });
+
+        self.publish_response(data, order_id, response).await
+    }
+
+    async fn publish_create_result(
+        &self,
+        data: &Value,
+        order_id: &str,
+        client_ref: &Option<String>,
+        result: Result<Order, String>,
+    ) -> Result<()> {
+        match result {
+            Ok(order) => {
+                let response = json!({
+                    "action": "ORDER_SUCCESS",
+                    "data": {
+                        "orderId": order_id,
+                        "message": "Order created successfully",
+                        "clientRef": order.client_ref
+                    }
+                });
+
+                self.publish_response(data, order_id, response).await?;
+
+                // Record the filled order so the state can be rebuilt from db_queue
+                let db_data = json!({
+                    "action": "SAVE_OPENED_ORDER",
+                    "order": order,
+                    "timestamp": chrono::Utc::now().timestamp()
+                });
+                self.enqueue_db_record(&db_data).await;
+            }
+            Err(e) => {
+                self.publish_order_failed(data, order_id, client_ref, &e)
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // `at_price` is the admin CLOSE_AT_PRICE variant, which settles at the message's `price`
+    async fn handle_close_order(&self, data: &Value, at_price: bool) -> Result<()> {
+        let order_id = self.get_string_field(data, "orderId")?;
+        let settle_price = if at_price {
+            Some(self.get_decimal_field(data, "price")?)
+        } else {
+            None
+        };
+        let min_close_price = self.get_optional_decimal_field(data, "minClosePrice")?;
+        let max_close_price = self.get_optional_decimal_field(data, "maxClosePrice")?;
+        let fraction = self.get_optional_decimal_field(data, "fraction")?;
+        let request_id = self
+            .get_optional_string_field(data, "requestId")?
+            .filter(|_| self.config.request_dedup_capacity > 0);
+        println!("Processing close order for: {}", order_id);
+
+        if let Some(request_id) = &request_id {
+            let previous = self.recent_closes.read().await.get(request_id).cloned();
+            if let Some(response) = previous {
+                info!(
+                    "Replaying response for duplicate close request {}",
+                    request_id
+                );
+                return self.publish_response(data, &order_id, response).await;
+            }
+        }
+
+        let result = match settle_price {
+            Some(price) => {
+                warn!(
+                    "Admin override: settling order {} at price {}",
+                    order_id, price
+                );
+                self.balance_manager
+                    .close_order_at_price(&order_id, price)
+                    .await
+            }
+            None => {
+                self.balance_manager
+                    .close_order(&order_id, fraction, min_close_price, max_close_price)
+                    .await
+            }
+        };
+
+        println!("Close order result: {:?}", result);
+
+        match result {
+            Ok((closed_order, remaining)) => {
+                println!("Order closed successfully, preparing response...");
+
+                let pnl = closed_order.pnl;
+                let message = format!("Order closed at price {}", closed_order.close_price);
+
+                let mut response = json!({
+                    "action": "ORDER_SUCCESS",
+                    "data": {
+                        "orderId": order_id,
+                        "pnl": pnl.to_string(), // Convert Decimal to String
+                        "closePrice": closed_order.close_price.to_string(),
+                        "openPrice": closed_order.open_price.to_string(),
+                        "quantity": closed_order.quantity.to_string(),
+                        "message": message,
+                        "clientRef": closed_order.client_ref
+                    }
+                });
+                if let Some(remaining) = &remaining {
+                    response["data"]["remainingQuantity"] = json!(remaining.quantity.to_string());
+                    response["data"]["realizedPnl"] = json!(remaining.realized_pnl.to_string());
+                }
+
+                println!("Response JSON: {}", response);
+                self.remember_close(&request_id, &response).await;
+
+                let stream_result = self.publish_response(data, &order_id, response).await;
+
+                println!("Stream add result: {:?}", stream_result);
+
+                // The close already happened; failing here would only get the message retried
+                if let Err(e) = stream_result {
+                    error!("Failed to add response to callback_response stream: {}", e);
+                }
+
+                // Publish to database processor using stream
+                println!("Adding to db_queue stream...");
+                let closed_at = chrono::Utc::now().timestamp();
+                let mut db_data = json!({
+                    "action": "SAVE_CLOSED_ORDER",
+                    "orderId": order_id,
+                    "user": closed_order.user_id,
+                    "pnl": pnl,
+                    "settledAmount": closed_order.settled_amount(),
+                    "closePrice": closed_order.close_price,
+                    "clientRef": closed_order.client_ref,
+                    "timestamp": closed_at
+                });
+                // A partial close leaves the remainder open under the same id
+                if let Some(remaining) = &remaining {
+                    db_data["remainingOrder"] = json!(remaining);
+                }
+                // Keeps override settlements distinguishable from feed-priced closes in the audit trail
+                if settle_price.is_some() {
+                    db_data["settledAtOverridePrice"] = json!(true);
+                }
+                self.add_iso_time(&mut db_data, "timestamp", closed_at * 1000);
+                self.enqueue_db_record(&db_data).await;
+            }
+            Err(e) => {
+                println!("Order close failed: {}", e);
+
+                let response = json!({
+                    "action": "ORDER_FAILED",
+                    "data": {
+                        "orderId": order_id,
+                        "message": e
+                    }
+                });
+                self.remember_close(&request_id, &response).await;
+
+                let stream_result = self.publish_response(data, &order_id, response).await;
+
+                println!("Error response stream result: {:?}", stream_result);
+
+                if let Err(stream_err) = stream_result {
+                    error!("Failed to add error response to stream: {}", stream_err);
+                    return Err(stream_err);
+                }
+            }
+        }
+
+        println!("handle_close_order completed");
+        Ok(())
+    }
+
+    async fn remember_close(&self, request_id: &Option<String>, response: &Value) {
+        if let Some(request_id) = request_id {
+            self.recent_closes.write().await.insert(
+                request_id.clone(),
+                response.clone(),
+                chrono::Utc::now().timestamp(),
+                self.config.request_dedup_ttl_secs,
+                self.config.request_dedup_capacity,
+            );
+        }
+    }
+
+    async fn handle_create_user(&self, data: &Value) -> Result<()> {
+        let user_id = self.get_string_field(data, "user")?;
+        let order_id = self.get_string_field(data, "orderId")?;
+
+        // Existing users are returned as-is; new ones get the configured starting holdings
+        let user_balance = self.balance_manager.get_or_create_user(&user_id).await;
+
+        let mut assets = json!({});
+        for (asset, (balance, decimals)) in &user_balance.asset_balances {
+            assets[asset] = json!({
+                "balance": balance,
+                "decimals": decimals
+            });
+        }
+
+        let response = json!({
+            "action": "USER_CREATED",
+            "data": {
+                "user": user_id,
+                "usdBalance": user_balance.usd_balance,
+                "assets": assets
+            }
+        });
+
+        self.publish_response(data, &order_id, response).await?;
+        Ok(())
+    }
+
+    async fn handle_reset_account(&self, data: &Value) -> Result<()> {
+        let user_id = self.get_string_field(data, "user")?;
+        let order_id = self.get_string_field(data, "orderId")?;
+
+        let cancelled = self.balance_manager.reset_account(&user_id).await;
+        let balance = self.balance_manager.get_user_balance_usd(&user_id).await;
+        info!(
+            "Reset account {}, cancelled {} orders",
+            user_id,
+            cancelled.len()
+        );
+
+        let response = json!({
+            "action": "ACCOUNT_RESET",
+            "data": {
+                "user": user_id,
+                "cancelledOrders": cancelled,
+                "usdBalance": balance.unwrap_or_default().to_string()
+            }
+        });
+        self.publish_response(data, &order_id, response).await?;
+
+        let db_data = json!({
+            "action": "SAVE_ACCOUNT_RESET",
+            "user": user_id,
+            "cancelledOrders": cancelled,
+            "timestamp": chrono::Utc::now().timestamp()
+        });
+        self.enqueue_db_record(&db_data).await;
+        Ok(())
+    }
+
+    async fn handle_get_balance_usd(&self, data: &Value) -> Result<()> {
+        let user_id = self.get_string_field(data, "user")?;
+        let order_id = self.get_string_field(data, "orderId")?;
+
+        // Ensure user exists
+        self.balance_manager.get_or_create_user(&user_id).await;
+
+        let result = self.balance_manager.get_user_balance_usd(&user_id).await;
+
+        match result {
+            Ok(balance) => {
+                let response = json!({
+                    "action": "BALANCE_USD",
+                    "data": {
+                        "balance": balance
+                    }
+                });
+
+                self.publish_response(data, &order_id, response).await?;
+            }
+            Err(e) => {
+                let response = json!({
+                    "action": "BALANCE_FAILED",
+                    "data": {
+                        "message": e
+                    }
+                });
+
+                self.publish_response(data, &order_id, response).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn handle_get_balance(&self, data: &Value) -> Result<()> {
+        let user_id = self.get_string_field(data, "user")?;
+        let order_id = self.get_string_field(data, "orderId")?;
+
+        // Ensure user exists, unless unknown users should be reported as such
+        if !self.config.unknown_user_not_found {
+            self.balance_manager.get_or_create_user(&user_id).await;
+        }
+
+        let result = self.balance_manager.get_user_balance(&user_id).await;
+
+        match result {
+            Ok(balances) => {
+                let mut response_data = json!({
+                    "action": "BALANCE"
+                });
+
+                for (asset, (balance, decimals)) in balances {
+                    response_data[&asset] = json!({
+                        "balance": balance,
+                        "decimals": decimals
+                    });
+                }
+
+                self.publish_response(data, &order_id, response_data)
+                    .await?;
+            }
+            Err(e) => {
+                let response = if self.config.unknown_user_not_found {
+                    json!({
+                        "action": "USER_NOT_FOUND",
+                        "data": {
+                            "user": user_id,
+                            "message": e
+                        }
+                    })
+                } else {
+                    json!({
+                        "action": "BALANCE_FAILED",
+                        "data": {
+                            "message": e
+                        }
+                    })
+                };
+
+                self.publish_response(data, &order_id, response).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn handle_get_supported_assets(&self, data: &Value) -> Result<()> {
+        let order_id = self.get_string_field(data, "orderId")?;
+        let mut supported_assets = json!([
+            {
+                "symbol": "BTC",
+                "name": "Bitcoin",
+                "imageUrl": "https://example.com/btc.png"
+            },
+            {
+                "symbol": "ETH",
+                "name": "Ethereum",
+                "imageUrl": "https://example.com/eth.png"
+            },
+            {
+                "symbol": "SOL",
+                "name": "Solana",
+                "imageUrl": "https://example.com/sol.png"
+            }
+        ]);
+
+        if let Some(assets) = supported_assets.as_array_mut() {
+            for asset in assets {
+                let symbol = asset["symbol"].as_str().unwrap_or_default().to_string();
+                asset["tradeable"] = json!(self.balance_manager.is_asset_tradeable(&symbol).await);
+                asset["emaPrice"] = json!(
+                    self.balance_manager
+                        .get_price(&symbol)
+                        .await
+                        .and_then(|price| price.ema_price)
+                        .map(|ema| ema.to_string())
+                );
+                let (long_notional, short_notional) =
+                    self.balance_manager.open_interest(&symbol).await;
+                asset["fundingRate"] = json!(
+                    self.balance_manager
+                        .funding_rate(long_notional, short_notional)
+                        .to_string()
+                );
+            }
+        }
+
+        let response = json!({
+            "action": "SUPPORTED_ASSETS",
+            "assets": supported_assets
+        });
+
+        self.publish_response(data, &order_id, response).await?;
+
+        Ok(())
+    }
+
+    // Trading rules a client needs to pre-validate an order. They are engine-wide today, so every
+    // asset reports the same limits; only the tick size follows the asset's price decimals.
+    async fn handle_get_margin_requirements(&self, data: &Value) -> Result<()> {
+        let order_id = self.get_string_field(data, "orderId")?;
+        let symbols = match self.get_optional_string_field(data, "symbol")? {
+            Some(symbol) => vec![symbol],
+            None => ["BTC", "ETH", "SOL"]
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+        };
+
+        let config = &self.config;
+        let tiers: Vec<Value> = config
+            .maintenance_bands()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(min_notional, rate)| {
+                json!({
+                    "minNotional": min_notional.to_string(),
+                    "rate": rate.to_string()
+                })
+            })
+            .collect();
+        let optional_limit = |limit: Decimal| (limit > Decimal::ZERO).then(|| limit.to_string());
+
+        let mut assets = Vec::new();
+        for symbol in symbols {
+            let tick_size = self
+                .balance_manager
+                .get_price(&symbol)
+                .await
+                .map(|price| Decimal::new(1, price.decimals).to_string());
+            assets.push(json!({
+                "symbol": symbol,
+                "tradeable": self.balance_manager.is_asset_tradeable(&symbol).await,
+                "maxLeverage": config.max_leverage,
+                "conservativeMaxLeverage": config.max_leverage.min(config.conservative_max_leverage),
+                // Without tiers a position liquidates once 90% of its collateral is lost
+                "maintenanceMarginPct": "10",
+                "maintenanceTiers": tiers,
+                "minOrderQuantity": optional_limit(config.min_order_quantity),
+                "maxOrderQuantity": optional_limit(config.max_order_quantity),
+                "maxFillQuantity": optional_limit(config.max_fill_quantity),
+                "minFillPrice": optional_limit(config.min_fill_price),
+                "marginStep": Decimal::new(1, config.money_decimals).to_string(),
+                "tickSize": tick_size
+            }));
+        }
+
+        let response = json!({
+            "action": "MARGIN_REQUIREMENTS",
+            "assets": assets
+        });
+
+        self.publish_response(data, &order_id, response).await?;
+
+        Ok(())
+    }
+
+    async fn handle_set_asset_tradeable(&self, data: &Value, tradeable: bool) -> Result<()> {
+        let symbol = self.get_string_field(data, "symbol")?;
+        let order_id = self.get_string_field(data, "orderId")?;
+
+        self.balance_manager
+            .set_asset_tradeable(&symbol, tradeable)
+            .await;
+        info!("Asset {} tradeable: {}", symbol, tradeable);
+
+        let response = json!({
+            "action": "ASSET_STATUS",
+            "data": {
+                "symbol": symbol,
+                "tradeable": tradeable
+            }
+        });
+
+        self.publish_response(data, &order_id, response).await?;
+
+        Ok(())
+    }
+
+    async fn handle_set_risk_profile(&self, data: &Value) -> Result<()> {
+        let user_id = self.get_string_field(data, "user")?;
+        let order_id = self.get_string_field(data, "orderId")?;
+        let profile = self.get_string_field(data, "riskProfile")?;
+        let default_leverage = self.get_optional_u32_field(data, "defaultLeverage")?;
+
+        let result = match serde_json::from_value::<RiskProfile>(json!(profile)) {
+            Ok(risk_profile) => {
+                self.balance_manager
+                    .set_risk_profile(&user_id, risk_profile, default_leverage)
+                    .await
+            }
+            Err(_) => Err(format!("Unknown risk profile: {}", profile)),
+        };
+
+        let response = match result {
+            Ok(()) => json!({
+                "action": "RISK_PROFILE_UPDATED",
+                "data": {
+                    "user": user_id,
+                    "riskProfile": profile,
+                    "defaultLeverage": default_leverage
+                }
+            }),
+            Err(e) => json!({
+                "action": "RISK_PROFILE_FAILED",
+                "data": {
+                    "message": e
+                }
+            }),
+        };
+
+        self.publish_response(data, &order_id, response).await?;
+
+        Ok(())
+    }
+
+    async fn handle_get_orders(&self, data: &Value) -> Result<()> {
+        let user_id = self.get_string_field(data, "user")?;
+        let order_id = self.get_string_field(data, "orderId")?;
+        // With a tag only orders carrying exactly that tag are listed; untagged ones never match
+        let tag = self.get_optional_string_field(data, "tag")?;
+
+        // Ensure user exists
+        self.balance_manager.get_or_create_user(&user_id).await;
+
+        // Orders on assets without a price have no unrealized pnl to report
+        let pnls: HashMap<String, (Decimal, Decimal)> = self
+            .balance_manager
+            .get_user_positions(&user_id)
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(order, pnl, gross_pnl)| (order.order_id, (pnl, gross_pnl)))
+            .collect();
+
+        let orders: Vec<Value> = self
+            .balance_manager
+            .get_user_orders(&user_id)
+            .await
+            .iter()
+            .filter(|order| tag.is_none() || order.tag == tag)
+            .map(|order| {
+                let mut order_data = json!(order);
+                if let Some((pnl, gross_pnl)) = pnls.get(&order.order_id) {
+                    order_data["unrealizedPnl"] = json!(pnl.to_string());
+                    order_data["grossUnrealizedPnl"] = json!(gross_pnl.to_string());
+                    order_data["pnlPercent"] = pnl_percent(*pnl, order);
+                }
+                let liquidation_price = self.balance_manager.calculate_liquidation_price(order);
+                order_data["liquidationPrice"] = json!(liquidation_price.to_string());
+                add_display_prices(&mut order_data, order, liquidation_price);
+                self.add_iso_time(&mut order_data, "timestamp", order.timestamp * 1000);
+                order_data
+            })
+            .collect();
+
+        let response = json!({
+            "action": "ORDERS",
+            "orders": orders
+        });
+
+        self.publish_response(data, &order_id, response).await?;
+
+        Ok(())
+    }
+
+    // Open orders with their unrealized pnl at the mid price and liquidation price, plus the
+    // level they would liquidate at if the free balance backed them too (cross margin)
+    async fn handle_get_positions(&self, data: &Value) -> Result<()> {
+        let user_id = self.get_string_field(data, "user")?;
+        let order_id = self.get_string_field(data, "orderId")?;
+
+        let response = match self.position_views(&user_id).await {
+            Ok(positions) => json!({
+                "action": "POSITIONS",
+                "positions": positions
+            }),
+            Err(e) => json!({
+                "action": "POSITIONS_FAILED",
+                "data": {
+                    "message": e
+                }
+            }),
+        };
+
+        self.publish_response(data, &order_id, response).await?;
+
+        Ok(())
+    }
+
+    // A user's open positions as GET_POSITIONS and positions:{user} subscribers see them
+    async fn position_views(&self, user_id: &str) -> Result<Vec<Value>, String> {
+        // Free balance that would also back each position under cross margin
+        let free_balance = self
+            .balance_manager
+            .get_user_balance_usd(user_id)
+            .await
+            .unwrap_or(Decimal::ZERO)
+            .max(Decimal::ZERO);
+
+        self.balance_manager
+            .get_user_positions(user_id)
+            .await
+            .map(|positions| {
+                positions
+                    .iter()
+                    .map(|(order, pnl, gross_pnl)| {
+                        let mut position = json!(order);
+                        position["unrealizedPnl"] = json!(pnl.to_string());
+                        position["grossUnrealizedPnl"] = json!(gross_pnl.to_string());
+                        position["pnlPercent"] = pnl_percent(*pnl, order);
+                        position["realizedPnl"] = json!(order.realized_pnl.to_string());
+                        position["totalPnl"] = json!((order.realized_pnl + pnl).to_string());
+                        let liquidation_price =
+                            self.balance_manager.calculate_liquidation_price(order);
+                        position["liquidationPrice"] = json!(liquidation_price.to_string());
+                        add_display_prices(&mut position, order, liquidation_price);
+                        position["crossLiquidationPrice"] = json!(
+                            self.balance_manager
+                                .liquidation_price_with(
+                                    order,
+                                    MarginMode::Cross,
+                                    order.collateral() + free_balance,
+                                )
+                                .to_string()
+                        );
+                        self.add_iso_time(&mut position, "timestamp", order.timestamp * 1000);
+                        position
+                    })
+                    .collect()
+            })
+    }
+
+    async fn handle_get_equity_curve(&self, data: &Value) -> Result<()> {
+        let user_id = self.get_string_field(data, "user")?;
+        let order_id = self.get_string_field(data, "orderId")?;
+
+        let curve = self.balance_manager.get_equity_curve(&user_id).await;
+
+        let points: Vec<Value> = curve
+            .iter()
+            .map(|point| {
+                let mut point_data = json!({
+                    "timestamp": point.timestamp,
+                    "equity": point.equity.to_string()
+                });
+                self.add_iso_time(&mut point_data, "timestamp", point.timestamp);
+                point_data
+            })
+            .collect();
+
+        let response = json!({
+            "action": "EQUITY_CURVE",
+            "data": {
+                "user": user_id,
+                "points": points
+            }
+        });
+
+        self.publish_response(data, &order_id, response).await?;
+
+        Ok(())
+    }
+
+    async fn handle_get_trade_statistics(&self, data: &Value) -> Result<()> {
+        let user_id = self.get_string_field(data, "user")?;
+        let order_id = self.get_string_field(data, "orderId")?;
+
+        let stats = self.balance_manager.get_trade_statistics(&user_id).await;
+
+        let response = json!({
+            "action": "TRADE_STATISTICS",
+            "data": {
+                "totalTrades": stats.total_trades,
+                "winCount": stats.win_count,
+                "lossCount": stats.loss_count,
+                "winRate": stats.win_rate,
+                "averageWin": stats.average_win,
+                "averageLoss": stats.average_loss,
+                "largestDrawdown": stats.largest_drawdown
+            }
+        });
+
+        self.publish_response(data, &order_id, response).await?;
+
+        Ok(())
+    }
+
+    async fn handle_get_leverage_utilization(&self, data: &Value) -> Result<()> {
+        let user_id = self.get_string_field(data, "user")?;
+        let order_id = self.get_string_field(data, "orderId")?;
+
+        // Per-asset leverage is that asset's notional over the whole account's equity
+        let response = match self.balance_manager.leverage_utilization(&user_id).await {
+            Ok(utilization) => {
+                let assets: Vec<Value> = utilization
+                    .assets
+                    .iter()
+                    .map(|(asset, notional)| {
+                        json!({
+                            "asset": asset,
+                            "notional": notional.to_string(),
+                            "leverage": (utilization.equity > Decimal::ZERO)
+                                .then(|| (notional / utilization.equity).round_dp(4).to_string())
+                        })
+                    })
+                    .collect();
+                json!({
+                    "action": "LEVERAGE_UTILIZATION",
+                    "data": {
+                        "user": user_id,
+                        "equity": utilization.equity.to_string(),
+                        "totalNotional": utilization.total_notional.to_string(),
+                        "effectiveLeverage": utilization
+                            .effective_leverage
+                            .map(|leverage| leverage.round_dp(4).to_string()),
+                        "assets": assets
+                    }
+                })
+            }
+            Err(e) => json!({
+                "action": "LEVERAGE_UTILIZATION_FAILED",
+                "data": {
+                    "user": user_id,
+                    "message": e
+                }
+            }),
+        };
+
+        self.publish_response(data, &order_id, response).await?;
+
+        Ok(())
+    }
+
+    async fn handle_get_platform_stats(&self, data: &Value) -> Result<()> {
+        let order_id = self.get_string_field(data, "orderId")?;
+
+        let (stats, insurance_fund, total_open_notional) =
+            self.balance_manager.platform_stats().await;
+
+        let mut stats_data = json!({
+            "realizedPnl": stats.realized_pnl.to_string(),
+            "feesCollected": stats.fees_collected.to_string(),
+            "liquidations": stats.liquidations,
+            "insuranceFund": insurance_fund.to_string(),
+            "totalOpenNotional": total_open_notional.to_string(),
+            "since": self.started_at
+        });
+        self.add_iso_time(&mut stats_data, "since", self.started_at);
+        let response = json!({
+            "action": "PLATFORM_STATS",
+            "data": stats_data
+        });
+
+        self.publish_response(data, &order_id, response).await?;
+
+        Ok(())
+    }
+
+    // The engine's current price for one asset, as orders would see it
+    async fn handle_get_price(&self, data: &Value) -> Result<()> {
+        let symbol = self.get_string_field(data, "symbol")?;
+        let order_id = self.get_string_field(data, "orderId")?;
+
+        let response = match self.balance_manager.get_price(&symbol).await {
+            Some(price) => {
+                let mut price_data = json!({
+                    "symbol": symbol,
+                    "buyPrice": price.buy_price.to_string(),
+                    "sellPrice": price.sell_price.to_string(),
+                    "decimals": price.decimals,
+                    "mid": ((price.buy_price + price.sell_price) / Decimal::from(2)).to_string(),
+                    "spread": (price.buy_price - price.sell_price).to_string(),
+                    "emaPrice": price.ema_price.map(|ema| ema.to_string()),
+                    "lastUpdated": price.updated_at
+                });
+                self.add_iso_time(&mut price_data, "lastUpdated", price.updated_at);
+                json!({
+                    "action": "PRICE",
+                    "data": price_data
+                })
+            }
+            None => json!({
+                "action": "PRICE_NOT_AVAILABLE",
+                "data": {
+                    "symbol": symbol,
+                    "message": "No price available for asset"
+                }
+            }),
+        };
+
+        self.publish_response(data, &order_id, response).await?;
+
+        Ok(())
+    }
+
+    async fn handle_get_price_history(&self, data: &Value) -> Result<()> {
+        let symbol = self.get_string_field(data, "symbol")?;
+        let order_id = self.get_string_field(data, "orderId")?;
+
+        let history = self.balance_manager.get_price_history(&symbol).await;
+
+        let points: Vec<Value> = history
+            .iter()
+            .map(|point| {
+                let mut point_data = json!({
+                    "timestamp": point.timestamp,
+                    "buyPrice": point.buy_price,
+                    "sellPrice": point.sell_price
+                });
+                self.add_iso_time(&mut point_data, "timestamp", point.timestamp);
+                point_data
+            })
+            .collect();
+
+        let response = json!({
+            "action": "PRICE_HISTORY",
+            "data": {
+                "symbol": symbol,
+                "prices": points
+            }
+        });
+
+        self.publish_response(data, &order_id, response).await?;
+
+        Ok(())
+    }
+
+    // Looks an order up by id, open first and then in the closed history
+    async fn handle_get_order(&self, data: &Value) -> Result<()> {
+        let order_id = self.get_string_field(data, "orderId")?;
+
+        let (open_order, closed_order) = {
+            match self.balance_manager.get_order(&order_id).await {
+                Some(order) => (Some(order), None),
+                None => (None, self.balance_manager.get_closed_order(&order_id).await),
+            }
+        };
+
+        let response = match (open_order, closed_order) {
+            (Some(order), _) => json!({
+                "action": "ORDER",
+                "data": {
+                    "status": "open",
+                    "streamId": order.stream_id,
+                    "order": order
+                }
+            }),
+            (None, Some(closed_order)) => json!({
+                "action": "ORDER",
+                "data": {
+                    "status": if closed_order.liquidated { "liquidated" } else { "closed" },
+                    "streamId": closed_order.stream_id,
+                    "order": closed_order
+                }
+            }),
+            (None, None) => json!({
+                "action": "ORDER_NOT_FOUND",
+                "data": {
+                    "orderId": order_id
+                }
+            }),
+        };
+
+        self.publish_response(data, &order_id, response).await?;
+
+        Ok(())
+    }
+
+    async fn handle_adjust_margin(&self, data: &Value) -> Result<()> {
+        let order_id = self.get_string_field(data, "orderId")?;
+        let amount = self.get_decimal_field(data, "amount")?;
+
+        let result = self
+            .balance_manager
+            .adjust_margin(&order_id, amount)
+            .await
+            .map(|order| {
+                (
+                    self.balance_manager.calculate_liquidation_price(&order),
+                    order,
+                )
+            });
+
+        match result {
+            Ok((liquidation_price, order)) => {
+                let response = json!({
+                    "action": "MARGIN_ADJUSTED",
+                    "data": {
+                        "orderId": order_id,
+                        "margin": order.collateral().to_string(),
+                        "liquidationPrice": liquidation_price.to_string(),
+                        "clientRef": order.client_ref
+                    }
+                });
+                self.publish_response(data, &order_id, response).await?;
+
+                let db_data = json!({
+                    "action": "SAVE_MARGIN_ADJUSTMENT",
+                    "orderId": order_id,
+                    "user": order.user_id,
+                    "amount": amount,
+                    "clientRef": order.client_ref,
+                    "timestamp": chrono::Utc::now().timestamp()
+                });
+                self.enqueue_db_record(&db_data).await;
+            }
+            Err(e) => {
+                let response = json!({
+                    "action": "MARGIN_ADJUST_FAILED",
+                    "data": {
+                        "orderId": order_id,
+                        "message": e
+                    }
+                });
+                self.publish_response(data, &order_id, response).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn handle_spot_trade(&self, data: &Value, side: &str) -> Result<()> {
+        let user_id = self.get_string_field(data, "user")?;
+        let asset = self.get_string_field(data, "asset")?;
+        let quantity = self.get_decimal_field(data, "quantity")?;
+        let order_id = self.get_string_field(data, "orderId")?;
+
+        let result = self
+            .balance_manager
+            .spot_trade(&user_id, &asset, side, quantity)
+            .await;
+
+        match result {
+            Ok(fill) => {
+                let response = json!({
+                    "action": "SPOT_FILLED",
+                    "data": {
+                        "asset": fill.asset,
+                        "side": fill.side,
+                        "quantity": fill.quantity.to_string(),
+                        "price": fill.price.to_string(),
+                        "fee": fill.fee.to_string(),
+                        "usdBalance": fill.usd_balance.to_string(),
+                        "assetBalance": fill.asset_balance.to_string()
+                    }
+                });
+                self.publish_response(data, &order_id, response).await?;
+
+                let db_data = json!({
+                    "action": "SAVE_SPOT_TRADE",
+                    "user": user_id,
+                    "asset": fill.asset,
+                    "side": fill.side,
+                    "quantity": fill.quantity,
+                    "price": fill.price,
+                    "fee": fill.fee,
+                    "decimals": fill.decimals,
+                    "timestamp": chrono::Utc::now().timestamp()
+                });
+                self.enqueue_db_record(&db_data).await;
+            }
+            Err(e) => {
+                let response = json!({
+                    "action": "SPOT_FAILED",
+                    "data": {
+                        "asset": asset,
+                        "side": side,
+                        "message": e
+                    }
+                });
+                self.publish_response(data, &order_id, response).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn handle_get_state_export(&self, data: &Value) -> Result<()> {
+        let order_id = self.get_string_field(data, "orderId")?;
+
+        let (orders_csv, balances_csv) = self.balance_manager.export_state_csv().await;
+
+        let response = json!({
+            "action": "STATE_EXPORT",
+            "data": {
+                "ordersCsv": orders_csv,
+                "balancesCsv": balances_csv
+            }
+        });
+
+        self.publish_response(data, &order_id, response).await?;
+
+        Ok(())
+    }
+
+    // Adds "<field>Iso" (RFC 3339) next to a numeric time field when iso_timestamps is on
+    fn add_iso_time(&self, target: &mut Value, field: &str, timestamp_ms: i64) {
+        if !self.config.iso_timestamps {
+            return;
+        }
+
+        if let Some(time) = chrono::DateTime::from_timestamp_millis(timestamp_ms) {
+            target[format!("{}Iso", field)] = json!(time.to_rfc3339());
+        }
+    }
+
+    fn get_string_field(&self, data: &Value, field: &str) -> Result<String> {
+        self.get_optional_string_field(data, field)?
+            .ok_or_else(|| anyhow::anyhow!("Missing or invalid field: {}", field))
+    }
+
+    fn get_decimal_field(&self, data: &Value, field: &str) -> Result<Decimal> {
+        self.get_optional_decimal_field(data, field)?
+            .ok_or_else(|| anyhow::anyhow!("Missing or invalid field: {}", field))
+    }
+
+    fn get_u32_field(&self, data: &Value, field: &str) -> Result<u32> {
+        self.get_optional_u32_field(data, field)?
+            .ok_or_else(|| anyhow::anyhow!("Missing or invalid field: {}", field))
+    }
+
+    fn get_i64_field(&self, data: &Value, field: &str) -> Result<i64> {
+        self.get_optional_i64_field(data, field)?
+            .ok_or_else(|| anyhow::anyhow!("Missing or invalid field: {}", field))
+    }
+
+    // Optional getters: absent or null -> Ok(None), present but unparseable -> Err
+    fn optional_value<'a>(&self, data: &'a Value, field: &str) -> Option<&'a Value> {
+        data.get(field).filter(|v| !v.is_null())
+    }
+
+    fn get_optional_string_field(&self, data: &Value, field: &str) -> Result<Option<String>> {
+        match self.optional_value(data, field) {
+            None => Ok(None),
+            Some(val) => val.as_str().map(|s| Some(s.to_string())).ok_or_else(|| {
+                anyhow::anyhow!("Invalid type for field {}: expected string", field)
+            }),
+        }
+    }
+
+    fn get_optional_decimal_field(&self, data: &Value, field: &str) -> Result<Option<Decimal>> {
+        let Some(val) = self.optional_value(data, field) else {
+            return Ok(None);
+        };
+
+        if let Some(s) = val.as_str() {
+            Decimal::from_str(s)
+                .map(Some)
+                .map_err(|e| anyhow::anyhow!("Invalid decimal string for field {}: {}", field, e))
+        } else if val.is_number() {
+            Decimal::from_str(&val.to_string())
+                .map(Some)
+                .map_err(|e| anyhow::anyhow!("Invalid decimal number for field {}: {}", field, e))
+        } else {
+            Err(anyhow::anyhow!(
+                "Invalid type for field {}: expected string or number",
+                field
+            ))
+        }
+    }
+
+    fn get_optional_u32_field(&self, data: &Value, field: &str) -> Result<Option<u32>> {
+        let Some(val) = self.optional_value(data, field) else {
+            return Ok(None);
+        };
+
+        if let Some(s) = val.as_str() {
+            s.parse::<u32>()
+                .map(Some)
+                .map_err(|e| anyhow::anyhow!("Invalid u32 string for field {}: {}", field, e))
+        } else if let Some(n) = val.as_u64() {
+            Ok(Some(n as u32))
+        } else {
+            Err(anyhow::anyhow!(
+                "Invalid type for field {}: expected string or number",
+                field
+            ))
+        }
+    }
+
+    fn get_optional_i64_field(&self, data: &Value, field: &str) -> Result<Option<i64>> {
+        let Some(val) = self.optional_value(data, field) else {
+            return Ok(None);
+        };
+
+        if let Some(s) = val.as_str() {
+            s.parse::<i64>()
+                .map(Some)
+                .map_err(|e| anyhow::anyhow!("Invalid i64 string for field {}: {}", field, e))
+        } else if let Some(n) = val.as_i64() {
+            Ok(Some(n))
+        } else {
+            Err(anyhow::anyhow!(
+                "Invalid type for field {}: expected string or number",
+                field
+            ))
+        }
+    }
+}
+
+// The configured snapshot path, with ".gz" appended when compression is on
+fn snapshot_file(config: &EngineConfig) -> String {
+    if config.snapshot_compress && !config.snapshot_path.ends_with(".gz") {
+        format!("{}.gz", config.snapshot_path)
+    } else {
+        config.snapshot_path.clone()
+    }
+}
+
+// Generation 0 is the latest snapshot itself; older ones are numbered before the extension:
+// snapshot.json -> snapshot.1.json, snapshot.json.gz -> snapshot.1.json.gz
+fn rotated_snapshot_file(path: &str, generation: usize) -> String {
+    if generation == 0 {
+        return path.to_string();
+    }
+    let (base, gz) = match path.strip_suffix(".gz") {
+        Some(base) => (base, ".gz"),
+        None => (path, ""),
+    };
+    match base.strip_suffix(".json") {
+        Some(stem) => format!("{}.{}.json{}", stem, generation, gz),
+        None => format!("{}.{}{}", base, generation, gz),
+    }
+}
+
+// Pretty JSON, optionally gzipped
+pub fn encode_snapshot(snapshot: &Value, compress: bool) -> Result<Vec<u8>> {
+    let json = serde_json::to_vec_pretty(snapshot)?;
+    if !compress {
+        return Ok(json);
+    }
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&json)?;
+    Ok(encoder.finish()?)
+}
+
+// Accepts plain or gzipped JSON, told apart by the gzip magic bytes
+pub fn decode_snapshot(bytes: &[u8]) -> Result<Value> {
+    if bytes.starts_with(&[0x1f, 0x8b]) {
+        let mut json = Vec::new();
+        GzDecoder::new(bytes).read_to_end(&mut json)?;
+        Ok(serde_json::from_slice(&json)?)
+    } else {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+// Unrealized pnl as a percentage of the order's collateral, to 2 places; null without collateral
+fn pnl_percent(pnl: Decimal, order: &Order) -> Value {
+    let collateral = order.collateral();
+    if collateral.is_zero() {
+        return Value::Null;
+    }
+    json!(
+        (pnl / collateral * Decimal::from(100))
+            .round_dp(2)
+            .to_string()
+    )
+}
+
+// Renders the order's prices at the scale of the feed it filled under, so a later decimals
+// change on the symbol doesn't change how an existing order reads
+fn add_display_prices(target: &mut Value, order: &Order, liquidation_price: Decimal) {
+    let Some(decimals) = order.decimals else {
+        return;
+    };
+    let Some(scale) = 10i64.checked_pow(decimals) else {
+        return;
+    };
+
+    let display = |price: Decimal| {
+        let mut value = (price / Decimal::from(scale)).round_dp(decimals);
+        value.rescale(decimals);
+        value.to_string()
+    };
+    target["openPriceDisplay"] = json!(display(order.open_price));
+    target["liquidationPriceDisplay"] = json!(display(liquidation_price));
+}
+
+// Serializes the full engine state in the snapshot.json format
+pub async fn snapshot_value(balance_manager: &BalanceManager, last_processed_id: &str) -> Value {
+    let users = balance_manager.users.read().await;
+    let orders_by_id = balance_manager.orders_by_id.read().await;
+    let orders_by_user = balance_manager.orders_by_user.read().await;
+    let liquidation_map = balance_manager.liquidation_map.read().await;
+    let prices = balance_manager.asset_prices.read().await;
+    let closed_orders = balance_manager.closed_orders.read().await;
+    let insurance_fund = balance_manager.insurance_fund.read().await;
+    let disabled_assets = balance_manager.disabled_assets.read().await;
+    let pending_orders = balance_manager.pending_orders.read().await;
+
+    // Log snapshot stats
+    let total_users_with_orders = orders_by_user.len();
+    info!(
+        "Saving snapshot: {} users, {} orders, {} users with orders, {} prices",
+        users.len(),
+        orders_by_id.len(),
+        total_users_with_orders,
+        prices.len()
+    );
+
+    let mut snapshot = json!({
+        "users": *users,
+        "orders_by_id": *orders_by_id,
+        "prices": *prices,
+        "closed_orders": *closed_orders,
+        "insurance_fund": *insurance_fund,
+        "disabled_assets": *disabled_assets,
+        "pending_orders": *pending_orders,
+        "last_processed_id": last_processed_id,
+        "timestamp": chrono::Utc::now().timestamp()
+    });
+    // Both maps are derivable from orders_by_id; load_snapshot rebuilds them when absent
+    if balance_manager.config.snapshot_derived_maps {
+        snapshot["orders_by_user"] = json!(*orders_by_user);
+        snapshot["liquidation_map"] = json!(*liquidation_map);
+    }
+    snapshot
+}
+
+// Exponential backoff with equal jitter: the delay for the nth consecutive failure is drawn
+// from [d/2, d] where d = min(base * 2^(n-1), max). `entropy` picks the point in that range.
+fn retry_delay_ms(failures: u32, base_ms: u64, max_ms: u64, entropy: u64) -> u64 {
+    let exponent = failures.saturating_sub(1).min(32);
+    let delay = base_ms.saturating_mul(1u64 << exponent).min(max_ms);
+    let half = delay / 2;
+    half + entropy % (delay - half + 1)
+}
+
+// Responses that report a rejection, which the v2 envelope marks with code ERROR. Most are
+// named by suffix; the rest are listed.
+fn is_error_action(action: &str) -> bool {
+    action.ends_with("_FAILED")
+        || action.ends_with("_NOT_FOUND")
+        || action.ends_with("_NOT_AVAILABLE")
+        || matches!(action, "UNAUTHORIZED" | "NOT_READY")
+}
+
+// Runs `task` forever, waiting `every` after each run finishes, so a slow run delays the next
+// instead of having missed ticks fire back-to-back
+pub async fn run_spaced<F, Fut>(every: tokio::time::Duration, mut task: F)
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = ()>,
+{
+    loop {
+        tokio::time::sleep(every).await;
+        task().await;
+    }
+}
+
+// Keeps a field read's value, recording its error instead of returning early
+fn collect_field<T>(errors: &mut Vec<String>, result: Result<T>) -> Option<T> {
+    result.map_err(|e| errors.push(e.to_string())).ok()
+}
+
+// Stream ids are "<ms>-<seq>" and order by (ms, seq); special ids like "$" don't parse
+pub fn parse_stream_id(id: &str) -> Option<(u64, u64)> {
+    let (ms, seq) = id.split_once('-')?;
+    Some((ms.parse().ok()?, seq.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StaleFeedPolicy;
+    use crate::stream_backend::{BackendFuture, MemoryBackend};
+    use crate::test_support::*;
+
+    #[tokio::test]
+    async fn order_before_warmup_is_processed_after_warmup() {
+        let mut harness = Harness::new(test_config());
+        // Four seconds old on arrival, so it is only fresh if judged at arrival, not at replay
+        let mut message = create_order_message("o1", "u1", "long", "100");
+        message["timestamp"] = json!(chrono::Utc::now().timestamp() - 4);
+        harness.push(message).await;
+        harness.start().await;
+
+        assert!(harness.no_response("o1", 2000).await);
+        assert!(!*harness.processor.ready.read().await);
+
+        harness.push_price("BTC", "1000", "999").await;
+        let response = harness.response("o1").await;
+        assert_eq!(response["action"], "ORDER_SUCCESS");
+        assert!(harness.balance_manager.get_order("o1").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn orders_past_the_deferred_cap_are_rejected_as_not_ready() {
+        let mut harness = Harness::new(EngineConfig {
+            warmup_max_deferred: 1,
+            ..test_config()
+        });
+        harness
+            .push(create_order_message("o1", "u1", "long", "100"))
+            .await;
+        let mut v2 = create_order_message("o2", "u1", "long", "100");
+        v2["apiVersion"] = json!(2);
+        harness.push(v2).await;
+        harness.start().await;
+
+        let rejected = harness.response("o2").await;
+        assert_eq!(rejected["action"], "NOT_READY");
+        assert_eq!(rejected["code"], "ERROR");
+        assert_eq!(rejected["message"], "Engine is warming up, retry later");
+
+        harness.push_price("BTC", "1000", "999").await;
+        assert_eq!(harness.response("o1").await["action"], "ORDER_SUCCESS");
+        assert!(harness.balance_manager.get_order("o2").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn pending_entries_are_reprocessed_after_a_crash() {
+        let config = EngineConfig {
+            use_consumer_group: true,
+            ..test_config()
+        };
+        let mut harness = Harness::new(config.clone());
+        harness.push_price("BTC", "1000", "999").await;
+        let mut message = create_order_message("o1", "u1", "long", "100");
+        message["timestamp"] = json!(chrono::Utc::now().timestamp() - 4);
+        harness.push(message).await;
+
+        // A previous run read both entries and crashed before applying or acking them
+        {
+            let mut backend = harness.backend.write().await;
+            backend
+                .ensure_group("orders", &config.consumer_group, "$")
+                .await
+                .unwrap();
+            let reply = backend
+                .read_group("orders", &config.consumer_group, &config.consumer_name, ">")
+                .await
+                .unwrap();
+            assert_eq!(reply.keys[0].ids.len(), 2);
+        }
+        // Long enough that the order would now fail a check against the wall clock
+        tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+
+        harness.start().await;
+        assert_eq!(harness.response("o1").await["action"], "ORDER_SUCCESS");
+        assert!(harness.balance_manager.get_order("o1").await.is_some());
+
+        let pending = harness
+            .backend
+            .write()
+            .await
+            .read_group("orders", &config.consumer_group, &config.consumer_name, "0")
+            .await
+            .unwrap();
+        assert!(pending.keys.is_empty());
+    }
+
+    #[tokio::test]
+    async fn old_format_snapshot_orders_index_at_the_tiered_liquidation_price() {
+        let path = temp_snapshot_path("old-format");
+        let mut old_order = order("o1", "u1", "long", "100", 10);
+        old_order.open_price = dec("1000");
+        old_order.quantity = dec("1");
+        let snapshot = json!({ "orders": { "u1": [old_order] } });
+        fs::write(&path, snapshot.to_string()).await.unwrap();
+
+        let config = EngineConfig {
+            snapshot_enabled: true,
+            snapshot_path: path.clone(),
+            snapshot_compress: false,
+            maintenance_tiers: "0:0.05".to_string(),
+            ..test_config()
+        };
+        let harness = Harness::new(config);
+        let loaded = harness.processor.load_snapshot().await;
+        let _ = fs::remove_file(&path).await;
+        loaded.unwrap();
+
+        let balance_manager = &harness.balance_manager;
+        let order = balance_manager.get_order("o1").await.unwrap();
+        let expected = balance_manager.calculate_liquidation_price(&order);
+        let keys: Vec<String> = balance_manager.liquidation_map.read().await["BTC"]
+            .keys()
+            .cloned()
+            .collect();
+        assert_eq!(keys, vec![expected.to_string()]);
+
+        // Closing has to find the entry under the key it computes, leaving nothing behind
+        balance_manager.remove_order("o1").await.unwrap();
+        let liquidation_map = balance_manager.liquidation_map.read().await;
+        assert!(liquidation_map.values().all(|entries| entries.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn ema_price_is_reported_wherever_prices_are() {
+        let mut harness = Harness::new(test_config());
+        harness.push_price("BTC", "1000", "999").await;
+        harness
+            .push(json!({"action": "SUBSCRIBE", "orderId": "s1", "channel": "prices:BTC"}))
+            .await;
+        harness.push_price("BTC", "1100", "1099").await;
+        harness
+            .push(json!({"action": "GET_PRICE", "orderId": "q1", "symbol": "BTC"}))
+            .await;
+        harness
+            .push(json!({"action": "GET_SUPPORTED_ASSETS", "orderId": "q2"}))
+            .await;
+        harness.start().await;
+
+        // alpha 0.1: the first tick seeds the average at 999.5, the second pulls it to 1009.5
+        let update = harness.response("prices:BTC").await;
+        assert_eq!(
+            dec(update["data"]["emaPrice"].as_str().unwrap()),
+            dec("1009.5")
+        );
+
+        let price = harness.response("q1").await;
+        assert_eq!(
+            dec(price["data"]["emaPrice"].as_str().unwrap()),
+            dec("1009.5")
+        );
+
+        let assets = harness.response("q2").await;
+        let btc = assets["assets"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|asset| asset["symbol"] == "BTC")
+            .unwrap();
+        assert_eq!(dec(btc["emaPrice"].as_str().unwrap()), dec("1009.5"));
+    }
+
+    fn price_message(buy: &str, sell: &str) -> HashMap<String, RedisValue> {
+        let message = json!({
+            "action": "LATEST_PRICE",
+            "symbol": "BTC",
+            "buyPrice": buy,
+            "sellPrice": sell,
+            "decimals": 4
+        });
+        HashMap::from([(
+            "data".to_string(),
+            RedisValue::Data(message.to_string().into_bytes()),
+        )])
+    }
+
+    #[tokio::test]
+    async fn held_price_is_applied_without_another_message() {
+        let harness = Harness::new(EngineConfig {
+            price_coalesce_ms: 100,
+            ..test_config()
+        });
+        let processor = harness.processor.clone();
+        processor
+            .process_message("1-0", price_message("1000", "999"))
+            .await
+            .unwrap();
+        processor
+            .process_message("2-0", price_message("1100", "1099"))
+            .await
+            .unwrap();
+        let held = harness.balance_manager.get_price("BTC").await.unwrap();
+        assert_eq!(held.buy_price, dec("1000"));
+
+        let flusher = tokio::spawn(async move { processor.run_held_price_flusher().await });
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        flusher.abort();
+
+        let applied = harness.balance_manager.get_price("BTC").await.unwrap();
+        assert_eq!(applied.buy_price, dec("1100"));
+    }
+
+    async fn db_queue_records(harness: &Harness) -> Vec<Value> {
+        let reply = harness
+            .backend
+            .write()
+            .await
+            .read_stream("db_queue", "0")
+            .await
+            .unwrap();
+        reply
+            .keys
+            .into_iter()
+            .flat_map(|key| key.ids)
+            .map(|entry| harness.processor.parse_message(&entry.map).unwrap())
+            .collect()
+    }
+
+    // db_queue once it holds `count` records; the record is written just after the response
+    async fn wait_for_db_records(harness: &Harness, count: usize) -> Vec<Value> {
+        for _ in 0..100 {
+            let records = db_queue_records(harness).await;
+            if records.len() >= count {
+                return records;
+            }
+            tokio::time::sleep(tokio::time::Duration::from_millis(20)).await;
+        }
+        panic!("db_queue never reached {} records", count);
+    }
+
+    async fn liquidate_three_longs(config: EngineConfig) -> Harness {
+        let harness = Harness::new(config);
+        let balance_manager = &harness.balance_manager;
+        balance_manager
+            .update_price(price("BTC", "1000", "999"))
+            .await
+            .unwrap();
+        for (order_id, user_id) in [("o1", "u1"), ("o2", "u2"), ("o3", "u3")] {
+            balance_manager
+                .create_order(order(order_id, user_id, "long", "100", 10))
+                .await
+                .unwrap();
+        }
+        balance_manager
+            .update_price(price("BTC", "700", "699"))
+            .await
+            .unwrap();
+        harness.processor.liquidate_breached(true).await;
+        harness
+    }
+
+    #[tokio::test]
+    async fn one_scan_liquidating_three_positions_writes_one_batched_record() {
+        let mut harness = liquidate_three_longs(EngineConfig {
+            batch_liquidation_records: true,
+            ..test_config()
+        })
+        .await;
+
+        let records = db_queue_records(&harness).await;
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["action"], "SAVE_LIQUIDATIONS");
+        let mut order_ids: Vec<&str> = records[0]["records"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|record| {
+                assert_eq!(record["action"], "SAVE_LIQUIDATED_ORDER");
+                record["orderId"].as_str().unwrap()
+            })
+            .collect();
+        order_ids.sort();
+        assert_eq!(order_ids, vec!["o1", "o2", "o3"]);
+
+        // Each owner is still told on its own channel
+        for order_id in ["o1", "o2", "o3"] {
+            let notice = harness.response(order_id).await;
+            assert_eq!(notice["action"], "ORDER_LIQUIDATED");
+            assert_eq!(notice["data"]["orderId"], order_id);
+            assert_eq!(notice["data"]["closePrice"], "699");
+            assert_eq!(
+                dec(notice["data"]["settledAmount"].as_str().unwrap()),
+                Decimal::ZERO
+            );
+            assert_eq!(dec(notice["data"]["fee"].as_str().unwrap()), Decimal::ZERO);
+        }
+    }
+
+    #[tokio::test]
+    async fn liquidation_records_stay_per_order_by_default() {
+        let harness = liquidate_three_longs(test_config()).await;
+
+        let records = db_queue_records(&harness).await;
+        assert_eq!(records.len(), 3);
+        assert!(
+            records
+                .iter()
+                .all(|record| record["action"] == "SAVE_LIQUIDATED_ORDER")
+        );
+    }
+
+    #[test]
+    fn optional_fields_distinguish_absent_and_null_from_invalid() {
+        let harness = Harness::new(test_config());
+        let processor = &harness.processor;
+        let data = json!({
+            "nothing": null,
+            "price": "101.5",
+            "leverage": 5,
+            "expiresAt": "1700000000",
+            "note": "hedge",
+            "bad": [1]
+        });
+
+        for field in ["absent", "nothing"] {
+            assert_eq!(
+                processor.get_optional_decimal_field(&data, field).unwrap(),
+                None
+            );
+            assert_eq!(
+                processor.get_optional_u32_field(&data, field).unwrap(),
+                None
+            );
+            assert_eq!(
+                processor.get_optional_i64_field(&data, field).unwrap(),
+                None
+            );
+            assert_eq!(
+                processor.get_optional_string_field(&data, field).unwrap(),
+                None
+            );
+        }
+
+        assert_eq!(
+            processor
+                .get_optional_decimal_field(&data, "price")
+                .unwrap(),
+            Some(dec("101.5"))
+        );
+        assert_eq!(
+            processor.get_optional_u32_field(&data, "leverage").unwrap(),
+            Some(5)
+        );
+        assert_eq!(
+            processor
+                .get_optional_i64_field(&data, "expiresAt")
+                .unwrap(),
+            Some(1_700_000_000)
+        );
+        assert_eq!(
+            processor.get_optional_string_field(&data, "note").unwrap(),
+            Some("hedge".to_string())
+        );
+
+        assert!(processor.get_optional_decimal_field(&data, "note").is_err());
+        assert!(processor.get_optional_u32_field(&data, "bad").is_err());
+        assert!(processor.get_optional_i64_field(&data, "note").is_err());
+        assert!(
+            processor
+                .get_optional_string_field(&data, "leverage")
+                .is_err()
+        );
+
+        // A nil data field is reported as such rather than as a missing one
+        let nil = HashMap::from([("data".to_string(), RedisValue::Nil)]);
+        let err = processor.parse_message(&nil).unwrap_err().to_string();
+        assert_eq!(err, "Data field is nil");
+    }
+
+    #[tokio::test]
+    async fn the_same_response_comes_in_either_api_version_shape() {
+        let mut harness = Harness::new(test_config());
+        harness.push_price("BTC", "1000", "999").await;
+        harness
+            .push(create_order_message("v1", "u1", "long", "100"))
+            .await;
+        let mut v2 = create_order_message("v2", "u1", "long", "100");
+        v2["apiVersion"] = json!(2);
+        harness.push(v2).await;
+        harness.start().await;
+
+        assert_eq!(
+            harness.response("v1").await,
+            json!({
+                "action": "ORDER_SUCCESS",
+                "data": {
+                    "orderId": "v1",
+                    "message": "Order created successfully",
+                    "clientRef": null
+                }
+            })
+        );
+        assert_eq!(
+            harness.response("v2").await,
+            json!({
+                "version": 2,
+                "action": "ORDER_SUCCESS",
+                "code": "OK",
+                "message": "Order created successfully",
+                "data": {
+                    "orderId": "v2",
+                    "message": "Order created successfully",
+                    "clientRef": null
+                }
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn memory_only_mode_never_writes_a_snapshot() {
+        let config = EngineConfig {
+            snapshot_path: temp_snapshot_path("memory-only"),
+            ..test_config()
+        };
+        let path = snapshot_file(&config);
+        let mut harness = Harness::new(config);
+        harness.push_price("BTC", "1000", "999").await;
+        harness
+            .push(create_order_message("o1", "u1", "long", "100"))
+            .await;
+        harness.start().await;
+        assert_eq!(harness.response("o1").await["action"], "ORDER_SUCCESS");
+
+        harness.processor.save_snapshot().await.unwrap();
+        assert!(!std::path::Path::new(&path).exists());
+    }
+
+    #[tokio::test]
+    async fn iso_timestamps_parse_back_to_the_numeric_instant() {
+        let mut harness = Harness::new(EngineConfig {
+            iso_timestamps: true,
+            ..test_config()
+        });
+        harness.push_price("BTC", "1000", "999").await;
+        harness
+            .push(json!({"action": "GET_PRICE", "orderId": "q1", "symbol": "BTC"}))
+            .await;
+        harness.start().await;
+
+        let price = harness.response("q1").await;
+        let numeric = price["data"]["lastUpdated"].as_i64().unwrap();
+        let iso = price["data"]["lastUpdatedIso"].as_str().unwrap();
+        let parsed = chrono::DateTime::parse_from_rfc3339(iso).unwrap();
+        assert_eq!(parsed.timestamp_millis(), numeric);
+    }
+
+    #[tokio::test]
+    async fn a_corrupt_snapshot_fails_the_self_test_with_the_reason() {
+        let path = temp_snapshot_path("corrupt-self-test");
+        fs::write(&path, "{\"orders_by_id\": [truncated")
+            .await
+            .unwrap();
+        let harness = Harness::new(EngineConfig {
+            snapshot_enabled: true,
+            snapshot_path: path.clone(),
+            ..test_config()
+        });
+
+        let result = harness.processor.startup_self_test().await;
+        let _ = fs::remove_file(&path).await;
+        let err = result.unwrap_err().to_string();
+        assert!(
+            err.starts_with("Startup self-test failed: snapshot: "),
+            "{}",
+            err
+        );
+        assert!(!err.contains("backend"), "{}", err);
+    }
+
+    // Fails reads as scripted (true = fail) and records when each read started, and fails the
+    // first `write_failures` stream writes; everything else goes to a MemoryBackend
+    struct FlakyBackend {
+        inner: MemoryBackend,
+        failures: VecDeque<bool>,
+        reads: Arc<std::sync::Mutex<Vec<tokio::time::Instant>>>,
+        write_failures: usize,
+        // Retained response lists by key, trimmed to the cap like LTRIM
+        retained: Arc<std::sync::Mutex<HashMap<String, Vec<String>>>>,
+        // Added to the backend's TIME reply
+        clock_skew_ms: i64,
+    }
+
+    impl StreamBackend for FlakyBackend {
+        fn ping(&mut self) -> BackendFuture<'_, ()> {
+            self.inner.ping()
+        }
+
+        fn server_time_ms(&mut self) -> BackendFuture<'_, i64> {
+            let skew = self.clock_skew_ms;
+            Box::pin(async move { Ok(chrono::Utc::now().timestamp_millis() + skew) })
+        }
+
+        fn read_stream<'a>(
+            &'a mut self,
+            stream: &'a str,
+            last_id: &'a str,
+        ) -> BackendFuture<'a, redis::streams::StreamReadReply> {
+            self.reads.lock().unwrap().push(tokio::time::Instant::now());
+            if self.failures.pop_front().unwrap_or(false) {
+                return Box::pin(async { Err(anyhow::anyhow!("connection refused")) });
+            }
+            self.inner.read_stream(stream, last_id)
+        }
+
+        fn ensure_group<'a>(
+            &'a mut self,
+            stream: &'a str,
+            group: &'a str,
+            start_id: &'a str,
+        ) -> BackendFuture<'a, ()> {
+            self.inner.ensure_group(stream, group, start_id)
+        }
+
+        fn read_group<'a>(
+            &'a mut self,
+            stream: &'a str,
+            group: &'a str,
+            consumer: &'a str,
+            id: &'a str,
+        ) -> BackendFuture<'a, redis::streams::StreamReadReply> {
+            self.inner.read_group(stream, group, consumer, id)
+        }
+
+        fn ack<'a>(
+            &'a mut self,
+            stream: &'a str,
+            group: &'a str,
+            id: &'a str,
+        ) -> BackendFuture<'a, ()> {
+            self.inner.ack(stream, group, id)
+        }
+
+        fn ensure_stream<'a>(&'a mut self, stream: &'a str) -> BackendFuture<'a, ()> {
+            self.inner.ensure_stream(stream)
+        }
+
+        fn add_to_stream<'a>(
+            &'a mut self,
+            stream: &'a str,
+            data: &'a str,
+        ) -> BackendFuture<'a, ()> {
+            if self.write_failures > 0 {
+                self.write_failures -= 1;
+                return Box::pin(async { Err(anyhow::anyhow!("connection refused")) });
+            }
+            self.inner.add_to_stream(stream, data)
+        }
+
+        fn publisher<'a>(
+            &'a mut self,
+            channel: &'a str,
+            message: &'a str,
+        ) -> BackendFuture<'a, ()> {
+            self.inner.publisher(channel, message)
+        }
+
+        fn retain_response<'a>(
+            &'a mut self,
+            key: &'a str,
+            message: &'a str,
+            cap: usize,
+            ttl_secs: u64,
+        ) -> BackendFuture<'a, ()> {
+            let mut retained = self.retained.lock().unwrap();
+            let list = retained.entry(key.to_string()).or_default();
+            list.push(message.to_string());
+            let excess = list.len().saturating_sub(cap);
+            list.drain(..excess);
+            self.inner.retain_response(key, message, cap, ttl_secs)
+        }
+    }
+
+    // The paused clock only moves through sleeps, so the gaps between reads are the retry delays
+    #[tokio::test(start_paused = true)]
+    async fn read_retries_back_off_and_reset_after_a_success() {
+        let reads = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let backend: Arc<RwLock<dyn StreamBackend>> = Arc::new(RwLock::new(FlakyBackend {
+            inner: MemoryBackend::new(0),
+            failures: VecDeque::from([true, true, true, false, true, true]),
+            reads: reads.clone(),
+            write_failures: 0,
+            retained: Default::default(),
+            clock_skew_ms: 0,
+        }));
+        let mut harness = Harness::with_backend(
+            EngineConfig {
+                retry_base_ms: 100,
+                retry_max_ms: 300,
+                ..test_config()
+            },
+            backend,
+        );
+        harness.start().await;
+        tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+
+        let reads = reads.lock().unwrap();
+        let gaps: Vec<u128> = reads
+            .windows(2)
+            .take(6)
+            .map(|pair| (pair[1] - pair[0]).as_millis())
+            .collect();
+        // Each delay falls in [d/2, d] for d = 100, 200, then 300 (capped); the success costs
+        // only the empty read's wait, and the next failure starts again from 100
+        let bounds = [
+            (50, 100),
+            (100, 200),
+            (150, 300),
+            (50, 50),
+            (50, 100),
+            (100, 200),
+        ];
+        for (gap, (low, high)) in gaps.iter().zip(bounds) {
+            assert!((low..=high).contains(gap), "{:?}", gaps);
+        }
+        assert_eq!(gaps.len(), bounds.len());
+    }
+
+    #[tokio::test]
+    async fn a_repeated_close_request_credits_the_user_once() {
+        let mut harness = Harness::new(test_config());
+        harness.push_price("BTC", "1000", "999").await;
+        harness
+            .push(create_order_message("o1", "u1", "long", "100"))
+            .await;
+        let close = json!({"action": "CLOSE_ORDER", "orderId": "o1", "requestId": "r1"});
+        harness.push(close.clone()).await;
+        harness.push(close).await;
+        harness.start().await;
+
+        assert_eq!(harness.response("o1").await["action"], "ORDER_SUCCESS");
+        let first = harness.response("o1").await;
+        let replayed = harness.response("o1").await;
+        assert_eq!(first, replayed);
+
+        // 100 of margin back, less the 1 lost selling 1 BTC at 999
+        let users = harness.balance_manager.users.read().await;
+        assert_eq!(users["u1"].usd_balance, dec("4999"));
+    }
+
+    #[tokio::test]
+    async fn client_ref_round_trips_from_create_through_close() {
+        let mut harness = Harness::new(test_config());
+        harness.push_price("BTC", "1000", "999").await;
+        let mut create = create_order_message("o1", "u1", "long", "100");
+        create["clientRef"] = json!("desk-7/42");
+        harness.push(create).await;
+        harness
+            .push(json!({"action": "CLOSE_ORDER", "orderId": "o1"}))
+            .await;
+        harness.start().await;
+
+        assert_eq!(
+            harness.response("o1").await["data"]["clientRef"],
+            "desk-7/42"
+        );
+        assert_eq!(
+            harness.response("o1").await["data"]["clientRef"],
+            "desk-7/42"
+        );
+
+        let records = wait_for_db_records(&harness, 2).await;
+        assert_eq!(records[0]["order"]["client_ref"], "desk-7/42");
+        assert_eq!(records[1]["action"], "SAVE_CLOSED_ORDER");
+        assert_eq!(records[1]["clientRef"], "desk-7/42");
+    }
+
+    #[tokio::test]
+    async fn a_failing_db_queue_still_credits_the_user_exactly_once() {
+        let mut inner = MemoryBackend::new(0);
+        for message in [
+            json!({"action": "LATEST_PRICE", "symbol": "BTC", "buyPrice": "1000", "sellPrice": "999", "decimals": 4}),
+            create_order_message("o1", "u1", "long", "100"),
+            json!({"action": "CLOSE_ORDER", "orderId": "o1"}),
+        ] {
+            inner
+                .add_to_stream("orders", &message.to_string())
+                .await
+                .unwrap();
+        }
+        // Both the open and the close record fail to write at first
+        let backend: Arc<RwLock<dyn StreamBackend>> = Arc::new(RwLock::new(FlakyBackend {
+            inner,
+            failures: VecDeque::new(),
+            reads: Arc::new(std::sync::Mutex::new(Vec::new())),
+            write_failures: 2,
+            retained: Default::default(),
+            clock_skew_ms: 0,
+        }));
+        let mut harness = Harness::with_backend(test_config(), backend);
+        harness.start().await;
+
+        assert_eq!(harness.response("o1").await["action"], "ORDER_SUCCESS");
+        assert_eq!(harness.response("o1").await["action"], "ORDER_SUCCESS");
+        let users = harness.balance_manager.users.read().await;
+        assert_eq!(users["u1"].usd_balance, dec("4999"));
+        drop(users);
+
+        // The retry buffer delivers each record once the queue recovers
+        let records = wait_for_db_records(&harness, 2).await;
+        let actions: Vec<_> = records.iter().map(|r| r["action"].clone()).collect();
+        assert_eq!(actions, ["SAVE_OPENED_ORDER", "SAVE_CLOSED_ORDER"]);
+        assert_eq!(records[1]["settledAmount"], "99");
+    }
+
+    #[tokio::test]
+    async fn orders_by_margin_and_by_quantity_fill_alike() {
+        let mut harness = Harness::new(test_config());
+        harness.push_price("BTC", "1000", "999").await;
+        harness
+            .push(create_order_message("by-margin", "u1", "long", "100"))
+            .await;
+        let mut by_quantity = create_order_message("by-quantity", "u2", "long", "100");
+        by_quantity.as_object_mut().unwrap().remove("margin");
+        by_quantity["quantity"] = json!("1");
+        harness.push(by_quantity).await;
+        let mut both = create_order_message("both", "u3", "long", "100");
+        both["quantity"] = json!("1");
+        harness.push(both).await;
+        let mut neither = create_order_message("neither", "u3", "long", "100");
+        neither.as_object_mut().unwrap().remove("margin");
+        harness.push(neither).await;
+        harness.start().await;
+
+        for order_id in ["by-margin", "by-quantity"] {
+            assert_eq!(harness.response(order_id).await["action"], "ORDER_SUCCESS");
+        }
+        for order_id in ["both", "neither"] {
+            let response = harness.response(order_id).await;
+            assert_eq!(response["action"], "ORDER_FAILED");
+            assert_eq!(
+                response["data"]["errors"],
+                json!(["Specify exactly one positive margin or quantity"])
+            );
+        }
+
+        let balance_manager = &harness.balance_manager;
+        let by_margin = balance_manager.get_order("by-margin").await.unwrap();
+        let by_quantity = balance_manager.get_order("by-quantity").await.unwrap();
+        assert_eq!(
+            (by_margin.margin, by_margin.quantity, by_margin.open_price),
+            (
+                by_quantity.margin,
+                by_quantity.quantity,
+                by_quantity.open_price
+            )
+        );
+        assert_eq!(
+            (by_quantity.margin, by_quantity.quantity),
+            (dec("100"), dec("1"))
+        );
+        let users = balance_manager.users.read().await;
+        assert_eq!(users["u1"].usd_balance, users["u2"].usd_balance);
+    }
+
+    #[tokio::test]
+    async fn a_compressed_snapshot_loads_identically_and_is_much_smaller() {
+        let path = temp_snapshot_path("compressed");
+        let config = EngineConfig {
+            snapshot_enabled: true,
+            snapshot_path: path.clone(),
+            snapshot_compress: true,
+            ..test_config()
+        };
+        let saved = Harness::new(config.clone());
+        let balance_manager = &saved.balance_manager;
+        balance_manager
+            .update_price(price("BTC", "1000", "999"))
+            .await
+            .unwrap();
+        for i in 0..500 {
+            let order_type = if i % 2 == 0 { "long" } else { "short" };
+            balance_manager
+                .create_order(order(
+                    &format!("o{}", i),
+                    &format!("u{}", i % 50),
+                    order_type,
+                    "10",
+                    10,
+                ))
+                .await
+                .unwrap();
+        }
+        saved.processor.save_snapshot().await.unwrap();
+
+        let restored = Harness::new(config.clone());
+        let loaded = restored.processor.load_snapshot().await;
+        let compressed_len = fs::metadata(snapshot_file(&config)).await.map(|m| m.len());
+        let _ = fs::remove_file(snapshot_file(&config)).await;
+        loaded.unwrap();
+
+        // Loading re-stamps price freshness, so only the state itself is compared
+        let state = |mut snapshot: Value| {
+            snapshot.as_object_mut().unwrap().remove("timestamp");
+            for price in snapshot["prices"].as_object_mut().unwrap().values_mut() {
+                price.as_object_mut().unwrap().remove("updated_at");
+            }
+            snapshot
+        };
+        let before = state(snapshot_value(&saved.balance_manager, "$").await);
+        let after = state(snapshot_value(&restored.balance_manager, "$").await);
+        assert_eq!(before, after);
+
+        let plain_len = encode_snapshot(&before, false).unwrap().len() as u64;
+        assert!(compressed_len.unwrap() * 5 < plain_len);
+    }
+
+    #[tokio::test]
+    async fn a_redelivered_older_id_is_skipped_while_a_newer_one_is_processed() {
+        let config = EngineConfig {
+            use_consumer_group: true,
+            ..test_config()
+        };
+        let mut harness = Harness::new(config.clone());
+        harness
+            .push(create_order_message("o1", "u1", "long", "100"))
+            .await;
+        harness.push_price("BTC", "1000", "999").await;
+        harness
+            .push(create_order_message("o2", "u1", "long", "100"))
+            .await;
+
+        // A previous run applied everything up to o1 but crashed before acking anything
+        let applied_id = {
+            let mut backend = harness.backend.write().await;
+            backend
+                .ensure_group("orders", &config.consumer_group, "$")
+                .await
+                .unwrap();
+            let reply = backend
+                .read_group("orders", &config.consumer_group, &config.consumer_name, ">")
+                .await
+                .unwrap();
+            reply.keys[0].ids[0].id.clone()
+        };
+        *harness.processor.last_processed_id.write().await = applied_id;
+
+        harness.start().await;
+        assert_eq!(harness.response("o2").await["action"], "ORDER_SUCCESS");
+        assert!(harness.no_response("o1", 200).await);
+        assert!(harness.balance_manager.get_order("o1").await.is_none());
+
+        // The skipped entries were acked along with the processed one
+        let pending = harness
+            .backend
+            .write()
+            .await
+            .read_group("orders", &config.consumer_group, &config.consumer_name, "0")
+            .await
+            .unwrap();
+        assert!(pending.keys.iter().all(|key| key.ids.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn a_created_order_records_the_stream_id_it_was_read_under() {
+        let mut harness = Harness::new(test_config());
+        harness.push_price("BTC", "1000", "999").await;
+        harness
+            .push(create_order_message("o1", "u1", "long", "100"))
+            .await;
+        harness.start().await;
+        assert_eq!(harness.response("o1").await["action"], "ORDER_SUCCESS");
+
+        let read_under = {
+            let reply = harness
+                .backend
+                .write()
+                .await
+                .read_stream("orders", "0")
+                .await
+                .unwrap();
+            reply.keys[0].ids[1].id.clone()
+        };
+
+        harness
+            .push(json!({ "action": "GET_ORDER", "orderId": "o1" }))
+            .await;
+        let found = harness.response("o1").await;
+        assert_eq!(found["action"], "ORDER");
+        assert_eq!(found["data"]["streamId"], read_under.as_str());
+        assert_eq!(
+            harness
+                .balance_manager
+                .get_order("o1")
+                .await
+                .unwrap()
+                .stream_id,
+            Some(read_under)
+        );
+    }
+
+    #[tokio::test]
+    async fn a_stop_entry_fills_rising_through_the_stop_and_a_limit_falling_to_the_limit() {
+        let mut harness = Harness::new(test_config());
+        harness.push_price("BTC", "1000", "999").await;
+        let mut stop = create_order_message("stop", "u1", "long", "100");
+        stop["triggerPrice"] = json!("1050");
+        stop["triggerType"] = json!("stop");
+        let mut limit = create_order_message("limit", "u1", "long", "100");
+        limit["triggerPrice"] = json!("950");
+        harness.push(stop).await;
+        harness.push(limit).await;
+        harness.start().await;
+        assert_eq!(harness.response("stop").await["action"], "ORDER_PENDING");
+        assert_eq!(harness.response("limit").await["action"], "ORDER_PENDING");
+
+        // Rising through the stop fills it; the limit is still waiting for a better price
+        harness.push_price("BTC", "1060", "1059").await;
+        assert_eq!(harness.response("stop").await["action"], "ORDER_SUCCESS");
+        assert!(harness.no_response("limit", 200).await);
+
+        harness.push_price("BTC", "940", "939").await;
+        assert_eq!(harness.response("limit").await["action"], "ORDER_SUCCESS");
+
+        let balance_manager = &harness.balance_manager;
+        let stop = balance_manager.get_order("stop").await.unwrap();
+        let limit = balance_manager.get_order("limit").await.unwrap();
+        assert_eq!(stop.open_price, dec("1060"));
+        assert_eq!(limit.open_price, dec("940"));
+    }
+
+    #[tokio::test]
+    async fn orders_and_positions_report_the_formula_liquidation_price() {
+        let mut harness = Harness::new(test_config());
+        harness.push_price("BTC", "1000", "1000").await;
+        harness
+            .push(create_order_message("long", "u1", "long", "100"))
+            .await;
+        let mut short = create_order_message("short", "u1", "short", "200");
+        short["leverage"] = json!(5);
+        harness.push(short).await;
+        harness
+            .push(json!({ "action": "GET_ORDERS", "user": "u1", "orderId": "q1" }))
+            .await;
+        harness
+            .push(json!({ "action": "GET_POSITIONS", "user": "u1", "orderId": "q2" }))
+            .await;
+        harness.start().await;
+
+        // 90% of the collateral lost: 1000 * (1 - 0.9 * 100 / 1000) and 1000 * (1 + 0.9 * 200 / 1000)
+        let expected = |order: &Value| match order["order_id"].as_str().unwrap() {
+            "long" => "910",
+            _ => "1180",
+        };
+        let orders = harness.response("q1").await;
+        let positions = harness.response("q2").await;
+        for listed in [&orders["orders"], &positions["positions"]] {
+            let listed = listed.as_array().unwrap();
+            assert_eq!(listed.len(), 2);
+            for order in listed {
+                assert_eq!(
+                    dec(order["liquidationPrice"].as_str().unwrap()),
+                    dec(expected(order))
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn a_seeded_user_reports_the_configured_holdings_in_get_balance() {
+        let mut harness = Harness::new(EngineConfig {
+            initial_asset_balances: "BTC:0.5:8,ETH:2:18".to_string(),
+            ..test_config()
+        });
+        harness.push_price("BTC", "1000", "999").await;
+        harness
+            .push(json!({ "action": "CREATE_USER", "user": "u1", "orderId": "c1" }))
+            .await;
+        harness
+            .push(json!({ "action": "GET_BALANCE", "user": "u1", "orderId": "q1" }))
+            .await;
+        harness.start().await;
+
+        let balance = harness.response("q1").await;
+        assert_eq!(balance["action"], "BALANCE");
+        assert_eq!(dec(balance["BTC"]["balance"].as_str().unwrap()), dec("0.5"));
+        assert_eq!(balance["BTC"]["decimals"], 8);
+        assert_eq!(dec(balance["ETH"]["balance"].as_str().unwrap()), dec("2"));
+        assert_eq!(balance["ETH"]["decimals"], 18);
+    }
+
+    #[tokio::test]
+    async fn get_balance_tells_an_unknown_user_from_one_without_holdings() {
+        let mut harness = Harness::new(EngineConfig {
+            unknown_user_not_found: true,
+            ..test_config()
+        });
+        harness.push_price("BTC", "1000", "999").await;
+        harness
+            .push(json!({ "action": "CREATE_USER", "user": "known", "orderId": "c1" }))
+            .await;
+        harness
+            .push(json!({ "action": "GET_BALANCE", "user": "known", "orderId": "q1" }))
+            .await;
+        harness
+            .push(json!({ "action": "GET_BALANCE", "user": "stranger", "orderId": "q2" }))
+            .await;
+        harness.start().await;
+
+        assert_eq!(harness.response("q1").await, json!({ "action": "BALANCE" }));
+        let missing = harness.response("q2").await;
+        assert_eq!(missing["action"], "USER_NOT_FOUND");
+        assert_eq!(missing["data"]["user"], "stranger");
+        assert!(
+            !harness
+                .balance_manager
+                .users
+                .read()
+                .await
+                .contains_key("stranger")
+        );
+    }
+
+    #[tokio::test]
+    async fn positions_report_realized_and_unrealized_pnl_after_a_partial_close() {
+        let mut harness = Harness::new(test_config());
+        harness.push_price("BTC", "1000", "1000").await;
+        harness
+            .push(create_order_message("o1", "u1", "long", "100"))
+            .await;
+        harness.push_price("BTC", "1100", "1100").await;
+        harness
+            .push(json!({ "action": "CLOSE_ORDER", "orderId": "o1", "fraction": "0.5" }))
+            .await;
+        harness.push_price("BTC", "1200", "1200").await;
+        harness
+            .push(json!({ "action": "GET_POSITIONS", "user": "u1", "orderId": "q1" }))
+            .await;
+        harness.start().await;
+
+        // Half of the 1 BTC long closed 100 up, the other half is now 200 up
+        let positions = harness.response("q1").await;
+        let position = &positions["positions"][0];
+        let field = |name: &str| dec(position[name].as_str().unwrap());
+        assert_eq!(field("quantity"), dec("0.5"));
+        assert_eq!(field("realizedPnl"), dec("50"));
+        assert_eq!(field("unrealizedPnl"), dec("100"));
+        assert_eq!(field("totalPnl"), dec("150"));
+    }
+
+    #[tokio::test]
+    async fn a_second_order_on_the_same_asset_inside_the_interval_is_too_frequent() {
+        let mut harness = Harness::new(EngineConfig {
+            min_order_interval_ms: 60_000,
+            ..test_config()
+        });
+        harness.push_price("BTC", "1000", "999").await;
+        harness.push_price("ETH", "100", "99").await;
+        harness
+            .push(create_order_message("btc1", "u1", "long", "100"))
+            .await;
+        harness
+            .push(create_order_message("btc2", "u1", "long", "100"))
+            .await;
+        let mut eth = create_order_message("eth1", "u1", "long", "100");
+        eth["asset"] = json!("ETH");
+        harness.push(eth).await;
+        harness.start().await;
+
+        assert_eq!(harness.response("btc1").await["action"], "ORDER_SUCCESS");
+        let throttled = harness.response("btc2").await;
+        assert_eq!(throttled["action"], "ORDER_FAILED");
+        assert_eq!(throttled["data"]["message"], "Too frequent");
+        assert_eq!(harness.response("eth1").await["action"], "ORDER_SUCCESS");
+    }
+
+    #[tokio::test]
+    async fn an_order_goes_end_to_end_through_the_in_memory_backend() {
+        let mut harness = Harness::new(test_config());
+        harness.push_price("BTC", "1000", "1000").await;
+        harness
+            .push(create_order_message("o1", "u1", "long", "100"))
+            .await;
+        harness.start().await;
+
+        let created = harness.response("o1").await;
+        assert_eq!(created["action"], "ORDER_SUCCESS");
+        assert_eq!(created["data"]["message"], "Order created successfully");
+
+        harness.push_price("BTC", "1010", "1010").await;
+        harness
+            .push(json!({ "action": "CLOSE_ORDER", "orderId": "o1" }))
+            .await;
+        let closed = harness.response("o1").await;
+        assert_eq!(closed["action"], "ORDER_SUCCESS");
+        assert_eq!(dec(closed["data"]["pnl"].as_str().unwrap()), dec("10"));
+
+        let records = wait_for_db_records(&harness, 2).await;
+        let actions: Vec<&str> = records
+            .iter()
+            .map(|record| record["action"].as_str().unwrap())
+            .collect();
+        assert_eq!(actions, vec!["SAVE_OPENED_ORDER", "SAVE_CLOSED_ORDER"]);
+        assert_eq!(
+            harness.balance_manager.get_user_balance_usd("u1").await,
+            Ok(dec("5010"))
+        );
+    }
+
+    #[tokio::test]
+    async fn an_order_keeps_its_fill_decimals_after_the_feed_changes_scale() {
+        let mut harness = Harness::new(EngineConfig {
+            allow_decimals_change: true,
+            ..test_config()
+        });
+        let quote = |raw: &str, decimals: u32| {
+            json!({
+                "action": "LATEST_PRICE",
+                "symbol": "BTC",
+                "buyPrice": raw,
+                "sellPrice": raw,
+                "decimals": decimals
+            })
+        };
+        // 1000.00 at 2 decimals, then the same 1000 at 6
+        harness.push(quote("100000", 2)).await;
+        harness
+            .push(create_order_message("o1", "u1", "long", "100"))
+            .await;
+        harness.push(quote("1000000000", 6)).await;
+        harness
+            .push(json!({ "action": "GET_ORDERS", "user": "u1", "orderId": "q1" }))
+            .await;
+        harness.start().await;
+
+        assert_eq!(harness.response("o1").await["action"], "ORDER_SUCCESS");
+        let orders = harness.response("q1").await;
+        let listed = &orders["orders"][0];
+        assert_eq!(listed["decimals"], 2);
+        assert_eq!(listed["openPriceDisplay"], "1000.00");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_slow_snapshot_delays_the_next_one_instead_of_overlapping_it() {
+        let running = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let starts = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let saver = {
+            let (running, starts) = (running.clone(), starts.clone());
+            run_spaced(tokio::time::Duration::from_secs(5), move || {
+                let (running, starts) = (running.clone(), starts.clone());
+                async move {
+                    // Each save takes longer than the interval
+                    let overlapping = running.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                    assert_eq!(overlapping, 0, "a save started while another was running");
+                    starts.lock().unwrap().push(tokio::time::Instant::now());
+                    tokio::time::sleep(tokio::time::Duration::from_secs(12)).await;
+                    running.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+                }
+            })
+        };
+        let _ = tokio::time::timeout(tokio::time::Duration::from_secs(60), saver).await;
+
+        let starts = starts.lock().unwrap();
+        // At 5s, 22s, 39s and 56s: each 12s save plus the 5s wait
+        assert_eq!(starts.len(), 4);
+        assert!(
+            starts
+                .windows(2)
+                .all(|pair| pair[1] - pair[0] == tokio::time::Duration::from_secs(17))
+        );
+    }
+
+    // An open long on BTC whose feed then goes quiet for longer than feed_timeout_secs
+    async fn silent_feed_harness(policy: StaleFeedPolicy) -> Harness {
+        let mut harness = Harness::new(EngineConfig {
+            feed_timeout_secs: 5,
+            stale_feed_policy: policy,
+            ..test_config()
+        });
+        harness.push_price("BTC", "1010", "1010").await;
+        harness
+            .push(create_order_message("o1", "u1", "long", "100"))
+            .await;
+        harness.start().await;
+        assert_eq!(harness.response("o1").await["action"], "ORDER_SUCCESS");
+
+        if let Some(btc) = harness
+            .balance_manager
+            .asset_prices
+            .write()
+            .await
+            .get_mut("BTC")
+        {
+            btc.updated_at -= 10_000;
+        }
+        harness
+    }
+
+    #[tokio::test]
+    async fn a_silent_feed_freezes_positions_under_the_freeze_policy() {
+        let mut harness = silent_feed_harness(StaleFeedPolicy::Freeze).await;
+        harness.processor.close_stale_positions().await;
+        assert!(harness.balance_manager.get_order("o1").await.is_some());
+
+        harness
+            .push(json!({ "action": "CLOSE_ORDER", "orderId": "o1" }))
+            .await;
+        let rejected = harness.response("o1").await;
+        assert_eq!(rejected["action"], "ORDER_FAILED");
+        assert_eq!(
+            rejected["data"]["message"],
+            "Price feed down for asset, positions are frozen"
+        );
+        assert!(harness.balance_manager.get_order("o1").await.is_some());
+    }
 
-                println!("Response JSON: {}", response);
+    #[tokio::test]
+    async fn a_silent_feed_closes_positions_at_the_last_price_under_the_close_policy() {
+        let mut harness = silent_feed_harness(StaleFeedPolicy::Close).await;
+        harness.processor.close_stale_positions().await;
 
-                let mut redis_manager = self.redis_manager.write().await;
-                println!("Got redis manager lock, adding to callback_response stream...");
+        let closed = harness.response("o1").await;
+        assert_eq!(closed["action"], "ORDER_FORCE_CLOSED");
+        assert_eq!(
+            dec(closed["data"]["closePrice"].as_str().unwrap()),
+            dec("1010")
+        );
+        assert!(harness.balance_manager.get_order("o1").await.is_none());
+        assert_eq!(
+            harness.balance_manager.get_user_balance_usd("u1").await,
+            Ok(dec("5000"))
+        );
+    }
 
-                let stream_result = redis_manager
-                    .publisher(&order_id, &response.to_string())
-                    .await;
+    #[tokio::test]
+    async fn a_stale_feed_close_waits_for_the_owners_lock() {
+        let harness = silent_feed_harness(StaleFeedPolicy::Close).await;
+        let user_lock = harness.balance_manager.lock_user("u1").await;
+        let processor = harness.processor.clone();
+        let close = tokio::spawn(async move { processor.close_stale_positions().await });
 
-                println!("Stream add result: {:?}", stream_result);
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        assert!(!close.is_finished());
+        assert!(harness.balance_manager.get_order("o1").await.is_some());
 
-                if let Err(e) = stream_result {
-                    error!("Failed to add response to callback_response stream: {}", e);
-                    return Err(e);
-                }
+        drop(user_lock);
+        close.await.unwrap();
+        assert!(harness.balance_manager.get_order("o1").await.is_none());
+    }
 
-                // Publish to database processor using stream
-                println!("Adding to db_queue stream...");
-                let db_data = json!({
-                    "action": "SAVE_CLOSED_ORDER",
-                    "orderId": order_id,
-                    "pnl": pnl,
-                    "closePrice": message,
-                    "timestamp": chrono::Utc::now().timestamp()
-                });
+    #[tokio::test]
+    async fn a_close_naming_another_user_still_waits_for_the_owners_lock() {
+        let mut harness = Harness::new(test_config());
+        harness.push_price("BTC", "1000", "1000").await;
+        harness
+            .push(create_order_message("o1", "u1", "long", "100"))
+            .await;
+        harness.start().await;
+        assert_eq!(harness.response("o1").await["action"], "ORDER_SUCCESS");
 
-                let db_result = redis_manager
-                    .add_to_stream("db_queue", &db_data.to_string())
-                    .await;
+        let user_lock = harness.balance_manager.lock_user("u1").await;
+        harness
+            .push(json!({ "action": "CLOSE_ORDER", "orderId": "o1", "user": "u2" }))
+            .await;
+        assert!(harness.no_response("o1", 200).await);
+        assert!(harness.balance_manager.get_order("o1").await.is_some());
 
-                println!("DB queue add result: {:?}", db_result);
+        drop(user_lock);
+        assert_eq!(harness.response("o1").await["action"], "ORDER_SUCCESS");
+        assert!(harness.balance_manager.get_order("o1").await.is_none());
+    }
 
-                if let Err(e) = db_result {
-                    error!("Failed to add to db_queue stream: {}", e);
-                }
+    #[tokio::test]
+    async fn admin_actions_need_the_configured_token_and_others_do_not() {
+        let mut harness = Harness::new(EngineConfig {
+            admin_token: "s3cret".to_string(),
+            ..test_config()
+        });
+        harness.push_price("BTC", "1000", "999").await;
+        let disable = |order_id: &str, token: Option<&str>| {
+            let mut message = json!({
+                "action": "DISABLE_ASSET",
+                "symbol": "BTC",
+                "orderId": order_id
+            });
+            if let Some(token) = token {
+                message["adminToken"] = json!(token);
             }
-            Err(e) => {
-                println!("Order close failed: {}", e);
+            message
+        };
+        harness.push(disable("a1", None)).await;
+        harness.push(disable("a2", Some("guess"))).await;
+        harness
+            .push(json!({ "action": "GET_BALANCE", "user": "u1", "orderId": "q1" }))
+            .await;
+        harness.start().await;
 
-                let response = json!({
-                    "action": "ORDER_FAILED",
-                    "data": {
-                        "orderId": order_id,
-                        "message": e
-                    }
-                });
+        for order_id in ["a1", "a2"] {
+            let rejected = harness.response(order_id).await;
+            assert_eq!(rejected["action"], "UNAUTHORIZED");
+            assert_eq!(rejected["data"]["message"], "Unauthorized");
+        }
+        assert!(harness.balance_manager.is_asset_tradeable("BTC").await);
+        assert_eq!(harness.response("q1").await["action"], "BALANCE");
 
-                let mut redis_manager = self.redis_manager.write().await;
-                let stream_result = redis_manager
-                    .publisher(&order_id, &response.to_string())
-                    .await;
+        let mut v2 = disable("a4", None);
+        v2["apiVersion"] = json!(2);
+        harness.push(v2).await;
+        let rejected = harness.response("a4").await;
+        assert_eq!(rejected["action"], "UNAUTHORIZED");
+        assert_eq!(rejected["code"], "ERROR");
+        assert_eq!(rejected["message"], "Unauthorized");
 
-                println!("Error response stream result: {:?}", stream_result);
+        harness.push(disable("a3", Some("s3cret"))).await;
+        assert_eq!(harness.response("a3").await["action"], "ASSET_STATUS");
+        assert!(!harness.balance_manager.is_asset_tradeable("BTC").await);
+    }
 
-                if let Err(stream_err) = stream_result {
-                    error!("Failed to add error response to stream: {}", stream_err);
-                    return Err(stream_err);
-                }
-            }
+    #[tokio::test]
+    async fn pnl_percent_is_the_unrealized_pnl_over_margin() {
+        let mut harness = Harness::new(test_config());
+        harness.push_price("BTC", "1000", "1000").await;
+        harness
+            .push(create_order_message("o1", "u1", "long", "100"))
+            .await;
+        harness.push_price("BTC", "1030", "1030").await;
+        harness
+            .push(json!({ "action": "GET_ORDERS", "user": "u1", "orderId": "q1" }))
+            .await;
+        harness
+            .push(json!({ "action": "GET_POSITIONS", "user": "u1", "orderId": "q2" }))
+            .await;
+        harness.start().await;
+
+        // 30 up on 100 of margin
+        let orders = harness.response("q1").await;
+        let positions = harness.response("q2").await;
+        for listed in [&orders["orders"][0], &positions["positions"][0]] {
+            assert_eq!(dec(listed["unrealizedPnl"].as_str().unwrap()), dec("30"));
+            assert_eq!(dec(listed["pnlPercent"].as_str().unwrap()), dec("30"));
         }
 
-        println!("handle_close_order completed");
-        Ok(())
+        let no_margin = order("o2", "u1", "long", "0", 10);
+        assert_eq!(pnl_percent(dec("30"), &no_margin), Value::Null);
     }
 
-    async fn handle_get_balance_usd(&self, data: &Value) -> Result<()> {
-        let user_id = self.get_string_field(data, "user")?;
-        let order_id = self.get_string_field(data, "orderId")?;
+    #[tokio::test]
+    async fn nothing_is_processed_while_the_pause_file_exists() {
+        let pause_file = temp_snapshot_path("pause");
+        fs::write(&pause_file, b"").await.unwrap();
+        let mut harness = Harness::new(EngineConfig {
+            pause_file: pause_file.clone(),
+            ..test_config()
+        });
+        harness.push_price("BTC", "1000", "999").await;
+        harness
+            .push(create_order_message("o1", "u1", "long", "100"))
+            .await;
+        harness.start().await;
 
-        // Ensure user exists
-        {
-            let balance_manager = self.balance_manager.read().await;
-            balance_manager.get_or_create_user(&user_id).await;
+        let unanswered = harness.no_response("o1", 700).await;
+        let health = harness.processor.health().await;
+        let _ = fs::remove_file(&pause_file).await;
+        assert!(unanswered);
+        assert_eq!(health["paused"], true);
+        assert_eq!(health["lastProcessedId"], "$");
+
+        // Nothing was read while paused, so resuming picks up the same messages
+        assert_eq!(harness.response("o1").await["action"], "ORDER_SUCCESS");
+        assert_eq!(harness.processor.health().await["paused"], false);
+    }
+
+    #[tokio::test]
+    async fn every_missing_or_invalid_create_field_is_reported_in_one_response() {
+        let mut harness = Harness::new(test_config());
+        harness.push_price("BTC", "1000", "999").await;
+        // Leverage may be left out for the default, so here it is present but unparseable
+        harness
+            .push(json!({
+                "action": "CREATE_ORDER",
+                "orderId": "o1",
+                "user": "u1",
+                "type": "long",
+                "leverage": "ten",
+                "timestamp": chrono::Utc::now().timestamp()
+            }))
+            .await;
+        harness.start().await;
+
+        let failed = harness.response("o1").await;
+        assert_eq!(failed["action"], "ORDER_FAILED");
+        let errors: Vec<&str> = failed["data"]["errors"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|error| error.as_str().unwrap())
+            .collect();
+        assert_eq!(errors.len(), 3, "{:?}", errors);
+        for field in ["asset", "leverage", "margin or quantity"] {
+            assert!(
+                errors.iter().any(|error| error.contains(field)),
+                "{} not reported in {:?}",
+                field,
+                errors
+            );
         }
+    }
 
-        let result = {
-            let balance_manager = self.balance_manager.read().await;
-            balance_manager.get_user_balance_usd(&user_id).await
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn a_query_flood_does_not_hold_up_an_order() {
+        let config = EngineConfig {
+            query_stream: "queries".to_string(),
+            ..test_config()
         };
+        let backend: Arc<RwLock<dyn StreamBackend>> = Arc::new(RwLock::new(MemoryBackend::new(0)));
+        let query_backend: Arc<RwLock<dyn StreamBackend>> =
+            Arc::new(RwLock::new(MemoryBackend::new(0)));
+        let balance_manager = Arc::new(BalanceManager::new(config.clone()));
+        let processor = Arc::new(
+            Processor::new(backend.clone(), balance_manager.clone(), config)
+                .with_query_backend(query_backend.clone()),
+        );
+        let mut responses = processor.subscribe_responses();
 
-        match result {
-            Ok(balance) => {
-                let response = json!({
-                    "action": "BALANCE_USD",
-                    "data": {
-                        "balance": balance
-                    }
-                });
-
-                let mut redis_manager = self.redis_manager.write().await;
-                redis_manager
-                    .publisher(&order_id, &response.to_string())
-                    .await?;
+        {
+            let mut query_backend = query_backend.write().await;
+            for i in 0..5000 {
+                let query =
+                    json!({ "action": "GET_BALANCE", "user": "u1", "orderId": format!("q{}", i) });
+                query_backend
+                    .add_to_stream("queries", &query.to_string())
+                    .await
+                    .unwrap();
             }
-            Err(e) => {
-                let response = json!({
-                    "action": "BALANCE_FAILED",
-                    "data": {
-                        "message": e
-                    }
-                });
+        }
+        let price = json!({
+            "action": "LATEST_PRICE",
+            "symbol": "BTC",
+            "buyPrice": "1000",
+            "sellPrice": "999",
+            "decimals": 4
+        });
+        backend
+            .write()
+            .await
+            .add_to_stream("orders", &price.to_string())
+            .await
+            .unwrap();
 
-                let mut redis_manager = self.redis_manager.write().await;
-                redis_manager
-                    .publisher(&order_id, &response.to_string())
-                    .await?;
+        processor.load_snapshot().await.unwrap();
+        let orders = {
+            let processor = processor.clone();
+            tokio::spawn(async move { processor.start_processing().await })
+        };
+        let queries = {
+            let processor = processor.clone();
+            tokio::spawn(async move { processor.start_query_processing().await })
+        };
+
+        let submitted = tokio::time::Instant::now();
+        let order = create_order_message("o1", "u1", "long", "100");
+        backend
+            .write()
+            .await
+            .add_to_stream("orders", &order.to_string())
+            .await
+            .unwrap();
+        let answered = async {
+            loop {
+                match responses.recv().await {
+                    Ok((channel, payload)) if channel == "o1" => return payload,
+                    // The flood's answers can outrun this receiver; only o1's matters
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(e) => panic!("response channel closed: {}", e),
+                }
             }
-        }
+        };
+        let payload = tokio::time::timeout(tokio::time::Duration::from_secs(2), answered)
+            .await
+            .expect("order was not answered");
+        let waited = submitted.elapsed();
+        orders.abort();
+        queries.abort();
 
-        Ok(())
+        let response: Value = serde_json::from_str(&payload).unwrap();
+        assert_eq!(response["action"], "ORDER_SUCCESS");
+        assert!(
+            waited < std::time::Duration::from_millis(500),
+            "order took {:?}",
+            waited
+        );
     }
 
-    async fn handle_get_balance(&self, data: &Value) -> Result<()> {
-        let user_id = self.get_string_field(data, "user")?;
-        let order_id = self.get_string_field(data, "orderId")?;
+    #[tokio::test]
+    async fn a_mutation_in_the_restored_ledger_is_skipped_but_acked_after_a_restart() {
+        let config = EngineConfig {
+            snapshot_enabled: true,
+            snapshot_path: temp_snapshot_path("ledger"),
+            ..test_config()
+        };
+        let mut before = Harness::new(config.clone());
+        before.push_price("BTC", "1000", "999").await;
+        before
+            .push(create_order_message("o1", "u1", "long", "100"))
+            .await;
+        before.start().await;
+        assert_eq!(before.response("o1").await["action"], "ORDER_SUCCESS");
+        before.processor.save_snapshot().await.unwrap();
 
-        // Ensure user exists
+        // The restored cursor only covers the price tick, so only the ledger knows o1 was applied
+        let path = snapshot_file(&config);
+        let mut snapshot = decode_snapshot(&fs::read(&path).await.unwrap()).unwrap();
+        let ids = {
+            let reply = before
+                .backend
+                .write()
+                .await
+                .read_stream("orders", "0")
+                .await
+                .unwrap();
+            reply.keys[0]
+                .ids
+                .iter()
+                .map(|entry| entry.id.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(snapshot["processed_ids"], json!([ids[1]]));
+        snapshot["last_processed_id"] = json!(ids[0]);
+        fs::write(&path, encode_snapshot(&snapshot, false).unwrap())
+            .await
+            .unwrap();
+
+        // Same stream, new process; the group had both entries delivered before the crash
+        let restarted_config = EngineConfig {
+            use_consumer_group: true,
+            consumer_group: "restarted".to_string(),
+            ..config.clone()
+        };
+        let mut after = Harness::with_backend(restarted_config.clone(), before.backend.clone());
+        drop(before);
         {
-            let balance_manager = self.balance_manager.read().await;
-            balance_manager.get_or_create_user(&user_id).await;
+            let mut backend = after.backend.write().await;
+            backend
+                .ensure_group("orders", "restarted", "0")
+                .await
+                .unwrap();
+            backend
+                .read_group("orders", "restarted", &restarted_config.consumer_name, ">")
+                .await
+                .unwrap();
         }
+        after.start().await;
+        let no_reapply = after.no_response("o1", 300).await;
+        let _ = fs::remove_file(&path).await;
 
-        let result = {
-            let balance_manager = self.balance_manager.read().await;
-            balance_manager.get_user_balance(&user_id).await
-        };
+        assert!(no_reapply);
+        assert_eq!(
+            after.balance_manager.get_user_balance_usd("u1").await,
+            Ok(dec("4900"))
+        );
+        let pending = after
+            .backend
+            .write()
+            .await
+            .read_group("orders", "restarted", &restarted_config.consumer_name, "0")
+            .await
+            .unwrap();
+        assert!(pending.keys.iter().all(|key| key.ids.is_empty()));
+    }
 
-        match result {
-            Ok(balances) => {
-                let mut response_data = json!({
-                    "action": "BALANCE"
-                });
+    #[tokio::test]
+    async fn get_price_returns_a_known_quote_and_not_available_otherwise() {
+        let mut harness = Harness::new(test_config());
+        harness.push_price("BTC", "1002", "998").await;
+        harness
+            .push(json!({ "action": "GET_PRICE", "symbol": "BTC", "orderId": "q1" }))
+            .await;
+        harness
+            .push(json!({ "action": "GET_PRICE", "symbol": "DOGE", "orderId": "q2" }))
+            .await;
+        harness.start().await;
 
-                for (asset, (balance, decimals)) in balances {
-                    response_data[&asset] = json!({
-                        "balance": balance,
-                        "decimals": decimals
-                    });
-                }
+        let known = harness.response("q1").await;
+        assert_eq!(known["action"], "PRICE");
+        let quote = &known["data"];
+        let field = |name: &str| dec(quote[name].as_str().unwrap());
+        assert_eq!(field("buyPrice"), dec("1002"));
+        assert_eq!(field("sellPrice"), dec("998"));
+        assert_eq!(field("mid"), dec("1000"));
+        assert_eq!(field("spread"), dec("4"));
+        assert_eq!(quote["decimals"], 4);
+        assert!(quote["lastUpdated"].as_i64().unwrap() > 0);
 
-                let mut redis_manager = self.redis_manager.write().await;
-                redis_manager
-                    .publisher(&order_id, &response_data.to_string())
-                    .await?;
-            }
-            Err(e) => {
-                let response = json!({
-                    "action": "BALANCE_FAILED",
-                    "data": {
-                        "message": e
-                    }
-                });
+        let unknown = harness.response("q2").await;
+        assert_eq!(unknown["action"], "PRICE_NOT_AVAILABLE");
+        assert_eq!(unknown["data"]["symbol"], "DOGE");
+    }
 
-                let mut redis_manager = self.redis_manager.write().await;
-                redis_manager
-                    .publisher(&order_id, &response.to_string())
-                    .await?;
-            }
+    #[tokio::test]
+    async fn a_large_limit_fills_over_two_ticks_into_one_position() {
+        let mut harness = Harness::new(EngineConfig {
+            max_fill_quantity: dec("1"),
+            ..test_config()
+        });
+        harness.push_price("BTC", "1010", "1010").await;
+        let mut limit = create_order_message("o1", "u1", "long", "100");
+        limit.as_object_mut().unwrap().remove("margin");
+        limit["quantity"] = json!("2");
+        limit["triggerPrice"] = json!("1000");
+        harness.push(limit).await;
+        harness.start().await;
+        assert_eq!(harness.response("o1").await["action"], "ORDER_PENDING");
+
+        harness.push_price("BTC", "1000", "1000").await;
+        let first = harness.response("o1").await;
+        assert_eq!(first["action"], "ORDER_PARTIALLY_FILLED");
+        assert_eq!(
+            dec(first["data"]["filledQuantity"].as_str().unwrap()),
+            dec("1")
+        );
+        assert_eq!(
+            dec(first["data"]["remainingQuantity"].as_str().unwrap()),
+            dec("1")
+        );
+
+        harness.push_price("BTC", "1000", "1000").await;
+        let second = harness.response("o1").await;
+        assert_eq!(second["action"], "ORDER_FILLED");
+        assert_eq!(
+            dec(second["data"]["filledQuantity"].as_str().unwrap()),
+            dec("2")
+        );
+        assert_eq!(
+            dec(second["data"]["remainingQuantity"].as_str().unwrap()),
+            dec("0")
+        );
+
+        let position = harness.balance_manager.get_order("o1").await.unwrap();
+        assert_eq!(position.quantity, dec("2"));
+        assert_eq!(position.margin, dec("200"));
+        assert!(
+            harness
+                .balance_manager
+                .pending_orders
+                .read()
+                .await
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn a_snapshot_of_only_the_orders_rebuilds_identical_derived_maps() {
+        let config = EngineConfig {
+            snapshot_enabled: true,
+            snapshot_path: temp_snapshot_path("derived"),
+            snapshot_derived_maps: false,
+            ..test_config()
+        };
+        let saved = Harness::new(config.clone());
+        let balance_manager = &saved.balance_manager;
+        balance_manager
+            .update_price(price("BTC", "1000", "999"))
+            .await
+            .unwrap();
+        for i in 0..20 {
+            let order_type = if i % 3 == 0 { "short" } else { "long" };
+            balance_manager
+                .create_order(order(
+                    &format!("o{:02}", i),
+                    &format!("u{}", i % 4),
+                    order_type,
+                    "100",
+                    2 + i % 7,
+                ))
+                .await
+                .unwrap();
         }
+        saved.processor.save_snapshot().await.unwrap();
 
-        Ok(())
+        let path = snapshot_file(&config);
+        let stored = decode_snapshot(&fs::read(&path).await.unwrap()).unwrap();
+        let restored = Harness::new(config.clone());
+        let loaded = restored.processor.load_snapshot().await;
+        let _ = fs::remove_file(&path).await;
+        loaded.unwrap();
+
+        assert!(stored.get("liquidation_map").is_none());
+        assert!(stored.get("orders_by_user").is_none());
+        let derived = |balance_manager: Arc<BalanceManager>| async move {
+            (
+                json!(&*balance_manager.liquidation_map.read().await),
+                json!(&*balance_manager.orders_by_user.read().await),
+            )
+        };
+        assert_eq!(
+            derived(restored.balance_manager.clone()).await,
+            derived(saved.balance_manager.clone()).await
+        );
     }
 
-    async fn handle_get_supported_assets(&self, data: &Value) -> Result<()> {
-        let order_id = self.get_string_field(data, "orderId")?;
-        let supported_assets = json!([
-            {
-                "symbol": "BTC",
-                "name": "Bitcoin",
-                "imageUrl": "https://example.com/btc.png"
-            },
-            {
-                "symbol": "ETH",
-                "name": "Ethereum",
-                "imageUrl": "https://example.com/eth.png"
+    #[tokio::test]
+    async fn every_notification_for_a_request_is_kept_in_its_response_list() {
+        let retained = Arc::new(std::sync::Mutex::new(HashMap::new()));
+        let backend: Arc<RwLock<dyn StreamBackend>> = Arc::new(RwLock::new(FlakyBackend {
+            inner: MemoryBackend::new(0),
+            failures: VecDeque::new(),
+            reads: Arc::new(std::sync::Mutex::new(Vec::new())),
+            write_failures: 0,
+            retained: retained.clone(),
+            clock_skew_ms: 0,
+        }));
+        let mut harness = Harness::with_backend(
+            EngineConfig {
+                max_fill_quantity: dec("1"),
+                response_log_cap: 10,
+                response_log_ttl_secs: 60,
+                ..test_config()
             },
-            {
-                "symbol": "SOL",
-                "name": "Solana",
-                "imageUrl": "https://example.com/sol.png"
-            }
-        ]);
+            backend,
+        );
+        harness.push_price("BTC", "1010", "1010").await;
+        let mut limit = create_order_message("o1", "u1", "long", "100");
+        limit.as_object_mut().unwrap().remove("margin");
+        limit["quantity"] = json!("2");
+        limit["triggerPrice"] = json!("1000");
+        harness.push(limit).await;
+        harness.push_price("BTC", "1000", "1000").await;
+        harness.push_price("BTC", "1000", "1000").await;
+        harness.start().await;
+        for expected in ["ORDER_PENDING", "ORDER_PARTIALLY_FILLED", "ORDER_FILLED"] {
+            assert_eq!(harness.response("o1").await["action"], expected);
+        }
 
-        let response = json!({
-            "action": "SUPPORTED_ASSETS",
-            "assets": supported_assets
+        let retained = retained.lock().unwrap();
+        let actions: Vec<Value> = retained["responses:o1"]
+            .iter()
+            .map(|payload| serde_json::from_str::<Value>(payload).unwrap()["action"].clone())
+            .collect();
+        assert_eq!(
+            actions,
+            vec![
+                json!("ORDER_PENDING"),
+                json!("ORDER_PARTIALLY_FILLED"),
+                json!("ORDER_FILLED")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn close_price_is_a_number_equal_to_the_settlement_price() {
+        let mut harness = Harness::new(test_config());
+        harness.push_price("BTC", "1000", "1000").await;
+        harness
+            .push(create_order_message("o1", "u1", "long", "100"))
+            .await;
+        harness.push_price("BTC", "1010", "1009.5").await;
+        harness
+            .push(json!({ "action": "CLOSE_ORDER", "orderId": "o1" }))
+            .await;
+        harness.start().await;
+        assert_eq!(harness.response("o1").await["action"], "ORDER_SUCCESS");
+
+        // Longs settle at the 1009.5 bid: 9.5 on quantity 1
+        let closed = harness.response("o1").await;
+        let data = &closed["data"];
+        let close_price: Decimal = data["closePrice"].as_str().unwrap().parse().unwrap();
+        assert_eq!(close_price, dec("1009.5"));
+        assert_eq!(dec(data["openPrice"].as_str().unwrap()), dec("1000"));
+        assert_eq!(dec(data["quantity"].as_str().unwrap()), dec("1"));
+        assert_eq!(dec(data["pnl"].as_str().unwrap()), dec("9.5"));
+        assert_eq!(data["message"], "Order closed at price 1009.5");
+
+        let records = wait_for_db_records(&harness, 2).await;
+        assert_eq!(records[1]["action"], "SAVE_CLOSED_ORDER");
+        assert_eq!(dec(records[1]["closePrice"].as_str().unwrap()), close_price);
+    }
+
+    #[tokio::test]
+    async fn margin_requirements_report_the_configured_settings() {
+        let mut harness = Harness::new(EngineConfig {
+            max_leverage: 50,
+            conservative_max_leverage: 20,
+            min_order_quantity: dec("0.01"),
+            max_order_quantity: dec("25"),
+            money_decimals: 2,
+            ..test_config()
         });
+        harness.push_price("BTC", "1000", "1000").await;
+        harness
+            .push(json!({ "action": "GET_MARGIN_REQUIREMENTS", "symbol": "BTC", "orderId": "q1" }))
+            .await;
+        harness
+            .push(json!({ "action": "GET_MARGIN_REQUIREMENTS", "orderId": "q2" }))
+            .await;
+        harness.start().await;
 
-        let mut redis_manager = self.redis_manager.write().await;
-        redis_manager
-            .publisher(&order_id, &response.to_string())
-            .await?;
+        let single = harness.response("q1").await;
+        assert_eq!(single["action"], "MARGIN_REQUIREMENTS");
+        let assets = single["assets"].as_array().unwrap();
+        assert_eq!(assets.len(), 1);
+        let btc = &assets[0];
+        assert_eq!(btc["symbol"], "BTC");
+        assert_eq!(btc["maxLeverage"], 50);
+        assert_eq!(btc["conservativeMaxLeverage"], 20);
+        assert_eq!(btc["maintenanceMarginPct"], "10");
+        assert_eq!(dec(btc["minOrderQuantity"].as_str().unwrap()), dec("0.01"));
+        assert_eq!(dec(btc["maxOrderQuantity"].as_str().unwrap()), dec("25"));
+        assert!(btc["maxFillQuantity"].is_null());
+        assert_eq!(btc["marginStep"], "0.01");
+        assert_eq!(btc["tickSize"], "0.0001");
 
-        Ok(())
+        let all = harness.response("q2").await;
+        let symbols: Vec<&str> = all["assets"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|asset| asset["symbol"].as_str().unwrap())
+            .collect();
+        assert_eq!(symbols, ["BTC", "ETH", "SOL"]);
     }
 
-    async fn handle_get_orders(&self, data: &Value) -> Result<()> {
-        let user_id = self.get_string_field(data, "user")?;
-        let order_id = self.get_string_field(data, "orderId")?;
+    #[tokio::test]
+    async fn close_at_price_settles_at_the_given_price_and_needs_the_admin_token() {
+        let mut harness = Harness::new(EngineConfig {
+            admin_token: "s3cret".to_string(),
+            ..test_config()
+        });
+        harness.push_price("BTC", "1000", "1000").await;
+        harness
+            .push(create_order_message("o1", "u1", "long", "100"))
+            .await;
+        harness
+            .push(json!({ "action": "CLOSE_AT_PRICE", "orderId": "o1", "price": "1050" }))
+            .await;
+        harness.start().await;
+        assert_eq!(harness.response("o1").await["action"], "ORDER_SUCCESS");
 
-        // Ensure user exists
-        {
-            let balance_manager = self.balance_manager.read().await;
-            balance_manager.get_or_create_user(&user_id).await;
-        }
+        let rejected = harness.response("o1").await;
+        assert_eq!(rejected["action"], "UNAUTHORIZED");
+        assert!(
+            harness
+                .balance_manager
+                .orders_by_id
+                .read()
+                .await
+                .contains_key("o1")
+        );
 
-        let orders = {
-            let balance_manager = self.balance_manager.read().await;
-            balance_manager.get_user_orders(&user_id).await
+        // 1 BTC bought at 1000 and settled at 1050 while the feed still says 1000
+        harness
+            .push(json!({
+                "action": "CLOSE_AT_PRICE",
+                "orderId": "o1",
+                "price": "1050",
+                "adminToken": "s3cret"
+            }))
+            .await;
+        let settled = harness.response("o1").await;
+        assert_eq!(settled["action"], "ORDER_SUCCESS");
+        assert_eq!(
+            dec(settled["data"]["closePrice"].as_str().unwrap()),
+            dec("1050")
+        );
+        assert_eq!(dec(settled["data"]["pnl"].as_str().unwrap()), dec("50"));
+        assert!(
+            !harness
+                .balance_manager
+                .orders_by_id
+                .read()
+                .await
+                .contains_key("o1")
+        );
+        assert_eq!(
+            harness.balance_manager.users.read().await["u1"].usd_balance,
+            dec("5050")
+        );
+    }
+
+    #[tokio::test]
+    async fn a_future_dated_snapshot_warns_or_aborts_per_config() {
+        let config = EngineConfig {
+            snapshot_enabled: true,
+            snapshot_path: temp_snapshot_path("future"),
+            snapshot_future_tolerance_secs: 60,
+            ..test_config()
         };
+        let saved = Harness::new(config.clone());
+        saved
+            .balance_manager
+            .update_price(price("BTC", "1000", "1000"))
+            .await
+            .unwrap();
+        saved
+            .balance_manager
+            .create_order(order("o1", "u1", "long", "100", 10))
+            .await
+            .unwrap();
+        saved.processor.save_snapshot().await.unwrap();
 
-        let response = json!({
-            "action": "ORDERS",
-            "orders": orders
+        let path = snapshot_file(&config);
+        let mut snapshot = decode_snapshot(&fs::read(&path).await.unwrap()).unwrap();
+        snapshot["timestamp"] = json!(chrono::Utc::now().timestamp() + 3600);
+        fs::write(&path, encode_snapshot(&snapshot, false).unwrap())
+            .await
+            .unwrap();
+
+        let warned = Harness::new(config.clone());
+        let warned_result = warned.processor.load_snapshot().await;
+        let aborted = Harness::new(EngineConfig {
+            snapshot_future_abort: true,
+            ..config.clone()
         });
+        let aborted_result = aborted.processor.load_snapshot().await;
+        let _ = fs::remove_file(&path).await;
 
-        let mut redis_manager = self.redis_manager.write().await;
-        redis_manager
-            .publisher(&order_id, &response.to_string())
-            .await?;
+        warned_result.unwrap();
+        assert!(
+            warned
+                .balance_manager
+                .orders_by_id
+                .read()
+                .await
+                .contains_key("o1")
+        );
+        let error = aborted_result.unwrap_err().to_string();
+        assert!(error.contains("in the future"), "{}", error);
+        assert!(aborted.balance_manager.orders_by_id.read().await.is_empty());
+    }
 
-        Ok(())
+    #[tokio::test]
+    async fn price_updates_are_published_only_while_subscribed() {
+        let mut harness = Harness::new(test_config());
+        harness.push_price("BTC", "1000", "1000").await;
+        harness
+            .push(json!({ "action": "SUBSCRIBE", "channel": "prices:BTC", "orderId": "s1" }))
+            .await;
+        harness.start().await;
+
+        let subscribed = harness.response("s1").await;
+        assert_eq!(subscribed["action"], "SUBSCRIBED");
+        assert_eq!(subscribed["data"]["channel"], "prices:BTC");
+        assert_eq!(subscribed["data"]["subscribers"], 1);
+
+        harness.push_price("BTC", "1010", "1010").await;
+        let update = harness.response("prices:BTC").await;
+        assert_eq!(update["action"], "PRICE_UPDATE");
+        assert_eq!(
+            dec(update["data"]["buyPrice"].as_str().unwrap()),
+            dec("1010")
+        );
+
+        harness
+            .push(json!({ "action": "UNSUBSCRIBE", "channel": "prices:BTC", "orderId": "s2" }))
+            .await;
+        let unsubscribed = harness.response("s2").await;
+        assert_eq!(unsubscribed["action"], "UNSUBSCRIBED");
+        assert_eq!(unsubscribed["data"]["subscribers"], 0);
+
+        harness.push_price("BTC", "1020", "1020").await;
+        assert!(harness.no_response("prices:BTC", 200).await);
     }
 
-    fn get_string_field(&self, data: &Value, field: &str) -> Result<String> {
-        data.get(field)
-            .and_then(|v| v.as_str())
-            .map(|s| s.to_string())
-            .ok_or_else(|| anyhow::anyhow!("Missing or invalid field: {}", field))
+    #[tokio::test]
+    async fn overflowed_db_records_spill_to_disk_and_drain_in_order() {
+        let spill_path = temp_snapshot_path("db-spill");
+        let _ = fs::remove_file(&spill_path).await;
+        // Every write fails until the fifth record has been enqueued
+        let backend: Arc<RwLock<dyn StreamBackend>> = Arc::new(RwLock::new(FlakyBackend {
+            inner: MemoryBackend::new(0),
+            failures: VecDeque::new(),
+            reads: Default::default(),
+            write_failures: 5,
+            retained: Default::default(),
+            clock_skew_ms: 0,
+        }));
+        let harness = Harness::with_backend(
+            EngineConfig {
+                db_retry_capacity: 2,
+                db_spill_path: spill_path.clone(),
+                ..test_config()
+            },
+            backend,
+        );
+        for seq in 0..5 {
+            harness
+                .processor
+                .enqueue_db_record(&json!({ "seq": seq }))
+                .await;
+        }
+
+        let spilled = fs::read_to_string(&spill_path).await.unwrap();
+        let spilled: Vec<Value> = spilled
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            spilled,
+            [json!({"seq": 0}), json!({"seq": 1}), json!({"seq": 2})]
+        );
+        assert_eq!(
+            harness
+                .processor
+                .pending_db_records
+                .read()
+                .await
+                .records
+                .len(),
+            2
+        );
+        assert!(db_queue_records(&harness).await.is_empty());
+
+        harness.processor.flush_db_records().await;
+        let drained: Vec<Value> = db_queue_records(&harness)
+            .await
+            .into_iter()
+            .map(|record| record["seq"].clone())
+            .collect();
+        assert_eq!(drained, [0, 1, 2, 3, 4]);
+        assert!(!std::path::Path::new(&spill_path).exists());
+        let pending = harness.processor.pending_db_records.read().await;
+        assert!(pending.records.is_empty() && !pending.spilled);
     }
 
-    fn get_decimal_field(&self, data: &Value, field: &str) -> Result<Decimal> {
-        let val = data
-            .get(field)
-            .ok_or_else(|| anyhow::anyhow!("Missing or invalid field: {}", field))?;
+    #[tokio::test]
+    async fn readiness_waits_for_every_warmup_asset_or_the_timeout() {
+        let config = EngineConfig {
+            warmup_assets: "BTC, ETH".to_string(),
+            warmup_timeout_secs: 0,
+            ..test_config()
+        };
+        let harness = Harness::new(config.clone());
+        harness.processor.load_snapshot().await.unwrap();
+        assert!(!harness.processor.refresh_readiness().await);
+        harness
+            .balance_manager
+            .update_price(price("BTC", "1000", "1000"))
+            .await
+            .unwrap();
+        assert!(!harness.processor.refresh_readiness().await);
+        harness
+            .balance_manager
+            .update_price(price("ETH", "100", "100"))
+            .await
+            .unwrap();
+        assert!(harness.processor.refresh_readiness().await);
 
-        if let Some(s) = val.as_str() {
-            Decimal::from_str(s)
-                .map_err(|e| anyhow::anyhow!("Invalid decimal string for field {}: {}", field, e))
-        } else if val.is_number() {
-            Decimal::from_str(&val.to_string())
-                .map_err(|e| anyhow::anyhow!("Invalid decimal number for field {}: {}", field, e))
-        } else {
-            Err(anyhow::anyhow!(
-                "Invalid type for field {}: expected string or number",
-                field
-            ))
+        // ETH never arrives, so only the timeout makes it ready
+        let harness = Harness::new(EngineConfig {
+            warmup_timeout_secs: 1,
+            ..config
+        });
+        harness.processor.load_snapshot().await.unwrap();
+        harness
+            .balance_manager
+            .update_price(price("BTC", "1000", "1000"))
+            .await
+            .unwrap();
+        assert!(!harness.processor.refresh_readiness().await);
+        tokio::time::sleep(tokio::time::Duration::from_millis(1100)).await;
+        assert!(harness.processor.refresh_readiness().await);
+    }
+
+    #[tokio::test]
+    async fn get_orders_filters_by_tag_and_untagged_orders_only_show_unfiltered() {
+        let mut harness = Harness::new(test_config());
+        harness.push_price("BTC", "1000", "1000").await;
+        for (order_id, tag) in [("o1", Some("swing")), ("o2", Some("scalp")), ("o3", None)] {
+            let mut create = create_order_message(order_id, "u1", "long", "100");
+            if let Some(tag) = tag {
+                create["tag"] = json!(tag);
+            }
+            harness.push(create).await;
+        }
+        for (query_id, tag) in [("q1", Some("swing")), ("q2", None), ("q3", Some("hold"))] {
+            let mut query = json!({ "action": "GET_ORDERS", "user": "u1", "orderId": query_id });
+            if let Some(tag) = tag {
+                query["tag"] = json!(tag);
+            }
+            harness.push(query).await;
+        }
+        harness.start().await;
+
+        let mut listed = Vec::new();
+        for query_id in ["q1", "q2", "q3"] {
+            let response = harness.response(query_id).await;
+            assert_eq!(response["action"], "ORDERS");
+            let mut order_ids: Vec<String> = response["orders"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|order| order["order_id"].as_str().unwrap().to_string())
+                .collect();
+            order_ids.sort();
+            listed.push(order_ids);
         }
+        assert_eq!(listed[0], ["o1"]);
+        assert_eq!(listed[1], ["o1", "o2", "o3"]);
+        assert!(listed[2].is_empty());
     }
 
-    fn get_u32_field(&self, data: &Value, field: &str) -> Result<u32> {
-        let val = data
-            .get(field)
-            .ok_or_else(|| anyhow::anyhow!("Missing or invalid field: {}", field))?;
+    #[tokio::test]
+    async fn a_corrupt_latest_snapshot_falls_back_to_the_previous_one() {
+        let config = EngineConfig {
+            snapshot_enabled: true,
+            snapshot_path: temp_snapshot_path("rotation"),
+            snapshot_retention: 3,
+            ..test_config()
+        };
+        let saved = Harness::new(config.clone());
+        saved
+            .balance_manager
+            .update_price(price("BTC", "1000", "1000"))
+            .await
+            .unwrap();
+        saved
+            .balance_manager
+            .create_order(order("o1", "u1", "long", "100", 10))
+            .await
+            .unwrap();
+        saved.processor.save_snapshot().await.unwrap();
+        saved
+            .balance_manager
+            .create_order(order("o2", "u1", "long", "100", 10))
+            .await
+            .unwrap();
+        saved.processor.save_snapshot().await.unwrap();
 
-        if let Some(s) = val.as_str() {
-            s.parse::<u32>()
-                .map_err(|e| anyhow::anyhow!("Invalid u32 string for field {}: {}", field, e))
-        } else if let Some(n) = val.as_u64() {
-            Ok(n as u32)
-        } else {
-            Err(anyhow::anyhow!(
-                "Invalid type for field {}: expected string or number",
-                field
-            ))
+        let latest = snapshot_file(&config);
+        fs::write(&latest, b"{\"users\": {").await.unwrap();
+
+        let restored = Harness::new(config.clone());
+        let loaded = restored.processor.load_snapshot().await;
+        for generation in 0..config.snapshot_retention {
+            let _ = fs::remove_file(rotated_snapshot_file(&latest, generation)).await;
         }
+        loaded.unwrap();
+
+        let orders_by_id = restored.balance_manager.orders_by_id.read().await;
+        assert!(orders_by_id.contains_key("o1"));
+        assert!(!orders_by_id.contains_key("o2"));
     }
 
-    fn get_i64_field(&self, data: &Value, field: &str) -> Result<i64> {
-        data.get(field)
-            .and_then(|v| v.as_i64())
-            .ok_or_else(|| anyhow::anyhow!("Missing or invalid field: {}", field))
+    #[tokio::test]
+    async fn a_skewed_backend_clock_is_flagged_past_the_tolerance() {
+        let skewed = |clock_skew_ms: i64| {
+            let backend: Arc<RwLock<dyn StreamBackend>> = Arc::new(RwLock::new(FlakyBackend {
+                inner: MemoryBackend::new(0),
+                failures: VecDeque::new(),
+                reads: Default::default(),
+                write_failures: 0,
+                retained: Default::default(),
+                clock_skew_ms,
+            }));
+            Harness::with_backend(
+                EngineConfig {
+                    clock_drift_tolerance_ms: 1000,
+                    ..test_config()
+                },
+                backend,
+            )
+        };
+
+        let ahead = skewed(5000);
+        let drift = ahead.processor.check_clock_drift().await.unwrap();
+        assert!((4900..=5100).contains(&drift), "{}", drift);
+        let health = ahead.processor.health().await;
+        assert_eq!(health["clockDriftExceeded"], true);
+
+        let behind = skewed(-5000);
+        behind.processor.check_clock_drift().await.unwrap();
+        assert_eq!(behind.processor.health().await["clockDriftExceeded"], true);
+
+        let in_sync = skewed(200);
+        in_sync.processor.check_clock_drift().await.unwrap();
+        assert_eq!(
+            in_sync.processor.health().await["clockDriftExceeded"],
+            false
+        );
+    }
+
+    #[tokio::test]
+    async fn a_burst_of_ticks_is_coalesced_into_the_first_and_the_last() {
+        let mut applied = Vec::new();
+        for price_coalesce_ms in [0, 200] {
+            let harness = Harness::new(EngineConfig {
+                price_coalesce_ms,
+                ..test_config()
+            });
+            for tick in 0..100 {
+                let buy = (1000 + tick).to_string();
+                harness
+                    .processor
+                    .process_message(&format!("{}-0", tick + 1), price_message(&buy, &buy))
+                    .await
+                    .unwrap();
+            }
+            tokio::time::sleep(tokio::time::Duration::from_millis(250)).await;
+            harness.processor.flush_held_prices().await;
+
+            let history = harness.balance_manager.get_price_history("BTC").await;
+            let latest = harness.balance_manager.get_price("BTC").await.unwrap();
+            assert_eq!(latest.buy_price, dec("1099"));
+            applied.push(history.len());
+        }
+
+        // Every tick without coalescing; with it the burst's first tick, then only its last
+        assert_eq!(applied, [100, 2]);
     }
 }
@@ -2,38 +2,180 @@ use anyhow::{Ok, Result};
 use redis::{
     AsyncCommands, Client,
     aio::MultiplexedConnection,
-    streams::{StreamReadOptions, StreamReadReply},
+    streams::{StreamMaxlen, StreamReadOptions, StreamReadReply},
 };
 
+use crate::stream_backend::{BackendFuture, CAPPED_STREAM, StreamBackend};
+
 pub struct RedisManager {
     pub connection: MultiplexedConnection,
+    // Approximate cap applied on XADD to CAPPED_STREAM; 0 leaves it untrimmed
+    max_len: usize,
 }
 
 impl RedisManager {
-    pub async fn new() -> Result<Self> {
+    pub async fn new(max_len: usize) -> Result<Self> {
         let client = Client::open("redis://127.0.0.1/")?;
         let connection = client.get_multiplexed_async_connection().await?;
-        Ok(Self { connection })
+        Ok(Self {
+            connection,
+            max_len,
+        })
+    }
+}
+
+impl StreamBackend for RedisManager {
+    fn ping(&mut self) -> BackendFuture<'_, ()> {
+        Box::pin(async move {
+            let _: String = redis::cmd("PING").query_async(&mut self.connection).await?;
+            Ok(())
+        })
+    }
+
+    fn server_time_ms(&mut self) -> BackendFuture<'_, i64> {
+        Box::pin(async move {
+            // TIME replies with [unix seconds, microseconds within the second]
+            let (secs, micros): (i64, i64) =
+                redis::cmd("TIME").query_async(&mut self.connection).await?;
+            Ok(secs * 1000 + micros / 1000)
+        })
+    }
+
+    fn read_stream<'a>(
+        &'a mut self,
+        stream: &'a str,
+        last_id: &'a str,
+    ) -> BackendFuture<'a, StreamReadReply> {
+        Box::pin(async move {
+            let opts = StreamReadOptions::default().block(1000).count(10);
+
+            let reply: StreamReadReply = self
+                .connection
+                .xread_options(&[stream], &[last_id], &opts)
+                .await?;
+
+            Ok(reply)
+        })
     }
 
-    pub async fn read_stream(&mut self, stream: &str, last_id: &str) -> Result<StreamReadReply> {
-        let opts = StreamReadOptions::default().block(1000).count(10);
+    fn ensure_group<'a>(
+        &'a mut self,
+        stream: &'a str,
+        group: &'a str,
+        start_id: &'a str,
+    ) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            let result: redis::RedisResult<()> = self
+                .connection
+                .xgroup_create_mkstream(stream, group, start_id)
+                .await;
 
-        let reply: StreamReadReply = self
-            .connection
-            .xread_options(&[stream], &[last_id], &opts)
-            .await?;
+            match result {
+                // Group already exists
+                Err(e) if e.code() == Some("BUSYGROUP") => Ok(()),
+                other => Ok(other?),
+            }
+        })
+    }
+
+    fn read_group<'a>(
+        &'a mut self,
+        stream: &'a str,
+        group: &'a str,
+        consumer: &'a str,
+        id: &'a str,
+    ) -> BackendFuture<'a, StreamReadReply> {
+        Box::pin(async move {
+            let opts = StreamReadOptions::default()
+                .group(group, consumer)
+                .block(1000)
+                .count(10);
+
+            let reply: StreamReadReply = self
+                .connection
+                .xread_options(&[stream], &[id], &opts)
+                .await?;
+
+            Ok(reply)
+        })
+    }
+
+    fn ack<'a>(
+        &'a mut self,
+        stream: &'a str,
+        group: &'a str,
+        id: &'a str,
+    ) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            let _: i64 = self.connection.xack(stream, group, &[id]).await?;
+            Ok(())
+        })
+    }
+
+    fn ensure_stream<'a>(&'a mut self, stream: &'a str) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            let exists: bool = self.connection.exists(stream).await?;
+            if !exists {
+                // Redis has no bare stream create; add and delete a placeholder entry
+                let id: String = self.connection.xadd(stream, "*", &[("init", "1")]).await?;
+                let _: i64 = self.connection.xdel(stream, &[id]).await?;
+            } else if self.max_len > 0 && stream == CAPPED_STREAM {
+                // Bring streams that grew before the cap was set back in line
+                let _: i64 = self
+                    .connection
+                    .xtrim(stream, StreamMaxlen::Approx(self.max_len))
+                    .await?;
+            }
+            Ok(())
+        })
+    }
 
-        Ok(reply)
+    // Trimming is approximate (MAXLEN ~), so Redis may keep somewhat more than the cap. Only the
+    // orders stream is capped; db_queue keeps everything until the consumer takes it.
+    fn add_to_stream<'a>(&'a mut self, stream: &'a str, data: &'a str) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            let _: String = if self.max_len > 0 && stream == CAPPED_STREAM {
+                self.connection
+                    .xadd_maxlen(
+                        stream,
+                        StreamMaxlen::Approx(self.max_len),
+                        "*",
+                        &[("data", data)],
+                    )
+                    .await?
+            } else {
+                self.connection.xadd(stream, "*", &[("data", data)]).await?
+            };
+            Ok(())
+        })
     }
 
-    pub async fn add_to_stream(&mut self, stream: &str, data: &str) -> Result<()> {
-        let _: String = self.connection.xadd(stream, "*", &[("data", data)]).await?;
-        Ok(())
+    fn publisher<'a>(&'a mut self, channel: &'a str, message: &'a str) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            let _: i64 = self.connection.publish(channel, message).await?;
+            Ok(())
+        })
     }
 
-    pub async fn publisher(&mut self, channel: &str, message: &str) -> Result<()> {
-        let _: i64 = self.connection.publish(channel, message).await?;
-        Ok(())
+    fn retain_response<'a>(
+        &'a mut self,
+        key: &'a str,
+        message: &'a str,
+        cap: usize,
+        ttl_secs: u64,
+    ) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            let _: () = redis::pipe()
+                .atomic()
+                .rpush(key, message)
+                .ignore()
+                .ltrim(key, -(cap as isize), -1)
+                .ignore()
+                .expire(key, ttl_secs as i64)
+                .ignore()
+                .query_async(&mut self.connection)
+                .await?;
+            Ok(())
+        })
     }
 }
@@ -0,0 +1,361 @@
+//replay.rs
+use anyhow::Result;
+use rust_decimal::Decimal;
+use serde_json::Value;
+use std::str::FromStr;
+use tokio::fs;
+use tracing::{info, warn};
+
+use crate::balance_manager::{BalanceManager, Order};
+use crate::config::EngineConfig;
+use crate::processor::{encode_snapshot, snapshot_value};
+
+// Rebuilds engine state offline from a db_queue event log (one JSON record per line)
+// and writes it out in the snapshot.json format
+pub async fn run(events_path: &str, output_path: &str, config: EngineConfig) -> Result<()> {
+    let content = fs::read_to_string(events_path).await?;
+    let balance_manager = BalanceManager::new(config);
+
+    let mut applied = 0;
+    for (line_number, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let event: Value = serde_json::from_str(line)
+            .map_err(|e| anyhow::anyhow!("Invalid event on line {}: {}", line_number + 1, e))?;
+
+        if apply_event(&balance_manager, &event).await? {
+            applied += 1;
+        }
+    }
+
+    let snapshot = snapshot_value(&balance_manager, "$").await;
+    fs::write(
+        output_path,
+        encode_snapshot(&snapshot, output_path.ends_with(".gz"))?,
+    )
+    .await?;
+
+    info!("Replayed {} events into {}", applied, output_path);
+    Ok(())
+}
+
+// Applies one db_queue record; returns false for records that carry no state change
+pub async fn apply_event(balance_manager: &BalanceManager, event: &Value) -> Result<bool> {
+    let action = event
+        .get("action")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| anyhow::anyhow!("Missing action"))?;
+
+    match action {
+        "SAVE_OPENED_ORDER" => {
+            let order: Order = serde_json::from_value(
+                event
+                    .get("order")
+                    .cloned()
+                    .ok_or_else(|| anyhow::anyhow!("Missing order"))?,
+            )?;
+
+            // Partial fills re-record the whole position and carry what that slice cost
+            let margin_charged = event
+                .get("marginCharged")
+                .and_then(decimal_value)
+                .unwrap_or(order.margin);
+
+            balance_manager.get_or_create_user(&order.user_id).await;
+            {
+                let mut users = balance_manager.users.write().await;
+                if let Some(user_balance) = users.get_mut(&order.user_id) {
+                    user_balance.usd_balance -= margin_charged;
+                }
+            }
+            balance_manager.remove_order(&order.order_id).await;
+            balance_manager.restore_order(order).await;
+            Ok(true)
+        }
+        "SAVE_CLOSED_ORDER" => {
+            let order_id = string_field(event, "orderId")?;
+            let Some(settled_amount) = event.get("settledAmount").and_then(decimal_value) else {
+                warn!("Closed order {} has no settledAmount, skipping", order_id);
+                return Ok(false);
+            };
+
+            let Some(order) = balance_manager.remove_order(&order_id).await else {
+                warn!("Closed order {} was never opened, skipping", order_id);
+                return Ok(false);
+            };
+
+            {
+                let mut users = balance_manager.users.write().await;
+                if let Some(user_balance) = users.get_mut(&order.user_id) {
+                    user_balance.usd_balance += settled_amount;
+                }
+            }
+
+            // Partial closes carry what is left of the position
+            if let Some(remaining) = event.get("remainingOrder").cloned() {
+                let remaining: Order = serde_json::from_value(remaining)?;
+                balance_manager.restore_order(remaining).await;
+            }
+            Ok(true)
+        }
+        "SAVE_MARGIN_ADJUSTMENT" => {
+            let order_id = string_field(event, "orderId")?;
+            let amount = event
+                .get("amount")
+                .and_then(decimal_value)
+                .ok_or_else(|| anyhow::anyhow!("Missing or invalid field: amount"))?;
+
+            let Some(mut order) = balance_manager.remove_order(&order_id).await else {
+                warn!("Margin adjustment for unknown order {}, skipping", order_id);
+                return Ok(false);
+            };
+            order.extra_margin += amount;
+
+            {
+                let mut users = balance_manager.users.write().await;
+                if let Some(user_balance) = users.get_mut(&order.user_id) {
+                    user_balance.usd_balance -= amount;
+                }
+            }
+            balance_manager.restore_order(order).await;
+            Ok(true)
+        }
+        "SAVE_ACCOUNT_RESET" => {
+            let user_id = string_field(event, "user")?;
+            balance_manager.reset_account(&user_id).await;
+            Ok(true)
+        }
+        "SAVE_SPOT_TRADE" => {
+            let user_id = string_field(event, "user")?;
+            let asset = string_field(event, "asset")?;
+            let side = string_field(event, "side")?;
+            let [quantity, price, fee] = ["quantity", "price", "fee"].map(|field| {
+                event
+                    .get(field)
+                    .and_then(decimal_value)
+                    .ok_or_else(|| anyhow::anyhow!("Missing or invalid field: {}", field))
+            });
+            let (quantity, price, fee) = (quantity?, price?, fee?);
+            let decimals = event.get("decimals").and_then(|v| v.as_u64()).unwrap_or(0) as u32;
+
+            balance_manager.get_or_create_user(&user_id).await;
+            let mut users = balance_manager.users.write().await;
+            if let Some(user_balance) = users.get_mut(&user_id) {
+                let value = balance_manager.round_money(quantity * price);
+                let holding = user_balance
+                    .asset_balances
+                    .entry(asset)
+                    .or_insert((Decimal::ZERO, decimals));
+                if side == "buy" {
+                    user_balance.usd_balance -= value + fee;
+                    holding.0 += quantity;
+                } else {
+                    user_balance.usd_balance += value - fee;
+                    holding.0 -= quantity;
+                }
+            }
+            Ok(true)
+        }
+        "SAVE_LIQUIDATED_ORDER" => {
+            // Whatever the liquidation fee left of the collateral was credited back; older
+            // records have no settledAmount because the margin was forfeited outright
+            let order_id = string_field(event, "orderId")?;
+            let Some(order) = balance_manager.remove_order(&order_id).await else {
+                return Ok(false);
+            };
+            let settled_amount = event
+                .get("settledAmount")
+                .and_then(decimal_value)
+                .unwrap_or(Decimal::ZERO);
+            if settled_amount > Decimal::ZERO {
+                let mut users = balance_manager.users.write().await;
+                if let Some(user_balance) = users.get_mut(&order.user_id) {
+                    user_balance.usd_balance += settled_amount;
+                }
+            }
+            Ok(true)
+        }
+        "SAVE_FUNDING" => {
+            // Net per-user payments: negative for the side charged, positive for the side paid
+            let payments = event
+                .get("payments")
+                .and_then(|v| v.as_object())
+                .ok_or_else(|| anyhow::anyhow!("Missing or invalid field: payments"))?;
+            for (user_id, amount) in payments {
+                let amount = decimal_value(amount).ok_or_else(|| {
+                    anyhow::anyhow!("Invalid funding payment for {}: {}", user_id, amount)
+                })?;
+                balance_manager.get_or_create_user(user_id).await;
+                let mut users = balance_manager.users.write().await;
+                if let Some(user_balance) = users.get_mut(user_id) {
+                    user_balance.usd_balance += amount;
+                }
+            }
+            Ok(!payments.is_empty())
+        }
+        "SAVE_LIQUIDATIONS" => {
+            // One liquidation scan's records, batched; applies each as if it came alone
+            let records = event
+                .get("records")
+                .and_then(|v| v.as_array())
+                .ok_or_else(|| anyhow::anyhow!("Missing or invalid field: records"))?;
+            let mut applied = false;
+            for record in records {
+                applied |= Box::pin(apply_event(balance_manager, record)).await?;
+            }
+            Ok(applied)
+        }
+        _ => Ok(false),
+    }
+}
+
+fn string_field(event: &Value, field: &str) -> Result<String> {
+    event
+        .get(field)
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string())
+        .ok_or_else(|| anyhow::anyhow!("Missing or invalid field: {}", field))
+}
+
+fn decimal_value(value: &Value) -> Option<Decimal> {
+    match value {
+        Value::String(s) => Decimal::from_str(s).ok(),
+        Value::Number(n) => Decimal::from_str(&n.to_string()).ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::Processor;
+    use crate::stream_backend::{MemoryBackend, StreamBackend};
+    use crate::test_support::*;
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+
+    // Three opens, a close and a spot buy across two users
+    const EVENTS: &str = r#"
+{"action":"SAVE_OPENED_ORDER","order":{"order_id":"o1","user_id":"u1","asset":"BTC","order_type":"long","margin":"100","leverage":10,"open_price":"1000","quantity":"1","timestamp":1700000000}}
+{"action":"SAVE_OPENED_ORDER","order":{"order_id":"o2","user_id":"u1","asset":"BTC","order_type":"short","margin":"50","leverage":5,"open_price":"1000","quantity":"0.25","timestamp":1700000001}}
+{"action":"SAVE_OPENED_ORDER","order":{"order_id":"o3","user_id":"u2","asset":"BTC","order_type":"long","margin":"200","leverage":2,"open_price":"1000","quantity":"0.4","timestamp":1700000002}}
+{"action":"SAVE_CLOSED_ORDER","orderId":"o1","settledAmount":"150"}
+{"action":"SAVE_SPOT_TRADE","user":"u2","asset":"BTC","side":"buy","quantity":"0.1","price":"1000","fee":"0.1","decimals":4}
+"#;
+
+    #[tokio::test]
+    async fn replaying_an_event_log_rebuilds_balances_and_open_orders() {
+        let events_path = temp_snapshot_path("replay-events");
+        let output_path = temp_snapshot_path("replay-output");
+        fs::write(&events_path, EVENTS).await.unwrap();
+        let replayed = run(&events_path, &output_path, test_config()).await;
+
+        let config = EngineConfig {
+            snapshot_enabled: true,
+            snapshot_path: output_path.clone(),
+            ..test_config()
+        };
+        let balance_manager = Arc::new(BalanceManager::new(config.clone()));
+        let backend: Arc<RwLock<dyn StreamBackend>> = Arc::new(RwLock::new(MemoryBackend::new(0)));
+        let loaded = Processor::new(backend, balance_manager.clone(), config)
+            .load_snapshot()
+            .await;
+        let _ = fs::remove_file(&events_path).await;
+        let _ = fs::remove_file(&output_path).await;
+        replayed.unwrap();
+        loaded.unwrap();
+
+        let users = balance_manager.users.read().await;
+        assert_eq!(users["u1"].usd_balance, dec("5000"));
+        assert_eq!(users["u2"].usd_balance, dec("4699.9"));
+        assert_eq!(users["u2"].asset_balances["BTC"], (dec("0.1"), 4));
+        drop(users);
+
+        let open = |user_id| {
+            let balance_manager = balance_manager.clone();
+            async move {
+                let mut ids: Vec<_> = balance_manager
+                    .get_user_orders(user_id)
+                    .await
+                    .into_iter()
+                    .map(|order| order.order_id)
+                    .collect();
+                ids.sort();
+                ids
+            }
+        };
+        assert_eq!(open("u1").await, ["o2"]);
+        assert_eq!(open("u2").await, ["o3"]);
+        assert_eq!(
+            balance_manager.get_order("o3").await.unwrap().quantity,
+            dec("0.4")
+        );
+    }
+
+    async fn db_queue_events(harness: &Harness) -> Vec<Value> {
+        let reply = harness
+            .backend
+            .write()
+            .await
+            .read_stream("db_queue", "0")
+            .await
+            .unwrap();
+        reply
+            .keys
+            .into_iter()
+            .flat_map(|key| key.ids)
+            .map(|entry| match entry.map.get("data") {
+                Some(redis::Value::Data(bytes)) => serde_json::from_slice(bytes).unwrap(),
+                other => panic!("unexpected data field {:?}", other),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn replaying_the_recorded_funding_reproduces_the_balances() {
+        let mut harness = Harness::new(EngineConfig {
+            funding_sensitivity: dec("0.01"),
+            funding_max_rate: dec("0.001"),
+            ..test_config()
+        });
+        harness.push_price("BTC", "1000", "1000").await;
+        for (order_id, user_id, order_type, margin) in [
+            ("l1", "u1", "long", "400"),
+            ("l2", "u2", "long", "100"),
+            ("s1", "u3", "short", "100"),
+        ] {
+            harness
+                .push(create_order_message(order_id, user_id, order_type, margin))
+                .await;
+        }
+        harness.start().await;
+        for order_id in ["l1", "l2", "s1"] {
+            assert_eq!(harness.response(order_id).await["action"], "ORDER_SUCCESS");
+        }
+        // Each open's record is written just after its response
+        for _ in 0..100 {
+            if db_queue_events(&harness).await.len() == 3 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        harness.processor.apply_funding().await;
+
+        let events = db_queue_events(&harness).await;
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[3]["action"], "SAVE_FUNDING");
+        let replayed = BalanceManager::new(test_config());
+        for event in &events {
+            assert!(apply_event(&replayed, event).await.unwrap());
+        }
+
+        let live = harness.balance_manager.users.read().await;
+        let replayed = replayed.users.read().await;
+        for user_id in ["u1", "u2", "u3"] {
+            assert_eq!(replayed[user_id].usd_balance, live[user_id].usd_balance);
+        }
+        assert_eq!(live["u3"].usd_balance, dec("4905"));
+    }
+}
@@ -0,0 +1,337 @@
+//stream_backend.rs
+use anyhow::Result;
+use redis::Value as RedisValue;
+use redis::streams::{StreamId, StreamKey, StreamReadReply};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+use crate::processor::parse_stream_id;
+
+pub type BackendFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T>> + Send + 'a>>;
+
+// The command stream max_len applies to. db_queue is never trimmed: its records may not have
+// reached the database yet.
+pub const CAPPED_STREAM: &str = "orders";
+
+// The stream and pub/sub operations the processor needs. RedisManager is the production
+// implementation; MemoryBackend keeps everything in process for running without Redis.
+// KafkaBackend feeds the orders stream from a Kafka topic and hands everything else to one of these.
+pub trait StreamBackend: Send + Sync {
+    fn ping(&mut self) -> BackendFuture<'_, ()>;
+
+    // The backend's own clock in ms since the epoch; Redis stamps stream ids with it
+    fn server_time_ms(&mut self) -> BackendFuture<'_, i64>;
+
+    fn read_stream<'a>(
+        &'a mut self,
+        stream: &'a str,
+        last_id: &'a str,
+    ) -> BackendFuture<'a, StreamReadReply>;
+
+    fn ensure_group<'a>(
+        &'a mut self,
+        stream: &'a str,
+        group: &'a str,
+        start_id: &'a str,
+    ) -> BackendFuture<'a, ()>;
+
+    // Use ">" for new entries or "0" for entries already delivered to this consumer but not acked
+    fn read_group<'a>(
+        &'a mut self,
+        stream: &'a str,
+        group: &'a str,
+        consumer: &'a str,
+        id: &'a str,
+    ) -> BackendFuture<'a, StreamReadReply>;
+
+    fn ack<'a>(&'a mut self, stream: &'a str, group: &'a str, id: &'a str)
+    -> BackendFuture<'a, ()>;
+
+    // Creates the stream if it's missing and trims it to the configured cap
+    fn ensure_stream<'a>(&'a mut self, stream: &'a str) -> BackendFuture<'a, ()>;
+
+    fn add_to_stream<'a>(&'a mut self, stream: &'a str, data: &'a str) -> BackendFuture<'a, ()>;
+
+    fn publisher<'a>(&'a mut self, channel: &'a str, message: &'a str) -> BackendFuture<'a, ()>;
+
+    // Appends to a capped list that expires ttl_secs after its latest entry
+    fn retain_response<'a>(
+        &'a mut self,
+        key: &'a str,
+        message: &'a str,
+        cap: usize,
+        ttl_secs: u64,
+    ) -> BackendFuture<'a, ()>;
+}
+
+// How long an empty read waits before returning, so the processing loop doesn't spin
+const MEMORY_EMPTY_READ_MS: u64 = 50;
+
+#[derive(Default)]
+struct MemoryGroup {
+    last_delivered: Option<(u64, u64)>,
+    pending: BTreeSet<(u64, u64)>,
+}
+
+// In-process streams with Redis-like ids and consumer group bookkeeping (one consumer per
+// group). Pub/sub messages are dropped here; WebSocket clients still get them from the
+// processor's response broadcast. Nothing outlives the process.
+#[derive(Default)]
+pub struct MemoryBackend {
+    streams: HashMap<String, VecDeque<StreamId>>,
+    groups: HashMap<(String, String), MemoryGroup>,
+    last_id: (u64, u64),
+    // Entries kept in CAPPED_STREAM before the oldest are trimmed (exactly, unlike MAXLEN ~); 0 keeps all
+    max_len: usize,
+}
+
+impl MemoryBackend {
+    pub fn new(max_len: usize) -> Self {
+        Self {
+            max_len,
+            ..Self::default()
+        }
+    }
+
+    fn trim(&mut self, stream: &str) {
+        if self.max_len == 0 || stream != CAPPED_STREAM {
+            return;
+        }
+        if let Some(entries) = self.streams.get_mut(stream) {
+            while entries.len() > self.max_len {
+                entries.pop_front();
+            }
+        }
+    }
+
+    fn entries_after(&self, stream: &str, after: Option<(u64, u64)>) -> Vec<StreamId> {
+        self.streams
+            .get(stream)
+            .map(|entries| {
+                entries
+                    .iter()
+                    .filter(|entry| after.is_none_or(|after| id_of(entry) > after))
+                    .take(10)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+impl StreamBackend for MemoryBackend {
+    fn ping(&mut self) -> BackendFuture<'_, ()> {
+        Box::pin(async { Ok(()) })
+    }
+
+    // Ids are stamped in process, so there is no other clock to drift from
+    fn server_time_ms(&mut self) -> BackendFuture<'_, i64> {
+        Box::pin(async { Ok(chrono::Utc::now().timestamp_millis()) })
+    }
+
+    // Nothing predates the process, so "$" reads from the start instead of only new entries
+    fn read_stream<'a>(
+        &'a mut self,
+        stream: &'a str,
+        last_id: &'a str,
+    ) -> BackendFuture<'a, StreamReadReply> {
+        Box::pin(async move {
+            let entries = self.entries_after(stream, parse_stream_id(last_id));
+            if entries.is_empty() {
+                tokio::time::sleep(Duration::from_millis(MEMORY_EMPTY_READ_MS)).await;
+            }
+            Ok(reply(stream, entries))
+        })
+    }
+
+    fn ensure_group<'a>(
+        &'a mut self,
+        stream: &'a str,
+        group: &'a str,
+        start_id: &'a str,
+    ) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            // As with read_stream, "$" starts the group at the beginning of the stream
+            let last_delivered = parse_stream_id(start_id).filter(|id| *id != (0, 0));
+            self.streams.entry(stream.to_string()).or_default();
+            self.groups
+                .entry((stream.to_string(), group.to_string()))
+                .or_insert_with(|| MemoryGroup {
+                    last_delivered,
+                    pending: BTreeSet::new(),
+                });
+            Ok(())
+        })
+    }
+
+    fn read_group<'a>(
+        &'a mut self,
+        stream: &'a str,
+        group: &'a str,
+        _consumer: &'a str,
+        id: &'a str,
+    ) -> BackendFuture<'a, StreamReadReply> {
+        Box::pin(async move {
+            let key = (stream.to_string(), group.to_string());
+            let Some(state) = self.groups.get(&key) else {
+                return Err(anyhow::anyhow!("NOGROUP No such consumer group {}", group));
+            };
+
+            let entries = if id == ">" {
+                let entries = self.entries_after(stream, state.last_delivered);
+                if let Some(state) = self.groups.get_mut(&key) {
+                    for entry in &entries {
+                        state.pending.insert(id_of(entry));
+                        state.last_delivered = Some(id_of(entry));
+                    }
+                }
+                entries
+            } else {
+                let after = parse_stream_id(id).unwrap_or((0, 0));
+                self.streams
+                    .get(stream)
+                    .map(|entries| {
+                        entries
+                            .iter()
+                            .filter(|entry| {
+                                id_of(entry) > after && state.pending.contains(&id_of(entry))
+                            })
+                            .take(10)
+                            .cloned()
+                            .collect()
+                    })
+                    .unwrap_or_default()
+            };
+
+            if entries.is_empty() && id == ">" {
+                tokio::time::sleep(Duration::from_millis(MEMORY_EMPTY_READ_MS)).await;
+            }
+            Ok(reply(stream, entries))
+        })
+    }
+
+    fn ack<'a>(
+        &'a mut self,
+        stream: &'a str,
+        group: &'a str,
+        id: &'a str,
+    ) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            if let Some(state) = self
+                .groups
+                .get_mut(&(stream.to_string(), group.to_string()))
+                && let Some(id) = parse_stream_id(id)
+            {
+                state.pending.remove(&id);
+            }
+            Ok(())
+        })
+    }
+
+    fn ensure_stream<'a>(&'a mut self, stream: &'a str) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            self.streams.entry(stream.to_string()).or_default();
+            self.trim(stream);
+            Ok(())
+        })
+    }
+
+    fn add_to_stream<'a>(&'a mut self, stream: &'a str, data: &'a str) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            let now = chrono::Utc::now().timestamp_millis().max(0) as u64;
+            self.last_id = if now > self.last_id.0 {
+                (now, 0)
+            } else {
+                (self.last_id.0, self.last_id.1 + 1)
+            };
+
+            self.streams
+                .entry(stream.to_string())
+                .or_default()
+                .push_back(StreamId {
+                    id: format!("{}-{}", self.last_id.0, self.last_id.1),
+                    map: HashMap::from([(
+                        "data".to_string(),
+                        RedisValue::Data(data.as_bytes().to_vec()),
+                    )]),
+                });
+            self.trim(stream);
+            Ok(())
+        })
+    }
+
+    fn publisher<'a>(&'a mut self, _channel: &'a str, _message: &'a str) -> BackendFuture<'a, ()> {
+        Box::pin(async { Ok(()) })
+    }
+
+    // Like pub/sub, there is no one outside the process to replay these to
+    fn retain_response<'a>(
+        &'a mut self,
+        _key: &'a str,
+        _message: &'a str,
+        _cap: usize,
+        _ttl_secs: u64,
+    ) -> BackendFuture<'a, ()> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+fn id_of(entry: &StreamId) -> (u64, u64) {
+    parse_stream_id(&entry.id).unwrap_or((0, 0))
+}
+
+fn reply(stream: &str, ids: Vec<StreamId>) -> StreamReadReply {
+    if ids.is_empty() {
+        return StreamReadReply { keys: Vec::new() };
+    }
+    StreamReadReply {
+        keys: vec![StreamKey {
+            key: stream.to_string(),
+            ids,
+        }],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payloads(reply: &StreamReadReply) -> Vec<String> {
+        reply
+            .keys
+            .iter()
+            .flat_map(|key| &key.ids)
+            .map(|entry| match entry.map.get("data") {
+                Some(RedisValue::Data(bytes)) => String::from_utf8(bytes.clone()).unwrap(),
+                other => panic!("unexpected data field {:?}", other),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn entries_past_the_cap_are_trimmed_oldest_first() {
+        let mut backend = MemoryBackend::new(5);
+        backend.ensure_stream("orders").await.unwrap();
+        for i in 0..12 {
+            backend
+                .add_to_stream("orders", &format!("m{}", i))
+                .await
+                .unwrap();
+        }
+
+        let reply = backend.read_stream("orders", "0").await.unwrap();
+        assert_eq!(payloads(&reply), vec!["m7", "m8", "m9", "m10", "m11"]);
+
+        // Persistence records are never trimmed
+        for i in 0..8 {
+            backend
+                .add_to_stream("db_queue", &format!("r{}", i))
+                .await
+                .unwrap();
+        }
+        let reply = backend.read_stream("db_queue", "0").await.unwrap();
+        assert_eq!(payloads(&reply).len(), 8);
+    }
+}
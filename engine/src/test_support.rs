@@ -0,0 +1,210 @@
+//test_support.rs
+// Builders and a memory-backed processor harness shared by the unit tests
+use rust_decimal::Decimal;
+use serde_json::Value;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::{RwLock, broadcast};
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant};
+
+use crate::balance_manager::{AssetPrice, BalanceManager, Order};
+use crate::config::EngineConfig;
+use crate::processor::Processor;
+use crate::stream_backend::{MemoryBackend, StreamBackend};
+
+pub fn dec(value: &str) -> Decimal {
+    Decimal::from_str(value).unwrap()
+}
+
+// Defaults with everything that touches the filesystem or the clock switched off
+pub fn test_config() -> EngineConfig {
+    EngineConfig {
+        snapshot_enabled: false,
+        use_consumer_group: false,
+        db_spill_path: String::new(),
+        pause_file: String::new(),
+        ..EngineConfig::default()
+    }
+}
+
+// An unfilled BTC order; create_order fills in the price and quantity
+pub fn order(
+    order_id: &str,
+    user_id: &str,
+    order_type: &str,
+    margin: &str,
+    leverage: u32,
+) -> Order {
+    Order {
+        order_id: order_id.to_string(),
+        user_id: user_id.to_string(),
+        asset: "BTC".to_string(),
+        order_type: order_type.to_string(),
+        margin: dec(margin),
+        leverage,
+        open_price: Decimal::ZERO,
+        quantity: Decimal::ZERO,
+        timestamp: chrono::Utc::now().timestamp(),
+        breach_since: None,
+        breach_ticks: 0,
+        breach_tick_seq: 0,
+        client_ref: None,
+        extra_margin: Decimal::ZERO,
+        stream_id: None,
+        realized_pnl: Decimal::ZERO,
+        decimals: None,
+        tag: None,
+    }
+}
+
+// A BalanceManager with BTC quoted at buy/sell
+pub async fn manager_with_price(config: EngineConfig, buy: &str, sell: &str) -> BalanceManager {
+    let balance_manager = BalanceManager::new(config);
+    balance_manager
+        .update_price(price("BTC", buy, sell))
+        .await
+        .unwrap();
+    balance_manager
+}
+
+pub fn price(symbol: &str, buy: &str, sell: &str) -> AssetPrice {
+    AssetPrice {
+        symbol: symbol.to_string(),
+        buy_price: dec(buy),
+        sell_price: dec(sell),
+        decimals: 4,
+        mark_price: None,
+        ema_price: None,
+        tick_seq: 0,
+        updated_at: 0,
+        bid_size: None,
+        ask_size: None,
+    }
+}
+
+// A snapshot path under the temp dir that no other test uses
+pub fn temp_snapshot_path(name: &str) -> String {
+    std::env::temp_dir()
+        .join(format!("engine-test-{}-{}.json", name, std::process::id()))
+        .to_string_lossy()
+        .to_string()
+}
+
+// A processor over a MemoryBackend. Messages pushed before or after start() are consumed by
+// the real processing loop; responses are read off the processor's broadcast.
+pub struct Harness {
+    pub processor: Arc<Processor>,
+    pub balance_manager: Arc<BalanceManager>,
+    pub backend: Arc<RwLock<dyn StreamBackend>>,
+    responses: broadcast::Receiver<(String, String)>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl Harness {
+    pub fn new(config: EngineConfig) -> Self {
+        let backend: Arc<RwLock<dyn StreamBackend>> = Arc::new(RwLock::new(MemoryBackend::new(0)));
+        Self::with_backend(config, backend)
+    }
+
+    pub fn with_backend(config: EngineConfig, backend: Arc<RwLock<dyn StreamBackend>>) -> Self {
+        let balance_manager = Arc::new(BalanceManager::new(config.clone()));
+        let processor = Arc::new(Processor::new(
+            backend.clone(),
+            balance_manager.clone(),
+            config,
+        ));
+        let responses = processor.subscribe_responses();
+        Self {
+            processor,
+            balance_manager,
+            backend,
+            responses,
+            task: None,
+        }
+    }
+
+    pub async fn push(&self, message: Value) {
+        self.backend
+            .write()
+            .await
+            .add_to_stream("orders", &message.to_string())
+            .await
+            .unwrap();
+    }
+
+    pub async fn push_price(&self, symbol: &str, buy: &str, sell: &str) {
+        self.push(serde_json::json!({
+            "action": "LATEST_PRICE",
+            "symbol": symbol,
+            "buyPrice": buy,
+            "sellPrice": sell,
+            "decimals": 4
+        }))
+        .await;
+    }
+
+    // Loads the (disabled) snapshot and starts the processing loop in the background
+    pub async fn start(&mut self) {
+        self.processor.load_snapshot().await.unwrap();
+        let processor = self.processor.clone();
+        self.task = Some(tokio::spawn(async move {
+            let _ = processor.start_processing().await;
+        }));
+    }
+
+    // The next response published on `channel`, skipping others; panics after two seconds
+    pub async fn response(&mut self, channel: &str) -> Value {
+        let deadline = Instant::now() + Duration::from_secs(2);
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match tokio::time::timeout(remaining, self.responses.recv()).await {
+                Ok(Ok((received, payload))) if received == channel => {
+                    return serde_json::from_str(&payload).unwrap();
+                }
+                Ok(Ok(_)) => continue,
+                Ok(Err(e)) => panic!("response channel closed: {}", e),
+                Err(_) => panic!("no response on {} within 2s", channel),
+            }
+        }
+    }
+
+    // Whether anything is published on `channel` within `ms`
+    pub async fn no_response(&mut self, channel: &str, ms: u64) -> bool {
+        let deadline = Instant::now() + Duration::from_millis(ms);
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match tokio::time::timeout(remaining, self.responses.recv()).await {
+                Ok(Ok((received, _))) if received == channel => return false,
+                Ok(Ok(_)) => continue,
+                _ => return true,
+            }
+        }
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+    }
+}
+
+pub fn create_order_message(
+    order_id: &str,
+    user_id: &str,
+    order_type: &str,
+    margin: &str,
+) -> Value {
+    serde_json::json!({
+        "action": "CREATE_ORDER",
+        "orderId": order_id,
+        "user": user_id,
+        "asset": "BTC",
+        "type": order_type,
+        "margin": margin,
+        "leverage": 10,
+        "timestamp": chrono::Utc::now().timestamp()
+    })
+}
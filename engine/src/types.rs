@@ -1,16 +1,111 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::str::FromStr;
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+// Every action the orders stream accepts, spelled on the wire as e.g. "CREATE_ORDER".
+// Processor::process_message matches on this without a wildcard arm, so a new variant
+// doesn't compile until it has a handler.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum Action {
+    LatestPrice,
     CreateOrder,
     CloseOrder,
-    GetBalance,
+    CloseAtPrice,
+    CreateUser,
+    ResetAccount,
+    SpotBuy,
+    SpotSell,
     GetBalanceUsd,
+    GetBalance,
     GetSupportedAssets,
-    LatestPrice,
+    GetOrders,
+    GetEquityCurve,
+    GetPositions,
+    GetOrder,
+    GetTradeStatistics,
+    GetPriceHistory,
+    GetPrice,
+    GetMarginRequirements,
+    GetLeverageUtilization,
+    DisableAsset,
+    EnableAsset,
+    SetRiskProfile,
+    AdjustMargin,
+    GetStateExport,
+    GetPlatformStats,
+    Subscribe,
+    Unsubscribe,
 }
 
+impl Action {
+    // Requires adminToken to match config.admin_token
+    pub fn is_admin(self) -> bool {
+        matches!(
+            self,
+            Action::DisableAsset
+                | Action::EnableAsset
+                | Action::GetStateExport
+                | Action::GetPlatformStats
+                | Action::CloseAtPrice
+        )
+    }
+
+    // Mutates one user's account, so it runs under that user's lock
+    pub fn is_user_scoped(self) -> bool {
+        matches!(
+            self,
+            Action::CreateOrder
+                | Action::CloseOrder
+                | Action::CloseAtPrice
+                | Action::ResetAccount
+                | Action::SpotBuy
+                | Action::SpotSell
+                | Action::AdjustMargin
+                | Action::SetRiskProfile
+        )
+    }
+
+    // Names an existing order by orderId; its lock belongs to the order's owner, whatever
+    // user the message claims
+    pub fn is_order_addressed(self) -> bool {
+        matches!(
+            self,
+            Action::CloseOrder | Action::CloseAtPrice | Action::AdjustMargin
+        )
+    }
+
+    // Read-only, so it is accepted on config.query_stream and never changes engine state
+    pub fn is_query(self) -> bool {
+        matches!(
+            self,
+            Action::GetBalanceUsd
+                | Action::GetBalance
+                | Action::GetSupportedAssets
+                | Action::GetOrders
+                | Action::GetEquityCurve
+                | Action::GetPositions
+                | Action::GetOrder
+                | Action::GetTradeStatistics
+                | Action::GetPriceHistory
+                | Action::GetPrice
+                | Action::GetMarginRequirements
+                | Action::GetLeverageUtilization
+                | Action::GetPlatformStats
+        )
+    }
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_value(serde_json::Value::String(s.to_string()))
+            .map_err(|_| format!("Unknown action: {}", s))
+    }
+}
+
+#[allow(dead_code)]
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Order {
     pub action: Action,
@@ -26,6 +121,7 @@ pub struct Order {
     pub decimals: Option<u32>,
 }
 
+#[allow(dead_code)]
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct OpenOrder {
     pub order_id: String,
@@ -38,6 +134,7 @@ pub struct OpenOrder {
     pub open_time: u64,
 }
 
+#[allow(dead_code)]
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct PriceData {
     pub buy_price: f64,
@@ -45,6 +142,7 @@ pub struct PriceData {
     pub decimals: u32,
 }
 
+#[allow(dead_code)]
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Snapshot {
     pub open_orders: Vec<OpenOrder>,
@@ -53,6 +151,7 @@ pub struct Snapshot {
     pub last_offset: String,
 }
 
+#[allow(dead_code)]
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Response {
     pub action: String,
@@ -60,3 +159,87 @@ pub struct Response {
     pub data: serde_json::Value,
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // No wildcard arm, so a new variant fails to compile here until it is listed, just as
+    // process_message fails until it has a handler
+    fn wire_name(action: Action) -> &'static str {
+        match action {
+            Action::LatestPrice => "LATEST_PRICE",
+            Action::CreateOrder => "CREATE_ORDER",
+            Action::CloseOrder => "CLOSE_ORDER",
+            Action::CloseAtPrice => "CLOSE_AT_PRICE",
+            Action::CreateUser => "CREATE_USER",
+            Action::ResetAccount => "RESET_ACCOUNT",
+            Action::SpotBuy => "SPOT_BUY",
+            Action::SpotSell => "SPOT_SELL",
+            Action::GetBalanceUsd => "GET_BALANCE_USD",
+            Action::GetBalance => "GET_BALANCE",
+            Action::GetSupportedAssets => "GET_SUPPORTED_ASSETS",
+            Action::GetOrders => "GET_ORDERS",
+            Action::GetEquityCurve => "GET_EQUITY_CURVE",
+            Action::GetPositions => "GET_POSITIONS",
+            Action::GetOrder => "GET_ORDER",
+            Action::GetTradeStatistics => "GET_TRADE_STATISTICS",
+            Action::GetPriceHistory => "GET_PRICE_HISTORY",
+            Action::GetPrice => "GET_PRICE",
+            Action::GetMarginRequirements => "GET_MARGIN_REQUIREMENTS",
+            Action::GetLeverageUtilization => "GET_LEVERAGE_UTILIZATION",
+            Action::DisableAsset => "DISABLE_ASSET",
+            Action::EnableAsset => "ENABLE_ASSET",
+            Action::SetRiskProfile => "SET_RISK_PROFILE",
+            Action::AdjustMargin => "ADJUST_MARGIN",
+            Action::GetStateExport => "GET_STATE_EXPORT",
+            Action::GetPlatformStats => "GET_PLATFORM_STATS",
+            Action::Subscribe => "SUBSCRIBE",
+            Action::Unsubscribe => "UNSUBSCRIBE",
+        }
+    }
+
+    #[test]
+    fn every_action_parses_from_its_wire_name_and_unknown_ones_do_not() {
+        let actions = [
+            Action::LatestPrice,
+            Action::CreateOrder,
+            Action::CloseOrder,
+            Action::CloseAtPrice,
+            Action::CreateUser,
+            Action::ResetAccount,
+            Action::SpotBuy,
+            Action::SpotSell,
+            Action::GetBalanceUsd,
+            Action::GetBalance,
+            Action::GetSupportedAssets,
+            Action::GetOrders,
+            Action::GetEquityCurve,
+            Action::GetPositions,
+            Action::GetOrder,
+            Action::GetTradeStatistics,
+            Action::GetPriceHistory,
+            Action::GetPrice,
+            Action::GetMarginRequirements,
+            Action::GetLeverageUtilization,
+            Action::DisableAsset,
+            Action::EnableAsset,
+            Action::SetRiskProfile,
+            Action::AdjustMargin,
+            Action::GetStateExport,
+            Action::GetPlatformStats,
+            Action::Subscribe,
+            Action::Unsubscribe,
+        ];
+        for action in actions {
+            let name = wire_name(action);
+            assert_eq!(name.parse::<Action>(), Ok(action));
+            assert_eq!(serde_json::to_value(action).unwrap(), name);
+        }
+
+        assert_eq!(
+            "CREATE_ORDERS".parse::<Action>(),
+            Err("Unknown action: CREATE_ORDERS".to_string())
+        );
+        assert!("create_order".parse::<Action>().is_err());
+    }
+}
@@ -0,0 +1,134 @@
+//ws_gateway.rs
+use anyhow::Result;
+use futures_util::{SinkExt, StreamExt};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{error, info, warn};
+
+use crate::processor::Processor;
+
+// Pushes responses straight to WebSocket clients. A client subscribes to the channels it
+// cares about (requestId / orderId / user) by sending {"subscribe": ["<channel>", ...]} and
+// then receives every payload published on those channels, exactly as sent over Redis.
+pub async fn serve_ws(processor: Arc<Processor>, addr: String) -> Result<()> {
+    let listener = TcpListener::bind(&addr).await?;
+    info!("WebSocket gateway listening on {}", addr);
+
+    loop {
+        let (socket, peer) = listener.accept().await?;
+        let processor = processor.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(processor, socket).await {
+                warn!("WebSocket client {} disconnected: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle_connection(processor: Arc<Processor>, socket: TcpStream) -> Result<()> {
+    let ws_stream = tokio_tungstenite::accept_async(socket).await?;
+    let (mut sink, mut stream) = ws_stream.split();
+    let mut responses = processor.subscribe_responses();
+    let mut channels: HashSet<String> = HashSet::new();
+
+    loop {
+        tokio::select! {
+            incoming = stream.next() => {
+                match incoming {
+                    Some(Ok(Message::Text(text))) => {
+                        channels.extend(parse_subscription(&text));
+                    }
+                    Some(Ok(Message::Ping(payload))) => {
+                        sink.send(Message::Pong(payload)).await?;
+                    }
+                    Some(Ok(Message::Close(_))) | None => return Ok(()),
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => return Err(e.into()),
+                }
+            }
+            published = responses.recv() => {
+                match published {
+                    Ok((channel, payload)) => {
+                        if channels.contains(&channel) {
+                            sink.send(Message::Text(payload)).await?;
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        error!("WebSocket client lagged, dropped {} responses", skipped);
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                }
+            }
+        }
+    }
+}
+
+// Accepts {"subscribe": "<channel>"} or {"subscribe": ["<channel>", ...]}
+fn parse_subscription(text: &str) -> Vec<String> {
+    let Ok(message) = serde_json::from_str::<Value>(text) else {
+        return Vec::new();
+    };
+
+    match message.get("subscribe") {
+        Some(Value::String(channel)) => vec![channel.clone()],
+        Some(Value::Array(channels)) => channels
+            .iter()
+            .filter_map(|c| c.as_str().map(str::to_string))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+    use tokio::time::{Duration, sleep, timeout};
+
+    #[tokio::test]
+    async fn a_subscribed_client_receives_the_order_response() {
+        let mut harness = Harness::new(test_config());
+        let addr = {
+            let probe = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            probe.local_addr().unwrap().to_string()
+        };
+        let gateway = tokio::spawn(serve_ws(harness.processor.clone(), addr.clone()));
+
+        let mut client = None;
+        for _ in 0..50 {
+            if let Ok((ws, _)) = tokio_tungstenite::connect_async(format!("ws://{}", addr)).await {
+                client = Some(ws);
+                break;
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+        let mut client = client.expect("gateway never accepted a connection");
+        client
+            .send(Message::Text(r#"{"subscribe": ["o1"]}"#.to_string()))
+            .await
+            .unwrap();
+        // The subscription has no ack; give the gateway a moment to register it
+        sleep(Duration::from_millis(100)).await;
+
+        harness.push_price("BTC", "1000", "999").await;
+        harness
+            .push(create_order_message("o1", "u1", "long", "100"))
+            .await;
+        harness.start().await;
+
+        let received = timeout(Duration::from_secs(2), client.next())
+            .await
+            .expect("no message within 2s")
+            .unwrap()
+            .unwrap();
+        let response: Value = serde_json::from_str(received.to_text().unwrap()).unwrap();
+        assert_eq!(response["action"], "ORDER_SUCCESS");
+        assert_eq!(response["data"]["orderId"], "o1");
+        gateway.abort();
+    }
+}